@@ -0,0 +1,575 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use crate::datastore::store::DataItem;
+use crate::helpers::Helper;
+use crate::rdb::rdb::RDBFileHelper;
+use crate::server::interpreter::Reply;
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum AppendFsync {
+    Always,
+    EverySec,
+    No
+}
+
+impl AppendFsync {
+    pub fn parse(value: &str) -> Result<AppendFsync, String> {
+        match value.to_lowercase().as_str() {
+            "always" => Ok(AppendFsync::Always),
+            "everysec" => Ok(AppendFsync::EverySec),
+            "no" => Ok(AppendFsync::No),
+            _ => Err("argument(s) must be one of the following: always, everysec, no".to_string())
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AppendFsync::Always => "always",
+            AppendFsync::EverySec => "everysec",
+            AppendFsync::No => "no"
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum AOFFileType {
+    Base,
+    Incr,
+    History
+}
+
+impl AOFFileType {
+    fn marker(&self) -> &'static str {
+        match self {
+            AOFFileType::Base => "b",
+            AOFFileType::Incr => "i",
+            AOFFileType::History => "h"
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct AOFInfo {
+    pub file_name: String,
+    pub seq: u64,
+    pub file_type: AOFFileType
+}
+
+/// Lists the files making up the append only log, in the same format as redis 7:
+/// one `file <name> seq <n> type <b|i|h>` line per file. The base file holds a compacted copy of
+/// the dataset, the incremental files hold the commands executed since, in order.
+#[derive(Debug,Clone)]
+pub struct AOFManifest {
+    pub base: Option<AOFInfo>,
+    pub incrs: Vec<AOFInfo>,
+    pub history: Vec<AOFInfo>,
+    curr_base_seq: u64,
+    curr_incr_seq: u64
+}
+
+impl AOFManifest {
+    fn new() -> Self {
+        Self {
+            base: None,
+            incrs: vec![],
+            history: vec![],
+            curr_base_seq: 0,
+            curr_incr_seq: 0
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("Can't read the AOF manifest {:?}: {}", path, e))?;
+        let mut manifest = Self::new();
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let (mut file_name, mut seq, mut file_type) = (None, None, None);
+            for pair in words.chunks(2) {
+                match pair {
+                    ["file", name] => file_name = Some(name.to_string()),
+                    ["seq", n] => seq = n.parse::<u64>().ok(),
+                    ["type", "b"] => file_type = Some(AOFFileType::Base),
+                    ["type", "i"] => file_type = Some(AOFFileType::Incr),
+                    ["type", "h"] => file_type = Some(AOFFileType::History),
+                    _ => {}
+                }
+            }
+            let info = match (file_name, seq, file_type) {
+                (Some(file_name), Some(seq), Some(file_type)) => AOFInfo { file_name, seq, file_type },
+                _ => return Err(format!("Invalid AOF manifest line: {}", line))
+            };
+            match info.file_type {
+                AOFFileType::Base => {
+                    manifest.curr_base_seq = info.seq;
+                    manifest.base = Some(info);
+                },
+                AOFFileType::Incr => {
+                    manifest.curr_incr_seq = manifest.curr_incr_seq.max(info.seq);
+                    manifest.incrs.push(info);
+                },
+                AOFFileType::History => {
+                    manifest.history.push(info);
+                }
+            }
+        }
+        Ok(manifest)
+    }
+
+    fn encode(&self) -> String {
+        let mut content = String::new();
+        for info in self.base.iter().chain(self.history.iter()).chain(self.incrs.iter()) {
+            content.push_str(&format!("file {} seq {} type {}\n", info.file_name, info.seq, info.file_type.marker()));
+        }
+        content
+    }
+
+    fn persist(&self, path: &Path) -> std::io::Result<()> {
+        let temp_path = path.with_file_name(format!("temp-{}", path.file_name().unwrap_or_default().to_string_lossy()));
+        let mut file = File::create(&temp_path)?;
+        file.write_all(self.encode().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)
+    }
+
+    /// Every file that has to be replayed to rebuild the dataset, base first.
+    pub fn files(&self) -> Vec<AOFInfo> {
+        self.base.iter().chain(self.incrs.iter()).cloned().collect()
+    }
+}
+
+/// The append only log: every write command is appended in RESP form to the current incremental
+/// file so the dataset can be rebuilt by replaying the log on startup.
+pub struct AppendOnlyFile {
+    dir: PathBuf,
+    file_name: String,
+    manifest: AOFManifest,
+    file: File,
+    buffer: Vec<u8>,
+    current_size: u64,
+    base_size: u64,
+    last_write_ok: bool,
+    last_fsync: Instant,
+    fsync_pending: bool,
+    fsync_child: Option<JoinHandle<std::io::Result<()>>>,
+    delayed_fsync: u64,
+    rewrite_child: Option<JoinHandle<std::io::Result<u64>>>,
+    rewrite_started: Option<Instant>,
+    rewrite_incr_seq: u64,
+    rewrite_use_rdb: bool,
+    pub rewrite_scheduled: bool,
+    last_rewrite_ok: bool,
+    last_rewrite_time_sec: Option<u64>,
+    rewrites: u64
+}
+
+impl AppendOnlyFile {
+    pub fn manifest_path(dir: &Path, file_name: &str) -> PathBuf {
+        dir.join(format!("{}.manifest", file_name))
+    }
+
+    /// Whether there is something to load: a manifest, or a single file written before the log
+    /// was split in several parts.
+    pub fn exists(dir: &Path, legacy_path: &Path, file_name: &str) -> bool {
+        Self::manifest_path(dir, file_name).exists() || legacy_path.exists()
+    }
+
+    /// Returns the manifest describing the log in `dir`. A single file log from before the
+    /// multi part layout is moved into `dir` and becomes the base of a new manifest.
+    pub fn resolve_manifest(dir: &Path, legacy_path: &Path, file_name: &str) -> Result<AOFManifest, String> {
+        let manifest_path = Self::manifest_path(dir, file_name);
+        if manifest_path.exists() {
+            return AOFManifest::load(&manifest_path);
+        }
+        let mut manifest = AOFManifest::new();
+        if legacy_path.exists() {
+            fs::create_dir_all(dir).map_err(|e| format!("Can't create the AOF directory {:?}: {}", dir, e))?;
+            fs::rename(legacy_path, dir.join(file_name))
+                .map_err(|e| format!("Can't move {:?} to the AOF directory: {}", legacy_path, e))?;
+            manifest.base = Some(AOFInfo { file_name: file_name.to_string(), seq: 1, file_type: AOFFileType::Base });
+            manifest.curr_base_seq = 1;
+            manifest.persist(&manifest_path).map_err(|e| format!("Can't write the AOF manifest: {}", e))?;
+            println!("Upgraded the append only file {:?} to the multi part layout in {:?}", legacy_path, dir);
+        }
+        Ok(manifest)
+    }
+
+    /// Opens the log for appending, starting a new incremental file when the manifest has none.
+    pub fn open(dir: &Path, legacy_path: &Path, file_name: &str) -> Result<Self, String> {
+        fs::create_dir_all(dir).map_err(|e| format!("Can't create the AOF directory {:?}: {}", dir, e))?;
+        let mut manifest = Self::resolve_manifest(dir, legacy_path, file_name)?;
+        let mut created_incr = false;
+        if manifest.incrs.is_empty() {
+            manifest.curr_incr_seq += 1;
+            manifest.incrs.push(AOFInfo {
+                file_name: format!("{}.{}.incr.aof", file_name, manifest.curr_incr_seq),
+                seq: manifest.curr_incr_seq,
+                file_type: AOFFileType::Incr
+            });
+            created_incr = true;
+        }
+        let incr_path = dir.join(&manifest.incrs.last().unwrap().file_name);
+        let file = OpenOptions::new().create(true).append(true).open(&incr_path)
+            .map_err(|e| format!("Can't open the append only file {:?}: {}", incr_path, e))?;
+        if created_incr {
+            manifest.persist(&Self::manifest_path(dir, file_name)).map_err(|e| format!("Can't write the AOF manifest: {}", e))?;
+        }
+        let current_size: u64 = manifest.files().iter()
+            .map(|x| fs::metadata(dir.join(&x.file_name)).map(|m| m.len()).unwrap_or(0))
+            .sum();
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            file_name: file_name.to_string(),
+            manifest,
+            file,
+            buffer: vec![],
+            current_size,
+            base_size: current_size,
+            last_write_ok: true,
+            last_fsync: Instant::now(),
+            fsync_pending: false,
+            fsync_child: None,
+            delayed_fsync: 0,
+            rewrite_child: None,
+            rewrite_started: None,
+            rewrite_incr_seq: 0,
+            rewrite_use_rdb: true,
+            rewrite_scheduled: false,
+            last_rewrite_ok: true,
+            last_rewrite_time_sec: None,
+            rewrites: 0
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn file_name(&self) -> &str {
+        &self.file_name
+    }
+
+    /// Appends a command to the log. The data reaches the kernel right away, when it reaches the
+    /// disk depends on the fsync policy.
+    pub fn feed(&mut self, argv: &[String], fsync_policy: &AppendFsync) {
+        self.buffer.extend_from_slice(Self::encode_command(argv).as_bytes());
+        self.flush(fsync_policy);
+    }
+
+    pub fn flush(&mut self, fsync_policy: &AppendFsync) {
+        if !self.buffer.is_empty() {
+            match self.file.write_all(&self.buffer) {
+                Ok(()) => {
+                    self.current_size += self.buffer.len() as u64;
+                    self.buffer.clear();
+                    self.last_write_ok = true;
+                    self.fsync_pending = true;
+                },
+                Err(e) => {
+                    // Keep the buffer around, the write is retried on the next flush
+                    if self.last_write_ok {
+                        println!("ERROR Writing to the AOF: {}", e);
+                    }
+                    self.last_write_ok = false;
+                    return;
+                }
+            }
+        }
+        if *fsync_policy == AppendFsync::Always && self.fsync_pending {
+            if let Err(e) = self.file.sync_data() {
+                println!("ERROR Can't fsync the AOF: {}", e);
+            }
+            self.fsync_pending = false;
+            self.last_fsync = Instant::now();
+        }
+    }
+
+    /// Called periodically. With `appendfsync everysec` the file is synced from a separate thread
+    /// about once a second so a slow disk never blocks the event loop, finished rewrites are
+    /// installed here as well.
+    pub fn cron(&mut self, fsync_policy: &AppendFsync) {
+        self.flush(fsync_policy);
+        if self.rewrite_child.as_ref().is_some_and(|x| x.is_finished()) {
+            self.finish_rewrite();
+        }
+        if self.fsync_child.as_ref().is_some_and(|x| x.is_finished()) {
+            if let Ok(Err(e)) = self.fsync_child.take().unwrap().join() {
+                println!("ERROR Can't fsync the AOF in the background: {}", e);
+            }
+        }
+        if *fsync_policy != AppendFsync::EverySec || !self.fsync_pending {
+            return;
+        }
+        if self.last_fsync.elapsed() < Duration::from_secs(1) {
+            return;
+        }
+        if self.fsync_child.is_some() {
+            self.delayed_fsync += 1;
+            return;
+        }
+        if let Ok(file) = self.file.try_clone() {
+            self.fsync_child = Some(std::thread::spawn(move || file.sync_data()));
+            self.fsync_pending = false;
+            self.last_fsync = Instant::now();
+        }
+    }
+
+    pub fn rewrite_in_progress(&self) -> bool {
+        self.rewrite_child.is_some()
+    }
+
+    /// Whether the log grew enough since the last rewrite for `auto-aof-rewrite-percentage` and
+    /// `auto-aof-rewrite-min-size` to ask for a new one.
+    pub fn should_auto_rewrite(&self, percentage: u64, min_size: u64) -> bool {
+        if percentage == 0 || self.rewrite_in_progress() || self.current_size < min_size {
+            return false;
+        }
+        let base = self.base_size.max(1);
+        let growth = (self.current_size.saturating_sub(base)) * 100 / base;
+        growth >= percentage
+    }
+
+    /// Starts compacting the log. New writes go to a fresh incremental file from now on while the
+    /// dataset, as it is right now, is written to a new base file from a separate thread.
+    pub fn start_rewrite(&mut self, data: &HashMap<String, DataItem>, functions: &[String], use_rdb_preamble: bool) -> Result<(), String> {
+        if self.rewrite_in_progress() {
+            return Err("Background append only file rewriting already in progress".to_string());
+        }
+        self.flush(&AppendFsync::Always);
+
+        let seq = self.manifest.curr_incr_seq + 1;
+        let incr = AOFInfo {
+            file_name: format!("{}.{}.incr.aof", self.file_name, seq),
+            seq,
+            file_type: AOFFileType::Incr
+        };
+        let file = OpenOptions::new().create(true).append(true).open(self.dir.join(&incr.file_name))
+            .map_err(|e| format!("Can't open a new incremental AOF: {}", e))?;
+        let mut manifest = self.manifest.clone();
+        manifest.curr_incr_seq = seq;
+        manifest.incrs.push(incr);
+        manifest.persist(&Self::manifest_path(&self.dir, &self.file_name))
+            .map_err(|e| format!("Can't write the AOF manifest: {}", e))?;
+        self.manifest = manifest;
+        self.file = file;
+        self.rewrite_incr_seq = seq;
+        self.rewrite_use_rdb = use_rdb_preamble;
+
+        let snapshot = data.clone();
+        let functions = functions.to_vec();
+        let temp_path = self.dir.join(format!("temp-rewriteaof-bg-{}.aof", std::process::id()));
+        self.rewrite_started = Some(Instant::now());
+        self.rewrite_scheduled = false;
+        self.rewrite_child = Some(std::thread::spawn(move || {
+            let content = if use_rdb_preamble {
+                RDBFileHelper::encode(&snapshot, &functions)
+            } else {
+                let mut content: Vec<u8> = vec![];
+                for code in &functions {
+                    content.extend_from_slice(Self::encode_command(&["FUNCTION".to_string(), "LOAD".to_string(), code.to_string()]).as_bytes());
+                }
+                content.extend(Self::dataset_commands(&snapshot));
+                content
+            };
+            let mut file = File::create(&temp_path)?;
+            file.write_all(&content)?;
+            file.sync_all()?;
+            Ok(content.len() as u64)
+        }));
+        Ok(())
+    }
+
+    /// Installs the base written by a finished rewrite. The old base and the incremental files it
+    /// replaces become history and are removed once the new manifest is on disk.
+    fn finish_rewrite(&mut self) {
+        let result = self.rewrite_child.take().unwrap().join();
+        self.last_rewrite_time_sec = self.rewrite_started.take().map(|x| x.elapsed().as_secs());
+        let temp_path = self.dir.join(format!("temp-rewriteaof-bg-{}.aof", std::process::id()));
+        let base_size = match result {
+            Ok(Ok(size)) => size,
+            Ok(Err(e)) => {
+                println!("ERROR Background AOF rewrite failed: {}", e);
+                let _ = fs::remove_file(&temp_path);
+                self.last_rewrite_ok = false;
+                return;
+            },
+            Err(_) => {
+                println!("ERROR Background AOF rewrite thread panicked");
+                let _ = fs::remove_file(&temp_path);
+                self.last_rewrite_ok = false;
+                return;
+            }
+        };
+
+        let mut manifest = self.manifest.clone();
+        manifest.curr_base_seq += 1;
+        let base = AOFInfo {
+            file_name: format!("{}.{}.base.{}", self.file_name, manifest.curr_base_seq, if self.rewrite_use_rdb { "rdb" } else { "aof" }),
+            seq: manifest.curr_base_seq,
+            file_type: AOFFileType::Base
+        };
+        if let Err(e) = fs::rename(&temp_path, self.dir.join(&base.file_name)) {
+            println!("ERROR Can't install the rewritten AOF base: {}", e);
+            self.last_rewrite_ok = false;
+            return;
+        }
+        if let Some(mut old_base) = manifest.base.replace(base) {
+            old_base.file_type = AOFFileType::History;
+            manifest.history.push(old_base);
+        }
+        let rewrite_incr_seq = self.rewrite_incr_seq;
+        let (kept, replaced): (Vec<AOFInfo>, Vec<AOFInfo>) = manifest.incrs.drain(..).partition(|x| x.seq >= rewrite_incr_seq);
+        manifest.incrs = kept;
+        manifest.history.extend(replaced.into_iter().map(|mut x| {
+            x.file_type = AOFFileType::History;
+            x
+        }));
+        if let Err(e) = manifest.persist(&Self::manifest_path(&self.dir, &self.file_name)) {
+            println!("ERROR Can't write the AOF manifest: {}", e);
+            let _ = fs::remove_file(self.dir.join(&manifest.base.as_ref().unwrap().file_name));
+            self.last_rewrite_ok = false;
+            return;
+        }
+        for history in manifest.history.drain(..) {
+            let _ = fs::remove_file(self.dir.join(&history.file_name));
+        }
+        let _ = manifest.persist(&Self::manifest_path(&self.dir, &self.file_name));
+        self.manifest = manifest;
+        self.current_size = self.manifest.files().iter()
+            .map(|x| fs::metadata(self.dir.join(&x.file_name)).map(|m| m.len()).unwrap_or(0))
+            .sum::<u64>()
+            .max(base_size);
+        self.base_size = self.current_size;
+        self.last_rewrite_ok = true;
+        self.rewrites += 1;
+        println!("Background AOF rewrite finished successfully");
+    }
+
+    pub fn encode_command(argv: &[String]) -> String {
+        Helper::build_resp(&Reply::ReplyArray(argv.iter().map(|x| Reply::ReplyBulkString(x.to_string())).collect()))
+    }
+
+    /// The shortest command sequence that recreates the dataset, expiries are written as absolute
+    /// timestamps so replaying the file later restores the same deadlines.
+    pub fn dataset_commands<'a>(data: impl IntoIterator<Item = (&'a String, &'a DataItem)>) -> Vec<u8> {
+        let mut content: Vec<u8> = vec![];
+        for (key, item) in data {
+            let mut argv = vec!["SET".to_string(), key.to_string(), item.data.to_string()];
+            if let Some(expiry) = item.expiry {
+                argv.push("PXAT".to_string());
+                argv.push(expiry.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis().to_string());
+            }
+            content.extend_from_slice(Self::encode_command(&argv).as_bytes());
+        }
+        content
+    }
+
+    pub fn info(&self) -> String {
+        let current_rewrite_time_sec = match self.rewrite_started {
+            Some(started) => started.elapsed().as_secs() as i64,
+            None => -1
+        };
+        let last_rewrite_time_sec = match self.last_rewrite_time_sec {
+            Some(x) => x as i64,
+            None => -1
+        };
+        format!(
+            "aof_rewrite_in_progress:{}\r\naof_rewrite_scheduled:{}\r\naof_last_rewrite_time_sec:{}\r\naof_current_rewrite_time_sec:{}\r\naof_last_bgrewrite_status:{}\r\naof_rewrites:{}\r\naof_last_write_status:{}\r\naof_current_size:{}\r\naof_base_size:{}\r\naof_buffer_length:{}\r\naof_delayed_fsync:{}\r\n",
+            self.rewrite_in_progress() as u8,
+            self.rewrite_scheduled as u8,
+            last_rewrite_time_sec,
+            current_rewrite_time_sec,
+            if self.last_rewrite_ok { "ok" } else { "err" },
+            self.rewrites,
+            if self.last_write_ok { "ok" } else { "err" },
+            self.current_size,
+            self.base_size,
+            self.buffer.len(),
+            self.delayed_fsync
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory of its own for every test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("aof-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn manifest_load_encode_roundtrip() {
+        let dir = temp_dir("roundtrip");
+        let path = dir.join("appendonly.aof.manifest");
+        let content = "file appendonly.aof.2.base.rdb seq 2 type b\n\
+                       file appendonly.aof.1.base.rdb seq 1 type h\n\
+                       file appendonly.aof.3.incr.aof seq 3 type i\n\
+                       file appendonly.aof.4.incr.aof seq 4 type i\n";
+        fs::write(&path, content).unwrap();
+
+        let manifest = AOFManifest::load(&path).unwrap();
+        assert_eq!(manifest.base.as_ref().unwrap().file_name, "appendonly.aof.2.base.rdb");
+        assert_eq!(manifest.history.len(), 1);
+        assert_eq!((manifest.curr_base_seq, manifest.curr_incr_seq), (2, 4));
+        let files: Vec<String> = manifest.files().into_iter().map(|x| x.file_name).collect();
+        assert_eq!(files, vec!["appendonly.aof.2.base.rdb", "appendonly.aof.3.incr.aof", "appendonly.aof.4.incr.aof"]);
+        assert_eq!(manifest.encode(), content);
+
+        manifest.persist(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), content);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn manifest_skips_comments_and_ignores_unknown_fields() {
+        let dir = temp_dir("comments");
+        let path = dir.join("appendonly.aof.manifest");
+        fs::write(&path, "# written by hand\n\nfile a.aof seq 7 type i startoffset 10\n").unwrap();
+        let manifest = AOFManifest::load(&path).unwrap();
+        assert!(manifest.base.is_none());
+        assert_eq!((manifest.incrs[0].seq, manifest.incrs[0].file_type), (7, AOFFileType::Incr));
+        assert_eq!(manifest.encode(), "file a.aof seq 7 type i\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn manifest_rejects_invalid_lines() {
+        let dir = temp_dir("invalid");
+        let path = dir.join("appendonly.aof.manifest");
+        for line in ["file a.aof seq 1", "file a.aof seq x type i", "file a.aof seq 1 type z"] {
+            fs::write(&path, line).unwrap();
+            assert_eq!(AOFManifest::load(&path).unwrap_err(), format!("Invalid AOF manifest line: {}", line));
+        }
+        assert!(AOFManifest::load(&dir.join("missing")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn legacy_file_becomes_the_base() {
+        let dir = temp_dir("legacy");
+        let legacy_path = dir.join("appendonly.aof");
+        fs::write(&legacy_path, AppendOnlyFile::encode_command(&["SET".to_string(), "k".to_string(), "v".to_string()])).unwrap();
+        let aof_dir = dir.join("appendonlydir");
+
+        let manifest = AppendOnlyFile::resolve_manifest(&aof_dir, &legacy_path, "appendonly.aof").unwrap();
+        assert!(!legacy_path.exists());
+        assert_eq!(fs::read_to_string(aof_dir.join("appendonly.aof")).unwrap(), "*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n");
+        assert_eq!(manifest.encode(), "file appendonly.aof seq 1 type b\n");
+        let persisted = AOFManifest::load(&AppendOnlyFile::manifest_path(&aof_dir, "appendonly.aof")).unwrap();
+        assert_eq!(persisted.encode(), manifest.encode());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
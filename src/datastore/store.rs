@@ -1,31 +1,193 @@
-use std::{collections::HashMap, time::SystemTime};
+use std::{collections::{HashMap, HashSet}, time::SystemTime};
 
-#[derive(Debug)]
+use crate::scripting::functions::Functions;
+
+#[derive(Debug,Clone)]
 pub struct DataItem {
     pub data: String,
     pub expiry: Option<SystemTime>
 }
 
 pub struct DataStore {
-    pub memory: HashMap<String, DataItem>
+    pub memory: HashMap<String, DataItem>,
+    // The libraries of `FUNCTION LOAD`, they persist and replicate along with the keys
+    pub functions: Functions,
+    // Keys with an expiry, what the active expiry cycle looks at, and the position of each one.
+    // Removing one moves the last into its place
+    expires: Vec<String>,
+    expire_positions: HashMap<String, usize>,
+    // Where the active expiry cycle resumes
+    expire_cursor: usize,
+    // Clients watching each key with `WATCH`, and the keys each client watches along with the
+    // expiry they had at the time
+    watchers: HashMap<String, HashSet<u64>>,
+    watched_keys: HashMap<u64, Vec<(String, Option<SystemTime>)>>,
+    // Clients one of whose watched keys was modified since they started watching it
+    dirty_watchers: HashSet<u64>
 }
 
 impl DataStore {
     pub fn new() -> Self {
-        Self {
-            memory: HashMap::new()
+        Self::from_memory(HashMap::new())
+    }
+
+    pub fn from_memory(memory: HashMap<String, DataItem>) -> Self {
+        let mut store = Self {
+            memory: HashMap::new(),
+            functions: Functions::default(),
+            expires: vec![],
+            expire_positions: HashMap::new(),
+            expire_cursor: 0,
+            watchers: HashMap::new(),
+            watched_keys: HashMap::new(),
+            dirty_watchers: HashSet::new()
+        };
+        store.extend(memory);
+        store
+    }
+
+    fn add_expire(&mut self, key: &str) {
+        if !self.expire_positions.contains_key(key) {
+            self.expire_positions.insert(key.to_string(), self.expires.len());
+            self.expires.push(key.to_string());
+        }
+    }
+
+    fn remove_expire(&mut self, key: &str) {
+        if let Some(position) = self.expire_positions.remove(key) {
+            self.expires.swap_remove(position);
+            if let Some(moved) = self.expires.get(position) {
+                self.expire_positions.insert(moved.clone(), position);
+            }
+        }
+    }
+
+    /// Stores the value, returns whether the key is new.
+    pub fn set(&mut self, key: String, value: DataItem) -> bool {
+        self.touch(&key);
+        if value.expiry.is_some() {
+            self.add_expire(&key);
+        } else {
+            self.remove_expire(&key);
+        }
+        self.memory.insert(key, value).is_none()
+    }
+
+    /// Adds keys loaded from a file, without any client being around to watch them.
+    pub fn extend(&mut self, data: HashMap<String, DataItem>) {
+        for (key, item) in &data {
+            if item.expiry.is_some() {
+                self.add_expire(key);
+            }
         }
+        self.memory.extend(data);
     }
 
-    pub fn set(&mut self, key: String, value: DataItem) {
-        self.memory.insert(key, value);
+    /// Removes a key, like `DEL`.
+    pub fn delete(&mut self, key: &str) -> Option<DataItem> {
+        self.touch(key);
+        self.remove_expire(key);
+        self.memory.remove(key)
     }
 
     pub fn get(&self, key: String) -> Option<&DataItem> {
         self.memory.get(&key)
     }
 
-    pub fn remove(&mut self, key: String) -> Option<DataItem> {
-        self.memory.remove(&key)
+    /// Drops the key if its expiry passed, which is how reads see it, returns whether it did.
+    /// Clients that watched it while it was alive notice the expiry by themselves.
+    pub fn expire_if_needed(&mut self, key: &str) -> bool {
+        let expired = match self.memory.get(key).and_then(|x| x.expiry) {
+            Some(expiry) => expiry <= SystemTime::now(),
+            None => false
+        };
+        if expired {
+            self.remove_expire(key);
+            self.memory.remove(key);
+        }
+        expired
+    }
+
+    /// Looks at up to `lookups` keys with an expiry, resuming where the previous cycle stopped,
+    /// and drops the expired ones. Returns the keys dropped.
+    pub fn active_expire_cycle(&mut self, lookups: usize) -> Vec<String> {
+        let now = SystemTime::now();
+        let mut expired = vec![];
+        for _ in 0..lookups.min(self.expires.len()) {
+            if self.expire_cursor >= self.expires.len() {
+                self.expire_cursor = 0;
+            }
+            let key = &self.expires[self.expire_cursor];
+            if !matches!(self.memory.get(key).and_then(|x| x.expiry), Some(x) if x <= now) {
+                self.expire_cursor += 1;
+                continue;
+            }
+            // The last key takes the place of this one, it is looked at next
+            let key = key.clone();
+            self.remove_expire(&key);
+            self.memory.remove(&key);
+            expired.push(key);
+        }
+        expired
+    }
+
+    /// Empties the dataset, like `FLUSHDB`. Watched keys that existed count as modified.
+    pub fn flush(&mut self) {
+        self.replace(HashMap::new());
+    }
+
+    /// Swaps the whole dataset, for example with the one received from a master.
+    pub fn replace(&mut self, memory: HashMap<String, DataItem>) {
+        let keys: Vec<String> = self.watchers.keys()
+            .filter(|key| self.memory.contains_key(*key) || memory.contains_key(*key))
+            .cloned()
+            .collect();
+        for key in keys {
+            self.touch(&key);
+        }
+        self.expires.clear();
+        self.expire_positions.clear();
+        self.memory.clear();
+        self.extend(memory);
+    }
+
+    /// Flags the clients watching the key, their transaction will fail.
+    fn touch(&mut self, key: &str) {
+        if let Some(clients) = self.watchers.get(key) {
+            self.dirty_watchers.extend(clients);
+        }
+    }
+
+    pub fn watch(&mut self, key: &str, client_id: u64) {
+        let clients = self.watchers.entry(key.to_string()).or_default();
+        if !clients.insert(client_id) {
+            return;
+        }
+        // A key already expired doesn't count as modified when it is finally removed
+        let expiry = self.memory.get(key).and_then(|x| x.expiry).filter(|x| *x > SystemTime::now());
+        self.watched_keys.entry(client_id).or_default().push((key.to_string(), expiry));
+    }
+
+    pub fn unwatch(&mut self, client_id: u64) {
+        for (key, _) in self.watched_keys.remove(&client_id).unwrap_or_default() {
+            if let Some(clients) = self.watchers.get_mut(&key) {
+                clients.remove(&client_id);
+                if clients.is_empty() {
+                    self.watchers.remove(&key);
+                }
+            }
+        }
+        self.dirty_watchers.remove(&client_id);
+    }
+
+    /// Whether a key watched by the client was modified, or expired, since it started watching.
+    pub fn is_watch_dirty(&self, client_id: u64) -> bool {
+        if self.dirty_watchers.contains(&client_id) {
+            return true;
+        }
+        let now = SystemTime::now();
+        self.watched_keys.get(&client_id).is_some_and(|keys| {
+            keys.iter().any(|(_, expiry)| matches!(expiry, Some(x) if *x <= now))
+        })
     }
 }
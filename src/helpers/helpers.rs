@@ -7,9 +7,9 @@ impl Helper {
         match reply {
             Reply::ReplyString(s) => {
                 let mut response = String::from("+");
-                response.push_str(&s);
+                response.push_str(s);
                 response.push_str("\r\n");
-                return response;
+                response
             },
             Reply::ReplyArray(arr_data) => {
                 let mut response = String::from("*");
@@ -19,17 +19,267 @@ impl Helper {
                     let x = Helper::build_resp(d);
                     response.push_str(&x);
                 }
-                return response;
+                response
+            },
+            Reply::ReplyPush(arr_data) => {
+                let mut response = String::from(">");
+                response.push_str(&(arr_data.len()).to_string());
+                response.push_str("\r\n");
+                for d in arr_data {
+                    let x = Helper::build_resp(d);
+                    response.push_str(&x);
+                }
+                response
+            },
+            Reply::ReplyInteger(i) => {
+                format!(":{}\r\n", i)
+            },
+            Reply::ReplyNull => {
+                "$-1\r\n".to_string()
             },
             Reply::ReplyBulkString(s) => {
                 let mut response = String::from("$");
                 response.push_str(&(s.len()).to_string());
                 response.push_str("\r\n");
-                response.push_str(&s);
+                response.push_str(s);
                 response.push_str("\r\n");
-                return response;
+                response
+            }
+        }
+    }
+
+    /// Random lowercase hex characters, like the 40 of a replication id.
+    pub fn random_hex(length: usize) -> String {
+        let mut bytes = vec![0u8; length];
+        let read = std::fs::File::open("/dev/urandom").and_then(|mut x| std::io::Read::read_exact(&mut x, &mut bytes));
+        if read.is_err() {
+            // Without /dev/urandom, fall back on the randomly seeded hasher of the standard library
+            use std::hash::{BuildHasher, Hasher};
+            let state = std::collections::hash_map::RandomState::new();
+            for (i, chunk) in bytes.chunks_mut(8).enumerate() {
+                let mut hasher = state.build_hasher();
+                hasher.write_usize(i);
+                hasher.write_u128(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos());
+                chunk.copy_from_slice(&hasher.finish().to_le_bytes()[..chunk.len()]);
+            }
+        }
+        bytes.iter().map(|x| format!("{:02x}", x)).collect::<String>()[..length].to_string()
+    }
+
+    /// The SHA1 digest of `data` in lowercase hex, what scripts are named by.
+    pub fn sha1_hex(data: &[u8]) -> String {
+        let mut state: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+        let mut message = data.to_vec();
+        message.push(0x80);
+        while message.len() % 64 != 56 {
+            message.push(0);
+        }
+        message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+        for block in message.chunks(64) {
+            let mut w = [0u32; 80];
+            for i in 0..16 {
+                w[i] = u32::from_be_bytes([block[i * 4], block[i * 4 + 1], block[i * 4 + 2], block[i * 4 + 3]]);
+            }
+            for i in 16..80 {
+                w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+            }
+            let [mut a, mut b, mut c, mut d, mut e] = state;
+            for (i, word) in w.iter().enumerate() {
+                let (f, k) = match i {
+                    0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                    20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                    40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                    _ => (b ^ c ^ d, 0xCA62C1D6)
+                };
+                let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+                e = d;
+                d = c;
+                c = b.rotate_left(30);
+                b = a;
+                a = temp;
+            }
+            for (x, y) in state.iter_mut().zip([a, b, c, d, e]) {
+                *x = x.wrapping_add(y);
             }
         }
+        state.iter().map(|x| format!("{:08x}", x)).collect()
     }
 
+    /// The SHA256 digest of `data` in lowercase hex, what ACL passwords are stored as.
+    pub fn sha256_hex(data: &[u8]) -> String {
+        const K: [u32; 64] = [
+            0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+            0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+            0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+            0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+            0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+            0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+            0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+            0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+        ];
+        let mut state: [u32; 8] = [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19];
+        let mut message = data.to_vec();
+        message.push(0x80);
+        while message.len() % 64 != 56 {
+            message.push(0);
+        }
+        message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+        for block in message.chunks(64) {
+            let mut w = [0u32; 64];
+            for i in 0..16 {
+                w[i] = u32::from_be_bytes([block[i * 4], block[i * 4 + 1], block[i * 4 + 2], block[i * 4 + 3]]);
+            }
+            for i in 16..64 {
+                let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+                let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+                w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+            }
+            let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+            for (k, word) in K.iter().zip(w.iter()) {
+                let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+                let choice = (e & f) ^ (!e & g);
+                let temp1 = h.wrapping_add(s1).wrapping_add(choice).wrapping_add(*k).wrapping_add(*word);
+                let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+                let majority = (a & b) ^ (a & c) ^ (b & c);
+                let temp2 = s0.wrapping_add(majority);
+                h = g;
+                g = f;
+                f = e;
+                e = d.wrapping_add(temp1);
+                d = c;
+                c = b;
+                b = a;
+                a = temp1.wrapping_add(temp2);
+            }
+            for (x, y) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+                *x = x.wrapping_add(y);
+            }
+        }
+        state.iter().map(|x| format!("{:08x}", x)).collect()
+    }
+
+    /// Redis style glob matching supporting `*`, `?`, `[...]` classes and `\` escapes.
+    pub fn glob_match(pattern: &str, string: &str) -> bool {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let string: Vec<char> = string.chars().collect();
+        Helper::glob_match_chars(&pattern, &string)
+    }
+
+    fn glob_match_chars(pattern: &[char], string: &[char]) -> bool {
+        let (mut p, mut s) = (0, 0);
+        while p < pattern.len() {
+            match pattern[p] {
+                '*' => {
+                    while p + 1 < pattern.len() && pattern[p + 1] == '*' {
+                        p += 1;
+                    }
+                    if p + 1 == pattern.len() {
+                        return true;
+                    }
+                    return (s..=string.len()).any(|i| Helper::glob_match_chars(&pattern[p + 1..], &string[i..]));
+                },
+                '?' => {
+                    if s >= string.len() {
+                        return false;
+                    }
+                    s += 1;
+                },
+                '[' => {
+                    if s >= string.len() {
+                        return false;
+                    }
+                    p += 1;
+                    let negate = pattern.get(p) == Some(&'^');
+                    if negate {
+                        p += 1;
+                    }
+                    let mut matched = false;
+                    while p < pattern.len() && pattern[p] != ']' {
+                        if pattern[p] == '\\' && p + 1 < pattern.len() {
+                            p += 1;
+                            matched |= pattern[p] == string[s];
+                        } else if p + 2 < pattern.len() && pattern[p + 1] == '-' && pattern[p + 2] != ']' {
+                            let (start, end) = if pattern[p] <= pattern[p + 2] { (pattern[p], pattern[p + 2]) } else { (pattern[p + 2], pattern[p]) };
+                            matched |= start <= string[s] && string[s] <= end;
+                            p += 2;
+                        } else {
+                            matched |= pattern[p] == string[s];
+                        }
+                        p += 1;
+                    }
+                    if matched == negate {
+                        return false;
+                    }
+                    s += 1;
+                },
+                c => {
+                    let c = if c == '\\' && p + 1 < pattern.len() {
+                        p += 1;
+                        pattern[p]
+                    } else {
+                        c
+                    };
+                    if s >= string.len() || string[s] != c {
+                        return false;
+                    }
+                    s += 1;
+                }
+            }
+            p += 1;
+        }
+        s == string.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_match_wildcards() {
+        assert!(Helper::glob_match("*", ""));
+        assert!(Helper::glob_match("*", "anything"));
+        assert!(Helper::glob_match("user:*", "user:1000"));
+        assert!(!Helper::glob_match("user:*", "users:1000"));
+        assert!(Helper::glob_match("*:*:name", "user:42:name"));
+        assert!(Helper::glob_match("a**b", "ab"));
+        assert!(Helper::glob_match("h?llo", "hello"));
+        assert!(!Helper::glob_match("h?llo", "hllo"));
+        assert!(!Helper::glob_match("hello", "hello!"));
+    }
+
+    #[test]
+    fn glob_match_classes() {
+        assert!(Helper::glob_match("h[ae]llo", "hallo"));
+        assert!(!Helper::glob_match("h[ae]llo", "hillo"));
+        assert!(Helper::glob_match("h[^e]llo", "hallo"));
+        assert!(!Helper::glob_match("h[^e]llo", "hello"));
+        assert!(Helper::glob_match("h[a-c]llo", "hbllo"));
+        // Reversed ranges work too
+        assert!(Helper::glob_match("h[c-a]llo", "hbllo"));
+        assert!(!Helper::glob_match("h[a-c]llo", "hdllo"));
+        assert!(!Helper::glob_match("[a]", ""));
+    }
+
+    #[test]
+    fn glob_match_escapes() {
+        assert!(Helper::glob_match("what\\?", "what?"));
+        assert!(!Helper::glob_match("what\\?", "whats"));
+        assert!(Helper::glob_match("\\*", "*"));
+        assert!(!Helper::glob_match("\\*", "x"));
+        assert!(Helper::glob_match("[\\]]", "]"));
+        assert!(Helper::glob_match("é*", "éclair"));
+    }
+
+    #[test]
+    fn sha1_digest() {
+        assert_eq!(Helper::sha1_hex(b""), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(Helper::sha1_hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    }
+
+    #[test]
+    fn sha256_digest() {
+        assert_eq!(Helper::sha256_hex(b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(Helper::sha256_hex(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
 }
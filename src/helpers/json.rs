@@ -0,0 +1,235 @@
+// Deeper documents are refused
+const MAX_DEPTH: usize = 1000;
+
+/// A parsed JSON document. Strings are kept as bytes, scripts may decode binary ones.
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonValue {
+    Null,
+    Boolean(bool),
+    Number(f64),
+    String(Vec<u8>),
+    Array(Vec<JsonValue>),
+    // Members in the order of the document, a repeated key comes twice
+    Object(Vec<(Vec<u8>, JsonValue)>)
+}
+
+impl JsonValue {
+    /// The member `key` of an object, the last one when it is repeated.
+    pub fn get(&self, key: &str) -> Option<&JsonValue> {
+        match self {
+            Self::Object(members) => members.iter().rev().find(|(x, _)| x == key.as_bytes()).map(|(_, value)| value),
+            _ => None
+        }
+    }
+}
+
+/// Parses a whole JSON document. Errors read like the ones of Lua cjson, which scripts see.
+pub fn parse(text: &[u8]) -> Result<JsonValue, String> {
+    let mut parser = JsonParser { text, position: 0 };
+    let value = parser.value(0)?;
+    parser.skip_whitespace();
+    if parser.position < text.len() {
+        return Err(parser.unexpected("the end"));
+    }
+    Ok(value)
+}
+
+struct JsonParser<'a> {
+    text: &'a [u8],
+    position: usize
+}
+
+impl JsonParser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.position < self.text.len() && matches!(self.text[self.position], b' ' | b'\t' | b'\n' | b'\r') {
+            self.position += 1;
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> String {
+        let found = match self.text.get(self.position) {
+            Some(_) => "invalid token",
+            None => "T_END"
+        };
+        format!("Expected {} but found {} at character {}", expected, found, self.position + 1)
+    }
+
+    fn literal(&mut self, word: &[u8], value: JsonValue) -> Result<JsonValue, String> {
+        if self.text[self.position..].starts_with(word) {
+            self.position += word.len();
+            return Ok(value);
+        }
+        Err(self.unexpected("value"))
+    }
+
+    fn value(&mut self, depth: usize) -> Result<JsonValue, String> {
+        if depth >= MAX_DEPTH {
+            return Err(format!("Found too many nested data structures ({}) at character {}", depth + 1, self.position + 1));
+        }
+        self.skip_whitespace();
+        match self.text.get(self.position) {
+            Some(b'{') => self.object(depth),
+            Some(b'[') => self.array(depth),
+            Some(b'"') => Ok(JsonValue::String(self.string()?)),
+            Some(b't') => self.literal(b"true", JsonValue::Boolean(true)),
+            Some(b'f') => self.literal(b"false", JsonValue::Boolean(false)),
+            Some(b'n') => self.literal(b"null", JsonValue::Null),
+            Some(c) if *c == b'-' || c.is_ascii_digit() => self.number(),
+            _ => Err(self.unexpected("value"))
+        }
+    }
+
+    fn number(&mut self) -> Result<JsonValue, String> {
+        let start = self.position;
+        while self.position < self.text.len() && matches!(self.text[self.position], b'0'..=b'9' | b'-' | b'+' | b'.' | b'e' | b'E') {
+            self.position += 1;
+        }
+        let text = String::from_utf8_lossy(&self.text[start..self.position]);
+        match text.parse::<f64>() {
+            Ok(x) => Ok(JsonValue::Number(x)),
+            Err(_) => {
+                self.position = start;
+                Err(self.unexpected("value"))
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self.text.get(self.position..self.position + 4).and_then(|x| std::str::from_utf8(x).ok());
+        match digits.and_then(|x| u32::from_str_radix(x, 16).ok()) {
+            Some(x) => {
+                self.position += 4;
+                Ok(x)
+            },
+            None => Err(format!("Expected value but found invalid unicode escape code at character {}", self.position + 1))
+        }
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, String> {
+        let start = self.position;
+        self.position += 1;
+        let mut out = vec![];
+        loop {
+            let Some(&c) = self.text.get(self.position) else {
+                self.position = start;
+                return Err(self.unexpected("value"));
+            };
+            self.position += 1;
+            match c {
+                b'"' => return Ok(out),
+                b'\\' => {
+                    let escaped = self.text.get(self.position).copied().unwrap_or(0);
+                    self.position += 1;
+                    match escaped {
+                        b'"' | b'\\' | b'/' => out.push(escaped),
+                        b'b' => out.push(8),
+                        b'f' => out.push(12),
+                        b'n' => out.push(b'\n'),
+                        b'r' => out.push(b'\r'),
+                        b't' => out.push(b'\t'),
+                        b'u' => {
+                            let mut code = self.hex4()?;
+                            // Characters outside the basic plane come as a surrogate pair
+                            if (0xD800..0xDC00).contains(&code) && self.text[self.position..].starts_with(b"\\u") {
+                                self.position += 2;
+                                let low = self.hex4()?;
+                                code = 0x10000 + ((code - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF);
+                            }
+                            let c = char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER);
+                            let mut buffer = [0; 4];
+                            out.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+                        },
+                        _ => {
+                            self.position -= 2;
+                            return Err(self.unexpected("value"));
+                        }
+                    }
+                },
+                c => out.push(c)
+            }
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Result<JsonValue, String> {
+        self.position += 1;
+        let mut items = vec![];
+        self.skip_whitespace();
+        if self.text.get(self.position) == Some(&b']') {
+            self.position += 1;
+            return Ok(JsonValue::Array(items));
+        }
+        loop {
+            items.push(self.value(depth + 1)?);
+            self.skip_whitespace();
+            match self.text.get(self.position) {
+                Some(b',') => self.position += 1,
+                Some(b']') => {
+                    self.position += 1;
+                    return Ok(JsonValue::Array(items));
+                },
+                _ => return Err(self.unexpected("comma or array end"))
+            }
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> Result<JsonValue, String> {
+        self.position += 1;
+        let mut members = vec![];
+        self.skip_whitespace();
+        if self.text.get(self.position) == Some(&b'}') {
+            self.position += 1;
+            return Ok(JsonValue::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.text.get(self.position) != Some(&b'"') {
+                return Err(self.unexpected("object key string"));
+            }
+            let key = self.string()?;
+            self.skip_whitespace();
+            if self.text.get(self.position) != Some(&b':') {
+                return Err(self.unexpected("colon"));
+            }
+            self.position += 1;
+            members.push((key, self.value(depth + 1)?));
+            self.skip_whitespace();
+            match self.text.get(self.position) {
+                Some(b',') => self.position += 1,
+                Some(b'}') => {
+                    self.position += 1;
+                    return Ok(JsonValue::Object(members));
+                },
+                _ => return Err(self.unexpected("comma or object end"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_values() {
+        let value = parse(br#" {"a": [1, -2.5e1, true, null], "b": {"c": "d\u00e9\n"}, "a": "last"} "#).unwrap();
+        assert_eq!(value.get("a"), Some(&JsonValue::String(b"last".to_vec())));
+        assert_eq!(value.get("b").and_then(|x| x.get("c")), Some(&JsonValue::String("dé\n".as_bytes().to_vec())));
+        match value {
+            JsonValue::Object(members) => assert_eq!(members[0].1, JsonValue::Array(vec![
+                JsonValue::Number(1.0), JsonValue::Number(-25.0), JsonValue::Boolean(true), JsonValue::Null
+            ])),
+            _ => panic!("expected an object")
+        }
+        // A surrogate pair is one character
+        assert_eq!(parse(br#""\ud83d\ude00""#).unwrap(), JsonValue::String("😀".as_bytes().to_vec()));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(parse(b"[1,").unwrap_err(), "Expected value but found T_END at character 4");
+        assert_eq!(parse(b"{\"a\" 1}").unwrap_err(), "Expected colon but found invalid token at character 6");
+        assert_eq!(parse(b"1 2").unwrap_err(), "Expected the end but found invalid token at character 3");
+        assert_eq!(parse(b"\"\\q\"").unwrap_err(), "Expected value but found invalid token at character 2");
+        assert!(parse("[".repeat(MAX_DEPTH + 1).as_bytes()).unwrap_err().starts_with("Found too many nested data structures"));
+    }
+}
@@ -1,4 +1,6 @@
+#[allow(clippy::module_inception)]
 pub mod helpers;
+pub mod json;
 
 pub use helpers::Helper;
 
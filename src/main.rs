@@ -2,70 +2,149 @@ mod server;
 mod datastore;
 mod rdb;
 mod helpers;
+mod aof;
+mod tools;
+mod scripting;
 
 use datastore::store::DataStore;
 
-use crate::server::{Server, ServerOptions, ServerRole, MasterServerOptions};
+use crate::server::{Server, ServerOptions, ServerRole, MasterServerOptions, SaveParam};
+use crate::server::config::{DEFAULT_AOF_FILE_NAME, DEFAULT_AOF_DIR_NAME};
 use crate::rdb::rdb::RDBFileHelper;
+use crate::helpers::Helper;
+use crate::aof::aof::{AppendFsync, AppendOnlyFile};
+use crate::scripting::functions::RestorePolicy;
+use crate::server::acl::Acl;
 
 use std::collections::VecDeque;
 
 
 fn main() {
+    // Like redis, the companion tools live in the same binary: they run when it is invoked through
+    // a link named after them (`mmdb-check-rdb`, ...), or with the matching flag (`--check-rdb`, ...).
+    let program = std::env::args().next().unwrap_or_default();
+    let first_argument = std::env::args().nth(1).unwrap_or_default();
+    if program.ends_with("mmdb-check-rdb") || first_argument == "--check-rdb" {
+        std::process::exit(tools::check_rdb::run(std::env::args().skip(1).filter(|x| x != "--check-rdb").collect()));
+    }
+    if program.ends_with("mmdb-check-aof") || first_argument == "--check-aof" {
+        std::process::exit(tools::check_aof::run(std::env::args().skip(1).filter(|x| x != "--check-aof").collect()));
+    }
+    if program.ends_with("mmdb-rdb-convert") || first_argument == "--rdb-convert" {
+        std::process::exit(tools::rdb_convert::run(std::env::args().skip(1).filter(|x| x != "--rdb-convert").collect()));
+    }
+
     let mut args: VecDeque<_> = VecDeque::from(std::env::args().skip(1).collect::<Vec<_>>());
     let mut server_options: ServerOptions = ServerOptions {
         rdb_file_name: None,
         rdb_dir_name: None,
         port: None,
         server_role: Some(ServerRole::Master(Some(MasterServerOptions {
-            master_replid: "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".to_string(),
-            master_repl_offset: 0
-        })))
+            master_replid: Helper::random_hex(40),
+            master_repl_offset: 0,
+            master_replid2: "0".repeat(40),
+            second_repl_offset: -1
+        }))),
+        save_params: SaveParam::defaults(),
+        appendonly: false,
+        appendfilename: DEFAULT_AOF_FILE_NAME.to_string(),
+        appendfsync: AppendFsync::EverySec,
+        aof_load_truncated: true,
+        appenddirname: DEFAULT_AOF_DIR_NAME.to_string(),
+        aof_use_rdb_preamble: true,
+        auto_aof_rewrite_percentage: 100,
+        auto_aof_rewrite_min_size: 64 * 1024 * 1024,
+        repl_diskless_sync: true,
+        repl_backlog_size: 1024 * 1024,
+        repl_timeout: 60,
+        repl_ping_replica_period: 10,
+        replica_read_only: true,
+        replica_serve_stale_data: true,
+        replica_announce_ip: None,
+        replica_announce_port: 0,
+        notify_keyspace_events: 0,
+        busy_reply_threshold: 5000,
+        requirepass: None,
+        masterauth: None,
+        acl: Acl::new(),
+        aclfile: None,
+        acllog_max_len: 128,
     };
     while let Some(option) = args.pop_front() {
+        if !option.starts_with("--") {
+            // Like redis-server, a bare argument is the path to a config file. Options given on
+            // the command line are applied afterwards so they take precedence.
+            if let Err(e) = server_options.load_config_file(std::path::Path::new(&option)) {
+                panic!("{}", e);
+            }
+            continue;
+        }
         if option == "--dir" {
             let rdb_dir_name = args.pop_front();
             server_options.rdb_dir_name = Some(std::path::PathBuf::from(rdb_dir_name
                 .expect("Expected a value for the passed argument")
                 .to_owned()));
-        }
-        if option == "--dbfilename" {
+        } else if option == "--dbfilename" {
             let rdb_db_file_name = args.pop_front();
             server_options.rdb_file_name = Some(std::path::PathBuf::from(rdb_db_file_name
                 .expect("Expected a value for the passed argument")
                 .to_owned()));
-        }
-
-        if option == "--port" {
+        } else if option == "--port" {
             let port = args.pop_front()
                 .expect("Expected a value to be passed for port");
             server_options.port = Some(port.parse().expect("Expected a number"));
-        }
-        if option == "--replicaof" {
+        } else if option == "--replicaof" {
             let replica_of: Vec<String> = args.pop_front()
                 .expect("Expected a value to be passed for port")
                 .split(" ")
                 .map(|x| x.to_string())
                 .collect();
             server_options.server_role = Some(ServerRole::Slave(server::SlaveServerOptions{
-                master_host: replica_of.get(0).unwrap_or(&"localhost".to_string()).to_string(),
+                master_host: replica_of.first().unwrap_or(&"localhost".to_string()).to_string(),
                 master_port: replica_of[1].parse().unwrap_or(6379)
             }))
+        } else {
+            let value = args.pop_front()
+                .expect("Expected a value for the passed argument");
+            if let Err(e) = server_options.set_config(option.trim_start_matches("--"), &value) {
+                panic!("{}", e);
+            }
         }
     }
 
+    if let Some(aclfile) = server_options.aclfile.clone() {
+        if let Err(e) = server_options.acl.load_file(std::path::Path::new(&aclfile)) {
+            println!("ERROR {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    // When the append only file is enabled and present it holds the most recent data, it is
+    // replayed by the server instead of loading the snapshot.
+    let load_rdb = !server_options.appendonly || !AppendOnlyFile::exists(&server_options.aof_dir(), &server_options.aof_path(), &server_options.appendfilename);
     let mut rdb_helper = RDBFileHelper::new(server_options.clone());
-    let exisisting_db = match rdb_helper.decode_kv_table() {
-        Ok(x) => {
-            DataStore {
-                memory: x
+    let exisisting_db = if load_rdb && rdb_helper.exists() {
+        match rdb_helper.decode_kv_table() {
+            Ok(x) => {
+                let mut store = DataStore::from_memory(x.data);
+                if let Err(e) = store.functions.restore(&x.functions, RestorePolicy::Append) {
+                    println!("ERROR Can't load the functions of {:?}: {}", server_options.rdb_path(), e);
+                    std::process::exit(1);
+                }
+                store
+            },
+            Err(e) => {
+                // Starting with an empty dataset would silently drop the data on the next save
+                println!("ERROR Can't load {:?}: {}. Check it with mmdb-check-rdb.", server_options.rdb_path(), e);
+                std::process::exit(1);
             }
-        },
-        Err(_) => {
-            DataStore::new()
         }
+    } else {
+        DataStore::new()
     };
     let mut server = Server::new(&format!("127.0.0.1:{}", server_options.port.unwrap_or(6379)), server_options, Some(exisisting_db));
-    server.connect_to_master();
+    if let Err(e) = server.load_append_only_file() {
+        panic!("{}", e);
+    }
     server.run_event_loop();
 }
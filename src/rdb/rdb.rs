@@ -1,99 +1,563 @@
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{fs, path};
-use std::collections::{VecDeque,HashMap};
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use crate::datastore::store::DataItem;
 use crate::server::ServerOptions;
 
+const RDB_VERSION: &str = "0011";
+
+// Makes the temporary file of every save unique
+static SAVE_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+const RDB_OPCODE_FUNCTION2: u8 = 0xf5;
+const RDB_OPCODE_AUX: u8 = 0xfa;
+const RDB_OPCODE_RESIZEDB: u8 = 0xfb;
+const RDB_OPCODE_EXPIRETIME_MS: u8 = 0xfc;
+const RDB_OPCODE_EXPIRETIME: u8 = 0xfd;
+const RDB_OPCODE_SELECTDB: u8 = 0xfe;
+const RDB_OPCODE_EOF: u8 = 0xff;
+
+const RDB_TYPE_STRING: u8 = 0x00;
+
+const RDB_ENC_INT8: u8 = 0;
+const RDB_ENC_INT16: u8 = 1;
+const RDB_ENC_INT32: u8 = 2;
+const RDB_ENC_LZF: u8 = 3;
+
+/// Where and why an RDB payload could not be read.
+#[derive(Debug)]
+pub struct RDBError {
+    pub offset: usize,
+    pub message: String
+}
+
+impl std::fmt::Display for RDBError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at offset {}", self.message, self.offset)
+    }
+}
+
+/// What an RDB payload holds: the keys, and the code of the function libraries.
+#[derive(Debug, Default)]
+pub struct RDBContent {
+    pub data: HashMap<String, DataItem>,
+    pub functions: Vec<String>
+}
+
+/// What the reader found in an RDB payload, in file order. `offset` is where the item starts.
+#[derive(Debug)]
+pub enum RDBEntry {
+    Header { version: u32 },
+    Aux { offset: usize, key: String, value: String },
+    Function { offset: usize, code: String },
+    SelectDb { offset: usize, db: u64 },
+    ResizeDb { offset: usize, db_size: u64, expires_size: u64 },
+    Key { offset: usize, db: u64, key: String, item: DataItem },
+    Eof { offset: usize, checksum: Option<u64> }
+}
+
 pub struct RDBFileHelper {
     file_path: Option<path::PathBuf>
 }
 
 impl RDBFileHelper {
     pub fn new(server_configuration: ServerOptions) -> Self {
-        let rdb_file_path: Option<path::PathBuf> = match server_configuration.rdb_dir_name {
-            Some(dir_name) => {
-                match server_configuration.rdb_file_name {
-                    Some(file_name) => {
-                        Some(dir_name.join(file_name))
-                    },
-                    None => {
-                        None
-                    }
-                }
-            },
-            None => {
-                None
-            }
-        };
         Self {
-            file_path: rdb_file_path
+            file_path: Some(server_configuration.rdb_path())
         }
     }
 
-    pub fn read_file(&mut self) -> Result<Vec<u8>, ()> {
+    pub fn exists(&self) -> bool {
+        self.file_path.as_ref().is_some_and(|x| x.exists())
+    }
+
+    pub fn read_file(&mut self) -> Result<Vec<u8>, RDBError> {
         match &self.file_path {
             Some(file_name) => {
                 match fs::read(file_name) {
                     Ok(result) => {
                         Ok(result)
                     },
-                    Err(_) => {
-                        println!("ERROR While reading rdb file");
-                        Err(())
+                    Err(e) => {
+                        Err(RDBError { offset: 0, message: format!("Can't read {:?}: {}", file_name, e) })
                     }
                 }
             },
             None => {
-                Err(())
+                Err(RDBError { offset: 0, message: "No rdb file configured".to_string() })
             }
         }
     }
 
-    pub fn get_kv_table(&mut self) -> Result<Vec<u8>, ()> {
+    pub fn decode_kv_table(&mut self) -> Result<RDBContent, RDBError> {
         let file_content = self.read_file()?;
-        let kp = file_content.iter().position(|x| *x == 0xfe).ok_or(())? + 2;
-        let ending = file_content.iter().position(|x| *x == 0xff).ok_or(())?;
-        let kv_table = file_content.get(kp+3..ending).ok_or(())?.to_vec();
-        Ok(kv_table)
-    }
-
-    pub fn decode_kv_table(&mut self) -> Result<HashMap<String, DataItem>, ()> {
-        let mut kv_table = VecDeque::from(self.get_kv_table()?);
-        let mut data_stored: HashMap<String, DataItem> = HashMap::new();
-        while let Some(mut key_length) = kv_table.pop_front() {
-            let mut expiry_value: Option<SystemTime> = None;
-            if key_length == 0xfc {
-                let mut exp: [u8; 8] = [0; 8];
-                for i in 0..8 {
-                    let v = kv_table.pop_front().ok_or(())?;
-                    exp[i] = v;
-                }
-                let exp = u64::from_le_bytes(exp);
-                let system_time = UNIX_EPOCH + Duration::from_millis(exp);
-                expiry_value = Some(system_time);
+        Self::decode(&file_content)
+    }
+
+    /// Decodes a complete RDB payload (header, auxiliary fields, databases and the EOF marker)
+    /// into the key value table and the function libraries. Only string values are understood
+    /// for now.
+    pub fn decode(content: &[u8]) -> Result<RDBContent, RDBError> {
+        let mut decoded = RDBContent::default();
+        Self::walk(content, |entry| {
+            match entry {
+                RDBEntry::Key { key, item, .. } => {
+                    decoded.data.insert(key, item);
+                },
+                RDBEntry::Function { code, .. } => {
+                    decoded.functions.push(code);
+                },
+                _ => {}
             }
-            key_length = kv_table.pop_front().ok_or(())?;
-            if key_length == 0 {
-                key_length = kv_table.pop_front().ok_or(())?;
+        })?;
+        Ok(decoded)
+    }
+
+    /// Reads an RDB payload and reports every entry to `on_entry` as it goes, so that tools can
+    /// still show what was readable before the first corrupted byte. Returns the number of bytes
+    /// consumed, including the checksum.
+    pub fn walk(content: &[u8], mut on_entry: impl FnMut(RDBEntry)) -> Result<usize, RDBError> {
+        let mut reader = RDBReader::new(content);
+        let magic = reader.read_bytes(9)?;
+        if &magic[0..5] != b"REDIS" {
+            return Err(RDBError { offset: 0, message: "Wrong signature trying to load DB from file".to_string() });
+        }
+        let version = String::from_utf8_lossy(&magic[5..9]).parse::<u32>()
+            .map_err(|_| RDBError { offset: 5, message: "Invalid RDB version".to_string() })?;
+        on_entry(RDBEntry::Header { version });
+        let mut db: u64 = 0;
+        let mut expiry_value: Option<SystemTime> = None;
+        loop {
+            let offset = reader.position;
+            let opcode = reader.read_u8()?;
+            match opcode {
+                RDB_OPCODE_AUX => {
+                    let key = reader.read_string()?;
+                    let value = reader.read_string()?;
+                    on_entry(RDBEntry::Aux { offset, key, value });
+                },
+                RDB_OPCODE_FUNCTION2 => {
+                    let code = reader.read_string()?;
+                    on_entry(RDBEntry::Function { offset, code });
+                },
+                RDB_OPCODE_SELECTDB => {
+                    db = reader.read_length()?;
+                    on_entry(RDBEntry::SelectDb { offset, db });
+                },
+                RDB_OPCODE_RESIZEDB => {
+                    let db_size = reader.read_length()?;
+                    let expires_size = reader.read_length()?;
+                    on_entry(RDBEntry::ResizeDb { offset, db_size, expires_size });
+                },
+                RDB_OPCODE_EXPIRETIME_MS => {
+                    let exp = u64::from_le_bytes(reader.read_array::<8>()?);
+                    expiry_value = Some(UNIX_EPOCH + Duration::from_millis(exp));
+                },
+                RDB_OPCODE_EXPIRETIME => {
+                    let exp = u32::from_le_bytes(reader.read_array::<4>()?);
+                    expiry_value = Some(UNIX_EPOCH + Duration::from_secs(exp as u64));
+                },
+                RDB_OPCODE_EOF => {
+                    let mut checksum = None;
+                    if version >= 5 {
+                        let checksum_offset = reader.position;
+                        let expected = u64::from_le_bytes(reader.read_array::<8>()?);
+                        // A zero checksum means the file was written with checksums disabled
+                        if expected != 0 {
+                            let actual = crc64(0, &content[..checksum_offset]);
+                            if actual != expected {
+                                return Err(RDBError { offset: checksum_offset, message: format!("Wrong RDB checksum expected: {:#x} got: {:#x}", expected, actual) });
+                            }
+                            checksum = Some(expected);
+                        }
+                    }
+                    on_entry(RDBEntry::Eof { offset, checksum });
+                    break;
+                },
+                RDB_TYPE_STRING => {
+                    let key = reader.read_string()?;
+                    let value = reader.read_string()?;
+                    on_entry(RDBEntry::Key {
+                        offset,
+                        db,
+                        key,
+                        item: DataItem {
+                            data: value,
+                            expiry: expiry_value.take()
+                        }
+                    });
+                },
+                _ => {
+                    return Err(RDBError { offset, message: format!("Unknown RDB opcode or value type {:#x}", opcode) });
+                }
             }
-            let mut key_string = String::from("");
-            for _ in 0..key_length {
-                let f = kv_table.pop_front().ok_or(())?;
-                key_string.push(char::from(f));
+        }
+        Ok(reader.position)
+    }
+
+    /// Serializes the function libraries and the key value table into an RDB payload, including
+    /// the trailing CRC64 checksum.
+    pub fn encode(data: &HashMap<String, DataItem>, functions: &[String]) -> Vec<u8> {
+        let mut writer = RDBWriter::new();
+        writer.buffer.extend_from_slice(format!("REDIS{}", RDB_VERSION).as_bytes());
+        writer.write_aux("redis-ver", "7.2.0");
+        writer.write_aux("redis-bits", &(usize::BITS).to_string());
+        let ctime = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        writer.write_aux("ctime", &ctime.to_string());
+        // Like redis, the libraries come before the keys
+        for code in functions {
+            writer.buffer.push(RDB_OPCODE_FUNCTION2);
+            writer.write_string(code);
+        }
+
+        if !data.is_empty() {
+            let expires = data.values().filter(|x| x.expiry.is_some()).count();
+            writer.buffer.push(RDB_OPCODE_SELECTDB);
+            writer.write_length(0);
+            writer.buffer.push(RDB_OPCODE_RESIZEDB);
+            writer.write_length(data.len() as u64);
+            writer.write_length(expires as u64);
+            for (key, item) in data {
+                if let Some(expiry) = item.expiry {
+                    let ms = expiry.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+                    writer.buffer.push(RDB_OPCODE_EXPIRETIME_MS);
+                    writer.buffer.extend_from_slice(&ms.to_le_bytes());
+                }
+                writer.buffer.push(RDB_TYPE_STRING);
+                writer.write_string(key);
+                writer.write_string(&item.data);
             }
-            let value_length = kv_table.pop_front().ok_or(())?;
+        }
+        writer.buffer.push(RDB_OPCODE_EOF);
+        let checksum = crc64(0, &writer.buffer);
+        writer.buffer.extend_from_slice(&checksum.to_le_bytes());
+        writer.buffer
+    }
+
+    /// Writes a snapshot to a temporary file next to the destination and renames it into place,
+    /// so a crash in the middle of a save never leaves a half written dump behind.
+    pub fn save_to(path: &path::Path, data: &HashMap<String, DataItem>, functions: &[String]) -> std::io::Result<()> {
+        let content = Self::encode(data, functions);
+        // SAVE and a background save or a snapshot for a replica can run at once, each needs its own file
+        let sequence = SAVE_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+        let temp_path = path.with_file_name(format!("temp-{}-{}.rdb", std::process::id(), sequence));
+        let mut file = fs::File::create(&temp_path)?;
+        file.write_all(&content)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)
+    }
+}
+
+/// The payload of `FUNCTION DUMP`, the libraries as they are in an RDB file followed by the RDB
+/// version and a CRC64 checksum, like redis.
+pub fn dump_functions(functions: &[String]) -> Vec<u8> {
+    let mut writer = RDBWriter::new();
+    for code in functions {
+        writer.buffer.push(RDB_OPCODE_FUNCTION2);
+        writer.write_string(code);
+    }
+    let version = RDB_VERSION.parse::<u16>().unwrap_or_default();
+    writer.buffer.extend_from_slice(&version.to_le_bytes());
+    let checksum = crc64(0, &writer.buffer);
+    writer.buffer.extend_from_slice(&checksum.to_le_bytes());
+    writer.buffer
+}
+
+/// The code of the libraries in a `FUNCTION DUMP` payload, checked against its version and checksum.
+pub fn restore_functions(payload: &[u8]) -> Result<Vec<String>, RDBError> {
+    let wrong = RDBError { offset: 0, message: "payload version or checksum are wrong".to_string() };
+    if payload.len() < 10 {
+        return Err(wrong);
+    }
+    let (content, footer) = payload.split_at(payload.len() - 10);
+    let version = u16::from_le_bytes([footer[0], footer[1]]);
+    let checksum = u64::from_le_bytes(footer[2..].try_into().unwrap_or_default());
+    if version > RDB_VERSION.parse::<u16>().unwrap_or_default() || checksum != crc64(0, &payload[..payload.len() - 8]) {
+        return Err(wrong);
+    }
+    let mut reader = RDBReader::new(content);
+    let mut functions = vec![];
+    while reader.position < content.len() {
+        let offset = reader.position;
+        if reader.read_u8()? != RDB_OPCODE_FUNCTION2 {
+            return Err(RDBError { offset, message: "given type is not a function".to_string() });
+        }
+        functions.push(reader.read_string()?);
+    }
+    Ok(functions)
+}
+
+struct RDBReader<'a> {
+    content: &'a [u8],
+    position: usize
+}
+
+impl<'a> RDBReader<'a> {
+    fn new(content: &'a [u8]) -> Self {
+        Self {
+            content,
+            position: 0
+        }
+    }
+
+    fn unexpected_eof(&self) -> RDBError {
+        RDBError { offset: self.position, message: "Unexpected EOF reading RDB file".to_string() }
+    }
+
+    fn read_u8(&mut self) -> Result<u8, RDBError> {
+        let byte = *self.content.get(self.position).ok_or(self.unexpected_eof())?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn read_bytes(&mut self, count: usize) -> Result<&'a [u8], RDBError> {
+        let bytes = self.content.get(self.position..self.position + count).ok_or(self.unexpected_eof())?;
+        self.position += count;
+        Ok(bytes)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], RDBError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.read_bytes(N)?);
+        Ok(array)
+    }
+
+    /// Reads a length prefix. Returns the length and whether the value uses a special encoding,
+    /// in which case the length holds the encoding type instead.
+    fn read_encoded_length(&mut self) -> Result<(u64, bool), RDBError> {
+        let offset = self.position;
+        let first = self.read_u8()?;
+        match first >> 6 {
+            0 => Ok(((first & 0x3f) as u64, false)),
+            1 => {
+                let second = self.read_u8()?;
+                Ok(((((first & 0x3f) as u64) << 8) | second as u64, false))
+            },
+            2 => {
+                match first {
+                    0x80 => Ok((u32::from_be_bytes(self.read_array::<4>()?) as u64, false)),
+                    0x81 => Ok((u64::from_be_bytes(self.read_array::<8>()?), false)),
+                    _ => Err(RDBError { offset, message: format!("Unknown length encoding {:#x}", first) })
+                }
+            },
+            _ => Ok(((first & 0x3f) as u64, true))
+        }
+    }
+
+    fn read_length(&mut self) -> Result<u64, RDBError> {
+        let offset = self.position;
+        match self.read_encoded_length()? {
+            (length, false) => Ok(length),
+            _ => Err(RDBError { offset, message: "Expected a length, found an encoded value".to_string() })
+        }
+    }
 
-            let mut value_string = String::from("");
-            for _ in 0..value_length {
-                let f = kv_table.pop_front().ok_or(())?;
-                value_string.push(char::from(f));
+    fn read_string(&mut self) -> Result<String, RDBError> {
+        let offset = self.position;
+        let (length, encoded) = self.read_encoded_length()?;
+        if !encoded {
+            let bytes = self.read_bytes(length as usize)?;
+            return Ok(String::from_utf8_lossy(bytes).to_string());
+        }
+        match length as u8 {
+            RDB_ENC_INT8 => Ok((self.read_u8()? as i8).to_string()),
+            RDB_ENC_INT16 => Ok(i16::from_le_bytes(self.read_array::<2>()?).to_string()),
+            RDB_ENC_INT32 => Ok(i32::from_le_bytes(self.read_array::<4>()?).to_string()),
+            RDB_ENC_LZF => {
+                let compressed_length = self.read_length()? as usize;
+                let original_length = self.read_length()? as usize;
+                let compressed = self.read_bytes(compressed_length)?;
+                let bytes = lzf_decompress(compressed, original_length)
+                    .map_err(|_| RDBError { offset, message: "Invalid LZF compressed string".to_string() })?;
+                Ok(String::from_utf8_lossy(&bytes).to_string())
+            },
+            encoding => Err(RDBError { offset, message: format!("Unknown string encoding {}", encoding) })
+        }
+    }
+}
+
+struct RDBWriter {
+    buffer: Vec<u8>
+}
+
+impl RDBWriter {
+    fn new() -> Self {
+        Self {
+            buffer: vec![]
+        }
+    }
+
+    fn write_length(&mut self, length: u64) {
+        if length < (1 << 6) {
+            self.buffer.push(length as u8);
+        } else if length < (1 << 14) {
+            self.buffer.push(((length >> 8) as u8) | 0x40);
+            self.buffer.push(length as u8);
+        } else if length <= u32::MAX as u64 {
+            self.buffer.push(0x80);
+            self.buffer.extend_from_slice(&(length as u32).to_be_bytes());
+        } else {
+            self.buffer.push(0x81);
+            self.buffer.extend_from_slice(&length.to_be_bytes());
+        }
+    }
+
+    fn write_string(&mut self, value: &str) {
+        self.write_length(value.len() as u64);
+        self.buffer.extend_from_slice(value.as_bytes());
+    }
+
+    fn write_aux(&mut self, key: &str, value: &str) {
+        self.buffer.push(RDB_OPCODE_AUX);
+        self.write_string(key);
+        self.write_string(value);
+    }
+}
+
+fn lzf_decompress(input: &[u8], expected_length: usize) -> Result<Vec<u8>, ()> {
+    let mut output: Vec<u8> = Vec::with_capacity(expected_length);
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            let literal = input.get(i..i + ctrl + 1).ok_or(())?;
+            output.extend_from_slice(literal);
+            i += ctrl + 1;
+        } else {
+            let mut length = ctrl >> 5;
+            if length == 7 {
+                length += *input.get(i).ok_or(())? as usize;
+                i += 1;
+            }
+            let back = ((ctrl & 0x1f) << 8) + *input.get(i).ok_or(())? as usize + 1;
+            i += 1;
+            if back > output.len() {
+                return Err(());
             }
-            data_stored.insert(key_string, DataItem {
-                data: value_string,
-                expiry: expiry_value
-            });
+            let start = output.len() - back;
+            for offset in 0..length + 2 {
+                output.push(output[start + offset]);
+            }
+        }
+    }
+    if output.len() != expected_length {
+        return Err(());
+    }
+    Ok(output)
+}
+
+/// CRC-64/Jones, the checksum Redis appends to RDB files.
+fn crc64(mut crc: u64, data: &[u8]) -> u64 {
+    const POLY: u64 = 0x95ac9329ac4bc9b5;
+    for byte in data {
+        crc ^= *byte as u64;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ POLY } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(body: &[u8]) -> Vec<u8> {
+        let mut content = b"REDIS0011".to_vec();
+        content.extend_from_slice(body);
+        content.push(RDB_OPCODE_EOF);
+        let checksum = crc64(0, &content);
+        content.extend_from_slice(&checksum.to_le_bytes());
+        content
+    }
+
+    #[test]
+    fn crc64_check_value() {
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6d914c4b8d9ca);
+        assert_eq!(crc64(0, b""), 0);
+    }
+
+    #[test]
+    fn encode_decode_roundtrip() {
+        let expiry = UNIX_EPOCH + Duration::from_millis(4_102_444_800_123);
+        let mut data = HashMap::new();
+        data.insert("short".to_string(), DataItem { data: "value".to_string(), expiry: None });
+        data.insert("expiring".to_string(), DataItem { data: "soon".to_string(), expiry: Some(expiry) });
+        // Lengths that take 2 and 5 bytes to encode
+        data.insert("k".repeat(100), DataItem { data: "v".repeat(20_000), expiry: None });
+        let functions = vec!["#!lua name=lib\nredis.register_function('f', function() return 1 end)".to_string()];
+
+        let decoded = RDBFileHelper::decode(&RDBFileHelper::encode(&data, &functions)).unwrap();
+        assert_eq!(decoded.functions, functions);
+        assert_eq!(decoded.data.len(), data.len());
+        for (key, item) in &data {
+            assert_eq!(decoded.data[key].data, item.data);
+            assert_eq!(decoded.data[key].expiry, item.expiry);
         }
-        Ok(data_stored)
+    }
+
+    #[test]
+    fn decode_encoded_strings() {
+        let mut body = vec![RDB_TYPE_STRING, 3];
+        body.extend_from_slice(b"lzf");
+        // A literal 'a' then a back reference copying it 9 times
+        body.extend_from_slice(&[0xc0 | RDB_ENC_LZF, 5, 10, 0x00, b'a', 0xe0, 0x00, 0x00]);
+        body.extend_from_slice(&[RDB_TYPE_STRING, 2, b'i', b'8', 0xc0 | RDB_ENC_INT8, 0xff]);
+        body.extend_from_slice(&[RDB_TYPE_STRING, 3, b'i', b'1', b'6', 0xc0 | RDB_ENC_INT16, 0x39, 0x30]);
+        body.extend_from_slice(&[RDB_TYPE_STRING, 3, b'i', b'3', b'2']);
+        body.push(0xc0 | RDB_ENC_INT32);
+        body.extend_from_slice(&(-100_000i32).to_le_bytes());
+
+        let decoded = RDBFileHelper::decode(&payload(&body)).unwrap();
+        assert_eq!(decoded.data["lzf"].data, "a".repeat(10));
+        assert_eq!(decoded.data["i8"].data, "-1");
+        assert_eq!(decoded.data["i16"].data, "12345");
+        assert_eq!(decoded.data["i32"].data, "-100000");
+    }
+
+    #[test]
+    fn lzf_rejects_bad_input() {
+        // A back reference before the start of the output
+        assert!(lzf_decompress(&[0x20, 0x05], 3).is_err());
+        // A literal running past the end
+        assert!(lzf_decompress(&[0x03, b'a'], 4).is_err());
+        assert!(lzf_decompress(&[0x00, b'a'], 2).is_err());
+    }
+
+    #[test]
+    fn decode_rejects_wrong_checksum() {
+        let mut content = payload(&[RDB_TYPE_STRING, 1, b'k', 1, b'v']);
+        let last = content.len() - 1;
+        content[last] ^= 1;
+        let error = RDBFileHelper::decode(&content).unwrap_err();
+        assert!(error.message.starts_with("Wrong RDB checksum"));
+
+        // Files written with checksums disabled end with zeros
+        let length = content.len();
+        content[length - 8..].fill(0);
+        assert_eq!(RDBFileHelper::decode(&content).unwrap().data["k"].data, "v");
+    }
+
+    #[test]
+    fn decode_rejects_bad_signature_and_truncation() {
+        assert!(RDBFileHelper::decode(b"RODIS0011\xff").is_err());
+        let content = payload(&[RDB_TYPE_STRING, 1, b'k', 5, b'v', b'a', b'l', b'u', b'e']);
+        // Cut in the middle of the value
+        let error = RDBFileHelper::decode(&content[..15]).unwrap_err();
+        assert_eq!((error.offset, error.message.as_str()), (13, "Unexpected EOF reading RDB file"));
+    }
+
+    #[test]
+    fn function_dump_roundtrip() {
+        let functions = vec!["#!lua name=a\n".to_string(), "#!lua name=b\n".to_string()];
+        let payload = dump_functions(&functions);
+        assert_eq!(restore_functions(&payload).unwrap(), functions);
+        let mut corrupted = payload.clone();
+        corrupted[3] ^= 1;
+        assert!(restore_functions(&corrupted).is_err());
     }
 }
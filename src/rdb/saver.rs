@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::datastore::store::DataItem;
+use crate::rdb::rdb::RDBFileHelper;
+use crate::server::SaveParam;
+
+/// After a failed background save we wait this long before an automatic save is attempted again.
+const BGSAVE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Keeps track of the dirty counter and of the snapshots taken with `SAVE` and `BGSAVE`.
+pub struct RDBSaver {
+    pub dirty: u64,
+    dirty_before_bgsave: u64,
+    pub last_save: SystemTime,
+    last_bgsave_ok: bool,
+    last_bgsave_try: Option<Instant>,
+    last_bgsave_time_sec: Option<u64>,
+    bgsave_started: Option<Instant>,
+    bgsave_child: Option<JoinHandle<std::io::Result<()>>>,
+    saves: u64,
+}
+
+impl RDBSaver {
+    pub fn new() -> Self {
+        Self {
+            dirty: 0,
+            dirty_before_bgsave: 0,
+            last_save: SystemTime::now(),
+            last_bgsave_ok: true,
+            last_bgsave_try: None,
+            last_bgsave_time_sec: None,
+            bgsave_started: None,
+            bgsave_child: None,
+            saves: 0,
+        }
+    }
+
+    pub fn is_saving(&self) -> bool {
+        self.bgsave_child.is_some()
+    }
+
+    /// Saves in the foreground, blocking every client until the dump is on disk.
+    pub fn save(&mut self, path: &Path, data: &HashMap<String, DataItem>, functions: &[String]) -> std::io::Result<()> {
+        RDBFileHelper::save_to(path, data, functions)?;
+        self.dirty = 0;
+        self.last_save = SystemTime::now();
+        self.saves += 1;
+        Ok(())
+    }
+
+    /// Snapshots the dataset and writes it from a separate thread so clients keep being served.
+    pub fn bgsave(&mut self, path: &Path, data: &HashMap<String, DataItem>, functions: &[String]) -> Result<(), String> {
+        if self.is_saving() {
+            return Err("Background save already in progress".to_string());
+        }
+        let snapshot = data.clone();
+        let functions = functions.to_vec();
+        let path = path.to_path_buf();
+        self.dirty_before_bgsave = self.dirty;
+        self.last_bgsave_try = Some(Instant::now());
+        self.bgsave_started = Some(Instant::now());
+        self.bgsave_child = Some(std::thread::spawn(move || RDBFileHelper::save_to(&path, &snapshot, &functions)));
+        Ok(())
+    }
+
+    /// Reaps a finished background save and tells whether it succeeded. Writes that happened
+    /// while saving stay dirty.
+    pub fn poll(&mut self) -> Option<bool> {
+        if !self.bgsave_child.as_ref().is_some_and(|x| x.is_finished()) {
+            return None;
+        }
+        let result = self.bgsave_child.take().unwrap().join();
+        self.last_bgsave_time_sec = self.bgsave_started.take().map(|x| x.elapsed().as_secs());
+        match result {
+            Ok(Ok(())) => {
+                println!("Background saving terminated with success");
+                self.dirty -= self.dirty_before_bgsave.min(self.dirty);
+                self.last_save = SystemTime::now();
+                self.last_bgsave_ok = true;
+                self.saves += 1;
+            },
+            Ok(Err(e)) => {
+                println!("ERROR Background saving failed: {}", e);
+                self.last_bgsave_ok = false;
+            },
+            Err(_) => {
+                println!("ERROR Background saving thread panicked");
+                self.last_bgsave_ok = false;
+            }
+        }
+        Some(self.last_bgsave_ok)
+    }
+
+    /// Whether one of the `save <seconds> <changes>` rules asks for a new snapshot.
+    pub fn should_save(&self, save_params: &[SaveParam]) -> bool {
+        if self.is_saving() {
+            return false;
+        }
+        if !self.last_bgsave_ok && self.last_bgsave_try.is_some_and(|x| x.elapsed() < BGSAVE_RETRY_DELAY) {
+            return false;
+        }
+        let since_last_save = self.last_save.elapsed().unwrap_or_default().as_secs();
+        save_params.iter().any(|x| self.dirty >= x.changes && since_last_save >= x.seconds)
+    }
+
+    pub fn info(&self) -> String {
+        let last_save = self.last_save.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let current_bgsave_time_sec = match self.bgsave_started {
+            Some(started) => started.elapsed().as_secs() as i64,
+            None => -1
+        };
+        let last_bgsave_time_sec = match self.last_bgsave_time_sec {
+            Some(x) => x as i64,
+            None => -1
+        };
+        format!(
+            "rdb_changes_since_last_save:{}\r\nrdb_bgsave_in_progress:{}\r\nrdb_last_save_time:{}\r\nrdb_last_bgsave_status:{}\r\nrdb_last_bgsave_time_sec:{}\r\nrdb_current_bgsave_time_sec:{}\r\nrdb_saves:{}\r\n",
+            self.dirty,
+            self.is_saving() as u8,
+            last_save,
+            if self.last_bgsave_ok { "ok" } else { "err" },
+            last_bgsave_time_sec,
+            current_bgsave_time_sec,
+            self.saves
+        )
+    }
+}
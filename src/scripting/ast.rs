@@ -0,0 +1,83 @@
+use std::sync::Arc;
+
+/// A compiled chunk only holds plain data, so that it can be cached by the server and run by
+/// the script threads.
+#[derive(Debug)]
+pub struct Block {
+    pub statements: Vec<Statement>,
+}
+
+#[derive(Debug)]
+pub struct Statement {
+    pub kind: StatementKind,
+    // Where it starts, what runtime errors report
+    pub line: u32,
+}
+
+#[derive(Debug)]
+pub enum StatementKind {
+    Local(Vec<String>, Vec<Expression>),
+    Assign(Vec<Expression>, Vec<Expression>),
+    Call(Expression),
+    Do(Block),
+    While(Expression, Block),
+    Repeat(Block, Expression),
+    If(Vec<(Expression, Block)>, Option<Block>),
+    NumericFor(String, Expression, Expression, Option<Expression>, Block),
+    GenericFor(Vec<String>, Vec<Expression>, Block),
+    LocalFunction(String, Arc<FunctionBody>),
+    Return(Vec<Expression>),
+    Break,
+}
+
+#[derive(Debug)]
+pub enum Expression {
+    Nil,
+    True,
+    False,
+    Vararg,
+    Number(f64),
+    Str(Vec<u8>),
+    Function(Arc<FunctionBody>),
+    Table(Vec<TableField>),
+    Name(String),
+    Index(Box<Expression>, Box<Expression>),
+    Call(Box<Expression>, Vec<Expression>),
+    Method(Box<Expression>, String, Vec<Expression>),
+    Binary(BinaryOperator, Box<Expression>, Box<Expression>),
+    Unary(UnaryOperator, Box<Expression>),
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+    // Parentheses keep a single value of a call or of `...`
+    Paren(Box<Expression>),
+}
+
+#[derive(Debug)]
+pub enum TableField {
+    Positional(Expression),
+    Keyed(Expression, Expression),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOperator {
+    Add, Sub, Mul, Div, Mod, Pow, Concat, Eq, Ne, Lt, Le, Gt, Ge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOperator {
+    Minus, Not, Length,
+}
+
+#[derive(Debug)]
+pub struct FunctionBody {
+    pub parameters: Vec<String>,
+    pub vararg: bool,
+    pub body: Block,
+}
+
+impl Expression {
+    /// Whether the expression can produce several values, when it is the last of a list.
+    pub fn is_multi(&self) -> bool {
+        matches!(self, Expression::Call(..) | Expression::Method(..) | Expression::Vararg)
+    }
+}
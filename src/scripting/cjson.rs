@@ -0,0 +1,155 @@
+use std::rc::Rc;
+
+use crate::helpers::json::{self, JsonValue};
+use crate::scripting::stdlib::{check_string, library};
+use crate::scripting::value::{format_number, LuaError, Table, Value};
+use crate::scripting::vm::Vm;
+
+// Deeper tables are refused
+const MAX_DEPTH: usize = 1000;
+
+/// Installs `cjson.encode`, `cjson.decode` and the `cjson.null` sentinel JSON nulls decode to.
+pub fn install(vm: &mut Vm) {
+    let cjson = library(&[("encode", encode), ("decode", decode)]);
+    cjson.borrow_mut().set_str("null", Value::new_table());
+    vm.set_global("cjson", Value::Table(cjson));
+}
+
+fn null(vm: &Vm) -> Value {
+    match vm.get_global("cjson") {
+        Value::Table(cjson) => cjson.borrow().get_str("null"),
+        _ => Value::Nil
+    }
+}
+
+fn encode(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    if arguments.len() != 1 {
+        return Err(vm.error("bad argument #1 to 'encode' (expected 1 argument)"));
+    }
+    let mut out = vec![];
+    encode_value(vm, &arguments[0], &null(vm), 0, &mut out)?;
+    Ok(vec![Value::bytes(&out)])
+}
+
+fn encode_string(s: &[u8], out: &mut Vec<u8>) {
+    out.push(b'"');
+    for c in s {
+        match c {
+            b'"' => out.extend_from_slice(b"\\\""),
+            b'\\' => out.extend_from_slice(b"\\\\"),
+            b'/' => out.extend_from_slice(b"\\/"),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            b'\r' => out.extend_from_slice(b"\\r"),
+            b'\t' => out.extend_from_slice(b"\\t"),
+            8 => out.extend_from_slice(b"\\b"),
+            12 => out.extend_from_slice(b"\\f"),
+            c if *c < 0x20 || *c == 0x7f => out.extend_from_slice(format!("\\u{:04x}", c).as_bytes()),
+            c => out.push(*c)
+        }
+    }
+    out.push(b'"');
+}
+
+/// The length of a table holding an array, keys `1..n` only.
+fn array_length(table: &Table) -> Option<usize> {
+    let mut count = 0;
+    let mut max = 0;
+    let mut key = Value::Nil;
+    while let Ok(Some((next, _))) = table.next(&key) {
+        match next {
+            Value::Number(x) if x >= 1.0 && x.fract() == 0.0 => max = max.max(x as usize),
+            _ => return None
+        }
+        count += 1;
+        key = next;
+    }
+    Some(max.max(count))
+}
+
+fn encode_value(vm: &Vm, value: &Value, null: &Value, depth: usize, out: &mut Vec<u8>) -> Result<(), LuaError> {
+    match value {
+        Value::Nil => out.extend_from_slice(b"null"),
+        Value::Boolean(x) => out.extend_from_slice(if *x { b"true" } else { b"false" }),
+        Value::Number(x) if !x.is_finite() => return Err(vm.error("Cannot serialise number: must not be NaN or Inf")),
+        Value::Number(x) => out.extend_from_slice(format_number(*x).as_bytes()),
+        Value::Str(x) => encode_string(x, out),
+        Value::Table(_) if value.raw_equals(null) => out.extend_from_slice(b"null"),
+        Value::Table(table) => {
+            if depth >= MAX_DEPTH {
+                return Err(vm.error(&format!("Cannot serialise, excessive nesting ({})", depth + 1)));
+            }
+            let table = table.borrow();
+            match array_length(&table) {
+                // Empty tables are objects
+                Some(length) if length > 0 => {
+                    let count = table.sequence().len();
+                    if length > 10 && length > count * 2 {
+                        return Err(vm.error("Cannot serialise table: excessively sparse array"));
+                    }
+                    out.push(b'[');
+                    for i in 1..=length {
+                        if i > 1 {
+                            out.push(b',');
+                        }
+                        encode_value(vm, &table.get(&Value::Number(i as f64)), null, depth + 1, out)?;
+                    }
+                    out.push(b']');
+                },
+                _ => {
+                    out.push(b'{');
+                    let mut key = Value::Nil;
+                    let mut first = true;
+                    while let Ok(Some((next, item))) = table.next(&key) {
+                        if !first {
+                            out.push(b',');
+                        }
+                        first = false;
+                        match &next {
+                            Value::Str(x) => encode_string(x, out),
+                            Value::Number(x) => encode_string(format_number(*x).as_bytes(), out),
+                            _ => return Err(vm.error("Cannot serialise table: table key must be a number or string"))
+                        }
+                        out.push(b':');
+                        encode_value(vm, &item, null, depth + 1, out)?;
+                        key = next;
+                    }
+                    out.push(b'}');
+                }
+            }
+        },
+        other => return Err(vm.error(&format!("Cannot serialise {}: type not supported", other.type_name())))
+    }
+    Ok(())
+}
+
+fn decode(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    if arguments.len() != 1 {
+        return Err(vm.error("bad argument #1 to 'decode' (expected 1 argument)"));
+    }
+    let text = check_string(vm, &arguments, 0, "decode")?;
+    let value = json::parse(&text).map_err(|e| vm.error(&e))?;
+    Ok(vec![to_lua(value, &null(vm))])
+}
+
+fn to_lua(value: JsonValue, null: &Value) -> Value {
+    match value {
+        JsonValue::Null => null.clone(),
+        JsonValue::Boolean(x) => Value::Boolean(x),
+        JsonValue::Number(x) => Value::Number(x),
+        JsonValue::String(x) => Value::bytes(&x),
+        JsonValue::Array(items) => {
+            let mut table = Table::default();
+            for item in items {
+                table.push(to_lua(item, null));
+            }
+            Value::Table(Rc::new(table.into()))
+        },
+        JsonValue::Object(members) => {
+            let mut table = Table::default();
+            for (key, item) in members {
+                let _ = table.set(Value::bytes(&key), to_lua(item, null));
+            }
+            Value::Table(Rc::new(table.into()))
+        }
+    }
+}
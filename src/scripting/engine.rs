@@ -0,0 +1,391 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::helpers::Helper;
+use crate::scripting::ast::FunctionBody;
+use crate::scripting::stdlib::{argument, check_number, check_string};
+use crate::scripting::value::{format_number, LuaError, Table, Value};
+use crate::scripting::vm::Vm;
+use crate::scripting::functions::{self, Library};
+use crate::scripting::{cjson, parser, stdlib};
+use crate::server::interpreter::SERVER_VERSION;
+
+/// Scripts run in a thread of their own, with a stack large enough for the deepest nesting the
+/// parser and the call depth limit allow.
+pub const SCRIPT_STACK_SIZE: usize = 32 * 1024 * 1024;
+
+// Flags scripts can declare in their shebang, only `no-writes` changes anything here
+const SCRIPT_FLAGS: [&str; 5] = ["no-writes", "allow-oom", "allow-stale", "no-cluster", "allow-cross-slot-keys"];
+
+// Replies nested deeper than this are refused, instead of overflowing the stack
+const MAX_REPLY_DEPTH: usize = 1000;
+
+/// A script compiled by `EVAL` or `SCRIPT LOAD`, cached by the SHA1 of its source.
+pub struct Script {
+    pub body: Arc<FunctionBody>,
+    // `#!lua flags=no-writes`, the script can run on read only replicas but can't write
+    pub no_writes: bool,
+}
+
+/// What the thread running a script asks the event loop for.
+pub enum ScriptMessage {
+    // A command of `redis.call`, answered with its RESP reply
+    Call(Vec<String>),
+    // The RESP reply of the script, it is over
+    Done(String),
+}
+
+// The `key=value` options of a shebang line
+pub type ShebangOptions = Vec<(String, String)>;
+
+/// Splits the `#!<engine> key=value ...` line off a script. The line is replaced by an empty
+/// one, so that errors report the lines of the source.
+pub fn split_shebang(source: &str) -> Result<(Option<ShebangOptions>, String), String> {
+    if !source.starts_with("#!") {
+        return Ok((None, source.to_string()));
+    }
+    let (line, rest) = source.split_once('\n').unwrap_or((source, ""));
+    let mut parts = line[2..].split(' ').filter(|x| !x.is_empty());
+    let engine = parts.next().unwrap_or_default();
+    if engine != "lua" {
+        return Err(format!("ERR Could not find engine '{}'", engine));
+    }
+    let mut options = vec![];
+    for part in parts {
+        match part.split_once('=') {
+            Some((key, value)) => options.push((key.to_string(), value.to_string())),
+            None => return Err(format!("ERR Unknown lua shebang option: {}", part))
+        }
+    }
+    Ok((Some(options), format!("\n{}", rest)))
+}
+
+impl Script {
+    /// Compiles the body of `EVAL` or `SCRIPT LOAD`. Errors are replies without their `-`.
+    pub fn compile(source: &str) -> Result<Script, String> {
+        let (options, body) = split_shebang(source)?;
+        let mut no_writes = false;
+        for (key, value) in options.unwrap_or_default() {
+            if key != "flags" {
+                return Err(format!("ERR Unknown lua shebang option: {}", key));
+            }
+            for flag in value.split(',').filter(|x| !x.is_empty()) {
+                if !SCRIPT_FLAGS.contains(&flag) {
+                    return Err(format!("ERR Unexpected flag in script shebang: {}", flag));
+                }
+                no_writes |= flag == "no-writes";
+            }
+        }
+        let body = parser::parse("user_script", body.as_bytes()).map_err(|e| format!("ERR Error compiling script (new function): {}", e))?;
+        Ok(Script { body, no_writes })
+    }
+}
+
+/// The event loop side of a script running in its thread.
+pub struct ScriptRun {
+    messages: Receiver<ScriptMessage>,
+    replies: Sender<String>,
+    kill: Arc<AtomicBool>,
+}
+
+/// The script side: where `redis.call` sends commands and waits for their replies.
+struct ScriptLink {
+    messages: Sender<ScriptMessage>,
+    replies: Receiver<String>,
+}
+
+impl ScriptRun {
+    /// Starts an `EVAL`, with `KEYS` and `ARGV` set from the arguments.
+    pub fn eval(script: Arc<Script>, sha: String, keys: Vec<String>, arguments: Vec<String>) -> ScriptRun {
+        ScriptRun::start("user_script", sha, move |vm| {
+            vm.set_global("KEYS", string_array(&keys));
+            vm.set_global("ARGV", string_array(&arguments));
+            vm.protect_globals = true;
+            vm.run(&script.body, vec![])
+        })
+    }
+
+    /// Starts an `FCALL`: the library registers its functions again, then the one called runs
+    /// with the keys and the arguments.
+    pub fn fcall(library: Arc<Library>, function: String, keys: Vec<String>, arguments: Vec<String>) -> ScriptRun {
+        ScriptRun::start("user_function", function.clone(), move |vm| {
+            let callback = functions::register(vm, &library.body)?.into_iter()
+                .find(|(info, _)| info.name == function)
+                .map(|(_, callback)| callback)
+                .unwrap_or(Value::Nil);
+            vm.call(&callback, vec![string_array(&keys), string_array(&arguments)])
+        })
+    }
+
+    /// Runs `job` in a new thread, in an environment with the libraries and `redis`. `name` is
+    /// what errors report the script as.
+    fn start(chunk_name: &'static str, name: String, job: impl FnOnce(&mut Vm) -> Result<Vec<Value>, LuaError> + Send + 'static) -> ScriptRun {
+        let (message_sender, messages) = channel();
+        let (replies, reply_receiver) = channel();
+        let kill = Arc::new(AtomicBool::new(false));
+        let kill_flag = kill.clone();
+        let spawned = thread::Builder::new().name("script".to_string()).stack_size(SCRIPT_STACK_SIZE).spawn(move || {
+            let mut vm = environment(chunk_name, kill_flag);
+            let link = Rc::new(ScriptLink { messages: message_sender.clone(), replies: reply_receiver });
+            install_calls(&mut vm, link);
+            let reply = match job(&mut vm) {
+                Ok(values) => to_resp(&values.into_iter().next().unwrap_or(Value::Nil), 0),
+                Err(e) => error_reply(&vm, e, &name)
+            };
+            let _ = message_sender.send(ScriptMessage::Done(reply));
+        });
+        if let Err(e) = spawned {
+            println!("ERROR Can't start a script thread: {}", e);
+        }
+        ScriptRun { messages, replies, kill }
+    }
+
+    /// Waits up to `timeout` for the next request of the script.
+    pub fn receive(&self, timeout: Duration) -> Option<ScriptMessage> {
+        match self.messages.recv_timeout(timeout) {
+            Ok(message) => Some(message),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => Some(ScriptMessage::Done("-ERR The script stopped unexpectedly\r\n".to_string()))
+        }
+    }
+
+    pub fn try_receive(&self) -> Option<ScriptMessage> {
+        match self.messages.try_recv() {
+            Ok(message) => Some(message),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(ScriptMessage::Done("-ERR The script stopped unexpectedly\r\n".to_string()))
+        }
+    }
+
+    /// Answers the last `Call` of the script.
+    pub fn reply(&self, reply: String) {
+        let _ = self.replies.send(reply);
+    }
+
+    /// Makes the script fail the next time it checks, with an error `pcall` can't catch.
+    pub fn kill(&self) {
+        self.kill.store(true, Ordering::Relaxed);
+    }
+}
+
+fn string_array(items: &[String]) -> Value {
+    let mut table = Table::default();
+    for item in items {
+        table.push(Value::str(item));
+    }
+    Value::Table(Rc::new(RefCell::new(table)))
+}
+
+/// An error reply as scripts see it, `{err = "CODE message"}`. Messages without a code get
+/// `ERR`, like the errors of `redis.error_reply`.
+fn error_table(message: &str) -> Value {
+    let message = message.trim_end_matches(['\r', '\n']);
+    let message = match message.strip_prefix('-') {
+        Some(x) if x.contains(' ') => x.to_string(),
+        Some(x) => format!("ERR {}", x),
+        None => format!("ERR {}", message)
+    };
+    let mut table = Table::default();
+    table.set_str("err", Value::str(&message));
+    Value::Table(Rc::new(RefCell::new(table)))
+}
+
+fn status_table(message: &str) -> Value {
+    let mut table = Table::default();
+    table.set_str("ok", Value::str(message));
+    Value::Table(Rc::new(RefCell::new(table)))
+}
+
+/// Error replies can't span several lines.
+fn single_line(text: &str) -> String {
+    text.replace(['\r', '\n'], " ")
+}
+
+/// The reply of a script that failed, with where it failed.
+fn error_reply(vm: &Vm, error: LuaError, name: &str) -> String {
+    let message = match error {
+        LuaError::Killed => "ERR Script killed by user with SCRIPT KILL...".to_string(),
+        LuaError::Error(Value::Table(table)) if matches!(table.borrow().get_str("err"), Value::Str(_)) => {
+            table.borrow().get_str("err").to_string_lossy()
+        },
+        LuaError::Error(value) => format!("ERR {}", value.to_string_lossy())
+    };
+    format!("-{} script: {}, on @{}:{}.\r\n", single_line(&message), name, vm.chunk_name, vm.line)
+}
+
+/// The RESP reply for what a script returned.
+fn to_resp(value: &Value, depth: usize) -> String {
+    match value {
+        Value::Nil | Value::Boolean(false) => "$-1\r\n".to_string(),
+        Value::Boolean(true) => ":1\r\n".to_string(),
+        Value::Number(x) => format!(":{}\r\n", *x as i64),
+        Value::Str(x) => {
+            let text = String::from_utf8_lossy(x);
+            format!("${}\r\n{}\r\n", text.len(), text)
+        },
+        Value::Table(table) => {
+            let table = table.borrow();
+            if let Value::Str(x) = table.get_str("err") {
+                return format!("-{}\r\n", single_line(&String::from_utf8_lossy(&x)));
+            }
+            if let Value::Str(x) = table.get_str("ok") {
+                return format!("+{}\r\n", single_line(&String::from_utf8_lossy(&x)));
+            }
+            if depth >= MAX_REPLY_DEPTH {
+                return "-ERR reached lua stack limit\r\n".to_string();
+            }
+            // The array ends at the first nil
+            let items: Vec<String> = table.sequence().iter().take_while(|x| !x.is_nil()).map(|x| to_resp(x, depth + 1)).collect();
+            format!("*{}\r\n{}", items.len(), items.concat())
+        },
+        Value::Function(_) => "$-1\r\n".to_string()
+    }
+}
+
+/// The Lua value for a RESP reply to `redis.call`, read from `position`.
+fn from_resp(reply: &[u8], position: &mut usize) -> Value {
+    let line_end = reply[*position..].windows(2).position(|x| x == b"\r\n").map(|x| x + *position).unwrap_or(reply.len());
+    let kind = reply.get(*position).copied().unwrap_or(b'_');
+    let line = String::from_utf8_lossy(&reply[(*position + 1).min(line_end)..line_end]).to_string();
+    *position = (line_end + 2).min(reply.len());
+    match kind {
+        b'+' => status_table(&line),
+        b'-' => error_table(&format!("-{}", line)),
+        b':' | b',' => Value::Number(line.parse().unwrap_or(0.0)),
+        b'#' => Value::Boolean(line == "t"),
+        b'$' => {
+            let length: i64 = line.parse().unwrap_or(-1);
+            if length < 0 {
+                return Value::Boolean(false);
+            }
+            let end = (*position + length as usize).min(reply.len());
+            let value = Value::bytes(&reply[*position..end]);
+            *position = (end + 2).min(reply.len());
+            value
+        },
+        b'*' | b'>' | b'~' => {
+            let count: i64 = line.parse().unwrap_or(-1);
+            if count < 0 {
+                return Value::Boolean(false);
+            }
+            let mut table = Table::default();
+            for _ in 0..count {
+                table.push(from_resp(reply, position));
+            }
+            Value::Table(Rc::new(RefCell::new(table)))
+        },
+        b'%' => {
+            let count: usize = line.parse().unwrap_or(0);
+            let mut table = Table::default();
+            for _ in 0..count {
+                let key = from_resp(reply, position);
+                let value = from_resp(reply, position);
+                let _ = table.set(key, value);
+            }
+            Value::Table(Rc::new(RefCell::new(table)))
+        },
+        _ => Value::Boolean(false)
+    }
+}
+
+/// `redis.call` when `raise`, otherwise `redis.pcall` which returns errors instead of raising
+/// them.
+fn redis_call(arguments: Vec<Value>, link: &ScriptLink, raise: bool) -> Result<Vec<Value>, LuaError> {
+    let fail = |error: Value| -> Result<Vec<Value>, LuaError> {
+        if raise {
+            return Err(LuaError::Error(error));
+        }
+        Ok(vec![error])
+    };
+    if arguments.is_empty() {
+        return fail(error_table("Please specify at least one argument for this redis lib call"));
+    }
+    let mut argv = Vec::with_capacity(arguments.len());
+    for value in &arguments {
+        match value {
+            Value::Str(x) => argv.push(String::from_utf8_lossy(x).to_string()),
+            Value::Number(x) => argv.push(format_number(*x)),
+            _ => return fail(error_table("Lua redis lib command arguments must be strings or integers"))
+        }
+    }
+    if link.messages.send(ScriptMessage::Call(argv)).is_err() {
+        return Err(LuaError::Killed);
+    }
+    let reply = match link.replies.recv() {
+        Ok(x) => x,
+        Err(_) => return Err(LuaError::Killed)
+    };
+    let value = from_resp(reply.as_bytes(), &mut 0);
+    if reply.starts_with('-') {
+        return fail(value);
+    }
+    Ok(vec![value])
+}
+
+/// Installs the `redis` library, whose calls go through `link`.
+/// A VM with the libraries and the `redis` table, but without `redis.call` and `redis.pcall`,
+/// which need the event loop.
+pub fn environment(chunk_name: &str, kill: Arc<AtomicBool>) -> Vm {
+    let mut vm = Vm::new(chunk_name, Some(kill));
+    stdlib::install(&mut vm);
+    cjson::install(&mut vm);
+    install_redis(&mut vm);
+    vm
+}
+
+fn install_calls(vm: &mut Vm, link: Rc<ScriptLink>) {
+    if let Value::Table(redis) = vm.get_global("redis") {
+        let call_link = link.clone();
+        redis.borrow_mut().set_str("call", Value::native(move |_, arguments| redis_call(arguments, &call_link, true)));
+        redis.borrow_mut().set_str("pcall", Value::native(move |_, arguments| redis_call(arguments, &link, false)));
+    }
+}
+
+fn install_redis(vm: &mut Vm) {
+    let mut redis = Table::default();
+    redis.set_str("error_reply", Value::native(|vm, arguments| {
+        let message = check_string(vm, &arguments, 0, "error_reply")?;
+        Ok(vec![error_table(&format!("-{}", String::from_utf8_lossy(&message)))])
+    }));
+    redis.set_str("status_reply", Value::native(|vm, arguments| {
+        let message = check_string(vm, &arguments, 0, "status_reply")?;
+        Ok(vec![status_table(&String::from_utf8_lossy(&message))])
+    }));
+    redis.set_str("sha1hex", Value::native(|vm, arguments| {
+        let data = check_string(vm, &arguments, 0, "sha1hex")?;
+        Ok(vec![Value::str(&Helper::sha1_hex(&data))])
+    }));
+    redis.set_str("log", Value::native(|vm, arguments| {
+        if arguments.len() < 2 {
+            return Err(vm.error("redis.log() requires two arguments or more."));
+        }
+        let level = check_number(vm, &arguments, 0, "log").map_err(|_| vm.error("First argument must be a number (log level)."))?;
+        if !(0.0..=3.0).contains(&level) {
+            return Err(vm.error("Invalid debug level."));
+        }
+        let message: Vec<String> = arguments[1..].iter().map(|x| x.to_string_lossy()).collect();
+        println!("{}", message.join(" "));
+        Ok(vec![])
+    }));
+    for (name, level) in [("LOG_DEBUG", 0.0), ("LOG_VERBOSE", 1.0), ("LOG_NOTICE", 2.0), ("LOG_WARNING", 3.0)] {
+        redis.set_str(name, Value::Number(level));
+    }
+    // Effects are always what gets replicated
+    redis.set_str("replicate_commands", Value::native(|_, _| Ok(vec![Value::Boolean(true)])));
+    redis.set_str("setresp", Value::native(|vm, arguments| {
+        match argument(&arguments, 0).to_number() {
+            Some(2.0) => Ok(vec![]),
+            Some(3.0) => Err(vm.error("RESP3 replies are not supported by scripts")),
+            _ => Err(vm.error("RESP version must be 2 or 3."))
+        }
+    }));
+    redis.set_str("REDIS_VERSION", Value::str(SERVER_VERSION));
+    let version_number = SERVER_VERSION.split('.').fold(0, |acc, x| acc * 256 + x.parse::<u32>().unwrap_or(0));
+    redis.set_str("REDIS_VERSION_NUM", Value::Number(version_number as f64));
+    vm.set_global("redis", Value::Table(Rc::new(RefCell::new(redis))));
+}
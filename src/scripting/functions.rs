@@ -0,0 +1,261 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::scripting::ast::FunctionBody;
+use crate::scripting::engine::{environment, split_shebang, SCRIPT_STACK_SIZE};
+use crate::scripting::parser;
+use crate::scripting::value::{LuaError, Table, Value};
+use crate::scripting::vm::Vm;
+
+// Loading a library that takes longer than this fails, its code must only register functions
+const LOAD_TIMEOUT: Duration = Duration::from_millis(500);
+
+const FUNCTION_FLAGS: [&str; 5] = ["no-writes", "allow-oom", "allow-stale", "no-cluster", "allow-cross-slot-keys"];
+
+// What the `redis` table offers while a library registers its functions
+const LOADING_API: [&str; 8] = ["log", "LOG_DEBUG", "LOG_VERBOSE", "LOG_NOTICE", "LOG_WARNING", "setresp", "REDIS_VERSION", "REDIS_VERSION_NUM"];
+
+/// A function registered by a library with `redis.register_function`.
+#[derive(Debug, Clone)]
+pub struct FunctionInfo {
+    pub name: String,
+    pub description: Option<String>,
+    pub flags: Vec<String>,
+}
+
+impl FunctionInfo {
+    /// Functions with the `no-writes` flag can be called by `FCALL_RO` and can't write.
+    pub fn no_writes(&self) -> bool {
+        self.flags.iter().any(|x| x == "no-writes")
+    }
+}
+
+/// A library loaded by `FUNCTION LOAD`, `#!lua name=<name>` followed by code registering its
+/// functions. The code is kept to list, dump and persist it.
+pub struct Library {
+    pub name: String,
+    pub code: String,
+    pub body: Arc<FunctionBody>,
+    pub functions: Vec<FunctionInfo>,
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|x| x.is_ascii_alphanumeric() || x == '_')
+}
+
+impl Library {
+    /// Compiles a library and runs it, in a thread of its own, to find out the functions it
+    /// registers. Errors are replies without their `-`.
+    pub fn load(code: &str) -> Result<Library, String> {
+        let (options, body) = split_shebang(code)?;
+        let mut name = None;
+        for (key, value) in options.ok_or("ERR Missing library metadata")? {
+            if key != "name" {
+                return Err(format!("ERR Invalid metadata value given: {}", key));
+            }
+            name = Some(value);
+        }
+        let name = name.ok_or("ERR Library name was not given")?;
+        if !valid_name(&name) {
+            return Err("ERR Library names can only contain letters, numbers, or underscores(_) and must be at least one character long".to_string());
+        }
+        let body = parser::parse("user_function", body.as_bytes()).map_err(|e| format!("ERR Error compiling function: {}", e))?;
+
+        let (sender, receiver) = channel();
+        let kill = Arc::new(AtomicBool::new(false));
+        let kill_flag = kill.clone();
+        let chunk = body.clone();
+        let spawned = thread::Builder::new().name("function-load".to_string()).stack_size(SCRIPT_STACK_SIZE).spawn(move || {
+            let mut vm = environment("user_function", kill_flag);
+            let result = match register(&mut vm, &chunk) {
+                Ok(registered) => Ok(registered.into_iter().map(|(info, _)| info).collect()),
+                Err(LuaError::Killed) => Err("ERR FUNCTION LOAD timeout".to_string()),
+                Err(LuaError::Error(e)) => Err(format!("ERR Error registering functions: {}", e.to_string_lossy()))
+            };
+            let _ = sender.send(result);
+        });
+        if let Err(e) = spawned {
+            return Err(format!("ERR Can't start a script thread: {}", e));
+        }
+        let functions: Vec<FunctionInfo> = match receiver.recv_timeout(LOAD_TIMEOUT) {
+            Ok(result) => result?,
+            Err(RecvTimeoutError::Timeout) => {
+                kill.store(true, Ordering::Relaxed);
+                receiver.recv().unwrap_or(Err("ERR FUNCTION LOAD timeout".to_string()))?
+            },
+            Err(RecvTimeoutError::Disconnected) => return Err("ERR Error registering functions".to_string())
+        };
+        if functions.is_empty() {
+            return Err("ERR No functions registered".to_string());
+        }
+        Ok(Library { name, code: code.to_string(), body, functions })
+    }
+}
+
+/// Runs the code of a library with `redis.register_function`, returns what it registered along
+/// with the callbacks. The `redis` table the VM had is back afterwards.
+pub fn register(vm: &mut Vm, body: &Arc<FunctionBody>) -> Result<Vec<(FunctionInfo, Value)>, LuaError> {
+    let registered: Rc<RefCell<Vec<(FunctionInfo, Value)>>> = Rc::new(RefCell::new(vec![]));
+    let redis = vm.get_global("redis");
+    let mut loading = Table::default();
+    if let Value::Table(redis) = &redis {
+        for name in LOADING_API {
+            loading.set_str(name, redis.borrow().get_str(name));
+        }
+    }
+    let sink = registered.clone();
+    loading.set_str("register_function", Value::native(move |vm, arguments| {
+        let (info, callback) = registration(vm, arguments)?;
+        if sink.borrow().iter().any(|(x, _)| x.name == info.name) {
+            return Err(vm.error("Function already exists in the library"));
+        }
+        sink.borrow_mut().push((info, callback));
+        Ok(vec![])
+    }));
+    vm.set_global("redis", Value::Table(Rc::new(RefCell::new(loading))));
+    vm.protect_globals = true;
+    let result = vm.run(body, vec![]);
+    vm.set_global("redis", redis);
+    result?;
+    let registered = registered.borrow().clone();
+    Ok(registered)
+}
+
+/// The arguments of `redis.register_function`: a name and a callback, or a table with
+/// `function_name`, `callback`, and optionally `flags` and `description`.
+fn registration(vm: &Vm, arguments: Vec<Value>) -> Result<(FunctionInfo, Value), LuaError> {
+    let (name, callback, flags, description) = match arguments.as_slice() {
+        [name, callback] => (name.clone(), callback.clone(), Value::Nil, Value::Nil),
+        [Value::Table(named)] => {
+            let named = named.borrow();
+            let mut key = Value::Nil;
+            while let Ok(Some((next, _))) = named.next(&key) {
+                let known = matches!(&next, Value::Str(x) if ["function_name", "callback", "flags", "description"].contains(&String::from_utf8_lossy(x).as_ref()));
+                if !known {
+                    return Err(vm.error("unknown argument given to redis.register_function"));
+                }
+                key = next;
+            }
+            (named.get_str("function_name"), named.get_str("callback"), named.get_str("flags"), named.get_str("description"))
+        },
+        [_] => return Err(vm.error("calling redis.register_function with a single argument is only applicable to Lua table (representing named arguments).")),
+        _ => return Err(vm.error("wrong number of arguments to redis.register_function"))
+    };
+    let name = match name {
+        Value::Str(x) => String::from_utf8_lossy(&x).to_string(),
+        _ => return Err(vm.error("function_name argument given to redis.register_function must be a string"))
+    };
+    if !valid_name(&name) {
+        return Err(vm.error("Function names can only contain letters, numbers, or underscores(_) and must be at least one character long"));
+    }
+    if !matches!(callback, Value::Function(_)) {
+        return Err(vm.error("callback argument given to redis.register_function must be a function"));
+    }
+    let flags = match flags {
+        Value::Nil => vec![],
+        Value::Table(flags) => {
+            let mut names = vec![];
+            for flag in flags.borrow().sequence() {
+                match flag {
+                    Value::Str(x) if FUNCTION_FLAGS.contains(&String::from_utf8_lossy(&x).as_ref()) => names.push(String::from_utf8_lossy(&x).to_string()),
+                    _ => return Err(vm.error("unknown flag given"))
+                }
+            }
+            names
+        },
+        _ => return Err(vm.error("flags argument to redis.register_function must be a table representing function flags"))
+    };
+    let description = match description {
+        Value::Nil => None,
+        Value::Str(x) => Some(String::from_utf8_lossy(&x).to_string()),
+        _ => return Err(vm.error("description argument given to redis.register_function must be a string"))
+    };
+    Ok((FunctionInfo { name, description, flags }, callback))
+}
+
+/// What `FUNCTION RESTORE` does with the libraries already loaded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RestorePolicy {
+    // Keeps them, a library of the payload with the same name is an error
+    Append,
+    // Replaces the ones with the same name
+    Replace,
+    // Drops them all first
+    Flush,
+}
+
+/// The libraries loaded, by name. They are part of the dataset: saved in RDB files, replicated,
+/// and left alone by `FLUSHALL`.
+#[derive(Default, Clone)]
+pub struct Functions {
+    libraries: BTreeMap<String, Arc<Library>>,
+}
+
+impl Functions {
+    /// Adds a library. One with the same name is replaced only with `replace`, and its functions
+    /// can't be named like the ones of another library.
+    pub fn add(&mut self, library: Library, replace: bool) -> Result<(), String> {
+        if !replace && self.libraries.contains_key(&library.name) {
+            return Err(format!("ERR Library '{}' already exists", library.name));
+        }
+        for other in self.libraries.values().filter(|x| x.name != library.name) {
+            if let Some(function) = library.functions.iter().find(|x| other.functions.iter().any(|y| y.name == x.name)) {
+                return Err(format!("ERR Function {} already exists", function.name));
+            }
+        }
+        self.libraries.insert(library.name.clone(), Arc::new(library));
+        Ok(())
+    }
+
+    /// Loads the code of libraries, the way `FUNCTION LOAD` does.
+    pub fn load(&mut self, code: &str, replace: bool) -> Result<String, String> {
+        let library = Library::load(code)?;
+        let name = library.name.clone();
+        self.add(library, replace)?;
+        Ok(name)
+    }
+
+    /// Loads libraries saved in an RDB file or dumped by `FUNCTION DUMP`. Nothing changes when one
+    /// of them can't be loaded.
+    pub fn restore(&mut self, codes: &[String], policy: RestorePolicy) -> Result<(), String> {
+        let mut restored = match policy {
+            RestorePolicy::Flush => Functions::default(),
+            _ => self.clone()
+        };
+        for code in codes {
+            restored.add(Library::load(code)?, policy == RestorePolicy::Replace)?;
+        }
+        *self = restored;
+        Ok(())
+    }
+
+    pub fn delete(&mut self, name: &str) -> bool {
+        self.libraries.remove(name).is_some()
+    }
+
+    pub fn flush(&mut self) {
+        self.libraries.clear();
+    }
+
+    pub fn libraries(&self) -> impl Iterator<Item = &Arc<Library>> {
+        self.libraries.values()
+    }
+
+    /// The library registering a function, with the function.
+    pub fn find(&self, function: &str) -> Option<(Arc<Library>, FunctionInfo)> {
+        self.libraries.values().find_map(|library| {
+            library.functions.iter().find(|x| x.name == function).map(|x| (library.clone(), x.clone()))
+        })
+    }
+
+    /// The code of every library, what RDB files and `FUNCTION DUMP` hold.
+    pub fn codes(&self) -> Vec<String> {
+        self.libraries.values().map(|x| x.code.clone()).collect()
+    }
+}
@@ -0,0 +1,341 @@
+/// The tokens of Lua 5.1.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Name(String),
+    Number(f64),
+    Str(Vec<u8>),
+    And, Break, Do, Else, Elseif, End, False, For, Function, If, In, Local, Nil, Not, Or, Repeat,
+    Return, Then, True, Until, While,
+    Plus, Minus, Star, Slash, Percent, Caret, Hash, Eq, Ne, Le, Ge, Lt, Gt, Assign, LParen, RParen,
+    LBrace, RBrace, LBracket, RBracket, Semicolon, Colon, Comma, Dot, Concat, Ellipsis,
+    Eof,
+}
+
+impl Token {
+    /// How the token appears in error messages, like `near 'end'`.
+    pub fn describe(&self) -> String {
+        let text = match self {
+            Token::Name(x) => x.as_str(),
+            Token::Number(x) => return crate::scripting::value::format_number(*x),
+            Token::Str(x) => return String::from_utf8_lossy(x).to_string(),
+            Token::And => "and", Token::Break => "break", Token::Do => "do", Token::Else => "else",
+            Token::Elseif => "elseif", Token::End => "end", Token::False => "false", Token::For => "for",
+            Token::Function => "function", Token::If => "if", Token::In => "in", Token::Local => "local",
+            Token::Nil => "nil", Token::Not => "not", Token::Or => "or", Token::Repeat => "repeat",
+            Token::Return => "return", Token::Then => "then", Token::True => "true", Token::Until => "until",
+            Token::While => "while", Token::Plus => "+", Token::Minus => "-", Token::Star => "*",
+            Token::Slash => "/", Token::Percent => "%", Token::Caret => "^", Token::Hash => "#",
+            Token::Eq => "==", Token::Ne => "~=", Token::Le => "<=", Token::Ge => ">=", Token::Lt => "<",
+            Token::Gt => ">", Token::Assign => "=", Token::LParen => "(", Token::RParen => ")",
+            Token::LBrace => "{", Token::RBrace => "}", Token::LBracket => "[", Token::RBracket => "]",
+            Token::Semicolon => ";", Token::Colon => ":", Token::Comma => ",", Token::Dot => ".",
+            Token::Concat => "..", Token::Ellipsis => "...", Token::Eof => "<eof>",
+        };
+        text.to_string()
+    }
+}
+
+fn keyword(name: &str) -> Option<Token> {
+    let token = match name {
+        "and" => Token::And, "break" => Token::Break, "do" => Token::Do, "else" => Token::Else,
+        "elseif" => Token::Elseif, "end" => Token::End, "false" => Token::False, "for" => Token::For,
+        "function" => Token::Function, "if" => Token::If, "in" => Token::In, "local" => Token::Local,
+        "nil" => Token::Nil, "not" => Token::Not, "or" => Token::Or, "repeat" => Token::Repeat,
+        "return" => Token::Return, "then" => Token::Then, "true" => Token::True, "until" => Token::Until,
+        "while" => Token::While,
+        _ => return None
+    };
+    Some(token)
+}
+
+/// Splits a chunk into tokens, each with the line it starts on. Errors are prefixed with the
+/// chunk name and line, like the ones of the reference implementation.
+pub fn tokenize(chunk_name: &str, source: &[u8]) -> Result<Vec<(Token, u32)>, String> {
+    let mut lexer = Lexer { source, position: 0, line: 1, token_line: 1 };
+    let mut tokens = vec![];
+    loop {
+        let token = lexer.next_token().map_err(|e| format!("{}:{}: {}", chunk_name, lexer.line, e))?;
+        let done = token == Token::Eof;
+        tokens.push((token, lexer.token_line));
+        if done {
+            return Ok(tokens);
+        }
+    }
+}
+
+struct Lexer<'a> {
+    source: &'a [u8],
+    position: usize,
+    line: u32,
+    // Where the last token started
+    token_line: u32,
+}
+
+impl Lexer<'_> {
+    fn peek(&self, offset: usize) -> u8 {
+        self.source.get(self.position + offset).copied().unwrap_or(0)
+    }
+
+    fn next_token(&mut self) -> Result<Token, String> {
+        self.skip_whitespace_and_comments()?;
+        self.token_line = self.line;
+        if self.position >= self.source.len() {
+            return Ok(Token::Eof);
+        }
+        let c = self.peek(0);
+        if c.is_ascii_alphabetic() || c == b'_' {
+            let start = self.position;
+            while self.peek(0).is_ascii_alphanumeric() || self.peek(0) == b'_' {
+                self.position += 1;
+            }
+            let name = String::from_utf8_lossy(&self.source[start..self.position]).to_string();
+            return Ok(keyword(&name).unwrap_or(Token::Name(name)));
+        }
+        if c.is_ascii_digit() || (c == b'.' && self.peek(1).is_ascii_digit()) {
+            return self.number();
+        }
+        if c == b'"' || c == b'\'' {
+            return self.string(c);
+        }
+        if c == b'[' && (self.peek(1) == b'[' || self.peek(1) == b'=') {
+            if let Some(level) = self.long_bracket_level() {
+                return Ok(Token::Str(self.long_string(level)?));
+            }
+        }
+        let two = [c, self.peek(1)];
+        let token = match &two {
+            b"==" => Some(Token::Eq),
+            b"~=" => Some(Token::Ne),
+            b"<=" => Some(Token::Le),
+            b">=" => Some(Token::Ge),
+            b".." if self.peek(2) == b'.' => {
+                self.position += 3;
+                return Ok(Token::Ellipsis);
+            },
+            b".." => Some(Token::Concat),
+            _ => None
+        };
+        if let Some(token) = token {
+            self.position += 2;
+            return Ok(token);
+        }
+        let token = match c {
+            b'+' => Token::Plus, b'-' => Token::Minus, b'*' => Token::Star, b'/' => Token::Slash,
+            b'%' => Token::Percent, b'^' => Token::Caret, b'#' => Token::Hash, b'<' => Token::Lt,
+            b'>' => Token::Gt, b'=' => Token::Assign, b'(' => Token::LParen, b')' => Token::RParen,
+            b'{' => Token::LBrace, b'}' => Token::RBrace, b'[' => Token::LBracket, b']' => Token::RBracket,
+            b';' => Token::Semicolon, b':' => Token::Colon, b',' => Token::Comma, b'.' => Token::Dot,
+            _ => return Err(format!("unexpected symbol near '{}'", c as char))
+        };
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn skip_whitespace_and_comments(&mut self) -> Result<(), String> {
+        loop {
+            let c = self.peek(0);
+            if self.position >= self.source.len() {
+                return Ok(());
+            }
+            if c == b'\n' {
+                self.line += 1;
+                self.position += 1;
+            } else if c.is_ascii_whitespace() {
+                self.position += 1;
+            } else if c == b'-' && self.peek(1) == b'-' {
+                self.position += 2;
+                if self.peek(0) == b'[' {
+                    if let Some(level) = self.long_bracket_level() {
+                        self.long_string(level)?;
+                        continue;
+                    }
+                }
+                while self.position < self.source.len() && self.peek(0) != b'\n' {
+                    self.position += 1;
+                }
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    /// The number of `=` of a `[==[` opening bracket at the current position.
+    fn long_bracket_level(&self) -> Option<usize> {
+        let mut level = 0;
+        while self.peek(1 + level) == b'=' {
+            level += 1;
+        }
+        if self.peek(1 + level) == b'[' {
+            Some(level)
+        } else {
+            None
+        }
+    }
+
+    fn long_string(&mut self, level: usize) -> Result<Vec<u8>, String> {
+        self.position += level + 2;
+        // A newline right after the opening bracket is skipped
+        if self.peek(0) == b'\r' {
+            self.position += 1;
+        }
+        if self.peek(0) == b'\n' {
+            self.line += 1;
+            self.position += 1;
+        }
+        let start = self.position;
+        loop {
+            if self.position >= self.source.len() {
+                return Err("unfinished long string near '<eof>'".to_string());
+            }
+            if self.peek(0) == b']' && (1..=level).all(|i| self.peek(i) == b'=') && self.peek(level + 1) == b']' {
+                let content = self.source[start..self.position].to_vec();
+                self.position += level + 2;
+                return Ok(content);
+            }
+            if self.peek(0) == b'\n' {
+                self.line += 1;
+            }
+            self.position += 1;
+        }
+    }
+
+    fn number(&mut self) -> Result<Token, String> {
+        let start = self.position;
+        if self.peek(0) == b'0' && (self.peek(1) == b'x' || self.peek(1) == b'X') {
+            self.position += 2;
+        }
+        loop {
+            let c = self.peek(0);
+            let exponent_sign = (c == b'+' || c == b'-') && matches!(self.source[self.position - 1], b'e' | b'E') && !self.source[start..self.position].starts_with(b"0x");
+            if exponent_sign || c.is_ascii_alphanumeric() || c == b'.' || c == b'_' {
+                self.position += 1;
+            } else {
+                break;
+            }
+        }
+        let text = String::from_utf8_lossy(&self.source[start..self.position]).to_string();
+        match crate::scripting::value::parse_number(&text) {
+            Some(x) => Ok(Token::Number(x)),
+            None => Err(format!("malformed number near '{}'", text))
+        }
+    }
+
+    fn string(&mut self, quote: u8) -> Result<Token, String> {
+        let start = self.position;
+        self.position += 1;
+        let mut content = vec![];
+        loop {
+            let c = self.peek(0);
+            if self.position >= self.source.len() || c == b'\n' {
+                return Err(format!("unfinished string near '{}'", String::from_utf8_lossy(&self.source[start..self.position])));
+            }
+            self.position += 1;
+            if c == quote {
+                return Ok(Token::Str(content));
+            }
+            if c != b'\\' {
+                content.push(c);
+                continue;
+            }
+            let escaped = self.peek(0);
+            self.position += 1;
+            match escaped {
+                b'n' => content.push(b'\n'),
+                b't' => content.push(b'\t'),
+                b'r' => content.push(b'\r'),
+                b'a' => content.push(7),
+                b'b' => content.push(8),
+                b'f' => content.push(12),
+                b'v' => content.push(11),
+                b'\\' | b'"' | b'\'' => content.push(escaped),
+                b'\n' => {
+                    self.line += 1;
+                    content.push(b'\n');
+                },
+                b'0'..=b'9' => {
+                    let mut value = (escaped - b'0') as u32;
+                    for _ in 0..2 {
+                        if !self.peek(0).is_ascii_digit() {
+                            break;
+                        }
+                        value = value * 10 + (self.peek(0) - b'0') as u32;
+                        self.position += 1;
+                    }
+                    if value > 255 {
+                        return Err("escape sequence too large".to_string());
+                    }
+                    content.push(value as u8);
+                },
+                b'x' => {
+                    let digits = [self.peek(0), self.peek(1)];
+                    let value = std::str::from_utf8(&digits).ok().and_then(|x| u8::from_str_radix(x, 16).ok());
+                    match value {
+                        Some(value) => {
+                            content.push(value);
+                            self.position += 2;
+                        },
+                        None => return Err("hexadecimal digit expected".to_string())
+                    }
+                },
+                _ => {
+                    content.push(escaped);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(source: &str) -> Vec<Token> {
+        tokenize("test", source.as_bytes()).unwrap().into_iter().map(|(token, _)| token).collect()
+    }
+
+    #[test]
+    fn keywords_names_and_operators() {
+        assert_eq!(tokens("local x_1 = a.b ~= nil"), vec![
+            Token::Local, Token::Name("x_1".to_string()), Token::Assign, Token::Name("a".to_string()), Token::Dot,
+            Token::Name("b".to_string()), Token::Ne, Token::Nil, Token::Eof
+        ]);
+        assert_eq!(tokens("... .. . <= >= == < > # ^ %"), vec![
+            Token::Ellipsis, Token::Concat, Token::Dot, Token::Le, Token::Ge, Token::Eq, Token::Lt, Token::Gt,
+            Token::Hash, Token::Caret, Token::Percent, Token::Eof
+        ]);
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(tokens("3 3.5 .5 1e2 0x1F 2E-1"), vec![
+            Token::Number(3.0), Token::Number(3.5), Token::Number(0.5), Token::Number(100.0), Token::Number(31.0),
+            Token::Number(0.2), Token::Eof
+        ]);
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(tokens(r#""a\"b\n" 'c\65\x41' [[long
+string]] [==[with ]] inside]==]"#), vec![
+            Token::Str(b"a\"b\n".to_vec()), Token::Str(b"cAA".to_vec()), Token::Str(b"long\nstring".to_vec()),
+            Token::Str(b"with ]] inside".to_vec()), Token::Eof
+        ]);
+    }
+
+    #[test]
+    fn comments_and_lines() {
+        let lines: Vec<(Token, u32)> = tokenize("test", b"-- comment\na --[[ long\ncomment ]] b\n\nc").unwrap();
+        assert_eq!(lines, vec![
+            (Token::Name("a".to_string()), 2), (Token::Name("b".to_string()), 3), (Token::Name("c".to_string()), 5),
+            (Token::Eof, 5)
+        ]);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(tokenize("test", b"x = \"unfinished").unwrap_err().starts_with("test:1:"));
+        assert!(tokenize("test", b"\n[[unfinished").unwrap_err().starts_with("test:"));
+        assert!(tokenize("test", b"x = 3x").is_err());
+        assert!(tokenize("test", b"x = @").is_err());
+    }
+}
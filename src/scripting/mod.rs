@@ -0,0 +1,10 @@
+pub mod lexer;
+pub mod ast;
+pub mod parser;
+pub mod value;
+pub mod vm;
+pub mod pattern;
+pub mod stdlib;
+pub mod cjson;
+pub mod engine;
+pub mod functions;
@@ -0,0 +1,542 @@
+use std::sync::Arc;
+
+use crate::scripting::ast::{BinaryOperator, Block, Expression, FunctionBody, Statement, StatementKind, TableField, UnaryOperator};
+use crate::scripting::lexer::{tokenize, Token};
+
+/// Nesting of blocks and expressions allowed, deeper chunks are refused rather than overflowing
+/// the stack.
+const MAX_SYNTAX_LEVELS: usize = 200;
+
+/// Compiles a chunk into the body of a vararg function, like `loadstring`.
+pub fn parse(chunk_name: &str, source: &[u8]) -> Result<Arc<FunctionBody>, String> {
+    let tokens = tokenize(chunk_name, source)?;
+    let mut parser = Parser { chunk_name, tokens, position: 0, depth: 0 };
+    let body = parser.block()?;
+    if parser.peek() != &Token::Eof {
+        return Err(parser.error(&format!("'<eof>' expected near '{}'", parser.peek().describe())));
+    }
+    Ok(Arc::new(FunctionBody { parameters: vec![], vararg: true, body }))
+}
+
+struct Parser<'a> {
+    chunk_name: &'a str,
+    tokens: Vec<(Token, u32)>,
+    position: usize,
+    depth: usize,
+}
+
+/// Left and right priorities of the binary operators, higher binds tighter. Right associative
+/// operators have a lower right priority.
+fn binary_operator(token: &Token) -> Option<(BinaryOperator, u8, u8)> {
+    let operator = match token {
+        Token::Plus => (BinaryOperator::Add, 6, 6),
+        Token::Minus => (BinaryOperator::Sub, 6, 6),
+        Token::Star => (BinaryOperator::Mul, 7, 7),
+        Token::Slash => (BinaryOperator::Div, 7, 7),
+        Token::Percent => (BinaryOperator::Mod, 7, 7),
+        Token::Caret => (BinaryOperator::Pow, 10, 9),
+        Token::Concat => (BinaryOperator::Concat, 5, 4),
+        Token::Eq => (BinaryOperator::Eq, 3, 3),
+        Token::Ne => (BinaryOperator::Ne, 3, 3),
+        Token::Lt => (BinaryOperator::Lt, 3, 3),
+        Token::Le => (BinaryOperator::Le, 3, 3),
+        Token::Gt => (BinaryOperator::Gt, 3, 3),
+        Token::Ge => (BinaryOperator::Ge, 3, 3),
+        _ => return None
+    };
+    Some(operator)
+}
+
+const UNARY_PRIORITY: u8 = 8;
+// `and` and `or` short-circuit, they are not plain binary operators
+const AND_PRIORITY: u8 = 2;
+const OR_PRIORITY: u8 = 1;
+
+impl Parser<'_> {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.position].0
+    }
+
+    fn peek_at(&self, offset: usize) -> &Token {
+        let index = (self.position + offset).min(self.tokens.len() - 1);
+        &self.tokens[index].0
+    }
+
+    fn line(&self) -> u32 {
+        self.tokens[self.position].1
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.position].0.clone();
+        if self.position < self.tokens.len() - 1 {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn error(&self, message: &str) -> String {
+        format!("{}:{}: {}", self.chunk_name, self.line(), message)
+    }
+
+    fn check(&mut self, token: Token) -> Result<(), String> {
+        if self.peek() == &token {
+            self.advance();
+            return Ok(());
+        }
+        Err(self.error(&format!("'{}' expected near '{}'", token.describe(), self.peek().describe())))
+    }
+
+    /// Like `check` for the token closing a construct opened on another line.
+    fn check_match(&mut self, token: Token, opening: Token, line: u32) -> Result<(), String> {
+        if self.peek() == &token {
+            self.advance();
+            return Ok(());
+        }
+        if line == self.line() {
+            return self.check(token);
+        }
+        Err(self.error(&format!("'{}' expected (to close '{}' at line {}) near '{}'", token.describe(), opening.describe(), line, self.peek().describe())))
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        match self.peek().clone() {
+            Token::Name(name) => {
+                self.advance();
+                Ok(name)
+            },
+            token => Err(self.error(&format!("<name> expected near '{}'", token.describe())))
+        }
+    }
+
+    fn enter(&mut self) -> Result<(), String> {
+        self.depth += 1;
+        if self.depth > MAX_SYNTAX_LEVELS {
+            return Err(self.error("chunk has too many syntax levels"));
+        }
+        Ok(())
+    }
+
+    fn block(&mut self) -> Result<Block, String> {
+        self.enter()?;
+        let mut statements = vec![];
+        loop {
+            match self.peek() {
+                Token::Eof | Token::End | Token::Else | Token::Elseif | Token::Until => break,
+                Token::Semicolon => {
+                    self.advance();
+                },
+                Token::Return => {
+                    let line = self.line();
+                    self.advance();
+                    let expressions = match self.peek() {
+                        Token::Eof | Token::End | Token::Else | Token::Elseif | Token::Until | Token::Semicolon => vec![],
+                        _ => self.expression_list()?
+                    };
+                    if self.peek() == &Token::Semicolon {
+                        self.advance();
+                    }
+                    statements.push(Statement { kind: StatementKind::Return(expressions), line });
+                    // Nothing can follow a return in its block
+                    if !matches!(self.peek(), Token::Eof | Token::End | Token::Else | Token::Elseif | Token::Until) {
+                        return Err(self.error(&format!("'<eof>' expected near '{}'", self.peek().describe())));
+                    }
+                    break;
+                },
+                _ => {
+                    let statement = self.statement()?;
+                    statements.push(statement);
+                }
+            }
+        }
+        self.depth -= 1;
+        Ok(Block { statements })
+    }
+
+    fn statement(&mut self) -> Result<Statement, String> {
+        let line = self.line();
+        let kind = match self.peek() {
+            Token::If => self.if_statement(line)?,
+            Token::While => {
+                self.advance();
+                let condition = self.expression()?;
+                self.check(Token::Do)?;
+                let body = self.block()?;
+                self.check_match(Token::End, Token::While, line)?;
+                StatementKind::While(condition, body)
+            },
+            Token::Do => {
+                self.advance();
+                let body = self.block()?;
+                self.check_match(Token::End, Token::Do, line)?;
+                StatementKind::Do(body)
+            },
+            Token::For => self.for_statement(line)?,
+            Token::Repeat => {
+                self.advance();
+                let body = self.block()?;
+                self.check_match(Token::Until, Token::Repeat, line)?;
+                let condition = self.expression()?;
+                StatementKind::Repeat(body, condition)
+            },
+            Token::Function => {
+                self.advance();
+                // `function a.b.c:m()` assigns to `a.b.c.m` a function taking `self` first
+                let mut target = Expression::Name(self.name()?);
+                let mut method = false;
+                loop {
+                    match self.peek() {
+                        Token::Dot => {
+                            self.advance();
+                            let key = self.name()?;
+                            target = Expression::Index(Box::new(target), Box::new(Expression::Str(key.into_bytes())));
+                        },
+                        Token::Colon => {
+                            self.advance();
+                            let key = self.name()?;
+                            target = Expression::Index(Box::new(target), Box::new(Expression::Str(key.into_bytes())));
+                            method = true;
+                            break;
+                        },
+                        _ => break
+                    }
+                }
+                let body = self.function_body(method, line)?;
+                StatementKind::Assign(vec![target], vec![Expression::Function(body)])
+            },
+            Token::Local => {
+                self.advance();
+                if self.peek() == &Token::Function {
+                    self.advance();
+                    let name = self.name()?;
+                    let body = self.function_body(false, line)?;
+                    StatementKind::LocalFunction(name, body)
+                } else {
+                    let mut names = vec![self.name()?];
+                    while self.peek() == &Token::Comma {
+                        self.advance();
+                        names.push(self.name()?);
+                    }
+                    let expressions = if self.peek() == &Token::Assign {
+                        self.advance();
+                        self.expression_list()?
+                    } else {
+                        vec![]
+                    };
+                    StatementKind::Local(names, expressions)
+                }
+            },
+            Token::Break => {
+                self.advance();
+                StatementKind::Break
+            },
+            _ => self.expression_statement()?
+        };
+        Ok(Statement { kind, line })
+    }
+
+    fn if_statement(&mut self, line: u32) -> Result<StatementKind, String> {
+        let mut clauses = vec![];
+        let mut otherwise = None;
+        self.advance();
+        let condition = self.expression()?;
+        self.check(Token::Then)?;
+        clauses.push((condition, self.block()?));
+        loop {
+            match self.peek() {
+                Token::Elseif => {
+                    self.advance();
+                    let condition = self.expression()?;
+                    self.check(Token::Then)?;
+                    clauses.push((condition, self.block()?));
+                },
+                Token::Else => {
+                    self.advance();
+                    otherwise = Some(self.block()?);
+                    self.check_match(Token::End, Token::If, line)?;
+                    break;
+                },
+                _ => {
+                    self.check_match(Token::End, Token::If, line)?;
+                    break;
+                }
+            }
+        }
+        Ok(StatementKind::If(clauses, otherwise))
+    }
+
+    fn for_statement(&mut self, line: u32) -> Result<StatementKind, String> {
+        self.advance();
+        let first = self.name()?;
+        match self.peek() {
+            Token::Assign => {
+                self.advance();
+                let start = self.expression()?;
+                self.check(Token::Comma)?;
+                let limit = self.expression()?;
+                let step = if self.peek() == &Token::Comma {
+                    self.advance();
+                    Some(self.expression()?)
+                } else {
+                    None
+                };
+                self.check(Token::Do)?;
+                let body = self.block()?;
+                self.check_match(Token::End, Token::For, line)?;
+                Ok(StatementKind::NumericFor(first, start, limit, step, body))
+            },
+            Token::Comma | Token::In => {
+                let mut names = vec![first];
+                while self.peek() == &Token::Comma {
+                    self.advance();
+                    names.push(self.name()?);
+                }
+                self.check(Token::In)?;
+                let expressions = self.expression_list()?;
+                self.check(Token::Do)?;
+                let body = self.block()?;
+                self.check_match(Token::End, Token::For, line)?;
+                Ok(StatementKind::GenericFor(names, expressions, body))
+            },
+            token => Err(self.error(&format!("'=' or 'in' expected near '{}'", token.describe())))
+        }
+    }
+
+    /// A call, or an assignment to a list of variables.
+    fn expression_statement(&mut self) -> Result<StatementKind, String> {
+        let first = self.suffixed_expression()?;
+        if matches!(self.peek(), Token::Assign | Token::Comma) {
+            let mut targets = vec![first];
+            while self.peek() == &Token::Comma {
+                self.advance();
+                targets.push(self.suffixed_expression()?);
+            }
+            self.check(Token::Assign)?;
+            if targets.iter().any(|x| !matches!(x, Expression::Name(_) | Expression::Index(..))) {
+                return Err(self.error("syntax error near '='"));
+            }
+            let values = self.expression_list()?;
+            return Ok(StatementKind::Assign(targets, values));
+        }
+        if !matches!(first, Expression::Call(..) | Expression::Method(..)) {
+            return Err(self.error(&format!("syntax error near '{}'", self.peek().describe())));
+        }
+        Ok(StatementKind::Call(first))
+    }
+
+    fn function_body(&mut self, method: bool, line: u32) -> Result<Arc<FunctionBody>, String> {
+        let mut parameters = vec![];
+        if method {
+            parameters.push("self".to_string());
+        }
+        let mut vararg = false;
+        self.check(Token::LParen)?;
+        if self.peek() != &Token::RParen {
+            loop {
+                match self.peek() {
+                    Token::Ellipsis => {
+                        self.advance();
+                        vararg = true;
+                        break;
+                    },
+                    _ => parameters.push(self.name()?)
+                }
+                if self.peek() != &Token::Comma {
+                    break;
+                }
+                self.advance();
+            }
+        }
+        self.check(Token::RParen)?;
+        let body = self.block()?;
+        self.check_match(Token::End, Token::Function, line)?;
+        Ok(Arc::new(FunctionBody { parameters, vararg, body }))
+    }
+
+    fn expression_list(&mut self) -> Result<Vec<Expression>, String> {
+        let mut expressions = vec![self.expression()?];
+        while self.peek() == &Token::Comma {
+            self.advance();
+            expressions.push(self.expression()?);
+        }
+        Ok(expressions)
+    }
+
+    fn expression(&mut self) -> Result<Expression, String> {
+        self.subexpression(0)
+    }
+
+    /// Operators binding tighter than `limit`, the precedence climbing of `lparser.c`.
+    fn subexpression(&mut self, limit: u8) -> Result<Expression, String> {
+        self.enter()?;
+        let unary = match self.peek() {
+            Token::Not => Some(UnaryOperator::Not),
+            Token::Minus => Some(UnaryOperator::Minus),
+            Token::Hash => Some(UnaryOperator::Length),
+            _ => None
+        };
+        let mut left = match unary {
+            Some(operator) => {
+                self.advance();
+                let operand = self.subexpression(UNARY_PRIORITY)?;
+                match (operator, operand) {
+                    // Negative literals are folded
+                    (UnaryOperator::Minus, Expression::Number(x)) => Expression::Number(-x),
+                    (operator, operand) => Expression::Unary(operator, Box::new(operand))
+                }
+            },
+            None => self.simple_expression()?
+        };
+        loop {
+            let token = self.peek().clone();
+            if token == Token::And && AND_PRIORITY > limit {
+                self.advance();
+                let right = self.subexpression(AND_PRIORITY)?;
+                left = Expression::And(Box::new(left), Box::new(right));
+                continue;
+            }
+            if token == Token::Or && OR_PRIORITY > limit {
+                self.advance();
+                let right = self.subexpression(OR_PRIORITY)?;
+                left = Expression::Or(Box::new(left), Box::new(right));
+                continue;
+            }
+            match binary_operator(&token) {
+                Some((operator, left_priority, right_priority)) if left_priority > limit => {
+                    self.advance();
+                    let right = self.subexpression(right_priority)?;
+                    left = Expression::Binary(operator, Box::new(left), Box::new(right));
+                },
+                _ => break
+            }
+        }
+        self.depth -= 1;
+        Ok(left)
+    }
+
+    fn simple_expression(&mut self) -> Result<Expression, String> {
+        let line = self.line();
+        let expression = match self.peek().clone() {
+            Token::Number(x) => Expression::Number(x),
+            Token::Str(x) => Expression::Str(x),
+            Token::Nil => Expression::Nil,
+            Token::True => Expression::True,
+            Token::False => Expression::False,
+            Token::Ellipsis => Expression::Vararg,
+            Token::LBrace => return self.table_constructor(),
+            Token::Function => {
+                self.advance();
+                return Ok(Expression::Function(self.function_body(false, line)?));
+            },
+            _ => return self.suffixed_expression()
+        };
+        self.advance();
+        Ok(expression)
+    }
+
+    fn primary_expression(&mut self) -> Result<Expression, String> {
+        match self.peek().clone() {
+            Token::Name(name) => {
+                self.advance();
+                Ok(Expression::Name(name))
+            },
+            Token::LParen => {
+                let line = self.line();
+                self.advance();
+                let inner = self.expression()?;
+                self.check_match(Token::RParen, Token::LParen, line)?;
+                Ok(Expression::Paren(Box::new(inner)))
+            },
+            token => Err(self.error(&format!("unexpected symbol near '{}'", token.describe())))
+        }
+    }
+
+    fn suffixed_expression(&mut self) -> Result<Expression, String> {
+        let mut expression = self.primary_expression()?;
+        loop {
+            match self.peek().clone() {
+                Token::Dot => {
+                    self.advance();
+                    let key = self.name()?;
+                    expression = Expression::Index(Box::new(expression), Box::new(Expression::Str(key.into_bytes())));
+                },
+                Token::LBracket => {
+                    self.advance();
+                    let key = self.expression()?;
+                    self.check(Token::RBracket)?;
+                    expression = Expression::Index(Box::new(expression), Box::new(key));
+                },
+                Token::Colon => {
+                    self.advance();
+                    let name = self.name()?;
+                    let arguments = self.call_arguments()?;
+                    expression = Expression::Method(Box::new(expression), name, arguments);
+                },
+                Token::LParen | Token::Str(_) | Token::LBrace => {
+                    // A call can't start on the line after its function, that would be ambiguous
+                    if self.peek() == &Token::LParen && self.position > 0 && self.tokens[self.position - 1].1 != self.line() {
+                        return Err(self.error("ambiguous syntax (function call x new statement) near '('"));
+                    }
+                    let arguments = self.call_arguments()?;
+                    expression = Expression::Call(Box::new(expression), arguments);
+                },
+                _ => return Ok(expression)
+            }
+        }
+    }
+
+    fn call_arguments(&mut self) -> Result<Vec<Expression>, String> {
+        match self.peek().clone() {
+            Token::Str(x) => {
+                self.advance();
+                Ok(vec![Expression::Str(x)])
+            },
+            Token::LBrace => Ok(vec![self.table_constructor()?]),
+            Token::LParen => {
+                let line = self.line();
+                self.advance();
+                if self.peek() == &Token::RParen {
+                    self.advance();
+                    return Ok(vec![]);
+                }
+                let arguments = self.expression_list()?;
+                self.check_match(Token::RParen, Token::LParen, line)?;
+                Ok(arguments)
+            },
+            token => Err(self.error(&format!("function arguments expected near '{}'", token.describe())))
+        }
+    }
+
+    fn table_constructor(&mut self) -> Result<Expression, String> {
+        let line = self.line();
+        self.check(Token::LBrace)?;
+        let mut fields = vec![];
+        while self.peek() != &Token::RBrace {
+            match (self.peek().clone(), self.peek_at(1).clone()) {
+                (Token::Name(name), Token::Assign) => {
+                    self.advance();
+                    self.advance();
+                    let value = self.expression()?;
+                    fields.push(TableField::Keyed(Expression::Str(name.into_bytes()), value));
+                },
+                (Token::LBracket, _) => {
+                    self.advance();
+                    let key = self.expression()?;
+                    self.check(Token::RBracket)?;
+                    self.check(Token::Assign)?;
+                    let value = self.expression()?;
+                    fields.push(TableField::Keyed(key, value));
+                },
+                _ => {
+                    fields.push(TableField::Positional(self.expression()?));
+                }
+            }
+            if matches!(self.peek(), Token::Comma | Token::Semicolon) {
+                self.advance();
+            } else {
+                break;
+            }
+        }
+        self.check_match(Token::RBrace, Token::LBrace, line)?;
+        Ok(Expression::Table(fields))
+    }
+}
@@ -0,0 +1,344 @@
+// Lua patterns, as used by `string.find`, `match`, `gmatch` and `gsub`. A port of the matcher of
+// the reference implementation, with its error messages.
+
+const MAX_CAPTURES: usize = 32;
+const ESCAPE: u8 = b'%';
+const SPECIALS: &[u8] = b"^$*+?.([%-";
+
+// The length of a capture that isn't closed yet, and of a position capture `()`
+const CAPTURE_UNFINISHED: isize = -1;
+const CAPTURE_POSITION: isize = -2;
+
+/// A capture of a successful match.
+#[derive(Debug, Clone)]
+pub enum Capture {
+    Text(Vec<u8>),
+    // `()` captures the position, counting from 1
+    Position(usize),
+}
+
+pub struct Matcher<'a> {
+    source: &'a [u8],
+    pattern: &'a [u8],
+    // Starts and lengths of the captures
+    captures: Vec<(usize, isize)>,
+}
+
+/// Whether a pattern has none of the special characters, so that `find` can do a plain search.
+pub fn is_plain(pattern: &[u8]) -> bool {
+    !pattern.iter().any(|x| SPECIALS.contains(x))
+}
+
+/// Where `needle` appears in `haystack` at or after `from`.
+pub fn find_plain(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(from);
+    }
+    if from > haystack.len() {
+        return None;
+    }
+    haystack[from..].windows(needle.len()).position(|x| x == needle).map(|x| x + from)
+}
+
+fn match_class(c: u8, class: u8) -> bool {
+    let matches = match class.to_ascii_lowercase() {
+        b'a' => c.is_ascii_alphabetic(),
+        b'c' => c.is_ascii_control(),
+        b'd' => c.is_ascii_digit(),
+        b'l' => c.is_ascii_lowercase(),
+        b'p' => c.is_ascii_punctuation(),
+        b's' => c.is_ascii_whitespace() || c == 11,
+        b'u' => c.is_ascii_uppercase(),
+        b'w' => c.is_ascii_alphanumeric(),
+        b'x' => c.is_ascii_hexdigit(),
+        b'z' => c == 0,
+        _ => return class == c
+    };
+    if class.is_ascii_uppercase() {
+        return !matches;
+    }
+    matches
+}
+
+impl<'a> Matcher<'a> {
+    pub fn new(source: &'a [u8], pattern: &'a [u8]) -> Self {
+        Self { source, pattern, captures: vec![] }
+    }
+
+    fn pattern_at(&self, p: usize) -> u8 {
+        self.pattern.get(p).copied().unwrap_or(0)
+    }
+
+    /// Tries to match the pattern, starting at `p`, on the source at `s`. Returns where the match
+    /// ends.
+    pub fn try_match(&mut self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        self.captures.clear();
+        self.do_match(s, p)
+    }
+
+    fn class_end(&self, mut p: usize) -> Result<usize, String> {
+        let c = self.pattern_at(p);
+        p += 1;
+        if c == ESCAPE {
+            if p >= self.pattern.len() {
+                return Err("malformed pattern (ends with '%')".to_string());
+            }
+            return Ok(p + 1);
+        }
+        if c == b'[' {
+            if self.pattern_at(p) == b'^' {
+                p += 1;
+            }
+            // Look for the `]`, the first character is never the end
+            loop {
+                if p >= self.pattern.len() {
+                    return Err("malformed pattern (missing ']')".to_string());
+                }
+                let c = self.pattern[p];
+                p += 1;
+                if c == ESCAPE && p < self.pattern.len() {
+                    p += 1;
+                }
+                if self.pattern_at(p) == b']' {
+                    break;
+                }
+            }
+            return Ok(p + 1);
+        }
+        Ok(p)
+    }
+
+    /// `p` is at the `[` of the class, `end` at its `]`.
+    fn match_bracket_class(&self, c: u8, mut p: usize, end: usize) -> bool {
+        let mut found = true;
+        if self.pattern_at(p + 1) == b'^' {
+            found = false;
+            p += 1;
+        }
+        p += 1;
+        while p < end {
+            if self.pattern[p] == ESCAPE {
+                p += 1;
+                if match_class(c, self.pattern_at(p)) {
+                    return found;
+                }
+            } else if self.pattern_at(p + 1) == b'-' && p + 2 < end {
+                if self.pattern[p] <= c && c <= self.pattern[p + 2] {
+                    return found;
+                }
+                p += 2;
+            } else if self.pattern[p] == c {
+                return found;
+            }
+            p += 1;
+        }
+        !found
+    }
+
+    fn single_match(&self, s: usize, p: usize, end: usize) -> bool {
+        if s >= self.source.len() {
+            return false;
+        }
+        let c = self.source[s];
+        match self.pattern[p] {
+            b'.' => true,
+            ESCAPE => match_class(c, self.pattern_at(p + 1)),
+            b'[' => self.match_bracket_class(c, p, end - 1),
+            x => x == c
+        }
+    }
+
+    fn do_match(&mut self, mut s: usize, mut p: usize) -> Result<Option<usize>, String> {
+        loop {
+            if p >= self.pattern.len() {
+                return Ok(Some(s));
+            }
+            match self.pattern[p] {
+                b'(' => {
+                    if self.pattern_at(p + 1) == b')' {
+                        return self.start_capture(s, p + 2, CAPTURE_POSITION);
+                    }
+                    return self.start_capture(s, p + 1, CAPTURE_UNFINISHED);
+                },
+                b')' => return self.end_capture(s, p + 1),
+                b'$' if p + 1 == self.pattern.len() => {
+                    return Ok(if s == self.source.len() { Some(s) } else { None });
+                },
+                ESCAPE if self.pattern_at(p + 1) == b'b' => {
+                    match self.match_balance(s, p + 2)? {
+                        Some(end) => {
+                            s = end;
+                            p += 4;
+                            continue;
+                        },
+                        None => return Ok(None)
+                    }
+                },
+                ESCAPE if self.pattern_at(p + 1) == b'f' => {
+                    p += 2;
+                    if self.pattern_at(p) != b'[' {
+                        return Err("missing '[' after '%f' in pattern".to_string());
+                    }
+                    let end = self.class_end(p)?;
+                    let previous = if s == 0 { 0 } else { self.source[s - 1] };
+                    let current = self.source.get(s).copied().unwrap_or(0);
+                    if self.match_bracket_class(previous, p, end - 1) || !self.match_bracket_class(current, p, end - 1) {
+                        return Ok(None);
+                    }
+                    p = end;
+                    continue;
+                },
+                ESCAPE if self.pattern_at(p + 1).is_ascii_digit() => {
+                    match self.match_capture(s, self.pattern_at(p + 1))? {
+                        Some(end) => {
+                            s = end;
+                            p += 2;
+                            continue;
+                        },
+                        None => return Ok(None)
+                    }
+                },
+                _ => {}
+            }
+            let end = self.class_end(p)?;
+            let matched = self.single_match(s, p, end);
+            match self.pattern_at(end) {
+                b'?' => {
+                    if matched {
+                        if let Some(result) = self.do_match(s + 1, end + 1)? {
+                            return Ok(Some(result));
+                        }
+                    }
+                    p = end + 1;
+                },
+                b'*' => return self.max_expand(s, p, end),
+                b'+' => {
+                    if !matched {
+                        return Ok(None);
+                    }
+                    return self.max_expand(s + 1, p, end);
+                },
+                b'-' => return self.min_expand(s, p, end),
+                _ => {
+                    if !matched {
+                        return Ok(None);
+                    }
+                    s += 1;
+                    p = end;
+                }
+            }
+        }
+    }
+
+    fn max_expand(&mut self, s: usize, p: usize, end: usize) -> Result<Option<usize>, String> {
+        let mut count = 0;
+        while self.single_match(s + count, p, end) {
+            count += 1;
+        }
+        loop {
+            if let Some(result) = self.do_match(s + count, end + 1)? {
+                return Ok(Some(result));
+            }
+            if count == 0 {
+                return Ok(None);
+            }
+            count -= 1;
+        }
+    }
+
+    fn min_expand(&mut self, mut s: usize, p: usize, end: usize) -> Result<Option<usize>, String> {
+        loop {
+            if let Some(result) = self.do_match(s, end + 1)? {
+                return Ok(Some(result));
+            }
+            if !self.single_match(s, p, end) {
+                return Ok(None);
+            }
+            s += 1;
+        }
+    }
+
+    fn start_capture(&mut self, s: usize, p: usize, what: isize) -> Result<Option<usize>, String> {
+        if self.captures.len() >= MAX_CAPTURES {
+            return Err("too many captures".to_string());
+        }
+        self.captures.push((s, what));
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.captures.pop();
+        }
+        Ok(result)
+    }
+
+    fn end_capture(&mut self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        let open = self.captures.iter().rposition(|x| x.1 == CAPTURE_UNFINISHED).ok_or("invalid pattern capture")?;
+        self.captures[open].1 = (s - self.captures[open].0) as isize;
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.captures[open].1 = CAPTURE_UNFINISHED;
+        }
+        Ok(result)
+    }
+
+    fn match_balance(&self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        if p + 1 >= self.pattern.len() {
+            return Err("unbalanced pattern".to_string());
+        }
+        let (open, close) = (self.pattern[p], self.pattern[p + 1]);
+        if s >= self.source.len() || self.source[s] != open {
+            return Ok(None);
+        }
+        let mut depth = 1;
+        let mut i = s + 1;
+        while i < self.source.len() {
+            let c = self.source[i];
+            if c == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(Some(i + 1));
+                }
+            } else if c == open {
+                depth += 1;
+            }
+            i += 1;
+        }
+        Ok(None)
+    }
+
+    fn match_capture(&self, s: usize, digit: u8) -> Result<Option<usize>, String> {
+        let index = (digit - b'0') as usize;
+        if index == 0 || index > self.captures.len() || self.captures[index - 1].1 == CAPTURE_UNFINISHED {
+            return Err("invalid capture index".to_string());
+        }
+        let (start, length) = self.captures[index - 1];
+        let length = length.max(0) as usize;
+        let captured = &self.source[start..start + length];
+        if self.source.len() - s >= length && &self.source[s..s + length] == captured {
+            return Ok(Some(s + length));
+        }
+        Ok(None)
+    }
+
+    /// Capture `i` of the last match, which spanned `start..end`. Without captures, the first
+    /// one is the whole match.
+    pub fn capture(&self, i: usize, start: usize, end: usize) -> Result<Capture, String> {
+        if i >= self.captures.len() {
+            if i == 0 {
+                return Ok(Capture::Text(self.source[start..end].to_vec()));
+            }
+            return Err("invalid capture index".to_string());
+        }
+        let (position, length) = self.captures[i];
+        match length {
+            CAPTURE_UNFINISHED => Err("unfinished capture".to_string()),
+            CAPTURE_POSITION => Ok(Capture::Position(position + 1)),
+            length => Ok(Capture::Text(self.source[position..position + length as usize].to_vec()))
+        }
+    }
+
+    /// All the captures of the last match, or the whole match when it has none and `whole`.
+    pub fn all_captures(&self, start: usize, end: usize, whole: bool) -> Result<Vec<Capture>, String> {
+        let count = if self.captures.is_empty() && whole { 1 } else { self.captures.len() };
+        (0..count).map(|i| self.capture(i, start, end)).collect()
+    }
+}
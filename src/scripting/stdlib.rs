@@ -0,0 +1,1135 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use crate::scripting::pattern::{find_plain, is_plain, Capture, Matcher};
+use crate::scripting::value::{format_g, LuaError, Table, Value};
+use crate::scripting::vm::{compare, Vm};
+
+type Builtin = fn(&mut Vm, Vec<Value>) -> Result<Vec<Value>, LuaError>;
+
+// Strings created by `string.rep` can't be larger than this
+const MAX_STRING_SIZE: usize = 512 * 1024 * 1024;
+
+/// Installs the libraries of Lua 5.1 available to scripts: the base functions, `string`,
+/// `table`, `math` and `bit`.
+pub fn install(vm: &mut Vm) {
+    let base: [(&str, Builtin); 17] = [
+        ("assert", assert), ("error", error), ("ipairs", ipairs), ("next", next), ("pairs", pairs),
+        ("pcall", pcall), ("xpcall", xpcall), ("select", select), ("tonumber", tonumber),
+        ("tostring", tostring), ("type", type_of), ("unpack", unpack), ("rawget", rawget),
+        ("rawset", rawset), ("rawequal", rawequal), ("print", print), ("collectgarbage", collectgarbage),
+    ];
+    for (name, function) in base {
+        vm.set_global(name, Value::native(function));
+    }
+
+    let string = library(&[
+        ("len", string_len), ("sub", string_sub), ("upper", string_upper), ("lower", string_lower),
+        ("rep", string_rep), ("reverse", string_reverse), ("byte", string_byte), ("char", string_char),
+        ("format", string_format), ("find", string_find), ("match", string_match),
+        ("gmatch", string_gmatch), ("gsub", string_gsub),
+    ]);
+    vm.string_library = Some(string.clone());
+    vm.set_global("string", Value::Table(string));
+
+    let table = library(&[
+        ("insert", table_insert), ("remove", table_remove), ("concat", table_concat),
+        ("sort", table_sort), ("getn", table_getn), ("maxn", table_maxn),
+    ]);
+    vm.set_global("table", Value::Table(table));
+
+    let math = library(&[
+        ("abs", math_abs), ("ceil", math_ceil), ("floor", math_floor), ("sqrt", math_sqrt),
+        ("exp", math_exp), ("log", math_log), ("log10", math_log10), ("sin", math_sin),
+        ("cos", math_cos), ("tan", math_tan), ("asin", math_asin), ("acos", math_acos),
+        ("atan", math_atan), ("atan2", math_atan2), ("sinh", math_sinh), ("cosh", math_cosh),
+        ("tanh", math_tanh), ("deg", math_deg), ("rad", math_rad), ("pow", math_pow),
+        ("fmod", math_fmod), ("modf", math_modf), ("frexp", math_frexp), ("ldexp", math_ldexp),
+        ("max", math_max), ("min", math_min),
+    ]);
+    install_random(&mut math.borrow_mut());
+    math.borrow_mut().set_str("huge", Value::Number(f64::INFINITY));
+    math.borrow_mut().set_str("pi", Value::Number(std::f64::consts::PI));
+    vm.set_global("math", Value::Table(math));
+
+    let bit = library(&[
+        ("tobit", bit_tobit), ("tohex", bit_tohex), ("bnot", bit_bnot), ("band", bit_band),
+        ("bor", bit_bor), ("bxor", bit_bxor), ("lshift", bit_lshift), ("rshift", bit_rshift),
+        ("arshift", bit_arshift), ("rol", bit_rol), ("ror", bit_ror), ("bswap", bit_bswap),
+    ]);
+    vm.set_global("bit", Value::Table(bit));
+}
+
+pub fn library(functions: &[(&str, Builtin)]) -> Rc<RefCell<Table>> {
+    let mut table = Table::default();
+    for (name, function) in functions {
+        table.set_str(name, Value::native(*function));
+    }
+    Rc::new(RefCell::new(table))
+}
+
+pub fn argument(arguments: &[Value], i: usize) -> Value {
+    arguments.get(i).cloned().unwrap_or(Value::Nil)
+}
+
+fn type_name_of(arguments: &[Value], i: usize) -> &'static str {
+    arguments.get(i).map(|x| x.type_name()).unwrap_or("no value")
+}
+
+pub fn bad_argument(vm: &Vm, i: usize, function: &str, message: &str) -> LuaError {
+    vm.error(&format!("bad argument #{} to '{}' ({})", i + 1, function, message))
+}
+
+pub fn check_string(vm: &Vm, arguments: &[Value], i: usize, function: &str) -> Result<Rc<[u8]>, LuaError> {
+    match arguments.get(i).and_then(|x| x.to_bytes()) {
+        Some(x) => Ok(x),
+        None => Err(bad_argument(vm, i, function, &format!("string expected, got {}", type_name_of(arguments, i))))
+    }
+}
+
+pub fn check_number(vm: &Vm, arguments: &[Value], i: usize, function: &str) -> Result<f64, LuaError> {
+    match arguments.get(i).and_then(|x| x.to_number()) {
+        Some(x) => Ok(x),
+        None => Err(bad_argument(vm, i, function, &format!("number expected, got {}", type_name_of(arguments, i))))
+    }
+}
+
+pub fn check_integer(vm: &Vm, arguments: &[Value], i: usize, function: &str) -> Result<i64, LuaError> {
+    Ok(check_number(vm, arguments, i, function)? as i64)
+}
+
+pub fn optional_integer(vm: &Vm, arguments: &[Value], i: usize, function: &str, default: i64) -> Result<i64, LuaError> {
+    if argument(arguments, i).is_nil() {
+        return Ok(default);
+    }
+    check_integer(vm, arguments, i, function)
+}
+
+pub fn check_table(vm: &Vm, arguments: &[Value], i: usize, function: &str) -> Result<Rc<RefCell<Table>>, LuaError> {
+    match arguments.get(i) {
+        Some(Value::Table(x)) => Ok(x.clone()),
+        _ => Err(bad_argument(vm, i, function, &format!("table expected, got {}", type_name_of(arguments, i))))
+    }
+}
+
+fn check_any(vm: &Vm, arguments: &[Value], i: usize, function: &str) -> Result<Value, LuaError> {
+    match arguments.get(i) {
+        Some(x) => Ok(x.clone()),
+        None => Err(bad_argument(vm, i, function, "value expected"))
+    }
+}
+
+// The base functions
+
+fn assert(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    if check_any(vm, &arguments, 0, "assert")?.truthy() {
+        return Ok(arguments);
+    }
+    let message = match arguments.get(1).and_then(|x| x.to_bytes()) {
+        Some(x) => String::from_utf8_lossy(&x).to_string(),
+        None => "assertion failed!".to_string()
+    };
+    Err(vm.error(&message))
+}
+
+fn error(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let value = argument(&arguments, 0);
+    let level = arguments.get(1).and_then(|x| x.to_number()).unwrap_or(1.0);
+    if let Value::Str(message) = &value {
+        if level > 0.0 {
+            return Err(vm.error(&String::from_utf8_lossy(message)));
+        }
+    }
+    Err(LuaError::Error(value))
+}
+
+fn ipairs(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let table = check_table(vm, &arguments, 0, "ipairs")?;
+    Ok(vec![Value::native(ipairs_next), Value::Table(table), Value::Number(0.0)])
+}
+
+fn ipairs_next(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let table = check_table(vm, &arguments, 0, "ipairs")?;
+    let i = check_number(vm, &arguments, 1, "ipairs")? + 1.0;
+    let value = table.borrow().get(&Value::Number(i));
+    if value.is_nil() {
+        return Ok(vec![Value::Nil]);
+    }
+    Ok(vec![Value::Number(i), value])
+}
+
+fn next(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let table = check_table(vm, &arguments, 0, "next")?;
+    let entry = table.borrow().next(&argument(&arguments, 1)).map_err(|e| vm.error(&e))?;
+    match entry {
+        Some((key, value)) => Ok(vec![key, value]),
+        None => Ok(vec![Value::Nil])
+    }
+}
+
+fn pairs(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let table = check_table(vm, &arguments, 0, "pairs")?;
+    Ok(vec![Value::native(next), Value::Table(table), Value::Nil])
+}
+
+fn pcall(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let function = check_any(vm, &arguments, 0, "pcall")?;
+    match vm.call(&function, arguments.into_iter().skip(1).collect()) {
+        Ok(mut values) => {
+            values.insert(0, Value::Boolean(true));
+            Ok(values)
+        },
+        Err(LuaError::Error(e)) => Ok(vec![Value::Boolean(false), e]),
+        Err(LuaError::Killed) => Err(LuaError::Killed)
+    }
+}
+
+fn xpcall(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let function = check_any(vm, &arguments, 0, "xpcall")?;
+    let handler = check_any(vm, &arguments, 1, "xpcall")?;
+    match vm.call(&function, vec![]) {
+        Ok(mut values) => {
+            values.insert(0, Value::Boolean(true));
+            Ok(values)
+        },
+        Err(LuaError::Error(e)) => {
+            let handled = vm.call(&handler, vec![e])?;
+            Ok(vec![Value::Boolean(false), handled.into_iter().next().unwrap_or(Value::Nil)])
+        },
+        Err(LuaError::Killed) => Err(LuaError::Killed)
+    }
+}
+
+fn select(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let count = arguments.len() as i64 - 1;
+    if let Some(Value::Str(x)) = arguments.first() {
+        if x.as_ref() == b"#" {
+            return Ok(vec![Value::Number(count as f64)]);
+        }
+    }
+    let mut n = check_integer(vm, &arguments, 0, "select")?;
+    if n < 0 {
+        n += count + 1;
+    }
+    if n < 1 {
+        return Err(bad_argument(vm, 0, "select", "index out of range"));
+    }
+    Ok(arguments.into_iter().skip(n as usize).collect())
+}
+
+fn tonumber(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let value = check_any(vm, &arguments, 0, "tonumber")?;
+    let base = optional_integer(vm, &arguments, 1, "tonumber", 10)?;
+    if base == 10 {
+        return Ok(vec![value.to_number().map(Value::Number).unwrap_or(Value::Nil)]);
+    }
+    if !(2..=36).contains(&base) {
+        return Err(bad_argument(vm, 1, "tonumber", "base out of range"));
+    }
+    let text = check_string(vm, &arguments, 0, "tonumber")?;
+    let text = String::from_utf8_lossy(&text).trim().to_lowercase();
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(x) => (true, x),
+        None => (false, text.as_str())
+    };
+    if digits.is_empty() {
+        return Ok(vec![Value::Nil]);
+    }
+    let mut result = 0.0;
+    for c in digits.chars() {
+        match c.to_digit(base as u32) {
+            Some(digit) => result = result * base as f64 + digit as f64,
+            None => return Ok(vec![Value::Nil])
+        }
+    }
+    Ok(vec![Value::Number(if negative { -result } else { result })])
+}
+
+fn tostring(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let value = check_any(vm, &arguments, 0, "tostring")?;
+    match value {
+        Value::Str(_) => Ok(vec![value]),
+        other => Ok(vec![Value::str(&other.display())])
+    }
+}
+
+fn type_of(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let value = check_any(vm, &arguments, 0, "type")?;
+    Ok(vec![Value::str(value.type_name())])
+}
+
+fn unpack(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let table = check_table(vm, &arguments, 0, "unpack")?;
+    let start = optional_integer(vm, &arguments, 1, "unpack", 1)?;
+    let length = table.borrow().len() as i64;
+    let end = optional_integer(vm, &arguments, 2, "unpack", length)?;
+    if end - start >= 8000 {
+        return Err(vm.error("too many results to unpack"));
+    }
+    let table = table.borrow();
+    Ok((start..=end).map(|i| table.get(&Value::Number(i as f64))).collect())
+}
+
+fn rawget(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let table = check_table(vm, &arguments, 0, "rawget")?;
+    let value = table.borrow().get(&argument(&arguments, 1));
+    Ok(vec![value])
+}
+
+fn rawset(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let table = check_table(vm, &arguments, 0, "rawset")?;
+    let result = table.borrow_mut().set(argument(&arguments, 1), argument(&arguments, 2));
+    result.map_err(|e| vm.error(&e))?;
+    Ok(vec![Value::Table(table)])
+}
+
+fn rawequal(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let a = check_any(vm, &arguments, 0, "rawequal")?;
+    let b = check_any(vm, &arguments, 1, "rawequal")?;
+    Ok(vec![Value::Boolean(a.raw_equals(&b))])
+}
+
+fn print(_vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let line: Vec<String> = arguments.iter().map(|x| x.display()).collect();
+    println!("{}", line.join("\t"));
+    Ok(vec![])
+}
+
+// Memory is reference counted, there is nothing to collect
+fn collectgarbage(_vm: &mut Vm, _arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    Ok(vec![Value::Number(0.0)])
+}
+
+// The string library
+
+/// A position counting from the end when negative, like `-1` for the last character.
+fn relative_position(position: i64, length: usize) -> i64 {
+    if position < 0 {
+        return length as i64 + position + 1;
+    }
+    position
+}
+
+fn string_len(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let s = check_string(vm, &arguments, 0, "len")?;
+    Ok(vec![Value::Number(s.len() as f64)])
+}
+
+fn string_sub(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let s = check_string(vm, &arguments, 0, "sub")?;
+    let start = relative_position(check_integer(vm, &arguments, 1, "sub")?, s.len()).max(1);
+    let end = relative_position(optional_integer(vm, &arguments, 2, "sub", -1)?, s.len()).min(s.len() as i64);
+    if start > end {
+        return Ok(vec![Value::str("")]);
+    }
+    Ok(vec![Value::bytes(&s[start as usize - 1..end as usize])])
+}
+
+fn string_upper(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let s = check_string(vm, &arguments, 0, "upper")?;
+    Ok(vec![Value::bytes(&s.to_ascii_uppercase())])
+}
+
+fn string_lower(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let s = check_string(vm, &arguments, 0, "lower")?;
+    Ok(vec![Value::bytes(&s.to_ascii_lowercase())])
+}
+
+fn string_rep(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let s = check_string(vm, &arguments, 0, "rep")?;
+    let n = check_integer(vm, &arguments, 1, "rep")?;
+    if n <= 0 {
+        return Ok(vec![Value::str("")]);
+    }
+    if s.len().saturating_mul(n as usize) > MAX_STRING_SIZE {
+        return Err(vm.error("resulting string too large"));
+    }
+    Ok(vec![Value::bytes(&s.repeat(n as usize))])
+}
+
+fn string_reverse(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let s = check_string(vm, &arguments, 0, "reverse")?;
+    let reversed: Vec<u8> = s.iter().rev().copied().collect();
+    Ok(vec![Value::bytes(&reversed)])
+}
+
+fn string_byte(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let s = check_string(vm, &arguments, 0, "byte")?;
+    let start = relative_position(optional_integer(vm, &arguments, 1, "byte", 1)?, s.len());
+    let end = relative_position(optional_integer(vm, &arguments, 2, "byte", start)?, s.len()).min(s.len() as i64);
+    let start = start.max(1);
+    if start > end {
+        return Ok(vec![]);
+    }
+    Ok(s[start as usize - 1..end as usize].iter().map(|x| Value::Number(*x as f64)).collect())
+}
+
+fn string_char(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let mut bytes = Vec::with_capacity(arguments.len());
+    for i in 0..arguments.len() {
+        let c = check_integer(vm, &arguments, i, "char")?;
+        if !(0..=255).contains(&c) {
+            return Err(bad_argument(vm, i, "char", "invalid value"));
+        }
+        bytes.push(c as u8);
+    }
+    Ok(vec![Value::bytes(&bytes)])
+}
+
+fn capture_value(capture: Capture) -> Value {
+    match capture {
+        Capture::Text(x) => Value::bytes(&x),
+        Capture::Position(x) => Value::Number(x as f64)
+    }
+}
+
+fn captures_values(vm: &Vm, captures: Result<Vec<Capture>, String>) -> Result<Vec<Value>, LuaError> {
+    match captures {
+        Ok(captures) => Ok(captures.into_iter().map(capture_value).collect()),
+        Err(e) => Err(vm.error(&e))
+    }
+}
+
+fn string_find(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    find(vm, arguments, true)
+}
+
+fn string_match(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    find(vm, arguments, false)
+}
+
+fn find(vm: &mut Vm, arguments: Vec<Value>, find: bool) -> Result<Vec<Value>, LuaError> {
+    let name = if find { "find" } else { "match" };
+    let s = check_string(vm, &arguments, 0, name)?;
+    let pattern = check_string(vm, &arguments, 1, name)?;
+    let init = relative_position(optional_integer(vm, &arguments, 2, name, 1)?, s.len()).max(1) as usize;
+    if init > s.len() + 1 {
+        return Ok(vec![Value::Nil]);
+    }
+    if find && (argument(&arguments, 3).truthy() || is_plain(&pattern)) {
+        return match find_plain(&s, &pattern, init - 1) {
+            Some(i) => Ok(vec![Value::Number((i + 1) as f64), Value::Number((i + pattern.len()) as f64)]),
+            None => Ok(vec![Value::Nil])
+        };
+    }
+    let anchor = pattern.first() == Some(&b'^');
+    let pattern_start = if anchor { 1 } else { 0 };
+    let mut matcher = Matcher::new(&s, &pattern);
+    let mut position = init - 1;
+    loop {
+        let end = matcher.try_match(position, pattern_start).map_err(|e| vm.error(&e))?;
+        if let Some(end) = end {
+            if find {
+                let mut values = vec![Value::Number((position + 1) as f64), Value::Number(end as f64)];
+                values.extend(captures_values(vm, matcher.all_captures(position, end, false))?);
+                return Ok(values);
+            }
+            return captures_values(vm, matcher.all_captures(position, end, true));
+        }
+        position += 1;
+        if anchor || position > s.len() {
+            return Ok(vec![Value::Nil]);
+        }
+    }
+}
+
+fn string_gmatch(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let s = check_string(vm, &arguments, 0, "gmatch")?;
+    let pattern = check_string(vm, &arguments, 1, "gmatch")?;
+    let position = Rc::new(Cell::new(0));
+    let iterator = Value::native(move |vm, _| {
+        let mut matcher = Matcher::new(&s, &pattern);
+        let mut start = position.get();
+        while start <= s.len() {
+            let end = matcher.try_match(start, 0).map_err(|e| vm.error(&e))?;
+            if let Some(end) = end {
+                // An empty match moves on by one character
+                position.set(if end == start { end + 1 } else { end });
+                return captures_values(vm, matcher.all_captures(start, end, true));
+            }
+            start += 1;
+        }
+        position.set(start);
+        Ok(vec![Value::Nil])
+    });
+    Ok(vec![iterator])
+}
+
+fn string_gsub(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let s = check_string(vm, &arguments, 0, "gsub")?;
+    let pattern = check_string(vm, &arguments, 1, "gsub")?;
+    let replacement = argument(&arguments, 2);
+    if !matches!(replacement, Value::Number(_) | Value::Str(_) | Value::Table(_) | Value::Function(_)) {
+        return Err(bad_argument(vm, 2, "gsub", "string/function/table expected"));
+    }
+    let max = optional_integer(vm, &arguments, 3, "gsub", s.len() as i64 + 1)?;
+    let anchor = pattern.first() == Some(&b'^');
+    let pattern_start = if anchor { 1 } else { 0 };
+    let mut matcher = Matcher::new(&s, &pattern);
+    let mut result = vec![];
+    let mut position = 0;
+    let mut count = 0;
+    while count < max {
+        let end = matcher.try_match(position, pattern_start).map_err(|e| vm.error(&e))?;
+        if let Some(end) = end {
+            count += 1;
+            add_replacement(vm, &matcher, &s, position, end, &replacement, &mut result)?;
+        }
+        match end {
+            Some(end) if end > position => position = end,
+            _ if position < s.len() => {
+                result.push(s[position]);
+                position += 1;
+            },
+            _ => break
+        }
+        if anchor {
+            break;
+        }
+    }
+    result.extend_from_slice(&s[position..]);
+    Ok(vec![Value::bytes(&result), Value::Number(count as f64)])
+}
+
+fn add_replacement(vm: &mut Vm, matcher: &Matcher, s: &[u8], start: usize, end: usize, replacement: &Value, result: &mut Vec<u8>) -> Result<(), LuaError> {
+    let value = match replacement {
+        Value::Table(table) => {
+            let key = matcher.capture(0, start, end).map_err(|e| vm.error(&e))?;
+            let value = table.borrow().get(&capture_value(key));
+            value
+        },
+        Value::Function(_) => {
+            let captures = captures_values(vm, matcher.all_captures(start, end, true))?;
+            vm.call(replacement, captures)?.into_iter().next().unwrap_or(Value::Nil)
+        },
+        _ => {
+            let template = replacement.to_bytes().unwrap_or_else(|| Rc::from(&b""[..]));
+            let mut i = 0;
+            while i < template.len() {
+                let c = template[i];
+                i += 1;
+                if c != b'%' {
+                    result.push(c);
+                    continue;
+                }
+                let Some(&next) = template.get(i) else { break };
+                i += 1;
+                if next == b'0' {
+                    result.extend_from_slice(&s[start..end]);
+                } else if next.is_ascii_digit() {
+                    let capture = matcher.capture((next - b'1') as usize, start, end).map_err(|e| vm.error(&e))?;
+                    match capture {
+                        Capture::Text(x) => result.extend_from_slice(&x),
+                        Capture::Position(x) => result.extend_from_slice(x.to_string().as_bytes())
+                    }
+                } else {
+                    result.push(next);
+                }
+            }
+            return Ok(());
+        }
+    };
+    match value {
+        Value::Nil | Value::Boolean(false) => result.extend_from_slice(&s[start..end]),
+        Value::Str(_) | Value::Number(_) => result.extend_from_slice(&value.to_bytes().unwrap_or_else(|| Rc::from(&b""[..]))),
+        other => return Err(vm.error(&format!("invalid replacement value (a {})", other.type_name())))
+    }
+    Ok(())
+}
+
+/// The flags, width and precision of a `string.format` conversion.
+struct FormatSpec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alternate: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+impl FormatSpec {
+    fn sign(&self, negative: bool) -> &'static str {
+        if negative {
+            "-"
+        } else if self.plus {
+            "+"
+        } else if self.space {
+            " "
+        } else {
+            ""
+        }
+    }
+
+    /// Pads to the width, with zeros between the sign and the digits when asked to.
+    fn pad(&self, sign: &str, prefix: &str, body: &str, zeros_allowed: bool) -> Vec<u8> {
+        let length = sign.len() + prefix.len() + body.len();
+        let fill = self.width.saturating_sub(length);
+        let text = if fill == 0 {
+            format!("{}{}{}", sign, prefix, body)
+        } else if self.left {
+            format!("{}{}{}{}", sign, prefix, body, " ".repeat(fill))
+        } else if self.zero && zeros_allowed {
+            format!("{}{}{}{}", sign, prefix, "0".repeat(fill), body)
+        } else {
+            format!("{}{}{}{}", " ".repeat(fill), sign, prefix, body)
+        };
+        text.into_bytes()
+    }
+
+    fn integer(&self, negative: bool, prefix: &str, digits: String) -> Vec<u8> {
+        let digits = match self.precision {
+            Some(0) if digits == "0" => String::new(),
+            Some(precision) if digits.len() < precision => format!("{}{}", "0".repeat(precision - digits.len()), digits),
+            _ => digits
+        };
+        self.pad(self.sign(negative), prefix, &digits, self.precision.is_none())
+    }
+}
+
+fn format_exponent(x: f64, precision: usize) -> String {
+    let text = format!("{:.*e}", precision, x);
+    let (mantissa, exponent) = text.split_once('e').unwrap_or((&text, "0"));
+    let exponent: i32 = exponent.parse().unwrap_or(0);
+    format!("{}e{}{:02}", mantissa, if exponent < 0 { '-' } else { '+' }, exponent.abs())
+}
+
+fn format_float(x: f64, conversion: u8, spec: &FormatSpec) -> Vec<u8> {
+    let precision = spec.precision.unwrap_or(6);
+    let negative = x.is_sign_negative() && !x.is_nan();
+    let x = x.abs();
+    let body = if x.is_nan() {
+        "nan".to_string()
+    } else if x.is_infinite() {
+        "inf".to_string()
+    } else {
+        match conversion.to_ascii_lowercase() {
+            b'f' if spec.alternate && precision == 0 => format!("{:.0}.", x),
+            b'f' => format!("{:.*}", precision, x),
+            b'e' => format_exponent(x, precision),
+            _ => format_g(x, precision, spec.alternate)
+        }
+    };
+    let body = if conversion.is_ascii_uppercase() { body.to_uppercase() } else { body };
+    spec.pad(spec.sign(negative), "", &body, x.is_finite())
+}
+
+fn format_quoted(s: &[u8], out: &mut Vec<u8>) {
+    out.push(b'"');
+    for c in s {
+        match c {
+            b'"' | b'\\' | b'\n' => {
+                out.push(b'\\');
+                out.push(*c);
+            },
+            b'\r' => out.extend_from_slice(b"\\r"),
+            0 => out.extend_from_slice(b"\\000"),
+            _ => out.push(*c)
+        }
+    }
+    out.push(b'"');
+}
+
+fn string_format(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let format = check_string(vm, &arguments, 0, "format")?;
+    let mut out = vec![];
+    let mut n = 0;
+    let mut i = 0;
+    while i < format.len() {
+        let c = format[i];
+        i += 1;
+        if c != b'%' {
+            out.push(c);
+            continue;
+        }
+        if format.get(i) == Some(&b'%') {
+            out.push(b'%');
+            i += 1;
+            continue;
+        }
+        let flags_start = i;
+        while i < format.len() && b"-+ #0".contains(&format[i]) {
+            i += 1;
+        }
+        if i - flags_start > 5 {
+            return Err(vm.error("invalid format (repeated flags)"));
+        }
+        let flags = &format[flags_start..i];
+        let read_digits = |i: &mut usize| -> Result<usize, LuaError> {
+            let start = *i;
+            while *i < format.len() && format[*i].is_ascii_digit() {
+                *i += 1;
+            }
+            if *i - start > 2 {
+                return Err(vm.error("invalid format (width or precision too long)"));
+            }
+            Ok(std::str::from_utf8(&format[start..*i]).ok().and_then(|x| x.parse().ok()).unwrap_or(0))
+        };
+        let width = read_digits(&mut i)?;
+        let mut precision = None;
+        if format.get(i) == Some(&b'.') {
+            i += 1;
+            precision = Some(read_digits(&mut i)?);
+        }
+        let spec = FormatSpec {
+            left: flags.contains(&b'-'),
+            plus: flags.contains(&b'+'),
+            space: flags.contains(&b' '),
+            alternate: flags.contains(&b'#'),
+            zero: flags.contains(&b'0'),
+            width,
+            precision,
+        };
+        let conversion = format.get(i).copied().unwrap_or(0);
+        i += 1;
+        n += 1;
+        match conversion {
+            b'c' => out.push(check_number(vm, &arguments, n, "format")? as i64 as u8),
+            b'd' | b'i' => {
+                let x = check_number(vm, &arguments, n, "format")? as i64;
+                out.extend(spec.integer(x < 0, "", x.unsigned_abs().to_string()));
+            },
+            b'u' => {
+                let x = check_number(vm, &arguments, n, "format")? as i64 as u64;
+                out.extend(spec.integer(false, "", x.to_string()));
+            },
+            b'o' => {
+                let x = check_number(vm, &arguments, n, "format")? as i64 as u64;
+                let prefix = if spec.alternate && x != 0 { "0" } else { "" };
+                out.extend(spec.integer(false, prefix, format!("{:o}", x)));
+            },
+            b'x' | b'X' => {
+                let x = check_number(vm, &arguments, n, "format")? as i64 as u64;
+                let (prefix, digits) = if conversion == b'x' { ("0x", format!("{:x}", x)) } else { ("0X", format!("{:X}", x)) };
+                let prefix = if spec.alternate && x != 0 { prefix } else { "" };
+                out.extend(spec.integer(false, prefix, digits));
+            },
+            b'e' | b'E' | b'f' | b'g' | b'G' => {
+                let x = check_number(vm, &arguments, n, "format")?;
+                out.extend(format_float(x, conversion, &spec));
+            },
+            b'q' => {
+                let s = check_string(vm, &arguments, n, "format")?;
+                format_quoted(&s, &mut out);
+            },
+            b's' => {
+                let s = check_string(vm, &arguments, n, "format")?;
+                let s = match spec.precision {
+                    Some(precision) if precision < s.len() => &s[..precision],
+                    _ => &s[..]
+                };
+                let fill = spec.width.saturating_sub(s.len());
+                if !spec.left {
+                    out.extend(std::iter::repeat_n(b' ', fill));
+                }
+                out.extend_from_slice(s);
+                if spec.left {
+                    out.extend(std::iter::repeat_n(b' ', fill));
+                }
+            },
+            _ => return Err(vm.error(&format!("invalid option '%{}' to 'format'", conversion as char)))
+        }
+    }
+    Ok(vec![Value::bytes(&out)])
+}
+
+// The table library
+
+fn table_insert(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let table = check_table(vm, &arguments, 0, "insert")?;
+    let mut table = table.borrow_mut();
+    let length = table.len() as i64;
+    match arguments.len() {
+        2 => table.push(arguments[1].clone()),
+        3 => {
+            let position = check_integer(vm, &arguments, 1, "insert")?;
+            let mut i = length;
+            while i >= position {
+                let value = table.get(&Value::Number(i as f64));
+                let _ = table.set(Value::Number((i + 1) as f64), value);
+                i -= 1;
+            }
+            let _ = table.set(Value::Number(position as f64), arguments[2].clone());
+        },
+        _ => return Err(vm.error("wrong number of arguments to 'insert'"))
+    }
+    Ok(vec![])
+}
+
+fn table_remove(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let table = check_table(vm, &arguments, 0, "remove")?;
+    let mut table = table.borrow_mut();
+    let length = table.len() as i64;
+    let position = optional_integer(vm, &arguments, 1, "remove", length)?;
+    if length == 0 || position < 1 || position > length {
+        return Ok(vec![]);
+    }
+    let removed = table.get(&Value::Number(position as f64));
+    for i in position..length {
+        let value = table.get(&Value::Number((i + 1) as f64));
+        let _ = table.set(Value::Number(i as f64), value);
+    }
+    let _ = table.set(Value::Number(length as f64), Value::Nil);
+    Ok(vec![removed])
+}
+
+fn table_concat(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let table = check_table(vm, &arguments, 0, "concat")?;
+    let separator = match argument(&arguments, 1) {
+        Value::Nil => Rc::from(&b""[..]),
+        _ => check_string(vm, &arguments, 1, "concat")?
+    };
+    let start = optional_integer(vm, &arguments, 2, "concat", 1)?;
+    let length = table.borrow().len() as i64;
+    let end = optional_integer(vm, &arguments, 3, "concat", length)?;
+    let mut out = vec![];
+    let table = table.borrow();
+    for i in start..=end {
+        match table.get(&Value::Number(i as f64)) {
+            value @ (Value::Str(_) | Value::Number(_)) => out.extend_from_slice(&value.to_bytes().unwrap_or_else(|| Rc::from(&b""[..]))),
+            _ => return Err(vm.error(&format!("invalid value (at index {}) in table for 'concat'", i)))
+        }
+        if i != end {
+            out.extend_from_slice(&separator);
+        }
+    }
+    Ok(vec![Value::bytes(&out)])
+}
+
+fn table_sort(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let table = check_table(vm, &arguments, 0, "sort")?;
+    let comparator = argument(&arguments, 1);
+    if !matches!(comparator, Value::Nil | Value::Function(_)) {
+        return Err(bad_argument(vm, 1, "sort", &format!("function expected, got {}", comparator.type_name())));
+    }
+    let values = table.borrow().sequence();
+    let sorted = merge_sort(vm, values, &comparator)?;
+    let mut table = table.borrow_mut();
+    for (i, value) in sorted.into_iter().enumerate() {
+        let _ = table.set(Value::Number((i + 1) as f64), value);
+    }
+    Ok(vec![])
+}
+
+/// A merge sort, since the comparisons can fail.
+fn merge_sort(vm: &mut Vm, mut values: Vec<Value>, comparator: &Value) -> Result<Vec<Value>, LuaError> {
+    if values.len() <= 1 {
+        return Ok(values);
+    }
+    let right = values.split_off(values.len() / 2);
+    let left = merge_sort(vm, values, comparator)?;
+    let right = merge_sort(vm, right, comparator)?;
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    while let (Some(a), Some(b)) = (left.peek(), right.peek()) {
+        let less = match comparator {
+            Value::Nil => compare(vm, b, a)?,
+            _ => vm.call(comparator, vec![b.clone(), a.clone()])?.first().map(|x| x.truthy()).unwrap_or(false)
+        };
+        if less {
+            merged.extend(right.next());
+        } else {
+            merged.extend(left.next());
+        }
+    }
+    merged.extend(left);
+    merged.extend(right);
+    Ok(merged)
+}
+
+fn table_getn(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let table = check_table(vm, &arguments, 0, "getn")?;
+    let length = table.borrow().len();
+    Ok(vec![Value::Number(length as f64)])
+}
+
+fn table_maxn(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let table = check_table(vm, &arguments, 0, "maxn")?;
+    let table = table.borrow();
+    let mut max = 0.0;
+    let mut key = Value::Nil;
+    while let Ok(Some((next, _))) = table.next(&key) {
+        if let Value::Number(x) = next {
+            if x > max {
+                max = x;
+            }
+        }
+        key = next;
+    }
+    Ok(vec![Value::Number(max)])
+}
+
+// The math library
+
+fn math_unary(vm: &Vm, arguments: &[Value], name: &str, f: fn(f64) -> f64) -> Result<Vec<Value>, LuaError> {
+    Ok(vec![Value::Number(f(check_number(vm, arguments, 0, name)?))])
+}
+
+fn math_binary(vm: &Vm, arguments: &[Value], name: &str, f: fn(f64, f64) -> f64) -> Result<Vec<Value>, LuaError> {
+    let a = check_number(vm, arguments, 0, name)?;
+    let b = check_number(vm, arguments, 1, name)?;
+    Ok(vec![Value::Number(f(a, b))])
+}
+
+fn math_abs(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    math_unary(vm, &arguments, "abs", f64::abs)
+}
+
+fn math_ceil(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    math_unary(vm, &arguments, "ceil", f64::ceil)
+}
+
+fn math_floor(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    math_unary(vm, &arguments, "floor", f64::floor)
+}
+
+fn math_sqrt(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    math_unary(vm, &arguments, "sqrt", f64::sqrt)
+}
+
+fn math_exp(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    math_unary(vm, &arguments, "exp", f64::exp)
+}
+
+fn math_log(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    math_unary(vm, &arguments, "log", f64::ln)
+}
+
+fn math_log10(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    math_unary(vm, &arguments, "log10", f64::log10)
+}
+
+fn math_sin(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    math_unary(vm, &arguments, "sin", f64::sin)
+}
+
+fn math_cos(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    math_unary(vm, &arguments, "cos", f64::cos)
+}
+
+fn math_tan(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    math_unary(vm, &arguments, "tan", f64::tan)
+}
+
+fn math_asin(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    math_unary(vm, &arguments, "asin", f64::asin)
+}
+
+fn math_acos(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    math_unary(vm, &arguments, "acos", f64::acos)
+}
+
+fn math_atan(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    math_unary(vm, &arguments, "atan", f64::atan)
+}
+
+fn math_atan2(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    math_binary(vm, &arguments, "atan2", f64::atan2)
+}
+
+fn math_sinh(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    math_unary(vm, &arguments, "sinh", f64::sinh)
+}
+
+fn math_cosh(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    math_unary(vm, &arguments, "cosh", f64::cosh)
+}
+
+fn math_tanh(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    math_unary(vm, &arguments, "tanh", f64::tanh)
+}
+
+fn math_deg(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    math_unary(vm, &arguments, "deg", f64::to_degrees)
+}
+
+fn math_rad(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    math_unary(vm, &arguments, "rad", f64::to_radians)
+}
+
+fn math_pow(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    math_binary(vm, &arguments, "pow", f64::powf)
+}
+
+fn math_fmod(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    math_binary(vm, &arguments, "fmod", |a, b| a % b)
+}
+
+fn math_modf(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let x = check_number(vm, &arguments, 0, "modf")?;
+    let fraction = if x.is_infinite() { 0.0 } else { x.fract() };
+    Ok(vec![Value::Number(x.trunc()), Value::Number(fraction)])
+}
+
+fn math_frexp(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let x = check_number(vm, &arguments, 0, "frexp")?;
+    if x == 0.0 || !x.is_finite() {
+        return Ok(vec![Value::Number(x), Value::Number(0.0)]);
+    }
+    let mut exponent = x.abs().log2().floor() as i32 + 1;
+    let mut mantissa = x / 2f64.powi(exponent);
+    // log2 can be off by one around powers of two
+    if mantissa.abs() >= 1.0 {
+        mantissa /= 2.0;
+        exponent += 1;
+    } else if mantissa.abs() < 0.5 {
+        mantissa *= 2.0;
+        exponent -= 1;
+    }
+    Ok(vec![Value::Number(mantissa), Value::Number(exponent as f64)])
+}
+
+fn math_ldexp(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let mantissa = check_number(vm, &arguments, 0, "ldexp")?;
+    let exponent = check_integer(vm, &arguments, 1, "ldexp")?;
+    Ok(vec![Value::Number(mantissa * 2f64.powi(exponent as i32))])
+}
+
+fn math_max(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let mut max = check_number(vm, &arguments, 0, "max")?;
+    for i in 1..arguments.len() {
+        let x = check_number(vm, &arguments, i, "max")?;
+        if x > max {
+            max = x;
+        }
+    }
+    Ok(vec![Value::Number(max)])
+}
+
+fn math_min(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let mut min = check_number(vm, &arguments, 0, "min")?;
+    for i in 1..arguments.len() {
+        let x = check_number(vm, &arguments, i, "min")?;
+        if x < min {
+            min = x;
+        }
+    }
+    Ok(vec![Value::Number(min)])
+}
+
+/// The seed of `lrand48` for a given `srand48` argument.
+fn random_seed(seed: i32) -> u64 {
+    ((seed as u32 as u64) << 16) | 0x330E
+}
+
+/// `math.random` and `math.randomseed`, backed by `lrand48` seeded with 0 for every script so
+/// that scripts are deterministic, like redis does.
+fn install_random(math: &mut Table) {
+    let state = Rc::new(Cell::new(random_seed(0)));
+    let seed = state.clone();
+    math.set_str("randomseed", Value::native(move |vm, arguments| {
+        let x = check_number(vm, &arguments, 0, "randomseed")?;
+        seed.set(random_seed(x as i64 as i32));
+        Ok(vec![])
+    }));
+    math.set_str("random", Value::native(move |vm, arguments| {
+        let next = (0x5DEECE66Du64.wrapping_mul(state.get()).wrapping_add(0xB)) & ((1 << 48) - 1);
+        state.set(next);
+        let r = ((next >> 17) % i32::MAX as u64) as f64 / i32::MAX as f64;
+        match arguments.len() {
+            0 => Ok(vec![Value::Number(r)]),
+            1 => {
+                let upper = check_number(vm, &arguments, 0, "random")?;
+                if upper < 1.0 {
+                    return Err(bad_argument(vm, 0, "random", "interval is empty"));
+                }
+                Ok(vec![Value::Number((r * upper).floor() + 1.0)])
+            },
+            2 => {
+                let lower = check_number(vm, &arguments, 0, "random")?;
+                let upper = check_number(vm, &arguments, 1, "random")?;
+                if lower > upper {
+                    return Err(bad_argument(vm, 1, "random", "interval is empty"));
+                }
+                Ok(vec![Value::Number((r * (upper - lower + 1.0)).floor() + lower)])
+            },
+            _ => Err(vm.error("wrong number of arguments"))
+        }
+    }));
+}
+
+// The bit library, with the semantics of LuaBitOp
+
+fn to_bit(x: f64) -> i32 {
+    // Rounds and wraps to 32 bits the way LuaBitOp does
+    (x + 6755399441055744.0).to_bits() as u32 as i32
+}
+
+fn check_bit(vm: &Vm, arguments: &[Value], i: usize, name: &str) -> Result<i32, LuaError> {
+    Ok(to_bit(check_number(vm, arguments, i, name)?))
+}
+
+fn bit_result(x: i32) -> Result<Vec<Value>, LuaError> {
+    Ok(vec![Value::Number(x as f64)])
+}
+
+fn bit_tobit(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    bit_result(check_bit(vm, &arguments, 0, "tobit")?)
+}
+
+fn bit_tohex(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let x = check_bit(vm, &arguments, 0, "tohex")? as u32;
+    let n = match argument(&arguments, 1) {
+        Value::Nil => 8,
+        _ => check_bit(vm, &arguments, 1, "tohex")?
+    };
+    let digits = (n.unsigned_abs() as usize).min(8);
+    let hex = if n < 0 { format!("{:08X}", x) } else { format!("{:08x}", x) };
+    Ok(vec![Value::str(&hex[8 - digits..])])
+}
+
+fn bit_bnot(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    bit_result(!check_bit(vm, &arguments, 0, "bnot")?)
+}
+
+fn bit_fold(vm: &Vm, arguments: &[Value], name: &str, f: fn(i32, i32) -> i32) -> Result<Vec<Value>, LuaError> {
+    let mut result = check_bit(vm, arguments, 0, name)?;
+    for i in 1..arguments.len() {
+        result = f(result, check_bit(vm, arguments, i, name)?);
+    }
+    bit_result(result)
+}
+
+fn bit_band(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    bit_fold(vm, &arguments, "band", |a, b| a & b)
+}
+
+fn bit_bor(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    bit_fold(vm, &arguments, "bor", |a, b| a | b)
+}
+
+fn bit_bxor(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    bit_fold(vm, &arguments, "bxor", |a, b| a ^ b)
+}
+
+fn bit_shift(vm: &Vm, arguments: &[Value], name: &str, f: fn(i32, u32) -> i32) -> Result<Vec<Value>, LuaError> {
+    let x = check_bit(vm, arguments, 0, name)?;
+    let n = check_bit(vm, arguments, 1, name)? as u32 & 31;
+    bit_result(f(x, n))
+}
+
+fn bit_lshift(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    bit_shift(vm, &arguments, "lshift", |x, n| ((x as u32) << n) as i32)
+}
+
+fn bit_rshift(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    bit_shift(vm, &arguments, "rshift", |x, n| ((x as u32) >> n) as i32)
+}
+
+fn bit_arshift(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    bit_shift(vm, &arguments, "arshift", |x, n| x >> n)
+}
+
+fn bit_rol(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    bit_shift(vm, &arguments, "rol", |x, n| x.rotate_left(n))
+}
+
+fn bit_ror(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    bit_shift(vm, &arguments, "ror", |x, n| x.rotate_right(n))
+}
+
+fn bit_bswap(vm: &mut Vm, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    bit_result(check_bit(vm, &arguments, 0, "bswap")?.swap_bytes())
+}
+
@@ -0,0 +1,384 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::scripting::ast::FunctionBody;
+use crate::scripting::vm::Vm;
+
+/// A builtin, which can keep state of its own like the iterator returned by `string.gmatch`.
+pub type NativeFunction = Rc<dyn Fn(&mut Vm, Vec<Value>) -> Result<Vec<Value>, LuaError>>;
+
+pub enum Function {
+    // A function of the script, with the locals it can see
+    Lua(Arc<FunctionBody>, Option<Rc<Scope>>),
+    Native(NativeFunction),
+}
+
+#[derive(Clone)]
+pub enum Value {
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    Str(Rc<[u8]>),
+    Table(Rc<RefCell<Table>>),
+    Function(Rc<Function>),
+}
+
+/// What makes a script stop: an error value, which `pcall` can catch, or `SCRIPT KILL`.
+pub enum LuaError {
+    Error(Value),
+    Killed,
+}
+
+/// A local variable, shared with the closures created in its scope. Each local gets its own
+/// node, so that a closure doesn't see the locals declared after it.
+pub struct Scope {
+    pub name: String,
+    pub value: RefCell<Value>,
+    pub parent: Option<Rc<Scope>>,
+}
+
+impl Scope {
+    pub fn lookup(scope: &Option<Rc<Scope>>, name: &str) -> Option<Rc<Scope>> {
+        let mut current = scope.clone();
+        while let Some(node) = current {
+            if node.name == name {
+                return Some(node);
+            }
+            current = node.parent.clone();
+        }
+        None
+    }
+
+    pub fn declare(scope: &Option<Rc<Scope>>, name: &str, value: Value) -> Option<Rc<Scope>> {
+        Some(Rc::new(Scope { name: name.to_string(), value: RefCell::new(value), parent: scope.clone() }))
+    }
+}
+
+impl Value {
+    pub fn str(x: &str) -> Value {
+        Value::Str(Rc::from(x.as_bytes()))
+    }
+
+    pub fn bytes(x: &[u8]) -> Value {
+        Value::Str(Rc::from(x))
+    }
+
+    pub fn new_table() -> Value {
+        Value::Table(Rc::new(RefCell::new(Table::default())))
+    }
+
+    pub fn native(function: impl Fn(&mut Vm, Vec<Value>) -> Result<Vec<Value>, LuaError> + 'static) -> Value {
+        Value::Function(Rc::new(Function::Native(Rc::new(function))))
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Boolean(_) => "boolean",
+            Value::Number(_) => "number",
+            Value::Str(_) => "string",
+            Value::Table(_) => "table",
+            Value::Function(_) => "function",
+        }
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(self, Value::Nil)
+    }
+
+    /// Only `nil` and `false` are false.
+    pub fn truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Boolean(false))
+    }
+
+    /// Numbers, and strings holding one, as arithmetic sees them.
+    pub fn to_number(&self) -> Option<f64> {
+        match self {
+            Value::Number(x) => Some(*x),
+            Value::Str(x) => parse_number(&String::from_utf8_lossy(x)),
+            _ => None
+        }
+    }
+
+    /// Strings, and numbers formatted like `tostring` does, as concatenation sees them.
+    pub fn to_bytes(&self) -> Option<Rc<[u8]>> {
+        match self {
+            Value::Str(x) => Some(x.clone()),
+            Value::Number(x) => Some(Rc::from(format_number(*x).as_bytes())),
+            _ => None
+        }
+    }
+
+    pub fn to_string_lossy(&self) -> String {
+        match self.to_bytes() {
+            Some(x) => String::from_utf8_lossy(&x).to_string(),
+            None => self.display()
+        }
+    }
+
+    /// What `tostring` returns.
+    pub fn display(&self) -> String {
+        match self {
+            Value::Nil => "nil".to_string(),
+            Value::Boolean(x) => x.to_string(),
+            Value::Number(x) => format_number(*x),
+            Value::Str(x) => String::from_utf8_lossy(x).to_string(),
+            Value::Table(x) => format!("table: {:p}", Rc::as_ptr(x)),
+            Value::Function(x) => match x.as_ref() {
+                Function::Native(_) => format!("function: builtin: {:p}", Rc::as_ptr(x)),
+                Function::Lua(..) => format!("function: {:p}", Rc::as_ptr(x)),
+            }
+        }
+    }
+
+    /// Equality without metamethods, tables and functions are equal to themselves only.
+    pub fn raw_equals(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::Table(a), Value::Table(b)) => Rc::ptr_eq(a, b),
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            _ => false
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Hash, Clone)]
+enum Key {
+    Boolean(bool),
+    Number(u64),
+    Str(Rc<[u8]>),
+    Reference(usize),
+}
+
+fn key_of(value: &Value) -> Option<Key> {
+    let key = match value {
+        Value::Nil => return None,
+        Value::Boolean(x) => Key::Boolean(*x),
+        Value::Number(x) if x.is_nan() => return None,
+        // 0 and -0 are the same key
+        Value::Number(x) => Key::Number(if *x == 0.0 { 0 } else { x.to_bits() }),
+        Value::Str(x) => Key::Str(x.clone()),
+        Value::Table(x) => Key::Reference(Rc::as_ptr(x) as *const u8 as usize),
+        Value::Function(x) => Key::Reference(Rc::as_ptr(x) as *const u8 as usize),
+    };
+    Some(key)
+}
+
+/// A Lua table: the values of the keys `1..n` in a vector, the others in an insertion ordered
+/// map. Removed entries stay as holes until the next compaction, so that `next` keeps working
+/// when the table is cleared while being traversed.
+#[derive(Default)]
+pub struct Table {
+    array: Vec<Value>,
+    entries: Vec<(Value, Value)>,
+    index: HashMap<Key, usize>,
+    holes: usize,
+}
+
+/// The position of an integer key in the array part, counting from 1.
+fn array_index(key: &Value) -> Option<usize> {
+    match key {
+        Value::Number(x) if *x >= 1.0 && x.fract() == 0.0 && *x < u32::MAX as f64 => Some(*x as usize),
+        _ => None
+    }
+}
+
+impl Table {
+    pub fn get(&self, key: &Value) -> Value {
+        if let Some(i) = array_index(key) {
+            if i <= self.array.len() {
+                return self.array[i - 1].clone();
+            }
+        }
+        match key_of(key).and_then(|x| self.index.get(&x)) {
+            Some(i) => self.entries[*i].1.clone(),
+            None => Value::Nil
+        }
+    }
+
+    pub fn get_str(&self, key: &str) -> Value {
+        self.get(&Value::str(key))
+    }
+
+    pub fn set(&mut self, key: Value, value: Value) -> Result<(), String> {
+        if let Some(i) = array_index(&key) {
+            if i <= self.array.len() {
+                self.array[i - 1] = value;
+                // The array part never ends with a hole
+                while matches!(self.array.last(), Some(Value::Nil)) {
+                    self.array.pop();
+                }
+                return Ok(());
+            }
+            if i == self.array.len() + 1 && !value.is_nil() {
+                self.array.push(value);
+                self.remove_entry(&key);
+                // Following keys already set move to the array part
+                loop {
+                    let next = Value::Number((self.array.len() + 1) as f64);
+                    let value = self.get(&next);
+                    if value.is_nil() {
+                        break;
+                    }
+                    self.remove_entry(&next);
+                    self.array.push(value);
+                }
+                return Ok(());
+            }
+        }
+        let hash_key = match key_of(&key) {
+            Some(x) => x,
+            None if key.is_nil() => return Err("table index is nil".to_string()),
+            None => return Err("table index is NaN".to_string())
+        };
+        match self.index.get(&hash_key) {
+            Some(i) => {
+                let entry = &mut self.entries[*i];
+                if entry.1.is_nil() && !value.is_nil() {
+                    self.holes -= 1;
+                } else if !entry.1.is_nil() && value.is_nil() {
+                    self.holes += 1;
+                }
+                entry.1 = value;
+            },
+            None if value.is_nil() => {},
+            None => {
+                if self.holes > 32 && self.holes * 2 > self.entries.len() {
+                    self.compact();
+                }
+                self.index.insert(hash_key, self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_str(&mut self, key: &str, value: Value) {
+        let _ = self.set(Value::str(key), value);
+    }
+
+    /// Appends at `#t + 1`.
+    pub fn push(&mut self, value: Value) {
+        let _ = self.set(Value::Number((self.array.len() + 1) as f64), value);
+    }
+
+    fn remove_entry(&mut self, key: &Value) {
+        if let Some(i) = key_of(key).and_then(|x| self.index.get(&x)) {
+            if !self.entries[*i].1.is_nil() {
+                self.entries[*i].1 = Value::Nil;
+                self.holes += 1;
+            }
+        }
+    }
+
+    fn compact(&mut self) {
+        self.entries.retain(|(_, value)| !value.is_nil());
+        self.index = self.entries.iter().enumerate().filter_map(|(i, (key, _))| key_of(key).map(|x| (x, i))).collect();
+        self.holes = 0;
+    }
+
+    /// A border of the table, what `#` returns.
+    pub fn len(&self) -> usize {
+        self.array.len()
+    }
+
+    /// The entry following `key` in the traversal order, what `next` returns.
+    pub fn next(&self, key: &Value) -> Result<Option<(Value, Value)>, String> {
+        let mut array_start = 0;
+        let mut entries_start = 0;
+        if !key.is_nil() {
+            match array_index(key) {
+                Some(i) if i <= self.array.len() => array_start = i,
+                _ => {
+                    array_start = self.array.len();
+                    match key_of(key).and_then(|x| self.index.get(&x)) {
+                        Some(i) => entries_start = i + 1,
+                        None => return Err("invalid key to 'next'".to_string())
+                    }
+                }
+            }
+        }
+        for i in array_start..self.array.len() {
+            if !self.array[i].is_nil() {
+                return Ok(Some((Value::Number((i + 1) as f64), self.array[i].clone())));
+            }
+        }
+        for (key, value) in self.entries.iter().skip(entries_start) {
+            if !value.is_nil() {
+                return Ok(Some((key.clone(), value.clone())));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The values of `1..#t`.
+    pub fn sequence(&self) -> Vec<Value> {
+        self.array.clone()
+    }
+}
+
+/// Formats a number like `%.14g`, what `tostring` does.
+pub fn format_number(x: f64) -> String {
+    if x.fract() == 0.0 && x.abs() < 1e15 {
+        return format!("{}", x as i64);
+    }
+    format_g(x, 14, false)
+}
+
+/// The `%g` conversion of C: fixed or scientific notation, whichever is shorter for the
+/// precision, without trailing zeros unless `alternate`.
+pub fn format_g(x: f64, precision: usize, alternate: bool) -> String {
+    if x.is_nan() {
+        return if x.is_sign_negative() { "-nan".to_string() } else { "nan".to_string() };
+    }
+    if x.is_infinite() {
+        return if x < 0.0 { "-inf".to_string() } else { "inf".to_string() };
+    }
+    let precision = precision.max(1);
+    let scientific = format!("{:.*e}", precision - 1, x);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap_or((&scientific, "0"));
+    let exponent: i32 = exponent.parse().unwrap_or(0);
+    let strip = |x: String| -> String {
+        if alternate || !x.contains('.') {
+            return x;
+        }
+        x.trim_end_matches('0').trim_end_matches('.').to_string()
+    };
+    if exponent < -4 || exponent >= precision as i32 {
+        let mantissa = strip(mantissa.to_string());
+        return format!("{}e{}{:02}", mantissa, if exponent < 0 { '-' } else { '+' }, exponent.abs());
+    }
+    let decimals = (precision as i32 - 1 - exponent).max(0) as usize;
+    strip(format!("{:.*}", decimals, x))
+}
+
+/// Reads a number the way `tonumber` and the arithmetic coercion do: decimal with an optional
+/// exponent, or hexadecimal, surrounded by optional spaces.
+pub fn parse_number(text: &str) -> Option<f64> {
+    let text = text.trim();
+    let (negative, unsigned) = match text.strip_prefix('-') {
+        Some(x) => (true, x),
+        None => (false, text.strip_prefix('+').unwrap_or(text))
+    };
+    let value = if let Some(hex) = unsigned.strip_prefix("0x").or_else(|| unsigned.strip_prefix("0X")) {
+        if hex.is_empty() || !hex.chars().all(|x| x.is_ascii_hexdigit()) {
+            return None;
+        }
+        hex.chars().fold(0.0, |acc, x| acc * 16.0 + x.to_digit(16).unwrap_or(0) as f64)
+    } else {
+        // Rust would also accept `inf` and `nan`
+        if unsigned.is_empty() || !unsigned.chars().all(|x| x.is_ascii_digit() || matches!(x, '.' | 'e' | 'E' | '+' | '-')) {
+            return None;
+        }
+        if !unsigned.starts_with(|x: char| x.is_ascii_digit() || x == '.') {
+            return None;
+        }
+        unsigned.parse::<f64>().ok()?
+    };
+    Some(if negative { -value } else { value })
+}
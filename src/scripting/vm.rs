@@ -0,0 +1,618 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::scripting::ast::{BinaryOperator, Block, Expression, FunctionBody, StatementKind, TableField, UnaryOperator};
+use crate::scripting::value::{Function, LuaError, Scope, Table, Value};
+
+/// Nested calls allowed before the script fails with a stack overflow, the limit of the C
+/// stack of the reference implementation.
+const MAX_CALL_DEPTH: usize = 200;
+
+/// How often, in calls and loop iterations, the kill flag is looked at.
+const KILL_CHECK_INTERVAL: u32 = 1000;
+
+/// A tree walking evaluator for the chunks of `parser`.
+pub struct Vm {
+    pub globals: Rc<RefCell<Table>>,
+    // What strings index, so that `s:upper()` works
+    pub string_library: Option<Rc<RefCell<Table>>>,
+    // Prefix of the error messages, like `user_script`
+    pub chunk_name: String,
+    // Line of the statement being executed
+    pub line: u32,
+    // Globals can't be created, nor read when they don't exist, like in redis
+    pub protect_globals: bool,
+    depth: usize,
+    steps: u32,
+    kill: Option<Arc<AtomicBool>>,
+}
+
+enum Flow {
+    Normal,
+    Break,
+    Return(Vec<Value>),
+}
+
+/// What a function call sees besides its locals.
+struct Frame {
+    varargs: Vec<Value>,
+}
+
+type Env = Option<Rc<Scope>>;
+
+impl Vm {
+    pub fn new(chunk_name: &str, kill: Option<Arc<AtomicBool>>) -> Self {
+        Self {
+            globals: Rc::new(RefCell::new(Table::default())),
+            string_library: None,
+            chunk_name: chunk_name.to_string(),
+            line: 0,
+            protect_globals: false,
+            depth: 0,
+            steps: 0,
+            kill,
+        }
+    }
+
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.globals.borrow_mut().set_str(name, value);
+    }
+
+    pub fn get_global(&self, name: &str) -> Value {
+        self.globals.borrow().get_str(name)
+    }
+
+    /// A runtime error, with the position of the statement being executed.
+    pub fn error(&self, message: &str) -> LuaError {
+        LuaError::Error(Value::str(&format!("{}:{}: {}", self.chunk_name, self.line, message)))
+    }
+
+    /// Runs a compiled chunk, returning what it returns.
+    pub fn run(&mut self, chunk: &Arc<FunctionBody>, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+        let function = Value::Function(Rc::new(Function::Lua(chunk.clone(), None)));
+        self.call(&function, arguments)
+    }
+
+    fn tick(&mut self) -> Result<(), LuaError> {
+        self.steps = self.steps.wrapping_add(1);
+        if self.steps.is_multiple_of(KILL_CHECK_INTERVAL) {
+            if let Some(kill) = &self.kill {
+                if kill.load(Ordering::Relaxed) {
+                    return Err(LuaError::Killed);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn call(&mut self, function: &Value, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+        let function = match function {
+            Value::Function(x) => x.clone(),
+            other => return Err(self.error(&format!("attempt to call a {} value", other.type_name())))
+        };
+        self.tick()?;
+        match function.as_ref() {
+            Function::Native(native) => native(self, arguments),
+            Function::Lua(body, env) => {
+                if self.depth >= MAX_CALL_DEPTH {
+                    return Err(self.error("stack overflow"));
+                }
+                self.depth += 1;
+                let caller_line = self.line;
+                let mut env = env.clone();
+                let mut arguments = arguments.into_iter();
+                for parameter in &body.parameters {
+                    env = Scope::declare(&env, parameter, arguments.next().unwrap_or(Value::Nil));
+                }
+                let frame = Frame {
+                    varargs: if body.vararg { arguments.collect() } else { vec![] }
+                };
+                let flow = self.exec_block(&body.body, env, &frame);
+                self.depth -= 1;
+                // Errors keep the line where they happened
+                let flow = flow?;
+                self.line = caller_line;
+                match flow {
+                    Flow::Return(values) => Ok(values),
+                    _ => Ok(vec![])
+                }
+            }
+        }
+    }
+
+    fn exec_block(&mut self, block: &Block, env: Env, frame: &Frame) -> Result<Flow, LuaError> {
+        self.exec_statements(block, env, frame).map(|(flow, _)| flow)
+    }
+
+    /// Also returns the scope at the end of the block, what the condition of `repeat` sees.
+    fn exec_statements(&mut self, block: &Block, mut env: Env, frame: &Frame) -> Result<(Flow, Env), LuaError> {
+        for statement in &block.statements {
+            self.line = statement.line;
+            match &statement.kind {
+                StatementKind::Local(names, expressions) => {
+                    let mut values = self.eval_list(expressions, &env, frame)?.into_iter();
+                    for name in names {
+                        env = Scope::declare(&env, name, values.next().unwrap_or(Value::Nil));
+                    }
+                },
+                StatementKind::LocalFunction(name, body) => {
+                    // The function can call itself
+                    env = Scope::declare(&env, name, Value::Nil);
+                    let function = Value::Function(Rc::new(Function::Lua(body.clone(), env.clone())));
+                    if let Some(scope) = &env {
+                        *scope.value.borrow_mut() = function;
+                    }
+                },
+                StatementKind::Assign(targets, expressions) => {
+                    self.assign(targets, expressions, &env, frame)?;
+                },
+                StatementKind::Call(expression) => {
+                    self.eval_multi(expression, &env, frame)?;
+                },
+                StatementKind::Do(body) => {
+                    match self.exec_block(body, env.clone(), frame)? {
+                        Flow::Normal => {},
+                        flow => return Ok((flow, env))
+                    }
+                },
+                StatementKind::While(condition, body) => {
+                    loop {
+                        self.tick()?;
+                        if !self.eval(condition, &env, frame)?.truthy() {
+                            break;
+                        }
+                        match self.exec_block(body, env.clone(), frame)? {
+                            Flow::Normal => {},
+                            Flow::Break => break,
+                            flow => return Ok((flow, env))
+                        }
+                    }
+                },
+                StatementKind::Repeat(body, condition) => {
+                    loop {
+                        self.tick()?;
+                        let (flow, inner) = self.exec_statements(body, env.clone(), frame)?;
+                        match flow {
+                            Flow::Normal => {},
+                            Flow::Break => break,
+                            flow => return Ok((flow, env))
+                        }
+                        if self.eval(condition, &inner, frame)?.truthy() {
+                            break;
+                        }
+                    }
+                },
+                StatementKind::If(clauses, otherwise) => {
+                    let mut chosen = otherwise.as_ref();
+                    for (condition, body) in clauses {
+                        if self.eval(condition, &env, frame)?.truthy() {
+                            chosen = Some(body);
+                            break;
+                        }
+                    }
+                    if let Some(body) = chosen {
+                        match self.exec_block(body, env.clone(), frame)? {
+                            Flow::Normal => {},
+                            flow => return Ok((flow, env))
+                        }
+                    }
+                },
+                StatementKind::NumericFor(name, start, limit, step, body) => {
+                    let start = self.eval(start, &env, frame)?.to_number().ok_or_else(|| self.error("'for' initial value must be a number"))?;
+                    let limit = self.eval(limit, &env, frame)?.to_number().ok_or_else(|| self.error("'for' limit must be a number"))?;
+                    let step = match step {
+                        Some(step) => self.eval(step, &env, frame)?.to_number().ok_or_else(|| self.error("'for' step must be a number"))?,
+                        None => 1.0
+                    };
+                    let mut i = start;
+                    while (step > 0.0 && i <= limit) || (step <= 0.0 && i >= limit) {
+                        self.tick()?;
+                        let inner = Scope::declare(&env, name, Value::Number(i));
+                        match self.exec_block(body, inner, frame)? {
+                            Flow::Normal => {},
+                            Flow::Break => break,
+                            flow => return Ok((flow, env))
+                        }
+                        i += step;
+                    }
+                },
+                StatementKind::GenericFor(names, expressions, body) => {
+                    let mut values = self.eval_list(expressions, &env, frame)?.into_iter();
+                    let iterator = values.next().unwrap_or(Value::Nil);
+                    let state = values.next().unwrap_or(Value::Nil);
+                    let mut control = values.next().unwrap_or(Value::Nil);
+                    loop {
+                        let results = self.call(&iterator, vec![state.clone(), control.clone()])?;
+                        let first = results.first().cloned().unwrap_or(Value::Nil);
+                        if first.is_nil() {
+                            break;
+                        }
+                        control = first;
+                        let mut inner = env.clone();
+                        let mut results = results.into_iter();
+                        for name in names {
+                            inner = Scope::declare(&inner, name, results.next().unwrap_or(Value::Nil));
+                        }
+                        match self.exec_block(body, inner, frame)? {
+                            Flow::Normal => {},
+                            Flow::Break => break,
+                            flow => return Ok((flow, env))
+                        }
+                    }
+                },
+                StatementKind::Return(expressions) => {
+                    let values = self.eval_list(expressions, &env, frame)?;
+                    return Ok((Flow::Return(values), env));
+                },
+                StatementKind::Break => {
+                    return Ok((Flow::Break, env));
+                }
+            }
+        }
+        Ok((Flow::Normal, env))
+    }
+
+    fn assign(&mut self, targets: &[Expression], expressions: &[Expression], env: &Env, frame: &Frame) -> Result<(), LuaError> {
+        let mut values = self.eval_list(expressions, env, frame)?.into_iter();
+        for target in targets {
+            let value = values.next().unwrap_or(Value::Nil);
+            match target {
+                Expression::Name(name) => {
+                    match Scope::lookup(env, name) {
+                        Some(scope) => *scope.value.borrow_mut() = value,
+                        None => {
+                            if self.protect_globals {
+                                return Err(self.error("Attempt to modify a readonly table"));
+                            }
+                            self.set_global(name, value);
+                        }
+                    }
+                },
+                Expression::Index(object, key) => {
+                    let object_value = self.eval(object, env, frame)?;
+                    let key = self.eval(key, env, frame)?;
+                    match &object_value {
+                        Value::Table(table) => {
+                            let result = table.borrow_mut().set(key, value);
+                            result.map_err(|e| self.error(&e))?;
+                        },
+                        other => return Err(self.error(&format!("attempt to index {}", self.describe(object, env, other))))
+                    }
+                },
+                _ => return Err(self.error("cannot assign to this expression"))
+            }
+        }
+        Ok(())
+    }
+
+    /// How an operand is named in error messages, like `global 'x' (a nil value)`.
+    fn describe(&self, expression: &Expression, env: &Env, value: &Value) -> String {
+        let name = match expression {
+            Expression::Name(name) if Scope::lookup(env, name).is_some() => format!("local '{}'", name),
+            Expression::Name(name) => format!("global '{}'", name),
+            Expression::Index(_, key) => match key.as_ref() {
+                Expression::Str(key) => format!("field '{}'", String::from_utf8_lossy(key)),
+                _ => String::new()
+            },
+            Expression::Method(_, name, _) => format!("method '{}'", name),
+            _ => String::new()
+        };
+        if name.is_empty() {
+            return format!("a {} value", value.type_name());
+        }
+        format!("{} (a {} value)", name, value.type_name())
+    }
+
+    /// The values of a list of expressions, the last one contributing all of its values.
+    fn eval_list(&mut self, expressions: &[Expression], env: &Env, frame: &Frame) -> Result<Vec<Value>, LuaError> {
+        let mut values = Vec::with_capacity(expressions.len());
+        for (i, expression) in expressions.iter().enumerate() {
+            if i == expressions.len() - 1 && expression.is_multi() {
+                values.extend(self.eval_multi(expression, env, frame)?);
+            } else {
+                values.push(self.eval(expression, env, frame)?);
+            }
+        }
+        Ok(values)
+    }
+
+    fn eval_multi(&mut self, expression: &Expression, env: &Env, frame: &Frame) -> Result<Vec<Value>, LuaError> {
+        match expression {
+            Expression::Vararg => Ok(frame.varargs.clone()),
+            Expression::Call(function, arguments) => {
+                let function_value = self.eval(function, env, frame)?;
+                if !matches!(function_value, Value::Function(_)) {
+                    return Err(self.error(&format!("attempt to call {}", self.describe(function, env, &function_value))));
+                }
+                let arguments = self.eval_list(arguments, env, frame)?;
+                self.call(&function_value, arguments)
+            },
+            Expression::Method(object, name, arguments) => {
+                let object_value = self.eval(object, env, frame)?;
+                let method = self.index(&object_value, &Value::str(name)).map_err(|_| {
+                    self.error(&format!("attempt to index {}", self.describe(object, env, &object_value)))
+                })?;
+                if !matches!(method, Value::Function(_)) {
+                    return Err(self.error(&format!("attempt to call {}", self.describe(expression, env, &method))));
+                }
+                let mut values = vec![object_value];
+                values.extend(self.eval_list(arguments, env, frame)?);
+                self.call(&method, values)
+            },
+            other => Ok(vec![self.eval(other, env, frame)?])
+        }
+    }
+
+    /// `object[key]`, strings are indexed by the string library.
+    pub fn index(&self, object: &Value, key: &Value) -> Result<Value, LuaError> {
+        match object {
+            Value::Table(table) => Ok(table.borrow().get(key)),
+            Value::Str(_) => match &self.string_library {
+                Some(library) => Ok(library.borrow().get(key)),
+                None => Ok(Value::Nil)
+            },
+            other => Err(self.error(&format!("attempt to index a {} value", other.type_name())))
+        }
+    }
+
+    fn eval(&mut self, expression: &Expression, env: &Env, frame: &Frame) -> Result<Value, LuaError> {
+        match expression {
+            Expression::Nil => Ok(Value::Nil),
+            Expression::True => Ok(Value::Boolean(true)),
+            Expression::False => Ok(Value::Boolean(false)),
+            Expression::Number(x) => Ok(Value::Number(*x)),
+            Expression::Str(x) => Ok(Value::bytes(x)),
+            Expression::Vararg => Ok(frame.varargs.first().cloned().unwrap_or(Value::Nil)),
+            Expression::Function(body) => Ok(Value::Function(Rc::new(Function::Lua(body.clone(), env.clone())))),
+            Expression::Name(name) => {
+                if let Some(scope) = Scope::lookup(env, name) {
+                    return Ok(scope.value.borrow().clone());
+                }
+                let value = self.get_global(name);
+                if value.is_nil() && self.protect_globals {
+                    return Err(self.error(&format!("Script attempted to access nonexistent global variable '{}'", name)));
+                }
+                Ok(value)
+            },
+            Expression::Index(object, key) => {
+                let object_value = self.eval(object, env, frame)?;
+                let key = self.eval(key, env, frame)?;
+                self.index(&object_value, &key).map_err(|_| {
+                    self.error(&format!("attempt to index {}", self.describe(object, env, &object_value)))
+                })
+            },
+            Expression::Call(..) | Expression::Method(..) => {
+                Ok(self.eval_multi(expression, env, frame)?.into_iter().next().unwrap_or(Value::Nil))
+            },
+            Expression::Paren(inner) => self.eval(inner, env, frame),
+            Expression::Table(fields) => self.table_constructor(fields, env, frame),
+            Expression::And(left, right) => {
+                let left = self.eval(left, env, frame)?;
+                if !left.truthy() {
+                    return Ok(left);
+                }
+                self.eval(right, env, frame)
+            },
+            Expression::Or(left, right) => {
+                let left = self.eval(left, env, frame)?;
+                if left.truthy() {
+                    return Ok(left);
+                }
+                self.eval(right, env, frame)
+            },
+            Expression::Unary(operator, operand) => {
+                let value = self.eval(operand, env, frame)?;
+                match operator {
+                    UnaryOperator::Not => Ok(Value::Boolean(!value.truthy())),
+                    UnaryOperator::Minus => match value.to_number() {
+                        Some(x) => Ok(Value::Number(-x)),
+                        None => Err(self.error(&format!("attempt to perform arithmetic on {}", self.describe(operand, env, &value))))
+                    },
+                    UnaryOperator::Length => match &value {
+                        Value::Str(x) => Ok(Value::Number(x.len() as f64)),
+                        Value::Table(x) => Ok(Value::Number(x.borrow().len() as f64)),
+                        _ => Err(self.error(&format!("attempt to get length of {}", self.describe(operand, env, &value))))
+                    }
+                }
+            },
+            Expression::Binary(operator, left, right) => {
+                let a = self.eval(left, env, frame)?;
+                let b = self.eval(right, env, frame)?;
+                self.binary(*operator, &a, &b).map_err(|e| match e {
+                    BinaryError::Operand(verb, first) => {
+                        let (expression, value) = if first { (left, &a) } else { (right, &b) };
+                        self.error(&format!("attempt to {} {}", verb, self.describe(expression, env, value)))
+                    },
+                    BinaryError::Message(message) => self.error(&message)
+                })
+            }
+        }
+    }
+
+    fn table_constructor(&mut self, fields: &[TableField], env: &Env, frame: &Frame) -> Result<Value, LuaError> {
+        let mut table = Table::default();
+        let mut position = 1;
+        for (i, field) in fields.iter().enumerate() {
+            match field {
+                TableField::Positional(expression) if i == fields.len() - 1 && expression.is_multi() => {
+                    for value in self.eval_multi(expression, env, frame)? {
+                        let _ = table.set(Value::Number(position as f64), value);
+                        position += 1;
+                    }
+                },
+                TableField::Positional(expression) => {
+                    let value = self.eval(expression, env, frame)?;
+                    let _ = table.set(Value::Number(position as f64), value);
+                    position += 1;
+                },
+                TableField::Keyed(key, value) => {
+                    let key = self.eval(key, env, frame)?;
+                    let value = self.eval(value, env, frame)?;
+                    table.set(key, value).map_err(|e| self.error(&e))?;
+                }
+            }
+        }
+        Ok(Value::Table(Rc::new(RefCell::new(table))))
+    }
+
+    fn binary(&self, operator: BinaryOperator, a: &Value, b: &Value) -> Result<Value, BinaryError> {
+        let arithmetic = |f: fn(f64, f64) -> f64| -> Result<Value, BinaryError> {
+            match (a.to_number(), b.to_number()) {
+                (Some(x), Some(y)) => Ok(Value::Number(f(x, y))),
+                (None, _) => Err(BinaryError::Operand("perform arithmetic on", true)),
+                _ => Err(BinaryError::Operand("perform arithmetic on", false))
+            }
+        };
+        match operator {
+            BinaryOperator::Add => arithmetic(|x, y| x + y),
+            BinaryOperator::Sub => arithmetic(|x, y| x - y),
+            BinaryOperator::Mul => arithmetic(|x, y| x * y),
+            BinaryOperator::Div => arithmetic(|x, y| x / y),
+            BinaryOperator::Mod => arithmetic(|x, y| x - (x / y).floor() * y),
+            BinaryOperator::Pow => arithmetic(|x, y| x.powf(y)),
+            BinaryOperator::Concat => {
+                match (a.to_bytes(), b.to_bytes()) {
+                    (Some(x), Some(y)) => {
+                        let mut joined = Vec::with_capacity(x.len() + y.len());
+                        joined.extend_from_slice(&x);
+                        joined.extend_from_slice(&y);
+                        Ok(Value::bytes(&joined))
+                    },
+                    (None, _) => Err(BinaryError::Operand("concatenate", true)),
+                    _ => Err(BinaryError::Operand("concatenate", false))
+                }
+            },
+            BinaryOperator::Eq => Ok(Value::Boolean(a.raw_equals(b))),
+            BinaryOperator::Ne => Ok(Value::Boolean(!a.raw_equals(b))),
+            BinaryOperator::Lt => Ok(Value::Boolean(less_than(a, b)?)),
+            BinaryOperator::Le => Ok(Value::Boolean(!less_than(b, a)?)),
+            BinaryOperator::Gt => Ok(Value::Boolean(less_than(b, a)?)),
+            BinaryOperator::Ge => Ok(Value::Boolean(!less_than(a, b)?)),
+        }
+    }
+}
+
+enum BinaryError {
+    // What couldn't be done, and whether the left operand is the culprit
+    Operand(&'static str, bool),
+    Message(String),
+}
+
+/// `a < b` for two numbers or two strings. `a <= b` is `not (b < a)`, which differs for NaN
+/// only.
+fn less_than(a: &Value, b: &Value) -> Result<bool, BinaryError> {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => Ok(x < y),
+        (Value::Str(x), Value::Str(y)) => Ok(x < y),
+        _ if a.type_name() == b.type_name() => Err(BinaryError::Message(format!("attempt to compare two {} values", a.type_name()))),
+        _ => Err(BinaryError::Message(format!("attempt to compare {} with {}", a.type_name(), b.type_name())))
+    }
+}
+
+/// `less_than` for `table.sort` and the other builtins.
+pub fn compare(vm: &Vm, a: &Value, b: &Value) -> Result<bool, LuaError> {
+    less_than(a, b).map_err(|e| match e {
+        BinaryError::Message(message) => vm.error(&message),
+        BinaryError::Operand(verb, _) => vm.error(verb)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scripting::engine::SCRIPT_STACK_SIZE;
+    use crate::scripting::{parser, stdlib};
+
+    /// Runs a chunk with the standard library, what it returns as `tostring` shows it. Like
+    /// scripts, on a thread with room for the deepest calls.
+    fn run(source: &str) -> Result<Vec<String>, String> {
+        let source = source.to_string();
+        std::thread::Builder::new().stack_size(SCRIPT_STACK_SIZE).spawn(move || {
+            let chunk = parser::parse("test", source.as_bytes())?;
+            let mut vm = Vm::new("test", None);
+            stdlib::install(&mut vm);
+            match vm.run(&chunk, vec![]) {
+                Ok(values) => Ok(values.iter().map(|x| x.display()).collect()),
+                Err(LuaError::Error(x)) => Err(x.to_string_lossy()),
+                Err(LuaError::Killed) => Err("killed".to_string())
+            }
+        }).unwrap().join().unwrap()
+    }
+
+    fn displayed(source: &str) -> Vec<String> {
+        run(source).unwrap()
+    }
+
+    #[test]
+    fn arithmetic_and_strings() {
+        assert_eq!(displayed("return 1 + 2 * 3, 7 % 3, 2 ^ 10, 7 / 2, -(3)"), vec!["7", "1", "1024", "3.5", "-3"]);
+        assert_eq!(displayed("return 'a' .. 1 .. 'b', #'hello', '10' + 1"), vec!["a1b", "5", "11"]);
+        assert_eq!(displayed("return 1 < 2, 'a' < 'b', 1 == '1', nil or false, 1 and 2"), vec!["true", "true", "false", "false", "2"]);
+    }
+
+    #[test]
+    fn control_flow() {
+        let source = "
+            local total = 0
+            for i = 1, 10 do
+                if i % 2 == 0 then total = total + i elseif i == 5 then total = total + 100 end
+            end
+            local n = 0
+            while true do n = n + 1 if n >= 3 then break end end
+            repeat n = n - 1 until n == 0
+            for i = 10, 1, -3 do total = total + i end
+            return total, n
+        ";
+        assert_eq!(displayed(source), vec!["152", "0"]);
+    }
+
+    #[test]
+    fn tables_and_iteration() {
+        let source = "
+            local t = {10, 20, 30, x = 'y', ['k' .. 1] = true}
+            t[#t + 1] = 40
+            local sum = 0
+            for i, v in ipairs(t) do sum = sum + i * v end
+            local keys = 0
+            for k, v in pairs(t) do keys = keys + 1 end
+            return sum, keys, t.x, t.k1, #t
+        ";
+        assert_eq!(displayed(source), vec!["300", "6", "y", "true", "4"]);
+    }
+
+    #[test]
+    fn closures_and_varargs() {
+        let source = "
+            local function counter()
+                local n = 0
+                return function() n = n + 1 return n end
+            end
+            local a, b = counter(), counter()
+            a() a()
+            local function count(...) return select('#', ...), ... end
+            local function fib(n) if n < 2 then return n end return fib(n - 1) + fib(n - 2) end
+            return a(), b(), count(nil, 2), fib(15)
+        ";
+        // Only the last call of a list keeps all its results
+        assert_eq!(displayed(source), vec!["3", "1", "2", "610"]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(run("local x = nil; return x.y").unwrap_err(), "test:1: attempt to index local 'x' (a nil value)");
+        assert_eq!(run("error('boom')").unwrap_err(), "test:1: boom");
+        assert_eq!(displayed("return pcall(function() error({code = 1}) end)")[0], "false");
+        assert_eq!(displayed("return pcall(error, 'caught', 0)"), vec!["false", "caught"]);
+        assert_eq!(run("local function f() return f() + 1 end return f()").unwrap_err(), "test:1: stack overflow");
+        assert!(run("return 1 +").unwrap_err().starts_with("test:1:"));
+    }
+
+    #[test]
+    fn kill_flag_stops_loops() {
+        let chunk = parser::parse("test", b"while true do end").unwrap();
+        let mut vm = Vm::new("test", Some(Arc::new(AtomicBool::new(true))));
+        assert!(matches!(vm.run(&chunk, vec![]), Err(LuaError::Killed)));
+    }
+}
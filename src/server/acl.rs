@@ -0,0 +1,697 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::helpers::Helper;
+use crate::server::commands::{command_channels, command_keys, command_names, command_spec, has_subcommands, KeyAccess};
+
+// Denials of the same kind within this many milliseconds share an entry of `ACL LOG`
+const LOG_GROUPING_MILLISECONDS: u128 = 60000;
+
+/// The categories of `ACL CAT` with their commands, `@all` holds every command.
+const CATEGORIES: [(&str, &[&str]); 21] = [
+    ("keyspace", &["del", "keys", "flushdb", "flushall"]),
+    ("read", &["get", "keys"]),
+    ("write", &["set", "del", "flushdb", "flushall"]),
+    ("set", &[]),
+    ("sortedset", &[]),
+    ("list", &[]),
+    ("hash", &[]),
+    ("string", &["set", "get"]),
+    ("bitmap", &[]),
+    ("hyperloglog", &[]),
+    ("geo", &[]),
+    ("stream", &[]),
+    ("pubsub", &["subscribe", "unsubscribe", "psubscribe", "punsubscribe", "ssubscribe", "sunsubscribe", "publish", "spublish", "pubsub"]),
+    ("admin", &["config", "save", "shutdown", "bgsave", "bgrewriteaof", "lastsave", "psync", "replconf", "replicaof", "slaveof", "acl"]),
+    ("fast", &["echo", "get", "ping", "lastsave", "multi", "discard", "watch", "unwatch", "publish", "spublish", "hello", "auth"]),
+    ("slow", &[
+        "set", "del", "config", "keys", "info", "save", "shutdown", "bgsave", "bgrewriteaof", "psync", "replconf", "wait", "waitaof",
+        "replicaof", "slaveof", "exec", "flushdb", "flushall", "subscribe", "unsubscribe", "psubscribe", "punsubscribe",
+        "ssubscribe", "sunsubscribe", "pubsub", "client", "eval", "evalsha", "eval_ro", "evalsha_ro", "fcall", "fcall_ro",
+        "script", "function", "acl",
+    ]),
+    ("blocking", &["wait", "waitaof"]),
+    ("dangerous", &["config", "keys", "info", "save", "shutdown", "bgsave", "bgrewriteaof", "lastsave", "psync", "replconf", "replicaof", "slaveof", "flushdb", "flushall", "acl"]),
+    ("connection", &["echo", "ping", "hello", "auth", "client", "wait", "waitaof"]),
+    ("transaction", &["multi", "exec", "discard", "watch", "unwatch"]),
+    ("scripting", &["eval", "evalsha", "eval_ro", "evalsha_ro", "fcall", "fcall_ro", "script", "function"]),
+];
+
+pub fn category_names() -> Vec<&'static str> {
+    CATEGORIES.iter().map(|(name, _)| *name).collect()
+}
+
+pub fn category_commands(name: &str) -> Option<&'static [&'static str]> {
+    CATEGORIES.iter().find(|(category, _)| *category == name).map(|(_, commands)| *commands)
+}
+
+/// A key pattern of a user, `~pattern` allows both reading and writing.
+#[derive(Debug, Clone)]
+struct KeyPattern {
+    pattern: String,
+    read: bool,
+    write: bool,
+}
+
+impl KeyPattern {
+    fn describe(&self) -> String {
+        match (self.read, self.write) {
+            (true, true) => format!("~{}", self.pattern),
+            (true, false) => format!("%R~{}", self.pattern),
+            _ => format!("%W~{}", self.pattern)
+        }
+    }
+}
+
+/// Why a command was refused, what `ACL LOG` records.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Denial {
+    // The command, or `command|subcommand`
+    Command(String),
+    Key(String),
+    Channel(String),
+}
+
+impl Denial {
+    pub fn reason(&self) -> &str {
+        match self {
+            Denial::Command(_) => "command",
+            Denial::Key(_) => "key",
+            Denial::Channel(_) => "channel"
+        }
+    }
+
+    pub fn object(&self) -> &str {
+        match self {
+            Denial::Command(x) | Denial::Key(x) | Denial::Channel(x) => x
+        }
+    }
+
+    pub fn error(&self, username: &str) -> String {
+        match self {
+            Denial::Command(x) => format!("-NOPERM User {} has no permissions to run the '{}' command\r\n", username, x),
+            Denial::Key(_) => "-NOPERM No permissions to access a key\r\n".to_string(),
+            Denial::Channel(_) => "-NOPERM No permissions to access a channel\r\n".to_string()
+        }
+    }
+}
+
+/// A user of `ACL SETUSER`: whether it can log in, with which passwords, and what it can run and
+/// access once logged in.
+#[derive(Debug, Clone)]
+pub struct User {
+    pub name: String,
+    enabled: bool,
+    // Any password is accepted
+    nopass: bool,
+    // The SHA256 of the passwords, in hex
+    passwords: Vec<String>,
+    // Commands allowed, by name or as `command|subcommand`
+    allowed: HashSet<String>,
+    // Subcommands denied although their command is allowed
+    denied: HashSet<String>,
+    // The command rules given, from the last `+@all` or `-@all`, what `ACL LIST` shows
+    command_rules: Vec<String>,
+    allkeys: bool,
+    keys: Vec<KeyPattern>,
+    allchannels: bool,
+    channels: Vec<String>,
+}
+
+fn valid_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|x| x.is_ascii_digit() || ('a'..='f').contains(&x))
+}
+
+impl User {
+    /// A new user can't do anything until rules say otherwise.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            enabled: false,
+            nopass: false,
+            passwords: vec![],
+            allowed: HashSet::new(),
+            denied: HashSet::new(),
+            command_rules: vec!["-@all".to_string()],
+            allkeys: false,
+            keys: vec![],
+            allchannels: false,
+            channels: vec![],
+        }
+    }
+
+    /// Applies a rule of `ACL SETUSER`, errors are the reason it is invalid.
+    pub fn apply(&mut self, rule: &str) -> Result<(), String> {
+        match rule.to_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            },
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            },
+            "allkeys" => return self.apply("~*"),
+            "resetkeys" => {
+                self.allkeys = false;
+                self.keys.clear();
+            },
+            "allchannels" => return self.apply("&*"),
+            "resetchannels" => {
+                self.allchannels = false;
+                self.channels.clear();
+            },
+            "allcommands" => return self.apply("+@all"),
+            "nocommands" => return self.apply("-@all"),
+            "reset" => {
+                for rule in ["resetpass", "resetkeys", "resetchannels", "off", "-@all"] {
+                    self.apply(rule)?;
+                }
+            },
+            _ => {
+                let (first, rest) = rule.split_at(rule.chars().next().map(|x| x.len_utf8()).unwrap_or(0));
+                match first {
+                    ">" => self.add_password(Helper::sha256_hex(rest.as_bytes())),
+                    "<" => self.remove_password(&Helper::sha256_hex(rest.as_bytes()))?,
+                    "#" | "!" if !valid_hash(rest) => {
+                        return Err("The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters".to_string());
+                    },
+                    "#" => self.add_password(rest.to_string()),
+                    "!" => self.remove_password(rest)?,
+                    "~" => self.add_key_pattern(rest, true, true)?,
+                    "%" => {
+                        let (flags, pattern) = rest.split_once('~').ok_or("Syntax error")?;
+                        let read = flags.to_uppercase().contains('R');
+                        let write = flags.to_uppercase().contains('W');
+                        if flags.is_empty() || !flags.to_uppercase().chars().all(|x| x == 'R' || x == 'W') {
+                            return Err("Syntax error".to_string());
+                        }
+                        self.add_key_pattern(pattern, read, write)?;
+                    },
+                    "&" => self.add_channel(rest)?,
+                    "+" | "-" => self.apply_command_rule(first == "+", &rest.to_lowercase())?,
+                    _ => return Err("Syntax error".to_string())
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn add_password(&mut self, hash: String) {
+        self.nopass = false;
+        if !self.passwords.contains(&hash) {
+            self.passwords.push(hash);
+        }
+    }
+
+    fn remove_password(&mut self, hash: &str) -> Result<(), String> {
+        if !self.passwords.iter().any(|x| x == hash) {
+            return Err("The password you are trying to remove from the user does not exist".to_string());
+        }
+        self.passwords.retain(|x| x != hash);
+        Ok(())
+    }
+
+    fn add_key_pattern(&mut self, pattern: &str, read: bool, write: bool) -> Result<(), String> {
+        let all = pattern == "*" && read && write;
+        if self.allkeys && !all {
+            return Err("Adding a pattern after the * pattern (or the 'allkeys' flag) is not valid and does not have any effect. Try 'resetkeys' to start with an empty list of patterns".to_string());
+        }
+        if all {
+            self.allkeys = true;
+            self.keys.clear();
+            return Ok(());
+        }
+        // The same pattern given twice gets the flags of both
+        match self.keys.iter_mut().find(|x| x.pattern == pattern) {
+            Some(existing) => {
+                existing.read |= read;
+                existing.write |= write;
+            },
+            None => self.keys.push(KeyPattern { pattern: pattern.to_string(), read, write })
+        }
+        Ok(())
+    }
+
+    fn add_channel(&mut self, pattern: &str) -> Result<(), String> {
+        if self.allchannels && pattern != "*" {
+            return Err("Adding a pattern after the * pattern (or the 'allchannels' flag) is not valid and does not have any effect. Try 'resetchannels' to start with an empty list of channels".to_string());
+        }
+        if pattern == "*" {
+            self.allchannels = true;
+            self.channels.clear();
+        } else if !self.channels.iter().any(|x| x == pattern) {
+            self.channels.push(pattern.to_string());
+        }
+        Ok(())
+    }
+
+    /// `+<command>`, `-<command>`, `+<command>|<subcommand>` or `+@<category>`, and their `-`.
+    fn apply_command_rule(&mut self, allow: bool, name: &str) -> Result<(), String> {
+        let unknown = "Unknown command or category name in ACL".to_string();
+        let sign = if allow { "+" } else { "-" };
+        if let Some(category) = name.strip_prefix('@') {
+            if category == "all" {
+                self.allowed = if allow { command_names().map(|x| x.to_string()).collect() } else { HashSet::new() };
+                self.denied.clear();
+                self.command_rules.clear();
+            } else {
+                for command in category_commands(category).ok_or(unknown)? {
+                    self.set_command(allow, command);
+                }
+            }
+        } else if let Some((command, subcommand)) = name.split_once('|') {
+            if !has_subcommands(command) || subcommand.is_empty() || subcommand.contains('|') {
+                return Err(unknown);
+            }
+            if allow {
+                self.allowed.insert(name.to_string());
+                self.denied.remove(name);
+            } else {
+                self.allowed.remove(name);
+                if self.allowed.contains(command) {
+                    self.denied.insert(name.to_string());
+                }
+            }
+        } else {
+            if command_spec(name).is_none() {
+                return Err(unknown);
+            }
+            self.set_command(allow, name);
+        }
+        self.command_rules.push(format!("{}{}", sign, name));
+        Ok(())
+    }
+
+    /// Allows or denies a whole command, the rules of its subcommands are forgotten.
+    fn set_command(&mut self, allow: bool, command: &str) {
+        let prefix = format!("{}|", command);
+        self.allowed.retain(|x| !x.starts_with(&prefix));
+        self.denied.retain(|x| !x.starts_with(&prefix));
+        if allow {
+            self.allowed.insert(command.to_string());
+        } else {
+            self.allowed.remove(command);
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn nopass(&self) -> bool {
+        self.nopass
+    }
+
+    pub fn check_password(&self, password: &str) -> bool {
+        self.nopass || self.passwords.contains(&Helper::sha256_hex(password.as_bytes()))
+    }
+
+    /// Whether the user may run the command with these arguments, the command name excluded.
+    pub fn check(&self, command: &str, args: &[String]) -> Result<(), Denial> {
+        let subcommand = args.first().filter(|_| has_subcommands(command)).map(|x| format!("{}|{}", command, x.to_lowercase()));
+        let allowed = match &subcommand {
+            Some(x) if self.allowed.contains(x) => true,
+            Some(x) if self.denied.contains(x) => false,
+            _ => self.allowed.contains(command)
+        };
+        if !allowed {
+            return Err(Denial::Command(subcommand.unwrap_or(command.to_string())));
+        }
+        if !self.allkeys {
+            for (key, access) in command_keys(command, args) {
+                let read = access != KeyAccess::Write;
+                let write = access != KeyAccess::Read;
+                if !self.keys.iter().any(|x| (x.read || !read) && (x.write || !write) && Helper::glob_match(&x.pattern, key)) {
+                    return Err(Denial::Key(key.to_string()));
+                }
+            }
+        }
+        if !self.allchannels {
+            // A pattern subscribed to has to be one of the patterns of the user, as is
+            for (channel, pattern) in command_channels(command, args) {
+                if !self.channels.iter().any(|x| if pattern { x == channel } else { Helper::glob_match(x, channel) }) {
+                    return Err(Denial::Channel(channel.to_string()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flags(&self) -> Vec<String> {
+        let mut flags = vec![if self.enabled { "on" } else { "off" }.to_string()];
+        if self.nopass {
+            flags.push("nopass".to_string());
+        }
+        flags
+    }
+
+    pub fn password_hashes(&self) -> &[String] {
+        &self.passwords
+    }
+
+    pub fn describe_commands(&self) -> String {
+        self.command_rules.join(" ")
+    }
+
+    pub fn describe_keys(&self) -> String {
+        if self.allkeys {
+            return "~*".to_string();
+        }
+        self.keys.iter().map(|x| x.describe()).collect::<Vec<String>>().join(" ")
+    }
+
+    pub fn describe_channels(&self) -> String {
+        if self.allchannels {
+            return "&*".to_string();
+        }
+        self.channels.iter().map(|x| format!("&{}", x)).collect::<Vec<String>>().join(" ")
+    }
+
+    /// The user as a line of `ACL LIST` and of the ACL file, rules that recreate it.
+    pub fn describe(&self) -> String {
+        let mut words = vec!["user".to_string(), self.name.clone()];
+        words.extend(self.flags());
+        words.extend(self.passwords.iter().map(|x| format!("#{}", x)));
+        words.push(self.describe_keys());
+        words.push(match self.describe_channels().as_str() {
+            "" => "resetchannels".to_string(),
+            x => x.to_string()
+        });
+        words.push(self.describe_commands());
+        words.retain(|x| !x.is_empty());
+        words.join(" ")
+    }
+}
+
+/// A group of denials reported by `ACL LOG`.
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub entry_id: u64,
+    pub count: u64,
+    pub reason: String,
+    // `toplevel`, `multi` or `lua`: where the command was run from
+    pub context: String,
+    pub object: String,
+    pub username: String,
+    pub client_info: String,
+    // Milliseconds since the epoch
+    pub created: u128,
+    pub updated: u128,
+}
+
+fn now_milliseconds() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis()
+}
+
+/// The users, the `default` one always exists, and the log of what they were denied.
+#[derive(Debug, Clone)]
+pub struct Acl {
+    users: BTreeMap<String, User>,
+    log: VecDeque<LogEntry>,
+    next_entry_id: u64,
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Acl {
+    pub fn new() -> Self {
+        let mut users = BTreeMap::new();
+        users.insert("default".to_string(), Self::default_user());
+        Self { users, log: VecDeque::new(), next_entry_id: 0 }
+    }
+
+    /// What connections are authenticated as from the start, unless a password is required.
+    fn default_user() -> User {
+        let mut user = User::new("default");
+        for rule in ["on", "nopass", "~*", "&*", "+@all"] {
+            let _ = user.apply(rule);
+        }
+        user
+    }
+
+    pub fn user(&self, name: &str) -> Option<&User> {
+        self.users.get(name)
+    }
+
+    pub fn users(&self) -> impl Iterator<Item = &User> {
+        self.users.values()
+    }
+
+    /// Creates the user or changes it, either every rule applies or none does.
+    pub fn set_user(&mut self, name: &str, rules: &[String]) -> Result<(), String> {
+        if name.contains(' ') || name.contains('\0') {
+            return Err("ERR Usernames can't contain spaces or null characters".to_string());
+        }
+        let mut user = self.users.get(name).cloned().unwrap_or(User::new(name));
+        for rule in rules {
+            user.apply(rule).map_err(|e| format!("ERR Error in ACL SETUSER modifier '{}': {}", rule, e))?;
+        }
+        self.users.insert(name.to_string(), user);
+        Ok(())
+    }
+
+    pub fn delete_user(&mut self, name: &str) -> bool {
+        self.users.remove(name).is_some()
+    }
+
+    pub fn authenticate(&self, username: &str, password: &str) -> bool {
+        self.users.get(username).is_some_and(|x| x.enabled && x.check_password(password))
+    }
+
+    /// `requirepass` is the password of the default user, without it any password does.
+    pub fn set_default_password(&mut self, password: Option<&str>) {
+        if let Some(user) = self.users.get_mut("default") {
+            let _ = user.apply("resetpass");
+            let _ = match password {
+                Some(x) => user.apply(&format!(">{}", x)),
+                None => user.apply("nopass")
+            };
+        }
+    }
+
+    /// Replaces the users with the ones of an ACL file, one `user <name> <rules...>` per line.
+    /// Nothing changes when a line is invalid.
+    pub fn load_file(&mut self, path: &Path) -> Result<(), String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("Error loading ACLs, opening file '{}': {}", path.display(), e))?;
+        let mut users: BTreeMap<String, User> = BTreeMap::new();
+        for (line_number, line) in content.lines().enumerate() {
+            let words: Vec<String> = line.split_whitespace().map(|x| x.to_string()).collect();
+            if words.is_empty() {
+                continue;
+            }
+            let error = |e: &str| format!("{}:{}: {}", path.display(), line_number + 1, e);
+            if words[0] != "user" || words.len() < 2 {
+                return Err(error("should start with user keyword followed by the username"));
+            }
+            if users.contains_key(&words[1]) {
+                return Err(error(&format!("Duplicate user '{}' found", words[1])));
+            }
+            let mut user = User::new(&words[1]);
+            for rule in &words[2..] {
+                user.apply(rule).map_err(|e| error(&format!("Error in applying operation '{}': {}", rule, e)))?;
+            }
+            users.insert(words[1].clone(), user);
+        }
+        users.entry("default".to_string()).or_insert_with(Self::default_user);
+        self.users = users;
+        Ok(())
+    }
+
+    /// Writes every user to the ACL file, through a temporary file so it is never half written.
+    pub fn save_file(&self, path: &Path) -> Result<(), String> {
+        let content: String = self.users.values().map(|x| format!("{}\n", x.describe())).collect();
+        let temp_path = path.with_extension(format!("tmp-{}", std::process::id()));
+        fs::write(&temp_path, content)
+            .and_then(|_| fs::rename(&temp_path, path))
+            .map_err(|e| format!("Error saving ACLs to '{}': {}", path.display(), e))
+    }
+
+    /// Records a denial, or counts it with a similar one of the last minute.
+    pub fn log_denial(&mut self, reason: &str, context: &str, object: &str, username: &str, client_info: String, max_len: usize) {
+        let now = now_milliseconds();
+        let similar = self.log.iter().position(|x| {
+            x.reason == reason && x.context == context && x.object == object && x.username == username && now - x.updated < LOG_GROUPING_MILLISECONDS
+        });
+        let entry = match similar.and_then(|x| self.log.remove(x)) {
+            Some(mut entry) => {
+                entry.count += 1;
+                entry.updated = now;
+                entry.client_info = client_info;
+                entry
+            },
+            None => {
+                self.next_entry_id += 1;
+                LogEntry {
+                    entry_id: self.next_entry_id - 1,
+                    count: 1,
+                    reason: reason.to_string(),
+                    context: context.to_string(),
+                    object: object.to_string(),
+                    username: username.to_string(),
+                    client_info,
+                    created: now,
+                    updated: now,
+                }
+            }
+        };
+        self.log.push_front(entry);
+        self.log.truncate(max_len);
+    }
+
+    /// The most recent first.
+    pub fn log_entries(&self) -> impl Iterator<Item = &LogEntry> {
+        self.log.iter()
+    }
+
+    pub fn reset_log(&mut self) {
+        self.log.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_with(rules: &[&str]) -> User {
+        let mut user = User::new("alice");
+        for rule in rules {
+            user.apply(rule).unwrap();
+        }
+        user
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn new_user_can_do_nothing() {
+        let user = User::new("alice");
+        assert!(!user.enabled());
+        assert!(!user.check_password("anything"));
+        assert_eq!(user.check("ping", &[]), Err(Denial::Command("ping".to_string())));
+        assert_eq!(user.describe(), "user alice off resetchannels -@all");
+    }
+
+    #[test]
+    fn passwords() {
+        let mut user = user_with(&["on", ">secret", ">other"]);
+        assert!(user.check_password("secret") && user.check_password("other"));
+        assert!(!user.check_password("wrong"));
+        user.apply("<other").unwrap();
+        assert!(!user.check_password("other"));
+        assert!(user.apply("<other").is_err());
+        user.apply(&format!("#{}", Helper::sha256_hex(b"hashed"))).unwrap();
+        assert!(user.check_password("hashed"));
+        assert!(user.apply("#NOTAHASH").is_err());
+        user.apply("nopass").unwrap();
+        assert!(user.check_password("anything") && user.password_hashes().is_empty());
+    }
+
+    #[test]
+    fn invalid_rules() {
+        let mut user = User::new("alice");
+        for rule in ["+nosuchcommand", "+@nosuchcategory", "+get|sub", "%X~key", "bogus", "+acl|"] {
+            assert!(user.apply(rule).is_err(), "{}", rule);
+        }
+        user.apply("allkeys").unwrap();
+        assert!(user.apply("~foo").is_err());
+        user.apply("allchannels").unwrap();
+        assert!(user.apply("&news").is_err());
+    }
+
+    #[test]
+    fn commands_and_categories() {
+        let user = user_with(&["+@all", "-@dangerous", "+info"]);
+        assert!(user.check("ping", &[]).is_ok());
+        assert!(user.check("info", &[]).is_ok());
+        assert_eq!(user.check("flushall", &[]), Err(Denial::Command("flushall".to_string())));
+        assert_eq!(user.describe_commands(), "+@all -@dangerous +info");
+    }
+
+    #[test]
+    fn subcommands() {
+        let user = user_with(&["+client", "-client|kill", "+acl|whoami"]);
+        assert!(user.check("client", &args(&["id"])).is_ok());
+        assert_eq!(user.check("client", &args(&["KILL"])), Err(Denial::Command("client|kill".to_string())));
+        assert!(user.check("acl", &args(&["whoami"])).is_ok());
+        assert_eq!(user.check("acl", &args(&["list"])), Err(Denial::Command("acl|list".to_string())));
+        // A rule for the whole command replaces the ones of its subcommands
+        let reset = user_with(&["+client", "-client|kill", "+client"]);
+        assert!(reset.check("client", &args(&["kill"])).is_ok());
+    }
+
+    #[test]
+    fn key_patterns() {
+        let user = user_with(&["+@all", "~cache:*", "%R~config:*", "%W~log:*"]);
+        assert!(user.check("get", &args(&["cache:1"])).is_ok());
+        assert!(user.check("set", &args(&["cache:1", "v"])).is_ok());
+        assert!(user.check("get", &args(&["config:a"])).is_ok());
+        assert_eq!(user.check("set", &args(&["config:a", "v"])), Err(Denial::Key("config:a".to_string())));
+        assert!(user.check("set", &args(&["log:1", "v"])).is_ok());
+        assert_eq!(user.check("get", &args(&["log:1"])), Err(Denial::Key("log:1".to_string())));
+        assert_eq!(user.check("get", &args(&["other"])), Err(Denial::Key("other".to_string())));
+        assert_eq!(user.describe_keys(), "~cache:* %R~config:* %W~log:*");
+    }
+
+    #[test]
+    fn channel_patterns() {
+        let user = user_with(&["+@all", "&news.*"]);
+        assert!(user.check("publish", &args(&["news.tech", "hi"])).is_ok());
+        assert_eq!(user.check("subscribe", &args(&["news.tech", "sports"])), Err(Denial::Channel("sports".to_string())));
+        // Subscribing to a pattern needs that very pattern
+        assert!(user.check("psubscribe", &args(&["news.*"])).is_ok());
+        assert_eq!(user.check("psubscribe", &args(&["news.t*"])), Err(Denial::Channel("news.t*".to_string())));
+    }
+
+    #[test]
+    fn describe_recreates_the_user() {
+        let original = user_with(&["on", ">secret", "~cache:*", "&news", "+@read", "-keys"]);
+        let mut recreated = User::new("alice");
+        for rule in original.describe().split(' ').skip(2) {
+            recreated.apply(rule).unwrap();
+        }
+        assert_eq!(recreated.describe(), original.describe());
+        assert!(recreated.check_password("secret"));
+        assert!(recreated.check("get", &args(&["cache:1"])).is_ok());
+        assert!(recreated.check("keys", &args(&["*"])).is_err());
+    }
+
+    #[test]
+    fn set_user_is_all_or_nothing() {
+        let mut acl = Acl::new();
+        acl.set_user("bob", &args(&["on", ">pass"])).unwrap();
+        assert!(acl.authenticate("bob", "pass"));
+        assert!(acl.set_user("bob", &args(&["resetpass", "+nosuchcommand"])).is_err());
+        assert!(acl.authenticate("bob", "pass"));
+        assert!(acl.set_user("b b", &[]).is_err());
+        assert!(acl.authenticate("default", "anything"));
+        acl.set_default_password(Some("required"));
+        assert!(!acl.authenticate("default", "anything"));
+        assert!(acl.authenticate("default", "required"));
+    }
+
+    #[test]
+    fn log_groups_similar_denials() {
+        let mut acl = Acl::new();
+        acl.log_denial("command", "toplevel", "get", "bob", "id=1".to_string(), 2);
+        acl.log_denial("command", "toplevel", "get", "bob", "id=2".to_string(), 2);
+        acl.log_denial("key", "toplevel", "k", "bob", "id=3".to_string(), 2);
+        acl.log_denial("key", "multi", "k", "bob", "id=4".to_string(), 2);
+        let entries: Vec<&LogEntry> = acl.log_entries().collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].entry_id, entries[0].context.as_str()), (2, "multi"));
+        assert_eq!((entries[1].entry_id, entries[1].count), (1, 1));
+        acl.reset_log();
+        assert_eq!(acl.log_entries().count(), 0);
+    }
+}
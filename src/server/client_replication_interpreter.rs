@@ -1,110 +1,340 @@
-use super::parser::DS;
+use std::time::{Duration, Instant};
+
+use super::parser::{RESPParser, ParseError, DS};
 use crate::helpers::Helper;
 use crate::server::interpreter::Reply;
+use crate::rdb::rdb::{RDBContent, RDBFileHelper};
 
+/// Drives the replica side of the link with the master: the handshake, the snapshot sent on a
+/// full resynchronization and then the stream of propagated commands.
 pub struct ReplicationInterpreter {
-    source_ds: Option<DS>,
-    source_code: Option<String>,
+    // What the master is told to reach us at, from `replica-announce-port` and `replica-announce-ip`
     port: u32,
-    state: ClientConnectionState
+    announce_ip: Option<String>,
+    // `masterauth`, sent with `AUTH` right after the `PING`
+    auth: Option<String>,
+    state: ClientConnectionState,
+    // Bytes received from the master and not processed yet
+    buffer: Vec<u8>,
+    pub master_replid: String,
+    // Offset in the master's replication stream of the next byte to process
+    pub offset: u64,
+    last_ack: Instant,
+    // Last time anything was received from the master, `None` before the first connection
+    last_io: Option<Instant>,
+    // Stream bytes processed since the last call to `take_stream`, passed on to our own replicas
+    stream: Vec<u8>
 }
 
+/// How often a connected replica reports its offset to the master.
+const ACK_INTERVAL: Duration = Duration::from_secs(1);
+
 pub enum ClientConnectionState {
+    Disconnected, // No connection with the master, until the next attempt
     BeforePing,
+    AuthSent, // Only when the master requires a password
     PingSentSuccessfully,
+    ReplConfIpSent, // Only when an ip is announced
     ReplConf1Sent,
     ReplConf2Sent, // This basically means that the handshake is complete
+    WaitingSnapshot, // The master accepted a full resynchronization, the RDB payload follows
+    Connected, // Everything else the master sends is the command stream
+}
+
+pub enum ReplicationEvent {
+    // More data from the master is needed
+    Pending,
+    // Has to be written back to the master
+    Reply(String),
+    // The dataset sent by the master for a full resynchronization
+    Snapshot(RDBContent),
+    // One propagated command, as RESP source code
+    Command(String),
+    // The master asked for the processed offset with `REPLCONF GETACK`
+    Ack(u64),
+    // The master sent something that can't be understood, the link has to be dropped
+    Error(String),
 }
 
 impl ReplicationInterpreter {
-    pub fn new(ds: Option<DS>, listening_port: &u32) -> Self {
+    /// A known replication id and offset, from an earlier link or from our own history as a
+    /// master, let the first synchronization be a partial one.
+    pub fn new(master_replid: String, offset: u64) -> Self {
         Self {
-            source_ds: ds,
-            source_code: None,
-            port: *listening_port,
-            state: ClientConnectionState::BeforePing
+            port: 0,
+            announce_ip: None,
+            auth: None,
+            state: ClientConnectionState::Disconnected,
+            buffer: vec![],
+            master_replid,
+            offset,
+            last_ack: Instant::now(),
+            last_io: None,
+            stream: vec![]
+        }
+    }
+
+    /// Starts the handshake over on a new connection, returns the `PING` that opens it.
+    pub fn restart(&mut self, listening_port: u32, announce_ip: Option<String>, auth: Option<String>) -> String {
+        self.port = listening_port;
+        self.announce_ip = announce_ip;
+        self.auth = auth;
+        self.state = ClientConnectionState::BeforePing;
+        self.buffer.clear();
+        self.last_io = Some(Instant::now());
+        Helper::build_resp(&Reply::ReplyArray(vec![Reply::ReplyBulkString("PING".to_string())]))
+    }
+
+    /// The connection was closed, a partial command received so far is discarded.
+    pub fn disconnect(&mut self) {
+        self.state = ClientConnectionState::Disconnected;
+        self.buffer.clear();
+    }
+
+    /// Whether the handshake is done and the stream of commands flows.
+    pub fn is_up(&self) -> bool {
+        matches!(self.state, ClientConnectionState::Connected)
+    }
+
+    /// Whether we are in the handshake or loading the snapshot of a full resynchronization.
+    pub fn sync_in_progress(&self) -> bool {
+        !matches!(self.state, ClientConnectionState::Disconnected | ClientConnectionState::Connected)
+    }
+
+    pub fn last_io_seconds_ago(&self) -> Option<u64> {
+        self.last_io.map(|x| x.elapsed().as_secs())
+    }
+
+    /// Whether the master stayed silent for too long, during the handshake or the stream. A
+    /// healthy master pings its replicas more often than that.
+    pub fn timed_out(&self, timeout: Duration) -> bool {
+        if matches!(self.state, ClientConnectionState::Disconnected) {
+            return false;
+        }
+        match self.last_io {
+            Some(last_io) => last_io.elapsed() > timeout,
+            None => false
+        }
+    }
+
+    pub fn take_stream(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.stream)
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        if !data.is_empty() {
+            self.last_io = Some(Instant::now());
+        }
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Whether the periodic acknowledgement is due.
+    pub fn should_ack(&self) -> bool {
+        matches!(self.state, ClientConnectionState::Connected) && self.last_ack.elapsed() >= ACK_INTERVAL
+    }
+
+    /// `REPLCONF ACK <offset>`, followed by `FACK <offset>` when everything processed was also
+    /// fsynced to the append only file.
+    pub fn ack(&mut self, offset: u64, fsynced: bool) -> String {
+        self.last_ack = Instant::now();
+        let mut argv = vec![
+            Reply::ReplyBulkString("REPLCONF".to_string()),
+            Reply::ReplyBulkString("ACK".to_string()),
+            Reply::ReplyBulkString(offset.to_string()),
+        ];
+        if fsynced {
+            argv.push(Reply::ReplyBulkString("FACK".to_string()));
+            argv.push(Reply::ReplyBulkString(offset.to_string()));
+        }
+        Helper::build_resp(&Reply::ReplyArray(argv))
+    }
+
+    /// Consumes the next complete message from what the master sent so far.
+    pub fn next_event(&mut self) -> ReplicationEvent {
+        match self.state {
+            ClientConnectionState::WaitingSnapshot => self.read_snapshot(),
+            ClientConnectionState::Connected => self.read_command(),
+            ClientConnectionState::Disconnected => ReplicationEvent::Pending,
+            _ => self.read_handshake_reply()
         }
     }
 
-    pub fn register(&mut self, ds: DS, source_code: &str) {
-        self.source_ds = Some(ds);
-        self.source_code = Some(source_code.to_string());
-    }
-
-    pub fn interpret(&mut self) -> Option<String> {
-        match &self.source_ds {
-            Some(DS::String(_, _)) => {
-                if let Some(source_code) = &self.source_code {
-                    match self.source_ds
-                        .as_ref()
-                        .unwrap()
-                        .get_value(&source_code)
-                        .to_lowercase()
-                        .as_str() {
-                        "pong" => {
-                            self.state = ClientConnectionState::PingSentSuccessfully;
-                            Some(Helper::build_resp(&Reply::ReplyArray(
-                                vec!(
-                                    Reply::ReplyBulkString("REPLCONF".to_string()),
-                                    Reply::ReplyBulkString("listening-port".to_string()),
-                                    Reply::ReplyBulkString(format!("{}", self.port)),
-                                )
-                            )))
-                        },
-                        "ok" => {
-                            match self.state {
-                                ClientConnectionState::PingSentSuccessfully => {
-                                    self.state = ClientConnectionState::ReplConf1Sent;
-                                    Some(Helper::build_resp(&Reply::ReplyArray(
-                                        vec!(
-                                            Reply::ReplyBulkString("REPLCONF".to_string()),
-                                            Reply::ReplyBulkString("capa".to_string()),
-                                            Reply::ReplyBulkString("psync2".to_string()),
-                                        )
-                                    )))
-                                },
-                                ClientConnectionState::ReplConf1Sent => {
-                                    self.state = ClientConnectionState::ReplConf2Sent;
-                                    Some(Helper::build_resp(&Reply::ReplyArray(
-                                        vec!(
-                                            Reply::ReplyBulkString("PSYNC".to_string()),
-                                            Reply::ReplyBulkString("?".to_string()),
-                                            Reply::ReplyBulkString("-1".to_string()),
-                                        )
-                                    )))
-                                },
-                                _ => {
-                                    Some(Helper::build_resp(
-                                        &Reply::ReplyBulkString("-ERROR Invalid Command".to_string())
-                                    ))
-                                }
-                                
-                            }
-                        },
-                        "fullresync" => {
-                            Some(Helper::build_resp(
-                                &Reply::ReplyString("OK".to_string())
-                            ))
-                        },
-                        c => {
-                            Some(Helper::build_resp(
-                                &Reply::ReplyBulkString("-ERROR Invalid Command".to_string())
-                            ))
-                        }
+    fn read_line(&mut self) -> Option<String> {
+        let end = self.buffer.windows(2).position(|x| x == b"\r\n")?;
+        let line = String::from_utf8_lossy(&self.buffer[..end]).to_string();
+        self.buffer.drain(..end + 2);
+        Some(line)
+    }
+
+    fn read_handshake_reply(&mut self) -> ReplicationEvent {
+        let line = match self.read_line() {
+            Some(line) => line,
+            None => return ReplicationEvent::Pending
+        };
+        // A master requiring a password answers the `PING` with `-NOAUTH`, `AUTH` comes next anyway
+        let authenticating = matches!(self.state, ClientConnectionState::BeforePing) && self.auth.is_some();
+        if let Some(error) = line.strip_prefix('-').filter(|x| !(authenticating && x.starts_with("NOAUTH"))) {
+            return ReplicationEvent::Error(format!("Error reply from the master during the handshake: {}", error));
+        }
+        let words: Vec<&str> = line.trim_start_matches(['+', '-']).split_whitespace().collect();
+        match words.first().map(|x| x.to_lowercase()).as_deref() {
+            Some("pong") | Some("noauth") if authenticating => {
+                self.state = ClientConnectionState::AuthSent;
+                ReplicationEvent::Reply(Helper::build_resp(&Reply::ReplyArray(
+                    vec!(
+                        Reply::ReplyBulkString("AUTH".to_string()),
+                        Reply::ReplyBulkString(self.auth.clone().unwrap_or_default()),
+                    )
+                )))
+            },
+            Some("pong") => self.send_listening_port(),
+            Some("ok") => {
+                match self.state {
+                    ClientConnectionState::AuthSent => self.send_listening_port(),
+                    ClientConnectionState::PingSentSuccessfully if self.announce_ip.is_some() => {
+                        self.state = ClientConnectionState::ReplConfIpSent;
+                        ReplicationEvent::Reply(Helper::build_resp(&Reply::ReplyArray(
+                            vec!(
+                                Reply::ReplyBulkString("REPLCONF".to_string()),
+                                Reply::ReplyBulkString("ip-address".to_string()),
+                                Reply::ReplyBulkString(self.announce_ip.clone().unwrap_or_default()),
+                            )
+                        )))
+                    },
+                    ClientConnectionState::PingSentSuccessfully | ClientConnectionState::ReplConfIpSent => {
+                        self.state = ClientConnectionState::ReplConf1Sent;
+                        ReplicationEvent::Reply(Helper::build_resp(&Reply::ReplyArray(
+                            vec!(
+                                Reply::ReplyBulkString("REPLCONF".to_string()),
+                                Reply::ReplyBulkString("capa".to_string()),
+                                Reply::ReplyBulkString("psync2".to_string()),
+                            )
+                        )))
+                    },
+                    ClientConnectionState::ReplConf1Sent => {
+                        self.state = ClientConnectionState::ReplConf2Sent;
+                        // After a first synchronization, ask to continue where the stream stopped
+                        let (replid, psync_offset) = if self.master_replid.is_empty() {
+                            ("?".to_string(), "-1".to_string())
+                        } else {
+                            (self.master_replid.clone(), (self.offset + 1).to_string())
+                        };
+                        ReplicationEvent::Reply(Helper::build_resp(&Reply::ReplyArray(
+                            vec!(
+                                Reply::ReplyBulkString("PSYNC".to_string()),
+                                Reply::ReplyBulkString(replid),
+                                Reply::ReplyBulkString(psync_offset),
+                            )
+                        )))
+                    },
+                    _ => {
+                        ReplicationEvent::Error("Unexpected +OK from the master".to_string())
+                    }
+                }
+            },
+            Some("fullresync") => {
+                let offset = words.get(2).and_then(|x| x.parse::<u64>().ok());
+                match (words.get(1), offset) {
+                    (Some(replid), Some(offset)) => {
+                        println!("Full resync from master: {}:{}", replid, offset);
+                        self.master_replid = replid.to_string();
+                        self.offset = offset;
+                        self.state = ClientConnectionState::WaitingSnapshot;
+                        self.next_event()
+                    },
+                    _ => {
+                        ReplicationEvent::Error(format!("Bad FULLRESYNC reply from the master: {}", line))
                     }
-                } else {
-                    println!("No Source code");
-                    Some(Helper::build_resp(
-                        &Reply::ReplyBulkString("-ERROR Invalid Command".to_string())
-                    ))
                 }
             },
-            c => {
-                println!("DS IS NOT A STRING it is {:?}", c);
-                Some(Helper::build_resp(
-                    &Reply::ReplyBulkString("-ERROR Invalid Command".to_string())
-                ))
+            Some("continue") => {
+                // The master may have changed its replication id, after a failover
+                if let Some(replid) = words.get(1) {
+                    self.master_replid = replid.to_string();
+                }
+                println!("Successful partial resynchronization with master, continuing from offset {}", self.offset);
+                self.state = ClientConnectionState::Connected;
+                self.next_event()
+            },
+            _ => {
+                ReplicationEvent::Error(format!("Unexpected reply from the master during the handshake: {}", line))
             }
         }
     }
+
+    fn send_listening_port(&mut self) -> ReplicationEvent {
+        self.state = ClientConnectionState::PingSentSuccessfully;
+        ReplicationEvent::Reply(Helper::build_resp(&Reply::ReplyArray(
+            vec!(
+                Reply::ReplyBulkString("REPLCONF".to_string()),
+                Reply::ReplyBulkString("listening-port".to_string()),
+                Reply::ReplyBulkString(format!("{}", self.port)),
+            )
+        )))
+    }
+
+    /// The snapshot comes as `$<length>\r\n` followed by the RDB file, without a trailing CRLF.
+    fn read_snapshot(&mut self) -> ReplicationEvent {
+        // The master may send newlines to keep the link alive while it prepares the snapshot
+        while self.buffer.first() == Some(&b'\n') {
+            self.buffer.remove(0);
+        }
+        let header_end = match self.buffer.windows(2).position(|x| x == b"\r\n") {
+            Some(x) => x,
+            None => return ReplicationEvent::Pending
+        };
+        let length = match std::str::from_utf8(&self.buffer[..header_end]).ok()
+            .and_then(|x| x.strip_prefix('$'))
+            .and_then(|x| x.parse::<usize>().ok()) {
+            Some(x) => x,
+            None => return ReplicationEvent::Error("Bad protocol from the master, expected the RDB payload length".to_string())
+        };
+        let payload_start = header_end + 2;
+        if self.buffer.len() < payload_start + length {
+            return ReplicationEvent::Pending;
+        }
+        let payload: Vec<u8> = self.buffer.drain(..payload_start + length).skip(payload_start).collect();
+        match RDBFileHelper::decode(&payload) {
+            Ok(data) => {
+                self.state = ClientConnectionState::Connected;
+                ReplicationEvent::Snapshot(data)
+            },
+            Err(e) => {
+                ReplicationEvent::Error(format!("Can't load the RDB received from the master: {}", e))
+            }
+        }
+    }
+
+    fn read_command(&mut self) -> ReplicationEvent {
+        let source_code = match std::str::from_utf8(&self.buffer) {
+            Ok(x) => x.to_string(),
+            // A multi byte character split between two reads, the rest comes with the next one
+            Err(e) if e.error_len().is_none() => String::from_utf8_lossy(&self.buffer[..e.valid_up_to()]).to_string(),
+            Err(_) => String::from_utf8_lossy(&self.buffer).to_string()
+        };
+        let mut rp = RESPParser::new();
+        rp.register(&source_code);
+        match rp.try_parse() {
+            Ok(ds) => {
+                let consumed = rp.position().min(self.buffer.len());
+                self.stream.extend(self.buffer.drain(..consumed));
+                let is_getack = match &ds {
+                    DS::RedArray(a) => {
+                        let words: Vec<String> = a.value.iter().take(2).map(|x| x.get_value(&source_code).to_lowercase()).collect();
+                        words == ["replconf", "getack"]
+                    },
+                    _ => false
+                };
+                // Like redis, the acknowledged offset doesn't include the GETACK itself
+                let offset = self.offset;
+                self.offset += consumed as u64;
+                if is_getack {
+                    return ReplicationEvent::Ack(offset);
+                }
+                ReplicationEvent::Command(source_code[..rp.position()].to_string())
+            },
+            Err(ParseError::Incomplete) => ReplicationEvent::Pending,
+            Err(ParseError::Invalid(e)) => ReplicationEvent::Error(format!("Protocol error in the replication stream: {}", e))
+        }
+    }
 }
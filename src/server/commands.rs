@@ -0,0 +1,154 @@
+/// What the interpreter has to know about a command before running it.
+#[derive(Debug, Clone, Copy)]
+pub struct CommandSpec {
+    // Number of arguments including the command name, negative for a minimum
+    pub arity: i32,
+    // Modifies the dataset, refused by read only replicas
+    pub write: bool,
+    // Allowed on a replica whose data may be stale, while the link with its master is down
+    pub stale: bool,
+    // Can't be queued in a transaction
+    pub no_multi: bool,
+    // Can't be called by scripts
+    pub no_script: bool,
+}
+
+const fn read(arity: i32) -> CommandSpec {
+    CommandSpec { arity, write: false, stale: false, no_multi: false, no_script: false }
+}
+
+const fn write(arity: i32) -> CommandSpec {
+    CommandSpec { arity, write: true, stale: false, no_multi: false, no_script: false }
+}
+
+const fn stale(arity: i32) -> CommandSpec {
+    CommandSpec { arity, write: false, stale: true, no_multi: false, no_script: false }
+}
+
+const fn no_multi(spec: CommandSpec) -> CommandSpec {
+    CommandSpec { no_multi: true, ..spec }
+}
+
+const fn no_script(spec: CommandSpec) -> CommandSpec {
+    CommandSpec { no_script: true, ..spec }
+}
+
+/// Every command the interpreter knows.
+const COMMAND_TABLE: [(&str, CommandSpec); 51] = [
+    ("echo", read(2)),
+    ("set", write(-3)),
+    ("get", read(2)),
+    ("del", write(-2)),
+    ("config", no_script(stale(-2))),
+    ("keys", read(2)),
+    ("info", stale(-1)),
+    ("save", no_script(no_multi(read(1)))),
+    ("bgsave", no_script(read(-1))),
+    ("shutdown", no_script(no_multi(stale(-1)))),
+    ("bgrewriteaof", no_script(read(1))),
+    ("lastsave", stale(1)),
+    ("psync", no_script(no_multi(read(-3)))),
+    ("replconf", no_script(no_multi(stale(-1)))),
+    ("wait", no_script(no_multi(read(3)))),
+    ("waitaof", no_script(no_multi(read(4)))),
+    ("replicaof", no_script(no_multi(stale(3)))),
+    ("slaveof", no_script(no_multi(stale(3)))),
+    ("ping", stale(-1)),
+    ("multi", no_script(no_multi(stale(1)))),
+    ("exec", no_script(no_multi(stale(1)))),
+    ("discard", no_script(no_multi(stale(1)))),
+    ("watch", no_script(no_multi(stale(-2)))),
+    ("unwatch", no_script(stale(1))),
+    ("flushdb", write(-1)),
+    ("flushall", write(-1)),
+    ("subscribe", no_script(no_multi(stale(-2)))),
+    ("unsubscribe", no_script(no_multi(stale(-1)))),
+    ("psubscribe", no_script(no_multi(stale(-2)))),
+    ("punsubscribe", no_script(no_multi(stale(-1)))),
+    ("ssubscribe", no_script(no_multi(stale(-2)))),
+    ("sunsubscribe", no_script(no_multi(stale(-1)))),
+    ("publish", stale(3)),
+    ("spublish", stale(3)),
+    ("pubsub", stale(-2)),
+    ("hello", no_script(no_multi(stale(-1)))),
+    ("auth", no_script(no_multi(stale(-2)))),
+    ("acl", no_script(stale(-2))),
+    ("client", no_script(stale(-2))),
+    ("eval", no_script(stale(-3))),
+    ("evalsha", no_script(stale(-3))),
+    ("eval_ro", no_script(stale(-3))),
+    ("evalsha_ro", no_script(stale(-3))),
+    ("script", no_script(stale(-2))),
+    ("fcall", no_script(stale(-3))),
+    ("fcall_ro", no_script(stale(-3))),
+    ("function", no_script(stale(-2))),
+    // The subcommands of `FUNCTION` that change the libraries, for replicas to refuse them
+    ("function|load", no_script(write(-3))),
+    ("function|delete", no_script(write(3))),
+    ("function|flush", no_script(write(-2))),
+    ("function|restore", no_script(write(-3))),
+];
+
+/// Commands whose first argument names a subcommand, ACL rules can allow those one by one.
+const CONTAINER_COMMANDS: [&str; 6] = ["config", "client", "script", "function", "pubsub", "acl"];
+
+/// How a command uses the keys it is given, what ACL key patterns are checked against.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyAccess {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+pub fn command_spec(name: &str) -> Option<CommandSpec> {
+    COMMAND_TABLE.iter()
+        .find(|(command, _)| *command == name)
+        .map(|(_, spec)| *spec)
+}
+
+impl CommandSpec {
+    /// Whether `argc` arguments, the command name included, are acceptable.
+    pub fn check_arity(&self, argc: usize) -> bool {
+        if self.arity < 0 {
+            argc >= (-self.arity) as usize
+        } else {
+            argc == self.arity as usize
+        }
+    }
+}
+
+/// The name of every command, subcommands aside.
+pub fn command_names() -> impl Iterator<Item = &'static str> {
+    COMMAND_TABLE.iter().map(|(name, _)| *name).filter(|x| !x.contains('|'))
+}
+
+pub fn has_subcommands(name: &str) -> bool {
+    CONTAINER_COMMANDS.contains(&name)
+}
+
+/// The keys among the arguments of a command, the command name excluded.
+pub fn command_keys<'a>(name: &str, args: &'a [String]) -> Vec<(&'a str, KeyAccess)> {
+    match name {
+        "get" => args.iter().take(1).map(|x| (x.as_str(), KeyAccess::Read)).collect(),
+        "set" => args.iter().take(1).map(|x| (x.as_str(), KeyAccess::Write)).collect(),
+        "del" => args.iter().map(|x| (x.as_str(), KeyAccess::Write)).collect(),
+        "watch" => args.iter().map(|x| (x.as_str(), KeyAccess::Read)).collect(),
+        // `<script or function> numkeys key... arg...`, scripts may read and write their keys
+        "eval" | "evalsha" | "eval_ro" | "evalsha_ro" | "fcall" | "fcall_ro" => {
+            let access = if name.ends_with("_ro") { KeyAccess::Read } else { KeyAccess::ReadWrite };
+            let numkeys = args.get(1).and_then(|x| x.parse::<usize>().ok()).unwrap_or(0);
+            args.iter().skip(2).take(numkeys).map(|x| (x.as_str(), access)).collect()
+        },
+        _ => vec![]
+    }
+}
+
+/// The pub/sub channels among the arguments of a command, with whether they are patterns.
+pub fn command_channels<'a>(name: &str, args: &'a [String]) -> Vec<(&'a str, bool)> {
+    match name {
+        "publish" | "spublish" => args.iter().take(1).map(|x| (x.as_str(), false)).collect(),
+        "subscribe" | "ssubscribe" => args.iter().map(|x| (x.as_str(), false)).collect(),
+        "psubscribe" => args.iter().map(|x| (x.as_str(), true)).collect(),
+        _ => vec![]
+    }
+}
@@ -0,0 +1,315 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::aof::aof::AppendFsync;
+use crate::helpers::Helper;
+use crate::server::server::{SaveParam, ServerOptions};
+use crate::server::notifications::{notify_flags_to_string, parse_notify_flags};
+
+pub const DEFAULT_RDB_DIR_NAME: &str = ".";
+pub const DEFAULT_RDB_FILE_NAME: &str = "dump.rdb";
+pub const DEFAULT_AOF_FILE_NAME: &str = "appendonly.aof";
+pub const DEFAULT_AOF_DIR_NAME: &str = "appendonlydir";
+
+/// Every parameter that can be read with `CONFIG GET`, in the order they are reported.
+const CONFIG_PARAMETERS: [&str; 26] = [
+    "dir", "dbfilename", "port", "save",
+    "appendonly", "appendfilename", "appenddirname", "appendfsync", "aof-load-truncated",
+    "aof-use-rdb-preamble", "auto-aof-rewrite-percentage", "auto-aof-rewrite-min-size",
+    "repl-diskless-sync", "repl-backlog-size", "repl-timeout", "repl-ping-replica-period",
+    "replica-read-only", "replica-serve-stale-data", "replica-announce-ip", "replica-announce-port",
+    "notify-keyspace-events", "busy-reply-threshold", "requirepass", "masterauth", "aclfile", "acllog-max-len",
+];
+
+impl SaveParam {
+    /// The snapshotting rules redis ships with: `save 3600 1 300 100 60 10000`.
+    pub fn defaults() -> Vec<SaveParam> {
+        vec![
+            SaveParam { seconds: 3600, changes: 1 },
+            SaveParam { seconds: 300, changes: 100 },
+            SaveParam { seconds: 60, changes: 10000 },
+        ]
+    }
+
+    /// Parses a list of `<seconds> <changes>` pairs, an empty string disables snapshotting.
+    pub fn parse_list(value: &str) -> Result<Vec<SaveParam>, String> {
+        let parts: Vec<&str> = value.split_whitespace().collect();
+        if parts.len() % 2 == 1 {
+            return Err("Invalid save parameters".to_string());
+        }
+        parts.chunks(2)
+            .map(|pair| {
+                match (pair[0].parse::<u64>(), pair[1].parse::<u64>()) {
+                    (Ok(seconds), Ok(changes)) => Ok(SaveParam { seconds, changes }),
+                    _ => Err("Invalid save parameters".to_string())
+                }
+            })
+            .collect()
+    }
+}
+
+impl ServerOptions {
+    pub fn rdb_path(&self) -> PathBuf {
+        let dir_name = self.rdb_dir_name.clone().unwrap_or(PathBuf::from(DEFAULT_RDB_DIR_NAME));
+        let file_name = self.rdb_file_name.clone().unwrap_or(PathBuf::from(DEFAULT_RDB_FILE_NAME));
+        dir_name.join(file_name)
+    }
+
+    /// Where a single file append only log lived before the multi part layout.
+    pub fn aof_path(&self) -> PathBuf {
+        let dir_name = self.rdb_dir_name.clone().unwrap_or(PathBuf::from(DEFAULT_RDB_DIR_NAME));
+        dir_name.join(&self.appendfilename)
+    }
+
+    /// The directory holding the manifest, base and incremental files of the append only log.
+    pub fn aof_dir(&self) -> PathBuf {
+        let dir_name = self.rdb_dir_name.clone().unwrap_or(PathBuf::from(DEFAULT_RDB_DIR_NAME));
+        dir_name.join(&self.appenddirname)
+    }
+
+    /// Returns the `(name, value)` pairs of every parameter matching the glob pattern.
+    pub fn get_config(&self, pattern: &str) -> Vec<(String, String)> {
+        CONFIG_PARAMETERS.iter()
+            .filter(|name| Helper::glob_match(&pattern.to_lowercase(), name))
+            .map(|name| (name.to_string(), self.get_config_value(name).unwrap_or_default()))
+            .collect()
+    }
+
+    fn get_config_value(&self, name: &str) -> Option<String> {
+        match name {
+            "dir" => Some(self.rdb_dir_name.clone().unwrap_or(PathBuf::from(DEFAULT_RDB_DIR_NAME)).to_string_lossy().to_string()),
+            "dbfilename" => Some(self.rdb_file_name.clone().unwrap_or(PathBuf::from(DEFAULT_RDB_FILE_NAME)).to_string_lossy().to_string()),
+            "port" => Some(self.port.unwrap_or(6379).to_string()),
+            "save" => {
+                Some(self.save_params.iter()
+                    .map(|x| format!("{} {}", x.seconds, x.changes))
+                    .collect::<Vec<String>>()
+                    .join(" "))
+            },
+            "appendonly" => Some(yes_no(self.appendonly)),
+            "appendfilename" => Some(self.appendfilename.clone()),
+            "appenddirname" => Some(self.appenddirname.clone()),
+            "appendfsync" => Some(self.appendfsync.name().to_string()),
+            "aof-load-truncated" => Some(yes_no(self.aof_load_truncated)),
+            "aof-use-rdb-preamble" => Some(yes_no(self.aof_use_rdb_preamble)),
+            "auto-aof-rewrite-percentage" => Some(self.auto_aof_rewrite_percentage.to_string()),
+            "auto-aof-rewrite-min-size" => Some(self.auto_aof_rewrite_min_size.to_string()),
+            "repl-diskless-sync" => Some(yes_no(self.repl_diskless_sync)),
+            "repl-backlog-size" => Some(self.repl_backlog_size.to_string()),
+            "repl-timeout" => Some(self.repl_timeout.to_string()),
+            "repl-ping-replica-period" => Some(self.repl_ping_replica_period.to_string()),
+            "replica-read-only" => Some(yes_no(self.replica_read_only)),
+            "replica-serve-stale-data" => Some(yes_no(self.replica_serve_stale_data)),
+            "replica-announce-ip" => Some(self.replica_announce_ip.clone().unwrap_or_default()),
+            "replica-announce-port" => Some(self.replica_announce_port.to_string()),
+            "notify-keyspace-events" => Some(notify_flags_to_string(self.notify_keyspace_events)),
+            "busy-reply-threshold" => Some(self.busy_reply_threshold.to_string()),
+            "requirepass" => Some(self.requirepass.clone().unwrap_or_default()),
+            "masterauth" => Some(self.masterauth.clone().unwrap_or_default()),
+            "aclfile" => Some(self.aclfile.clone().unwrap_or_default()),
+            "acllog-max-len" => Some(self.acllog_max_len.to_string()),
+            _ => None
+        }
+    }
+
+    /// Sets a parameter the same way for the command line, the config file and `CONFIG SET`.
+    pub fn set_config(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name.to_lowercase().as_str() {
+            "dir" => {
+                self.rdb_dir_name = Some(PathBuf::from(value));
+            },
+            "dbfilename" => {
+                self.rdb_file_name = Some(PathBuf::from(value));
+            },
+            "port" => {
+                self.port = Some(value.parse().map_err(|_| "argument couldn't be parsed into an integer".to_string())?);
+            },
+            "save" => {
+                self.save_params = SaveParam::parse_list(value)?;
+            },
+            "appendonly" => {
+                self.appendonly = parse_yes_no(value)?;
+            },
+            "appendfilename" => {
+                if value.contains('/') {
+                    return Err("appendfilename can't be a path, just a filename".to_string());
+                }
+                self.appendfilename = value.to_string();
+            },
+            "appendfsync" => {
+                self.appendfsync = AppendFsync::parse(value)?;
+            },
+            "aof-load-truncated" => {
+                self.aof_load_truncated = parse_yes_no(value)?;
+            },
+            "appenddirname" => {
+                if value.contains('/') {
+                    return Err("appenddirname can't be a path, just a dirname".to_string());
+                }
+                self.appenddirname = value.to_string();
+            },
+            "aof-use-rdb-preamble" => {
+                self.aof_use_rdb_preamble = parse_yes_no(value)?;
+            },
+            "auto-aof-rewrite-percentage" => {
+                self.auto_aof_rewrite_percentage = value.parse().map_err(|_| "argument couldn't be parsed into an integer".to_string())?;
+            },
+            "auto-aof-rewrite-min-size" => {
+                self.auto_aof_rewrite_min_size = parse_memory(value)?;
+            },
+            "repl-diskless-sync" => {
+                self.repl_diskless_sync = parse_yes_no(value)?;
+            },
+            "repl-backlog-size" => {
+                self.repl_backlog_size = parse_memory(value)?;
+            },
+            "repl-timeout" => {
+                self.repl_timeout = parse_seconds(value)?;
+            },
+            "repl-ping-replica-period" | "repl-ping-slave-period" => {
+                self.repl_ping_replica_period = parse_seconds(value)?;
+            },
+            "replica-read-only" | "slave-read-only" => {
+                self.replica_read_only = parse_yes_no(value)?;
+            },
+            "replica-serve-stale-data" | "slave-serve-stale-data" => {
+                self.replica_serve_stale_data = parse_yes_no(value)?;
+            },
+            "replica-announce-ip" | "slave-announce-ip" => {
+                self.replica_announce_ip = if value.is_empty() { None } else { Some(value.to_string()) };
+            },
+            "notify-keyspace-events" => {
+                self.notify_keyspace_events = parse_notify_flags(value)?;
+            },
+            "busy-reply-threshold" | "lua-time-limit" => {
+                self.busy_reply_threshold = value.parse().map_err(|_| "argument couldn't be parsed into an integer".to_string())?;
+            },
+            "requirepass" => {
+                self.requirepass = if value.is_empty() { None } else { Some(value.to_string()) };
+                self.acl.set_default_password(self.requirepass.as_deref());
+            },
+            "masterauth" => {
+                self.masterauth = if value.is_empty() { None } else { Some(value.to_string()) };
+            },
+            "aclfile" => {
+                self.aclfile = if value.is_empty() { None } else { Some(value.to_string()) };
+            },
+            "acllog-max-len" => {
+                self.acllog_max_len = value.parse().map_err(|_| "argument couldn't be parsed into an integer".to_string())?;
+            },
+            "replica-announce-port" | "slave-announce-port" => {
+                self.replica_announce_port = match value.parse::<u32>() {
+                    Ok(x) if x <= 65535 => x,
+                    _ => return Err("argument must be a port number".to_string())
+                };
+            },
+            _ => {
+                return Err(format!("Unknown option or number of arguments for CONFIG SET - '{}'", name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads a redis style config file: one `directive arguments...` per line, `#` starts a comment.
+    /// Like redis, `save` lines accumulate and the first one replaces the built in rules.
+    pub fn load_config_file(&mut self, path: &Path) -> Result<(), String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("Can't open config file {:?}: {}", path, e))?;
+        let mut save_seen = false;
+        for (line_number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let words = split_config_line(line)
+                .ok_or(format!("Unbalanced quotes in config file at line {}", line_number + 1))?;
+            let directive = words[0].to_lowercase();
+            let value = words[1..].join(" ");
+            if directive == "save" {
+                if !save_seen {
+                    self.save_params.clear();
+                    save_seen = true;
+                }
+                self.save_params.extend(SaveParam::parse_list(&value)
+                    .map_err(|e| format!("{} at line {}", e, line_number + 1))?);
+                continue;
+            }
+            // `user <name> <rules...>` defines a user the way `ACL SETUSER` does
+            if directive == "user" && words.len() > 1 {
+                self.acl.set_user(&words[1], &words[2..])
+                    .map_err(|e| format!("{} at line {}", e, line_number + 1))?;
+                continue;
+            }
+            self.set_config(&directive, &value)
+                .map_err(|e| format!("{} at line {}", e, line_number + 1))?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits a config line into words, honouring double quoted arguments.
+fn split_config_line(line: &str) -> Option<Vec<String>> {
+    let mut words: Vec<String> = vec![];
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut has_word = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                has_word = true;
+            },
+            c if c.is_whitespace() && !in_quotes => {
+                if has_word {
+                    words.push(std::mem::take(&mut current));
+                    has_word = false;
+                }
+            },
+            c => {
+                current.push(c);
+                has_word = true;
+            }
+        }
+    }
+    if in_quotes {
+        return None;
+    }
+    if has_word {
+        words.push(current);
+    }
+    Some(words)
+}
+
+fn yes_no(value: bool) -> String {
+    if value { "yes".to_string() } else { "no".to_string() }
+}
+
+fn parse_yes_no(value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err("argument must be 'yes' or 'no'".to_string())
+    }
+}
+
+/// A strictly positive number of seconds.
+fn parse_seconds(value: &str) -> Result<u64, String> {
+    match value.parse::<u64>() {
+        Ok(x) if x > 0 => Ok(x),
+        _ => Err("argument must be a positive number of seconds".to_string())
+    }
+}
+
+/// Parses sizes the way redis does: a plain number of bytes or one suffixed with k, kb, m, mb, g or gb.
+pub fn parse_memory(value: &str) -> Result<u64, String> {
+    let value = value.to_lowercase();
+    let units: [(&str, u64); 6] = [
+        ("kb", 1024), ("k", 1000), ("mb", 1024 * 1024), ("m", 1000 * 1000), ("gb", 1024 * 1024 * 1024), ("g", 1000 * 1000 * 1000),
+    ];
+    let (number, multiplier) = units.iter()
+        .find(|(suffix, _)| value.ends_with(suffix) && value[..value.len() - suffix.len()].chars().all(|c| c.is_ascii_digit()))
+        .map(|(suffix, multiplier)| (&value[..value.len() - suffix.len()], *multiplier))
+        .unwrap_or((value.as_str(), 1));
+    number.parse::<u64>()
+        .map(|x| x * multiplier)
+        .map_err(|_| "argument must be a memory value".to_string())
+}
@@ -1,29 +1,116 @@
-use crate::server::parser::DS;
+use crate::server::parser::{RESPParser, DS};
 use crate::datastore::store::{DataItem, DataStore};
-use crate::server::server::{ServerOptions, ServerRole};
+use crate::server::server::{MasterServerOptions, ServerOptions, ServerRole, SlaveServerOptions};
+use crate::server::replication::{Replica, ReplicaState, ReplicationBacklog, WaitingClient, MASTER_CLIENT_ID};
+use crate::server::client_replication_interpreter::{ReplicationInterpreter, ReplicationEvent};
+use crate::server::commands::command_spec;
+use crate::server::acl::{self as acl, Denial};
+use crate::server::transaction::Transaction;
+use crate::server::pubsub::PubSub;
+use crate::server::tracking::{Tracking, TrackingOptions};
+use crate::server::script::RunningScript;
+use crate::scripting::engine::{Script, ScriptMessage, ScriptRun};
+use crate::scripting::functions::RestorePolicy;
+use crate::server::notifications::{NOTIFY_EXPIRED, NOTIFY_GENERIC, NOTIFY_KEYEVENT, NOTIFY_KEYSPACE, NOTIFY_KEY_MISS, NOTIFY_NEW, NOTIFY_STRING};
 use crate::helpers::Helper;
-use base64::prelude::*;
+use crate::rdb::rdb::{self as rdb, RDBContent, RDBFileHelper};
+use crate::rdb::saver::RDBSaver;
+use crate::aof::aof::{AppendFsync, AppendOnlyFile};
 
-use std::{
-    time::{Duration, SystemTime},
-    fs
-};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::fs;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How often `cron` does its housekeeping, the equivalent of redis' `hz 10`.
+const CRON_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Keys with an expiry looked at by each round of the active expiry cycle, another round follows
+/// while more than a quarter of them had expired.
+const ACTIVE_EXPIRE_LOOKUPS: usize = 20;
+const ACTIVE_EXPIRE_MAX_ROUNDS: usize = 16;
+
+/// Where RESP2 clients receive the invalidations of the clients redirecting them, with `CLIENT
+/// TRACKING on REDIRECT <id>`.
+const INVALIDATE_CHANNEL: &str = "__redis__:invalidate";
+
+/// Reported by `HELLO`, the redis version whose behaviour is implemented.
+pub const SERVER_VERSION: &str = "7.2.0";
+
+/// All a client subscribed to channels or patterns can do.
+const SUBSCRIBE_CONTEXT_COMMANDS: [&str; 9] = ["subscribe", "unsubscribe", "psubscribe", "punsubscribe", "ssubscribe", "sunsubscribe", "ping", "quit", "reset"];
 
 pub struct RESPInterpreter<'a> {
     source_code: String,
     data_store: &'a mut DataStore,
-    server_options: &'a mut ServerOptions
+    server_options: &'a mut ServerOptions,
+    rdb_saver: RDBSaver,
+    append_only_file: Option<AppendOnlyFile>,
+    last_cron: Instant,
+    last_replica_ping: Instant,
+    // The connection whose command is being interpreted
+    current_client: u64,
+    replicas: Vec<Replica>,
+    // Created when the first replica connects
+    backlog: Option<ReplicationBacklog>,
+    // Dump being encoded in memory for replicas when `repl-diskless-sync` is on
+    replication_snapshot: Option<JoinHandle<Vec<u8>>>,
+    // Whether the background save in progress was started for replicas
+    replication_bgsave: bool,
+    // Connections to drop once their pending output is written
+    clients_to_close: Vec<u64>,
+    // Clients blocked in `WAIT` or `WAITAOF`
+    waiting_clients: Vec<WaitingClient>,
+    // Replies for clients other than the one whose command is being interpreted
+    client_output: HashMap<u64, Vec<u8>>,
+    // Replication offset right after the last write of each client, what `WAIT` waits for
+    client_write_offsets: HashMap<u64, u64>,
+    // The state of the link with our master, when we are a replica
+    master_link: Option<ReplicationInterpreter>,
+    // Address of every connection, and the port replicas announced with `REPLCONF listening-port`
+    client_addresses: HashMap<u64, SocketAddr>,
+    listening_ports: HashMap<u64, u32>,
+    // Set with `REPLCONF ip-address` by replicas configured with `replica-announce-ip`
+    announced_ips: HashMap<u64, String>,
+    // Clients between `MULTI` and `EXEC`
+    transactions: HashMap<u64, Transaction>,
+    // Writes of the transaction being executed, propagated together once it is done
+    transaction_propagation: Option<Vec<Vec<String>>>,
+    // Replaying the append only file, whose writes are neither logged again nor replicated
+    loading: bool,
+    pubsub: PubSub,
+    tracking: Tracking,
+    // What `CLIENT CACHING` said about the command being interpreted
+    current_caching: Option<bool>,
+    // Version of the protocol each client switched to with `HELLO`, RESP2 otherwise
+    client_protocols: HashMap<u64, u8>,
+    // Scripts loaded with `SCRIPT LOAD` or run by `EVAL`, by the SHA1 of their source
+    scripts: HashMap<String, Arc<Script>>,
+    running_script: Option<RunningScript>,
+    // The user each client is authenticated as, the default one from the start unless it
+    // requires a password
+    client_users: HashMap<u64, String>,
+    // Set by `SHUTDOWN`, the event loop stops once the pending output is written
+    shutdown_requested: bool
 }
 
+#[allow(clippy::enum_variant_names)]
 pub enum Reply {
     ReplyArray(Vec<Reply>),
+    // Out of band data for RESP3 clients, like pub/sub messages and invalidations
+    ReplyPush(Vec<Reply>),
     ReplyBulkString(String),
     ReplyString(String),
+    ReplyInteger(i64),
+    ReplyNull,
 }
 
 pub enum InterpreterResponse {
     String(String),
-    Bytes(Vec<u8>),
 }
 
 pub struct SetOptions {
@@ -32,345 +119,2656 @@ pub struct SetOptions {
 
 impl<'a> RESPInterpreter<'a> {
     pub fn new(ds: &'a mut DataStore, server_options: &'a mut ServerOptions) -> Self {
+        let master_link = match &server_options.server_role {
+            Some(ServerRole::Slave(_)) => Some(ReplicationInterpreter::new(String::new(), 0)),
+            _ => None
+        };
         Self {
             source_code: String::from(""),
             data_store: ds,
-            server_options
+            server_options,
+            rdb_saver: RDBSaver::new(),
+            append_only_file: None,
+            last_cron: Instant::now(),
+            last_replica_ping: Instant::now(),
+            current_client: 0,
+            replicas: vec![],
+            backlog: None,
+            replication_snapshot: None,
+            replication_bgsave: false,
+            clients_to_close: vec![],
+            waiting_clients: vec![],
+            client_output: HashMap::new(),
+            client_write_offsets: HashMap::new(),
+            master_link,
+            client_addresses: HashMap::new(),
+            listening_ports: HashMap::new(),
+            announced_ips: HashMap::new(),
+            transactions: HashMap::new(),
+            transaction_propagation: None,
+            loading: false,
+            pubsub: PubSub::new(),
+            tracking: Tracking::new(),
+            current_caching: None,
+            client_protocols: HashMap::new(),
+            scripts: HashMap::new(),
+            running_script: None,
+            client_users: HashMap::new(),
+            shutdown_requested: false
         }
     }
 
-    pub fn register(&mut self, src_code: &str) {
-        self.source_code = src_code.to_string();
+    /// Starts logging writes to the append only file. Unless the existing log is known to match
+    /// the dataset (it was just replayed on startup) a rewrite creates a new base from the dataset.
+    pub fn start_append_only(&mut self, rewrite: bool) -> Result<(), String> {
+        let (dir, legacy_path) = (self.server_options.aof_dir(), self.server_options.aof_path());
+        let existed = AppendOnlyFile::exists(&dir, &legacy_path, &self.server_options.appendfilename);
+        let mut aof = AppendOnlyFile::open(&dir, &legacy_path, &self.server_options.appendfilename)?;
+        if rewrite || !existed {
+            aof.start_rewrite(&self.data_store.memory, &self.data_store.functions.codes(), self.server_options.aof_use_rdb_preamble)?;
+        }
+        self.append_only_file = Some(aof);
+        Ok(())
     }
 
-    fn build_command(&self, value: DS) -> Result<(String, Vec<DS>), ()> {
-        match value {
-            DS::RedArray(a) => {
-                if let Some(command) = a.value.first() {
-                    if let DS::BulkString(start, end) = command {
-                        Ok(
-                            (
-                                self.source_code.get(*start..*end).unwrap().to_lowercase().to_string(),
-                                a.value.into_iter().skip(1).collect()
-                            )
-                        )
-                    } else {
-                        Err(())
-                    }
-                } else {
-                    Err(())
+    /// Set while the append only file is replayed on startup.
+    pub fn set_loading(&mut self, loading: bool) {
+        self.loading = loading;
+    }
+
+    pub fn appendonly(&self) -> bool {
+        self.server_options.appendonly
+    }
+
+    fn stop_append_only(&mut self) {
+        if let Some(mut aof) = self.append_only_file.take() {
+            aof.flush(&AppendFsync::Always);
+        }
+    }
+
+    /// Brings the runtime state in line with the options after a `CONFIG SET`.
+    fn apply_config_changes(&mut self) -> Result<(), String> {
+        if self.server_options.appendonly && self.append_only_file.is_none() {
+            if let Err(e) = self.start_append_only(true) {
+                self.server_options.appendonly = false;
+                return Err(format!("Failed to start the append only file: {}", e));
+            }
+        } else if !self.server_options.appendonly && self.append_only_file.is_some() {
+            self.stop_append_only();
+        } else if self.append_only_file.as_ref().is_some_and(|x| x.dir() != self.server_options.aof_dir() || x.file_name() != self.server_options.appendfilename) {
+            self.stop_append_only();
+            self.start_append_only(true).map_err(|e| format!("Failed to start the append only file: {}", e))?;
+        }
+        if let Some(backlog) = &mut self.backlog {
+            backlog.resize(self.server_options.repl_backlog_size);
+        }
+        Ok(())
+    }
+
+    /// Hands a successfully executed write command over to everything that has to replay it.
+    fn propagate(&mut self, argv: Vec<String>) {
+        if self.loading {
+            return;
+        }
+        if let Some(queue) = &mut self.transaction_propagation {
+            queue.push(argv);
+            return;
+        }
+        // Messages only reach the subscribers of the replicas, they are not part of the dataset
+        let message = argv[0].eq_ignore_ascii_case("publish") || argv[0].eq_ignore_ascii_case("spublish");
+        if let (Some(aof), false) = (&mut self.append_only_file, message) {
+            aof.feed(&argv, &self.server_options.appendfsync);
+        }
+        // A replica passes on the stream of its master as is, never its own writes
+        if let Some(ServerRole::Slave(_)) = &self.server_options.server_role {
+            return;
+        }
+        self.propagate_to_replicas(argv);
+        let offset = self.master_repl_offset();
+        self.client_write_offsets.insert(self.current_client, offset);
+    }
+
+    /// Appends a command to the replication stream, without logging it to the append only file.
+    fn propagate_to_replicas(&mut self, argv: Vec<String>) {
+        let command = AppendOnlyFile::encode_command(&argv);
+        self.feed_replication_stream(command.as_bytes());
+        if let Some(ServerRole::Master(Some(master_options))) = &mut self.server_options.server_role {
+            master_options.master_repl_offset += command.len() as u64;
+        }
+    }
+
+    fn feed_replication_stream(&mut self, data: &[u8]) {
+        for replica in &mut self.replicas {
+            // Replicas still waiting for a snapshot to start will find this write in it
+            if !matches!(replica.state, ReplicaState::WaitBgsaveStart) {
+                replica.output.extend_from_slice(data);
+            }
+        }
+        if let Some(backlog) = &mut self.backlog {
+            backlog.feed(data);
+        }
+    }
+
+    /// Takes what was propagated to a replica since the last call, `None` if the client is not one.
+    /// Until its snapshot is ready the writes stay buffered.
+    pub fn take_replica_output(&mut self, client_id: u64) -> Option<Vec<u8>> {
+        let replica = self.replicas.iter_mut().find(|x| x.client_id == client_id)?;
+        match replica.state {
+            ReplicaState::Online => Some(std::mem::take(&mut replica.output)),
+            _ => Some(vec![])
+        }
+    }
+
+    /// Takes the replies produced for a client while serving other ones.
+    pub fn take_client_output(&mut self, client_id: u64) -> Option<Vec<u8>> {
+        self.client_output.remove(&client_id)
+    }
+
+    /// Whether the client waits for a reply, its next commands must not be served meanwhile.
+    pub fn is_blocked(&self, client_id: u64) -> bool {
+        self.waiting_clients.iter().any(|x| x.client_id == client_id)
+            || self.running_script.as_ref().is_some_and(|x| x.detached && x.client_id == client_id)
+    }
+
+    /// Whether a script runs in the background, nothing but its commands touch the dataset then.
+    pub fn script_running(&self) -> bool {
+        self.running_script.is_some()
+    }
+
+    /// Writes and fsyncs the append only file right away, returns whether it is enabled.
+    pub fn fsync_append_only(&mut self) -> bool {
+        match &mut self.append_only_file {
+            Some(aof) => {
+                aof.flush(&AppendFsync::Always);
+                true
+            },
+            None => false
+        }
+    }
+
+    fn acked_replicas(&self, offset: u64, fsynced: bool) -> u64 {
+        self.replicas.iter()
+            .filter(|x| matches!(x.state, ReplicaState::Online))
+            .filter(|x| if fsynced { x.aof_ack_offset >= offset } else { x.ack_offset >= offset })
+            .count() as u64
+    }
+
+    fn wait_reply(&self, waiting: &WaitingClient) -> String {
+        let acked = self.acked_replicas(waiting.offset, waiting.numlocal.is_some());
+        match waiting.numlocal {
+            Some(numlocal) => Helper::build_resp(&Reply::ReplyArray(vec![Reply::ReplyInteger(numlocal as i64), Reply::ReplyInteger(acked as i64)])),
+            None => Helper::build_resp(&Reply::ReplyInteger(acked as i64))
+        }
+    }
+
+    /// Unblocks the clients in `WAIT` whose writes were acknowledged by enough replicas, or whose
+    /// timeout expired. Called on every iteration of the event loop.
+    pub fn serve_waiting_clients(&mut self) {
+        self.serve_script();
+        let now = Instant::now();
+        let mut i = 0;
+        while i < self.waiting_clients.len() {
+            let waiting = &self.waiting_clients[i];
+            let acked = self.acked_replicas(waiting.offset, waiting.numlocal.is_some());
+            if acked >= waiting.numreplicas || waiting.deadline.is_some_and(|x| now >= x) {
+                let waiting = self.waiting_clients.remove(i);
+                let reply = self.wait_reply(&waiting);
+                self.client_output.entry(waiting.client_id).or_default().extend_from_slice(reply.as_bytes());
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Whether the connection has to be dropped, it is only reported once.
+    pub fn shutdown_requested(&self) -> bool {
+        self.shutdown_requested
+    }
+
+    /// Flushes the append only file to disk, the last step before the process exits.
+    pub fn prepare_shutdown(&mut self) {
+        self.stop_append_only();
+        println!("Redis is now ready to exit, bye bye...");
+    }
+
+    pub fn should_close(&mut self, client_id: u64) -> bool {
+        let count = self.clients_to_close.len();
+        self.clients_to_close.retain(|x| *x != client_id);
+        self.clients_to_close.len() != count
+    }
+
+    /// Our offset in the replication stream, the one of the master we follow on a replica.
+    fn master_repl_offset(&self) -> u64 {
+        match (&self.server_options.server_role, &self.master_link) {
+            (Some(ServerRole::Master(Some(master_options))), _) => master_options.master_repl_offset,
+            (Some(ServerRole::Slave(_)), Some(link)) => link.offset,
+            _ => 0
+        }
+    }
+
+    fn master_replid(&self) -> String {
+        match (&self.server_options.server_role, &self.master_link) {
+            (Some(ServerRole::Master(Some(master_options))), _) => master_options.master_replid.clone(),
+            (Some(ServerRole::Slave(_)), Some(link)) => link.master_replid.clone(),
+            _ => String::new()
+        }
+    }
+
+    /// The master to follow, as set by `--replicaof` or `REPLICAOF`.
+    pub fn master_address(&self) -> Option<(String, u32)> {
+        match &self.server_options.server_role {
+            Some(ServerRole::Slave(slave_options)) => Some((slave_options.master_host.clone(), slave_options.master_port)),
+            _ => None
+        }
+    }
+
+    /// A new connection with the master was opened, returns what starts the handshake.
+    pub fn master_link_connected(&mut self) -> Vec<u8> {
+        let listening_port = match self.server_options.replica_announce_port {
+            0 => self.server_options.port.unwrap_or(6379),
+            x => x
+        };
+        let announce_ip = self.server_options.replica_announce_ip.clone();
+        let auth = self.server_options.masterauth.clone();
+        match &mut self.master_link {
+            Some(link) => link.restart(listening_port, announce_ip, auth).into_bytes(),
+            None => vec![]
+        }
+    }
+
+    /// Processes what the master sent and returns what has to be written back to it. An error
+    /// means the link is unusable and has to be dropped.
+    pub fn process_master_link(&mut self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let mut link = match self.master_link.take() {
+            Some(link) => link,
+            None => return Ok(vec![])
+        };
+        link.feed(data);
+        let mut output: Vec<u8> = vec![];
+        let mut replid = link.master_replid.clone();
+        let result = loop {
+            match link.next_event() {
+                ReplicationEvent::Pending => break Ok(output),
+                ReplicationEvent::Reply(response) => {
+                    output.extend_from_slice(response.as_bytes());
+                },
+                ReplicationEvent::Ack(offset) => {
+                    let fsynced = self.fsync_append_only();
+                    output.extend_from_slice(link.ack(offset, fsynced).as_bytes());
+                },
+                ReplicationEvent::Snapshot(content) => {
+                    println!("MASTER <-> REPLICA sync: Loading {} keys received from the master", content.data.len());
+                    self.load_snapshot(content);
+                    // Our own replicas followed a history that was just replaced
+                    self.drop_replicas();
+                    self.backlog = Some(ReplicationBacklog::new(self.server_options.repl_backlog_size, link.offset));
+                },
+                ReplicationEvent::Command(source_code) => {
+                    self.apply_master_command(&source_code);
+                },
+                ReplicationEvent::Error(e) => break Err(e)
+            }
+            if link.master_replid != replid {
+                // Also when the master continued our history under a new id after a failover,
+                // our replicas resynchronize to learn it
+                replid = link.master_replid.clone();
+                self.drop_replicas();
+            }
+            let stream = link.take_stream();
+            if !stream.is_empty() {
+                self.feed_replication_stream(&stream);
+            }
+        };
+        self.master_link = Some(link);
+        result
+    }
+
+    /// The periodic `REPLCONF ACK` to send to the master, if it is due. An error means the
+    /// master stayed silent for longer than `repl-timeout`.
+    pub fn master_link_cron(&mut self) -> Result<Vec<u8>, String> {
+        let timeout = Duration::from_secs(self.server_options.repl_timeout);
+        if let Some(link) = &self.master_link {
+            if link.timed_out(timeout) {
+                if link.is_up() {
+                    return Err("MASTER timeout: no data nor PING received...".to_string());
                 }
+                return Err("Timeout connecting to the MASTER...".to_string());
+            }
+        }
+        if !self.master_link.as_ref().is_some_and(|x| x.should_ack()) {
+            return Ok(vec![]);
+        }
+        let fsynced = self.fsync_append_only();
+        match &mut self.master_link {
+            Some(link) => {
+                let offset = link.offset;
+                Ok(link.ack(offset, fsynced).into_bytes())
             },
-            _ => {
-                Err(())
+            None => Ok(vec![])
+        }
+    }
+
+    /// The connection with the master was closed or dropped.
+    pub fn master_link_lost(&mut self) {
+        if let Some(link) = &mut self.master_link {
+            link.disconnect();
+        }
+    }
+
+    /// Whether the link with the master completed its synchronization.
+    pub fn master_link_up(&self) -> bool {
+        self.master_link.as_ref().is_some_and(|x| x.is_up())
+    }
+
+    /// Pings the replicas through the stream so that they can tell a dead master from an idle
+    /// one, and drops the ones which stopped acknowledging.
+    fn replication_cron(&mut self) {
+        let timeout = Duration::from_secs(self.server_options.repl_timeout);
+        for replica in &self.replicas {
+            if matches!(replica.state, ReplicaState::Online) && replica.ack_time.elapsed() > timeout {
+                println!("Disconnecting timedout replica {}", replica.client_id);
+                self.clients_to_close.push(replica.client_id);
+            }
+        }
+        let clients_to_close = &self.clients_to_close;
+        self.replicas.retain(|x| !clients_to_close.contains(&x.client_id));
+        if self.last_replica_ping.elapsed() < Duration::from_secs(self.server_options.repl_ping_replica_period) {
+            return;
+        }
+        self.last_replica_ping = Instant::now();
+        if !self.replicas.is_empty() {
+            if let Some(ServerRole::Master(_)) = &self.server_options.server_role {
+                self.propagate_to_replicas(vec!["PING".to_string()]);
             }
         }
     }
 
-    pub fn build_response(&self, ds: &DS) -> String {
-        match ds {
-            DS::String(start, end) => {
-                let mut response = String::from("+");
-                response.push_str(&(self.source_code[*start..*end]));
-                response.push_str("\r\n");
-                return response;
+    /// Commands from the master are applied without replying.
+    fn apply_master_command(&mut self, source_code: &str) {
+        let mut rp = RESPParser::new();
+        rp.register(source_code);
+        self.register(source_code);
+        self.set_client(MASTER_CLIENT_ID);
+        match rp.try_parse() {
+            Ok(DS::RedArray(a)) if a.value.is_empty() => {},
+            Ok(ds) => {
+                let _ = self.interpret(ds);
             },
-            DS::RedArray(x) => {
-                let mut response = String::from("*");
-                response.push_str(&(x.value.len()).to_string());
-                response.push_str("\r\n");
-                for d in &x.value {
-                    let x = self.build_response(&d);
-                    response.push_str(&x);
+            Err(_) => {}
+        }
+    }
+
+    fn drop_replicas(&mut self) {
+        for replica in &self.replicas {
+            self.clients_to_close.push(replica.client_id);
+        }
+        self.replicas.clear();
+    }
+
+    /// `REPLICAOF NO ONE`: the replica becomes a master with a new history. The one of its old
+    /// master is kept as the secondary id so that the other replicas can partially resync.
+    fn promote_to_master(&mut self) {
+        let (replid2, offset) = match self.master_link.take() {
+            Some(link) if !link.master_replid.is_empty() => (link.master_replid, link.offset),
+            Some(link) => ("0".repeat(40), link.offset),
+            None => ("0".repeat(40), 0)
+        };
+        let master_options = MasterServerOptions {
+            master_replid: Helper::random_hex(40),
+            master_repl_offset: offset,
+            master_replid2: replid2,
+            second_repl_offset: offset as i64 + 1
+        };
+        println!("Setting secondary replication ID to {}, valid up to offset: {}. New replication ID is {}", master_options.master_replid2, master_options.second_repl_offset, master_options.master_replid);
+        if self.backlog.is_none() {
+            self.backlog = Some(ReplicationBacklog::new(self.server_options.repl_backlog_size, offset));
+        }
+        self.server_options.server_role = Some(ServerRole::Master(Some(master_options)));
+        // Our replicas reconnect and continue with a partial resynchronization, learning the new id
+        self.drop_replicas();
+        println!("MASTER MODE enabled");
+    }
+
+    /// `REPLICAOF host port`: follows a new master, trying to continue our current history.
+    fn follow_master(&mut self, master_host: String, master_port: u32) {
+        let (replid, offset) = match (&self.server_options.server_role, &self.master_link) {
+            (Some(ServerRole::Slave(_)), Some(link)) => (link.master_replid.clone(), link.offset),
+            _ => (self.master_replid(), self.master_repl_offset())
+        };
+        self.master_link = Some(ReplicationInterpreter::new(replid, offset));
+        // Clients blocked in WAIT can't be satisfied by a replica
+        for waiting in std::mem::take(&mut self.waiting_clients) {
+            self.client_output.entry(waiting.client_id).or_default().extend_from_slice(b"-UNBLOCKED force unblock from blocking operation, instance state changed (master -> replica?)\r\n");
+        }
+        println!("Connecting to MASTER {}:{}", master_host, master_port);
+        self.server_options.server_role = Some(ServerRole::Slave(SlaveServerOptions { master_host, master_port }));
+    }
+
+    /// Serves a `PSYNC <replid> <offset>` from the backlog when the replica was following this
+    /// master, or the one it replaced, and the bytes it misses are still there. Like redis the
+    /// offset asked for is the one of the first missing byte, counting from 1.
+    fn try_partial_resync(&mut self, replid: &str, psync_offset: &str) -> bool {
+        let (master_replid, master_replid2, second_repl_offset) = match &self.server_options.server_role {
+            Some(ServerRole::Master(Some(x))) => (x.master_replid.clone(), x.master_replid2.clone(), x.second_repl_offset),
+            // A replica serves the history of its master
+            Some(ServerRole::Slave(_)) => (self.master_replid(), "0".repeat(40), -1),
+            _ => return false
+        };
+        let psync_offset = match psync_offset.parse::<i64>() {
+            Ok(x) if x > 0 => x,
+            _ => return false
+        };
+        if replid != master_replid && (replid != master_replid2 || psync_offset > second_repl_offset) {
+            if replid != "?" {
+                println!("Partial resynchronization not accepted: replication id mismatch (asked for '{}', mine is '{}')", replid, master_replid);
+            }
+            return false;
+        }
+        let missing = match self.backlog.as_ref().and_then(|x| x.since(psync_offset as u64 - 1)) {
+            Some(x) => x,
+            None => {
+                println!("Unable to partial resync with replica {} for lack of backlog", self.current_client);
+                return false;
+            }
+        };
+        let mut replica = self.new_replica();
+        replica.state = ReplicaState::Online;
+        replica.output = Helper::build_resp(&Reply::ReplyString(format!("CONTINUE {}", master_replid))).into_bytes();
+        replica.output.extend_from_slice(&missing);
+        println!("Partial resynchronization request from replica {} accepted, sending {} bytes of backlog", self.current_client, missing.len());
+        self.replicas.retain(|x| x.client_id != self.current_client);
+        self.replicas.push(replica);
+        true
+    }
+
+    /// Starts producing a snapshot for the replicas waiting for a full resynchronization. With
+    /// `repl-diskless-sync` it is encoded in memory and sent straight to the sockets, otherwise a
+    /// background save writes the dump file which is then sent.
+    fn start_replication_snapshot(&mut self) {
+        if !self.replicas.iter().any(|x| matches!(x.state, ReplicaState::WaitBgsaveStart)) {
+            return;
+        }
+        if self.replication_snapshot.is_some() || self.rdb_saver.is_saving() {
+            return;
+        }
+        if self.server_options.repl_diskless_sync {
+            println!("Starting diskless snapshot for replication");
+            let snapshot = self.data_store.memory.clone();
+            let functions = self.data_store.functions.codes();
+            self.replication_snapshot = Some(std::thread::spawn(move || RDBFileHelper::encode(&snapshot, &functions)));
+        } else {
+            println!("Starting BGSAVE for replication");
+            if let Err(e) = self.rdb_saver.bgsave(&self.server_options.rdb_path(), &self.data_store.memory, &self.data_store.functions.codes()) {
+                println!("ERROR Can't start the snapshot for replication: {}", e);
+                self.drop_waiting_replicas();
+                return;
+            }
+            self.replication_bgsave = true;
+        }
+        let offset = self.master_repl_offset();
+        for replica in &mut self.replicas {
+            if matches!(replica.state, ReplicaState::WaitBgsaveStart) {
+                replica.state = ReplicaState::WaitBgsaveEnd;
+                replica.psync_offset = offset;
+            }
+        }
+    }
+
+    /// Hands the snapshot over to the replicas it was produced for, followed by the writes that
+    /// were buffered meanwhile. Without a snapshot they are disconnected.
+    fn finish_replication_snapshot(&mut self, payload: Option<Vec<u8>>) {
+        let payload = match payload {
+            Some(x) => x,
+            None => {
+                self.drop_waiting_replicas();
+                return;
+            }
+        };
+        let replid = self.master_replid();
+        for replica in &mut self.replicas {
+            if !matches!(replica.state, ReplicaState::WaitBgsaveEnd) {
+                continue;
+            }
+            let mut output = Helper::build_resp(&Reply::ReplyString(format!("FULLRESYNC {} {}", replid, replica.psync_offset))).into_bytes();
+            output.extend_from_slice(format!("${}\r\n", payload.len()).as_bytes());
+            output.extend_from_slice(&payload);
+            output.append(&mut replica.output);
+            replica.output = output;
+            replica.state = ReplicaState::Online;
+            // Loading the snapshot may take a while, the replica acknowledges once it is done
+            replica.ack_time = Instant::now();
+            println!("Synchronization with replica {} succeeded", replica.client_id);
+        }
+    }
+
+    fn drop_waiting_replicas(&mut self) {
+        for replica in &self.replicas {
+            if !matches!(replica.state, ReplicaState::Online) {
+                println!("ERROR Can't synchronize replica {}, closing the connection", replica.client_id);
+                self.clients_to_close.push(replica.client_id);
+            }
+        }
+        self.replicas.retain(|x| matches!(x.state, ReplicaState::Online));
+    }
+
+    /// Replaces the dataset with the one the master sent for a full resynchronization.
+    fn load_snapshot(&mut self, content: RDBContent) {
+        self.data_store.replace(content.data);
+        if let Err(e) = self.data_store.functions.restore(&content.functions, RestorePolicy::Flush) {
+            println!("ERROR Can't load the functions received from the master: {}", e);
+        }
+        self.invalidate_all();
+        // The log has to describe the new dataset, not the one that was thrown away
+        if let Some(aof) = &mut self.append_only_file {
+            if aof.rewrite_in_progress() {
+                aof.rewrite_scheduled = true;
+            } else if let Err(e) = aof.start_rewrite(&self.data_store.memory, &self.data_store.functions.codes(), self.server_options.aof_use_rdb_preamble) {
+                println!("ERROR Can't rewrite the append only file: {}", e);
+            }
+        }
+    }
+
+    /// Remembers the address of a new connection, authenticated as the default user unless it
+    /// requires a password.
+    pub fn client_connected(&mut self, client_id: u64, address: SocketAddr) {
+        self.client_addresses.insert(client_id, address);
+        if self.server_options.acl.user("default").is_some_and(|x| x.enabled() && x.nopass()) {
+            self.client_users.insert(client_id, "default".to_string());
+        }
+    }
+
+    /// Whether the client has to `AUTH` before anything else. The stream of the master never does.
+    fn auth_required(&self) -> bool {
+        self.current_client != MASTER_CLIENT_ID && !self.client_users.contains_key(&self.current_client)
+    }
+
+    /// Checks the credentials of `AUTH` or `HELLO AUTH`, failures are logged.
+    fn authenticate(&mut self, username: &str, password: &str) -> Result<(), String> {
+        if !self.server_options.acl.authenticate(username, password) {
+            let client_info = self.client_info(self.current_client);
+            self.server_options.acl.log_denial("auth", "toplevel", "AUTH", username, client_info, self.server_options.acllog_max_len);
+            return Err("-WRONGPASS invalid username-password pair or user is disabled.\r\n".to_string());
+        }
+        self.client_users.insert(self.current_client, username.to_string());
+        Ok(())
+    }
+
+    /// How `ACL LOG` describes a client.
+    fn client_info(&self, client_id: u64) -> String {
+        let address = self.client_addresses.get(&client_id).map(|x| x.to_string()).unwrap_or_default();
+        let user = self.client_users.get(&client_id).cloned().unwrap_or_default();
+        format!("id={} addr={} user={} resp={}", client_id, address, user, self.protocol(client_id))
+    }
+
+    /// Checks the command against the rules of the user of the client, denials are logged. The
+    /// stream of the master and the append only file are trusted.
+    fn check_permissions(&mut self, command: &str, args: &std::collections::VecDeque<DS>) -> Option<String> {
+        if self.current_client == MASTER_CLIENT_ID || ["auth", "hello"].contains(&command) || command_spec(command).is_none() {
+            return None;
+        }
+        let username = self.client_users.get(&self.current_client)?.clone();
+        let args: Vec<String> = args.iter().map(|x| x.get_value(&self.source_code)).collect();
+        let denial = match self.server_options.acl.user(&username) {
+            Some(user) => user.check(command, &args).err()?,
+            None => Denial::Command(command.to_string())
+        };
+        let context = match &self.running_script {
+            Some(script) if script.client_id == self.current_client => "lua",
+            _ if self.transaction_propagation.is_some() => "multi",
+            _ => "toplevel"
+        };
+        let client_info = self.client_info(self.current_client);
+        self.server_options.acl.log_denial(denial.reason(), context, denial.object(), &username, client_info, self.server_options.acllog_max_len);
+        Some(denial.error(&username))
+    }
+
+    /// Drops the connections authenticated as users that no longer exist.
+    fn drop_clients_of_deleted_users(&mut self) {
+        let acl = &self.server_options.acl;
+        let orphans: Vec<u64> = self.client_users.iter()
+            .filter(|(_, user)| acl.user(user).is_none())
+            .map(|(client_id, _)| *client_id)
+            .collect();
+        for client_id in orphans {
+            self.client_users.remove(&client_id);
+            self.clients_to_close.push(client_id);
+        }
+    }
+
+    /// The current client, which just sent `PSYNC`, as a replica.
+    fn new_replica(&self) -> Replica {
+        let ip = match self.announced_ips.get(&self.current_client) {
+            Some(ip) => ip.clone(),
+            None => self.client_addresses.get(&self.current_client).map(|x| x.ip().to_string()).unwrap_or_default()
+        };
+        let listening_port = self.listening_ports.get(&self.current_client).copied().unwrap_or(0);
+        Replica::new(self.current_client, ip, listening_port)
+    }
+
+    /// Forgets everything tied to a connection that went away.
+    pub fn client_closed(&mut self, client_id: u64) {
+        if self.replicas.iter().any(|x| x.client_id == client_id) {
+            println!("Connection with replica {} lost", client_id);
+        }
+        self.replicas.retain(|x| x.client_id != client_id);
+        self.waiting_clients.retain(|x| x.client_id != client_id);
+        self.client_output.remove(&client_id);
+        self.client_write_offsets.remove(&client_id);
+        self.client_addresses.remove(&client_id);
+        self.client_users.remove(&client_id);
+        self.listening_ports.remove(&client_id);
+        self.announced_ips.remove(&client_id);
+        self.transactions.remove(&client_id);
+        self.data_store.unwatch(client_id);
+        self.pubsub.remove_client(client_id);
+        self.tracking.disable(client_id);
+        self.client_protocols.remove(&client_id);
+    }
+
+    /// Periodic housekeeping called from the event loop: reaps background saves and triggers new
+    /// ones according to the configured `save` rules.
+    pub fn cron(&mut self) {
+        if self.last_cron.elapsed() < CRON_INTERVAL {
+            return;
+        }
+        self.last_cron = Instant::now();
+        let bgsave_result = self.rdb_saver.poll();
+        if let (Some(ok), true) = (bgsave_result, self.replication_bgsave) {
+            self.replication_bgsave = false;
+            let payload = if ok { fs::read(self.server_options.rdb_path()).ok() } else { None };
+            self.finish_replication_snapshot(payload);
+        }
+        if self.replication_snapshot.as_ref().is_some_and(|x| x.is_finished()) {
+            let payload = self.replication_snapshot.take().unwrap().join().ok();
+            self.finish_replication_snapshot(payload);
+        }
+        self.start_replication_snapshot();
+        self.replication_cron();
+        // Keys don't expire under the feet of a running script
+        if self.running_script.is_none() {
+            self.active_expire_cycle();
+        }
+        if let Some(aof) = &mut self.append_only_file {
+            aof.cron(&self.server_options.appendfsync);
+            let auto_rewrite = aof.should_auto_rewrite(self.server_options.auto_aof_rewrite_percentage, self.server_options.auto_aof_rewrite_min_size);
+            if (aof.rewrite_scheduled || auto_rewrite) && !aof.rewrite_in_progress() && !self.rdb_saver.is_saving() {
+                if auto_rewrite {
+                    println!("Starting automatic rewriting of AOF on growth");
                 }
-                return response;
+                if let Err(e) = aof.start_rewrite(&self.data_store.memory, &self.data_store.functions.codes(), self.server_options.aof_use_rdb_preamble) {
+                    println!("ERROR Can't rewrite the append only file: {}", e);
+                }
+            }
+        }
+        if self.rdb_saver.should_save(&self.server_options.save_params) {
+            println!("{} changes since last save, saving...", self.rdb_saver.dirty);
+            if let Err(e) = self.rdb_saver.bgsave(&self.server_options.rdb_path(), &self.data_store.memory, &self.data_store.functions.codes()) {
+                println!("ERROR Can't start background save: {}", e);
+            }
+        }
+    }
+
+    fn replication_info(&self) -> String {
+        let mut info = String::from("# Replication\r\n");
+        match (&self.server_options.server_role, &self.master_link) {
+            (Some(ServerRole::Slave(slave_options)), link) => {
+                let (link_up, last_io, sync_in_progress, offset) = match link {
+                    Some(link) => (link.is_up(), link.last_io_seconds_ago(), link.sync_in_progress(), link.offset),
+                    None => (false, None, false, 0)
+                };
+                info.push_str("role:slave\r\n");
+                info.push_str(&format!("master_host:{}\r\nmaster_port:{}\r\n", slave_options.master_host, slave_options.master_port));
+                info.push_str(&format!("master_link_status:{}\r\n", if link_up { "up" } else { "down" }));
+                info.push_str(&format!("master_last_io_seconds_ago:{}\r\n", last_io.map(|x| x as i64).unwrap_or(-1)));
+                info.push_str(&format!("master_sync_in_progress:{}\r\n", sync_in_progress as u8));
+                info.push_str(&format!("slave_repl_offset:{}\r\n", offset));
+                info.push_str(&format!("slave_read_only:{}\r\n", self.server_options.replica_read_only as u8));
             },
-            DS::BulkString(start, end) => {
-                let mut response = String::from("$");
-                response.push_str(&(end - start).to_string());
-                response.push_str("\r\n");
-                response.push_str(&(self.source_code[*start..*end]));
-                response.push_str("\r\n");
-                return response;
+            _ => {
+                info.push_str("role:master\r\n");
+            }
+        }
+        info.push_str(&format!("connected_slaves:{}\r\n", self.replicas.len()));
+        for (i, replica) in self.replicas.iter().enumerate() {
+            info.push_str(&format!(
+                "slave{}:ip={},port={},state={},offset={},lag={}\r\n",
+                i, replica.ip, replica.listening_port, replica.state.name(), replica.ack_offset, replica.ack_time.elapsed().as_secs()
+            ));
+        }
+        let (replid2, second_repl_offset) = match &self.server_options.server_role {
+            Some(ServerRole::Master(Some(master_options))) => (master_options.master_replid2.clone(), master_options.second_repl_offset),
+            _ => ("0".repeat(40), -1)
+        };
+        let replid = match self.master_replid() {
+            x if x.is_empty() => "0".repeat(40),
+            x => x
+        };
+        info.push_str(&format!("master_replid:{}\r\nmaster_replid2:{}\r\n", replid, replid2));
+        info.push_str(&format!("master_repl_offset:{}\r\nsecond_repl_offset:{}\r\n", self.master_repl_offset(), second_repl_offset));
+        match &self.backlog {
+            Some(backlog) => {
+                info.push_str(&format!(
+                    "repl_backlog_active:1\r\nrepl_backlog_size:{}\r\nrepl_backlog_first_byte_offset:{}\r\nrepl_backlog_histlen:{}\r\n",
+                    backlog.size(), backlog.first_byte_offset() + 1, backlog.histlen()
+                ));
+            },
+            None => {
+                info.push_str(&format!(
+                    "repl_backlog_active:0\r\nrepl_backlog_size:{}\r\nrepl_backlog_first_byte_offset:0\r\nrepl_backlog_histlen:0\r\n",
+                    self.server_options.repl_backlog_size
+                ));
             }
         }
+        info
     }
 
+    /// Queues a command of a transaction. One that would fail anyway, because it doesn't exist or
+    /// can't be queued, aborts the whole transaction. The number of arguments was checked already.
+    fn queue_command(&mut self, command: &str, args: &std::collections::VecDeque<DS>) -> Vec<InterpreterResponse> {
+        let error = match command_spec(command) {
+            None => Some(format!("-ERR unknown command '{}', with args beginning with: \r\n", command)),
+            Some(spec) if spec.no_multi => Some("-ERR Command not allowed inside a transaction\r\n".to_string()),
+            Some(_) => self.check_replica_access(command)
+        };
+        let mut argv = vec![command.to_string()];
+        argv.extend(args.iter().map(|x| x.get_value(&self.source_code)));
+        let transaction = self.transactions.entry(self.current_client).or_default();
+        match error {
+            Some(error) => {
+                transaction.dirty = true;
+                vec![
+                    InterpreterResponse::String(error)
+                ]
+            },
+            None => {
+                transaction.commands.push(argv);
+                vec![
+                    InterpreterResponse::String("+QUEUED\r\n".to_string())
+                ]
+            }
+        }
+    }
 
-    pub fn interpret(&mut self, ds: DS) -> Vec<InterpreterResponse> {
-        let cmd = self.build_command(ds);
-        if let Err(_) = cmd {
-            return vec![
-                InterpreterResponse::String("- Error while interpreting the message".to_string())
-            ];
-        } else {
-            let v = cmd.unwrap();
-            let leader_cmd = v.0;
-            let mut leader_args = std::collections::VecDeque::from(v.1);
-            match leader_cmd.as_str() {
-                "echo" => {
-                    vec![
-                        InterpreterResponse::String(self.build_response(leader_args.front().expect("Expected an argument")))
-                    ]
+    /// Runs the queued commands one after the other, nothing else runs in between. Their writes
+    /// reach the append only file and the replicas as a single `MULTI`/`EXEC` block.
+    fn exec_transaction(&mut self, commands: Vec<Vec<String>>) -> String {
+        let source_code = std::mem::take(&mut self.source_code);
+        self.transaction_propagation = Some(vec![]);
+        let mut replies = format!("*{}\r\n", commands.len());
+        for argv in commands {
+            let encoded = AppendOnlyFile::encode_command(&argv);
+            let mut rp = RESPParser::new();
+            rp.register(&encoded);
+            self.register(&encoded);
+            if let Ok(ds) = rp.try_parse() {
+                for response in self.interpret(ds) {
+                    match response {
+                        InterpreterResponse::String(x) => replies.push_str(&x)
+                    }
+                }
+            }
+        }
+        self.source_code = source_code;
+        let writes = self.transaction_propagation.take().unwrap_or_default();
+        if !writes.is_empty() {
+            self.propagate(vec!["MULTI".to_string()]);
+            for argv in writes {
+                self.propagate(argv);
+            }
+            self.propagate(vec!["EXEC".to_string()]);
+        }
+        replies
+    }
+
+    /// Compiles a script unless it is cached already, returns it with its SHA1.
+    fn load_script(&mut self, source: &str) -> Result<(String, Arc<Script>), String> {
+        let sha = Helper::sha1_hex(source.as_bytes());
+        if let Some(script) = self.scripts.get(&sha) {
+            return Ok((sha, script.clone()));
+        }
+        let script = Arc::new(Script::compile(source).map_err(|e| format!("-{}\r\n", e))?);
+        self.scripts.insert(sha.clone(), script.clone());
+        Ok((sha, script))
+    }
+
+    /// The keys and the arguments of `EVAL` or `FCALL`, which come after the script or the function
+    /// and the number of keys.
+    fn script_keys(command: &str, args: &[String]) -> Result<(Vec<String>, Vec<String>), String> {
+        if args.len() < 2 {
+            return Err(format!("-ERR wrong number of arguments for '{}' command\r\n", command));
+        }
+        let numkeys = args[1].parse::<i64>().map_err(|_| "-ERR value is not an integer or out of range\r\n".to_string())?;
+        if numkeys < 0 {
+            return Err("-ERR Number of keys can't be negative\r\n".to_string());
+        }
+        if numkeys as usize > args.len() - 2 {
+            return Err("-ERR Number of keys can't be greater than number of args\r\n".to_string());
+        }
+        let keys = args[2..2 + numkeys as usize].to_vec();
+        let arguments = args[2 + numkeys as usize..].to_vec();
+        Ok((keys, arguments))
+    }
+
+    /// Stops the running script for `SCRIPT KILL`, or the running function for `FUNCTION KILL`,
+    /// unless it already wrote.
+    fn kill_script(&self, function: bool) -> &'static str {
+        match &self.running_script {
+            Some(script) if script.function == function => {
+                if script.wrote {
+                    return "-UNKILLABLE Sorry the script already executed write commands against the dataset. You can either wait the script termination or kill the server in a hard way using the SHUTDOWN NOSAVE command.\r\n";
+                }
+                script.run.kill();
+                "+OK\r\n"
+            },
+            _ => "-NOTBUSY No scripts in execution right now.\r\n"
+        }
+    }
+
+    /// `FUNCTION LIST`: for every library its name, engine, functions, and with `WITHCODE` its code.
+    fn list_functions(&self, args: &[String]) -> String {
+        let mut with_code = false;
+        let mut pattern = None;
+        let mut i = 0;
+        while i < args.len() {
+            match args[i].to_lowercase().as_str() {
+                "withcode" => with_code = true,
+                "libraryname" if pattern.is_none() => {
+                    i += 1;
+                    match args.get(i) {
+                        Some(x) => pattern = Some(x.clone()),
+                        None => return "-ERR library name argument was not given\r\n".to_string()
+                    }
                 },
-                "set" => {
-                    let key = leader_args.pop_front().unwrap();
-                    let value = leader_args.pop_front().expect("Expectend another argument");
-                    
-                    let key_string;
-                    match key {
-                        DS::BulkString(start, end) => {
-                            key_string = self.source_code.get(start..end).unwrap();
+                "libraryname" => return "-ERR library name can be given only once\r\n".to_string(),
+                _ => return format!("-ERR Unknown argument {}\r\n", args[i])
+            }
+            i += 1;
+        }
+        let libraries = self.data_store.functions.libraries()
+            .filter(|x| pattern.as_ref().is_none_or(|pattern| Helper::glob_match(pattern, &x.name)))
+            .map(|library| {
+                let functions = library.functions.iter().map(|function| Reply::ReplyArray(vec![
+                    Reply::ReplyBulkString("name".to_string()),
+                    Reply::ReplyBulkString(function.name.clone()),
+                    Reply::ReplyBulkString("description".to_string()),
+                    match &function.description {
+                        Some(x) => Reply::ReplyBulkString(x.clone()),
+                        None => Reply::ReplyNull
+                    },
+                    Reply::ReplyBulkString("flags".to_string()),
+                    Reply::ReplyArray(function.flags.iter().map(|x| Reply::ReplyString(x.clone())).collect()),
+                ])).collect();
+                let mut fields = vec![
+                    Reply::ReplyBulkString("library_name".to_string()),
+                    Reply::ReplyBulkString(library.name.clone()),
+                    Reply::ReplyBulkString("engine".to_string()),
+                    Reply::ReplyBulkString("LUA".to_string()),
+                    Reply::ReplyBulkString("functions".to_string()),
+                    Reply::ReplyArray(functions),
+                ];
+                if with_code {
+                    fields.push(Reply::ReplyBulkString("library_code".to_string()));
+                    fields.push(Reply::ReplyBulkString(library.code.clone()));
+                }
+                Reply::ReplyArray(fields)
+            })
+            .collect();
+        Helper::build_resp(&Reply::ReplyArray(libraries))
+    }
+
+    /// Serves the commands of a script until it is done. One still running after
+    /// `busy-reply-threshold` goes on in the background: its client stays blocked and the other
+    /// ones are answered with `-BUSY`, until it ends or `SCRIPT KILL` stops it. Scripts run by
+    /// `EXEC` or replayed from the master always run to completion.
+    fn run_script(&mut self, run: ScriptRun, read_only: bool, function: bool) -> Vec<InterpreterResponse> {
+        let nested = self.transaction_propagation.is_some();
+        if !nested {
+            self.transaction_propagation = Some(vec![]);
+        }
+        self.running_script = Some(RunningScript { run, client_id: self.current_client, started: Instant::now(), wrote: false, read_only, nested, function, detached: false });
+        let can_detach = !nested && self.current_client != MASTER_CLIENT_ID;
+        loop {
+            let started = match &self.running_script {
+                Some(script) => script.started,
+                None => return vec![]
+            };
+            let threshold = Duration::from_millis(self.server_options.busy_reply_threshold);
+            let timeout = match threshold.checked_sub(started.elapsed()) {
+                Some(x) if can_detach => x,
+                None if can_detach => {
+                    println!("Slow script detected: still in execution after {} milliseconds.", started.elapsed().as_millis());
+                    if let Some(script) = &mut self.running_script {
+                        script.detached = true;
+                    }
+                    return vec![];
+                },
+                _ => CRON_INTERVAL
+            };
+            let message = match &self.running_script {
+                Some(script) => script.run.receive(timeout),
+                None => return vec![]
+            };
+            if let Some(reply) = message.and_then(|x| self.handle_script_message(x)) {
+                return vec![
+                    InterpreterResponse::String(reply)
+                ];
+            }
+        }
+    }
+
+    /// Answers a request of the running script. Returns its reply once it is done, after
+    /// propagating its writes: a single one alone, several wrapped in `MULTI`/`EXEC`.
+    fn handle_script_message(&mut self, message: ScriptMessage) -> Option<String> {
+        match message {
+            ScriptMessage::Call(argv) => {
+                let reply = self.script_call(argv);
+                if let Some(script) = &self.running_script {
+                    script.run.reply(reply);
+                }
+                None
+            },
+            ScriptMessage::Done(reply) => {
+                let script = self.running_script.take()?;
+                if !script.nested {
+                    let mut writes = self.transaction_propagation.take().unwrap_or_default();
+                    if writes.len() == 1 {
+                        self.propagate(writes.remove(0));
+                    } else if !writes.is_empty() {
+                        self.propagate(vec!["MULTI".to_string()]);
+                        for argv in writes {
+                            self.propagate(argv);
+                        }
+                        self.propagate(vec!["EXEC".to_string()]);
+                    }
+                }
+                Some(reply)
+            }
+        }
+    }
+
+    /// Serves the script running in the background, its reply goes to its client once it is done.
+    fn serve_script(&mut self) {
+        loop {
+            let (message, client_id) = match &self.running_script {
+                Some(script) if script.detached => (script.run.try_receive(), script.client_id),
+                _ => return
+            };
+            let message = match message {
+                Some(x) => x,
+                None => return
+            };
+            let previous_client = self.current_client;
+            self.current_client = client_id;
+            let reply = self.handle_script_message(message);
+            self.current_client = previous_client;
+            if let Some(reply) = reply {
+                if self.client_addresses.contains_key(&client_id) {
+                    self.client_output.entry(client_id).or_default().extend_from_slice(reply.as_bytes());
+                }
+                return;
+            }
+        }
+    }
+
+    /// Runs a command of `redis.call` for the running script, the way `EXEC` runs queued ones.
+    fn script_call(&mut self, argv: Vec<String>) -> String {
+        let command = argv[0].to_lowercase();
+        let spec = match command_spec(&command) {
+            Some(x) => x,
+            None => return "-ERR Unknown Redis command called from script\r\n".to_string()
+        };
+        if spec.no_script {
+            return "-ERR This Redis command is not allowed from script\r\n".to_string();
+        }
+        if !spec.check_arity(argv.len()) {
+            return "-ERR Wrong number of args calling Redis command from script\r\n".to_string();
+        }
+        if let (Some(script), true) = (&self.running_script, spec.write) {
+            if script.read_only {
+                return "-ERR Write commands are not allowed from read-only scripts.\r\n".to_string();
+            }
+        }
+        let source_code = std::mem::take(&mut self.source_code);
+        let encoded = AppendOnlyFile::encode_command(&argv);
+        let mut rp = RESPParser::new();
+        rp.register(&encoded);
+        self.register(&encoded);
+        let mut reply = String::new();
+        if let Ok(ds) = rp.try_parse() {
+            for response in self.interpret(ds) {
+                match response {
+                    InterpreterResponse::String(x) => reply.push_str(&x)
+                }
+            }
+        }
+        self.source_code = source_code;
+        // Once a write went through the script can't be killed anymore, a refused one changed nothing
+        if let (Some(script), true) = (&mut self.running_script, spec.write && !reply.starts_with('-')) {
+            script.wrote = true;
+        }
+        reply
+    }
+
+    /// `[kind, channel or pattern, number of subscriptions]`, the reply to every subscribe and
+    /// unsubscribe command.
+    fn subscription_reply(&self, kind: &str, name: Option<&str>, count: usize) -> String {
+        let resp3 = self.protocol(self.current_client) == 3;
+        let name = match name {
+            Some(name) => Helper::build_resp(&Reply::ReplyBulkString(name.to_string())),
+            None if resp3 => "_\r\n".to_string(),
+            None => "$-1\r\n".to_string()
+        };
+        format!("{}3\r\n{}{}:{}\r\n", if resp3 { '>' } else { '*' }, Helper::build_resp(&Reply::ReplyBulkString(kind.to_string())), name, count)
+    }
+
+    /// The reply of `ACL LOG [<count> | RESET]`, the most recent entries first.
+    fn acl_log(&mut self, argument: Option<&String>) -> Result<String, String> {
+        let count = match argument.map(|x| x.to_lowercase()) {
+            None => 10,
+            Some(x) if x == "reset" => {
+                self.server_options.acl.reset_log();
+                return Ok("+OK\r\n".to_string());
+            },
+            Some(x) => x.parse::<usize>().map_err(|_| "ERR value is not an integer or out of range".to_string())?
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let entries: Vec<String> = self.server_options.acl.log_entries().take(count).map(|entry| {
+            let fields = vec![
+                ("count", Reply::ReplyInteger(entry.count as i64)),
+                ("reason", Reply::ReplyBulkString(entry.reason.clone())),
+                ("context", Reply::ReplyBulkString(entry.context.clone())),
+                ("object", Reply::ReplyBulkString(entry.object.clone())),
+                ("username", Reply::ReplyBulkString(entry.username.clone())),
+                ("age-seconds", Reply::ReplyBulkString(format!("{:.3}", now.saturating_sub(entry.created) as f64 / 1000.0))),
+                ("client-info", Reply::ReplyBulkString(entry.client_info.clone())),
+                ("entry-id", Reply::ReplyInteger(entry.entry_id as i64)),
+                ("timestamp-created", Reply::ReplyInteger(entry.created as i64)),
+                ("timestamp-last-updated", Reply::ReplyInteger(entry.updated as i64)),
+            ];
+            self.map_reply(&fields)
+        }).collect();
+        Ok(format!("*{}\r\n{}", entries.len(), entries.concat()))
+    }
+
+    /// A map in RESP3, a flat array of names and values in RESP2.
+    fn map_reply(&self, fields: &[(&str, Reply)]) -> String {
+        let mut response = if self.protocol(self.current_client) == 3 {
+            format!("%{}\r\n", fields.len())
+        } else {
+            format!("*{}\r\n", fields.len() * 2)
+        };
+        for (name, value) in fields {
+            response.push_str(&Helper::build_resp(&Reply::ReplyBulkString(name.to_string())));
+            response.push_str(&Helper::build_resp(value));
+        }
+        response
+    }
+
+    /// Version of the protocol the client speaks.
+    fn protocol(&self, client_id: u64) -> u8 {
+        self.client_protocols.get(&client_id).copied().unwrap_or(2)
+    }
+
+    /// Messages sent to a client out of band are pushes in RESP3, plain arrays in RESP2.
+    fn push_message(&self, client_id: u64, items: Vec<Reply>) -> String {
+        if self.protocol(client_id) == 3 {
+            return Helper::build_resp(&Reply::ReplyPush(items));
+        }
+        Helper::build_resp(&Reply::ReplyArray(items))
+    }
+
+    /// Delivers a message to the subscribers of the channel, returns how many received it.
+    fn publish(&mut self, channel: &str, message: &str) -> usize {
+        let receivers = self.pubsub.receivers(channel);
+        for receiver in &receivers {
+            let mut framing = match &receiver.pattern {
+                Some(pattern) => vec![
+                    Reply::ReplyBulkString("pmessage".to_string()),
+                    Reply::ReplyBulkString(pattern.clone()),
+                ],
+                None => vec![
+                    Reply::ReplyBulkString("message".to_string()),
+                ]
+            };
+            framing.push(Reply::ReplyBulkString(channel.to_string()));
+            framing.push(Reply::ReplyBulkString(message.to_string()));
+            let framing = self.push_message(receiver.client_id, framing);
+            self.client_output.entry(receiver.client_id).or_default().extend_from_slice(framing.as_bytes());
+        }
+        receivers.len()
+    }
+
+    /// Publishes `__keyspace@0__:<key>` and `__keyevent@0__:<event>` when the class of the event
+    /// is enabled in `notify-keyspace-events`.
+    fn notify_keyspace_event(&mut self, class: u32, event: &str, key: &str) {
+        let flags = self.server_options.notify_keyspace_events;
+        if flags & class == 0 {
+            return;
+        }
+        if flags & NOTIFY_KEYSPACE != 0 {
+            self.publish(&format!("__keyspace@0__:{}", key), event);
+        }
+        if flags & NOTIFY_KEYEVENT != 0 {
+            self.publish(&format!("__keyevent@0__:{}", event), key);
+        }
+    }
+
+    /// A key removed because its expiry passed: the replicas and the log get a `DEL`, so that
+    /// they don't depend on their own clock.
+    fn key_expired(&mut self, key: &str) {
+        self.invalidate_key(key);
+        self.notify_keyspace_event(NOTIFY_EXPIRED, "expired", key);
+        self.rdb_saver.dirty += 1;
+        self.propagate(vec!["DEL".to_string(), key.to_string()]);
+    }
+
+    /// Lazy expiry, done before a command looks at a key.
+    fn expire_if_needed(&mut self, key: &str) {
+        if self.data_store.expire_if_needed(key) {
+            self.key_expired(key);
+        }
+    }
+
+    /// Active expiry, done by the master for keys nobody reads. Replicas wait for the `DEL` of
+    /// their master.
+    fn active_expire_cycle(&mut self) {
+        if !matches!(self.server_options.server_role, Some(ServerRole::Master(_)) | None) {
+            return;
+        }
+        for _ in 0..ACTIVE_EXPIRE_MAX_ROUNDS {
+            let expired = self.data_store.active_expire_cycle(ACTIVE_EXPIRE_LOOKUPS);
+            for key in &expired {
+                self.key_expired(key);
+            }
+            if expired.len() * 4 <= ACTIVE_EXPIRE_LOOKUPS {
+                break;
+            }
+        }
+    }
+
+    /// Delivers a message to the subscribers of the shard channel, returns how many received it.
+    fn spublish(&mut self, channel: &str, message: &str) -> usize {
+        let receivers = self.pubsub.shard_receivers(channel);
+        for client_id in &receivers {
+            let framing = self.push_message(*client_id, vec![
+                Reply::ReplyBulkString("smessage".to_string()),
+                Reply::ReplyBulkString(channel.to_string()),
+                Reply::ReplyBulkString(message.to_string()),
+            ]);
+            self.client_output.entry(*client_id).or_default().extend_from_slice(framing.as_bytes());
+        }
+        receivers.len()
+    }
+
+    /// A key was modified, the clients that may have cached it have to drop it.
+    fn invalidate_key(&mut self, key: &str) {
+        let targets = self.tracking.key_modified(key, self.current_client);
+        self.send_invalidations(targets, Some(key));
+    }
+
+    /// The whole dataset changed, every tracking client drops its cache.
+    fn invalidate_all(&mut self) {
+        let targets = self.tracking.flush();
+        self.send_invalidations(targets, None);
+    }
+
+    /// `invalidate` messages with the key, or null for all of them. RESP3 clients get them as
+    /// pushes, RESP2 ones only through a redirect client subscribed to `__redis__:invalidate`.
+    fn send_invalidations(&mut self, targets: Vec<(u64, TrackingOptions)>, key: Option<&str>) {
+        for (client_id, options) in targets {
+            let receiver = options.redirect.unwrap_or(client_id);
+            if !self.client_addresses.contains_key(&receiver) {
+                // The client the messages were redirected to went away
+                if self.protocol(client_id) == 3 {
+                    let message = self.push_message(client_id, vec![
+                        Reply::ReplyBulkString("tracking-redir-broken".to_string()),
+                        Reply::ReplyInteger(receiver as i64),
+                    ]);
+                    self.client_output.entry(client_id).or_default().extend_from_slice(message.as_bytes());
+                }
+                continue;
+            }
+            let resp3 = self.protocol(receiver) == 3;
+            let keys = match key {
+                Some(key) => Helper::build_resp(&Reply::ReplyArray(vec![Reply::ReplyBulkString(key.to_string())])),
+                None if resp3 => "_\r\n".to_string(),
+                None => "*-1\r\n".to_string()
+            };
+            let message = if resp3 {
+                format!(">2\r\n{}{}", Helper::build_resp(&Reply::ReplyBulkString("invalidate".to_string())), keys)
+            } else if options.redirect.is_some() && self.pubsub.is_subscribed(receiver) {
+                format!("*3\r\n{}{}{}", Helper::build_resp(&Reply::ReplyBulkString("message".to_string())), Helper::build_resp(&Reply::ReplyBulkString(INVALIDATE_CHANNEL.to_string())), keys)
+            } else {
+                continue;
+            };
+            self.client_output.entry(receiver).or_default().extend_from_slice(message.as_bytes());
+        }
+    }
+
+    /// `CLIENT TRACKING on` with its options, for the current client.
+    fn enable_tracking(&mut self, args: &[String]) -> Result<(), String> {
+        let mut options = TrackingOptions::default();
+        let mut args = args.iter();
+        while let Some(option) = args.next() {
+            match option.to_lowercase().as_str() {
+                "redirect" => {
+                    let id = match args.next().map(|x| x.parse::<u64>()) {
+                        Some(Ok(x)) => x,
+                        _ => return Err("-ERR value is not an integer or out of range\r\n".to_string())
+                    };
+                    if !self.client_addresses.contains_key(&id) {
+                        return Err("-ERR The client ID you want redirect to does not exist\r\n".to_string());
+                    }
+                    options.redirect = Some(id);
+                },
+                "prefix" => {
+                    match args.next() {
+                        Some(prefix) => options.prefixes.push(prefix.clone()),
+                        None => return Err("-ERR syntax error\r\n".to_string())
+                    }
+                },
+                "bcast" => options.bcast = true,
+                "optin" => options.optin = true,
+                "optout" => options.optout = true,
+                "noloop" => options.noloop = true,
+                _ => return Err("-ERR syntax error\r\n".to_string())
+            }
+        }
+        if options.optin && options.optout {
+            return Err("-ERR You can't use both OPTIN and OPTOUT\r\n".to_string());
+        }
+        if options.bcast && (options.optin || options.optout) {
+            return Err("-ERR OPTIN and OPTOUT are not compatible with BCAST\r\n".to_string());
+        }
+        // The mode stays the same until tracking is turned off
+        if let Some(current) = self.tracking.options(self.current_client) {
+            if current.bcast != options.bcast {
+                return Err("-ERR You can't switch BCAST mode on/off before disabling tracking for this client, and then re-enabling it with a different mode.\r\n".to_string());
+            }
+            if (current.optin && options.optout) || (current.optout && options.optin) {
+                return Err("-ERR You can't switch OPTIN/OPTOUT mode before disabling tracking for this client, and then re-enabling it with a different mode.\r\n".to_string());
+            }
+        }
+        if !options.bcast && !options.prefixes.is_empty() {
+            return Err("-ERR PREFIX option requires BCAST mode to be enabled\r\n".to_string());
+        }
+        self.tracking.enable(self.current_client, options);
+        Ok(())
+    }
+
+    /// On a replica, clients can't write and may not be allowed to read stale data. The stream
+    /// of the master is always applied.
+    fn check_replica_access(&self, command: &str) -> Option<String> {
+        if !matches!(self.server_options.server_role, Some(ServerRole::Slave(_))) || self.current_client == MASTER_CLIENT_ID {
+            return None;
+        }
+        let flags = command_spec(command)?;
+        if flags.write && self.server_options.replica_read_only {
+            return Some("-READONLY You can't write against a read only replica.\r\n".to_string());
+        }
+        if !flags.stale && !self.server_options.replica_serve_stale_data && !self.master_link_up() {
+            return Some("-MASTERDOWN Link with MASTER is down and replica-serve-stale-data is set to 'no'.\r\n".to_string());
+        }
+        None
+    }
+
+    pub fn register(&mut self, src_code: &str) {
+        self.source_code = src_code.to_string();
+    }
+
+    pub fn set_client(&mut self, client_id: u64) {
+        self.current_client = client_id;
+    }
+
+    fn build_command(&self, value: DS) -> Result<(String, Vec<DS>), ()> {
+        match value {
+            DS::RedArray(a) => {
+                if let Some(DS::BulkString(start, end)) = a.value.first() {
+                    Ok(
+                        (
+                            self.source_code.get(*start..*end).unwrap().to_lowercase().to_string(),
+                            a.value.into_iter().skip(1).collect()
+                        )
+                    )
+                } else {
+                    Err(())
+                }
+            },
+            _ => {
+                Err(())
+            }
+        }
+    }
+
+    pub fn build_response(&self, ds: &DS) -> String {
+        match ds {
+            DS::String(start, end) => {
+                let mut response = String::from("+");
+                response.push_str(&(self.source_code[*start..*end]));
+                response.push_str("\r\n");
+                response
+            },
+            DS::RedArray(x) => {
+                let mut response = String::from("*");
+                response.push_str(&(x.value.len()).to_string());
+                response.push_str("\r\n");
+                for d in &x.value {
+                    let x = self.build_response(d);
+                    response.push_str(&x);
+                }
+                response
+            },
+            DS::BulkString(start, end) => {
+                let mut response = String::from("$");
+                response.push_str(&(end - start).to_string());
+                response.push_str("\r\n");
+                response.push_str(&(self.source_code[*start..*end]));
+                response.push_str("\r\n");
+                response
+            },
+            DS::Error(start, end) => {
+                format!("-{}\r\n", &self.source_code[*start..*end])
+            },
+            DS::Integer(i) => {
+                Helper::build_resp(&Reply::ReplyInteger(*i))
+            },
+            DS::Null => {
+                "$-1\r\n".to_string()
+            }
+        }
+    }
+
+
+    pub fn interpret(&mut self, ds: DS) -> Vec<InterpreterResponse> {
+        let cmd = self.build_command(ds);
+        if let Ok(v) = cmd {
+            let leader_cmd = v.0;
+            let mut leader_args = std::collections::VecDeque::from(v.1);
+            // Checked once for every command, they can then index their arguments safely
+            if command_spec(&leader_cmd).is_some_and(|x| !x.check_arity(leader_args.len() + 1)) {
+                // Like any command refused while queueing, the transaction is aborted
+                if let Some(transaction) = self.transactions.get_mut(&self.current_client) {
+                    transaction.dirty = true;
+                }
+                return vec![
+                    InterpreterResponse::String(format!("-ERR wrong number of arguments for '{}' command\r\n", leader_cmd))
+                ];
+            }
+            // `HELLO` may authenticate too, and says why it can't be used otherwise
+            if self.auth_required() && !["auth", "hello"].contains(&leader_cmd.as_str()) {
+                return vec![
+                    InterpreterResponse::String("-NOAUTH Authentication required.\r\n".to_string())
+                ];
+            }
+            // Only the commands of a script in the background, and what can stop it, run meanwhile
+            if let Some(script) = &self.running_script {
+                let subcommand = leader_args.front().map(|x| x.get_value(&self.source_code).to_lowercase());
+                let allowed = leader_cmd == "replconf"
+                    || (["script", "function"].contains(&leader_cmd.as_str()) && subcommand.as_deref() == Some("kill"))
+                    || (leader_cmd == "shutdown" && subcommand.as_deref() == Some("nosave"));
+                if script.client_id != self.current_client && !allowed {
+                    let kill = if script.function { "FUNCTION KILL" } else { "SCRIPT KILL" };
+                    return vec![
+                        InterpreterResponse::String(format!("-BUSY Redis is busy running a script. You can only call {} or SHUTDOWN NOSAVE.\r\n", kill))
+                    ];
+                }
+            }
+            // `CLIENT CACHING` applies to the command that follows it only
+            self.current_caching = self.tracking.take_caching(self.current_client);
+            // RESP3 tells replies and messages apart, subscribers can keep sending any command
+            if self.protocol(self.current_client) == 2 && self.pubsub.is_subscribed(self.current_client) && !SUBSCRIBE_CONTEXT_COMMANDS.contains(&leader_cmd.as_str()) {
+                return vec![
+                    InterpreterResponse::String(format!("-ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context\r\n", leader_cmd))
+                ];
+            }
+            if let Some(error) = self.check_permissions(&leader_cmd, &leader_args) {
+                // Like any command refused while queueing, the transaction is aborted
+                if let Some(transaction) = self.transactions.get_mut(&self.current_client) {
+                    transaction.dirty = true;
+                }
+                return vec![
+                    InterpreterResponse::String(error)
+                ];
+            }
+            if self.transactions.contains_key(&self.current_client) && !["multi", "exec", "discard", "watch"].contains(&leader_cmd.as_str()) {
+                return self.queue_command(&leader_cmd, &leader_args);
+            }
+            if let Some(error) = self.check_replica_access(&leader_cmd) {
+                return vec![
+                    InterpreterResponse::String(error)
+                ];
+            }
+            match leader_cmd.as_str() {
+                "echo" => {
+                    vec![
+                        InterpreterResponse::String(self.build_response(leader_args.front().expect("Expected an argument")))
+                    ]
+                },
+                "set" => {
+                    let key = leader_args.pop_front().unwrap();
+                    let value = leader_args.pop_front().expect("Expectend another argument");
+                    
+                    let key_string;
+                    match key {
+                        DS::BulkString(start, end) => {
+                            key_string = self.source_code.get(start..end).unwrap();
+                        },
+                        _ => {
+                            return vec![
+                                InterpreterResponse::String("-ERROR Expected the key to be a string".to_owned())
+                            ]
+                        }
+                    }
+
+                    let value_string;
+                    match value {
+                        DS::BulkString(start, end) => {
+                            value_string = self.source_code.get(start..end).unwrap();
+                        },
+                        _ => {
+                            return vec![
+                                InterpreterResponse::String("-ERROR Expected the value to be a string".to_owned())
+                            ]
+                        }
+                    }
+                    
+                    let mut args = SetOptions {
+                        expiry: None
+                    };
+                    
+                    if !leader_args.is_empty() {
+                        while let Some(current_option) = leader_args.pop_front() {
+                            match current_option {
+                                DS::BulkString(start, end) => {
+                                    if let unit @ ("ex" | "px" | "exat" | "pxat") = self.source_code.get(start..end).unwrap().to_lowercase().as_str() {
+                                        let d = leader_args.pop_front();
+                                        let x = match d.map(|x| x.get_value(&self.source_code).parse::<u64>()) {
+                                            Some(Ok(x)) => x,
+                                            _ => {
+                                                return vec![
+                                                    InterpreterResponse::String("-ERR value is not an integer or out of range\r\n".to_owned())
+                                                ]
+                                            }
+                                        };
+                                        args.expiry = Some(match unit {
+                                            "ex" => SystemTime::now() + Duration::from_secs(x),
+                                            "px" => SystemTime::now() + Duration::from_millis(x),
+                                            "exat" => UNIX_EPOCH + Duration::from_secs(x),
+                                            _ => UNIX_EPOCH + Duration::from_millis(x),
+                                        });
+                                    }
+                                },
+                                _ => {
+                                    return vec![
+                                        InterpreterResponse::String("-ERROR Invalid argument type".to_owned())
+                                    ]
+                                }
+                            }
+                        }
+                    }
+                    
+                    let key_string = key_string.to_owned();
+                    let value_string = value_string.to_owned();
+                    let is_new = self.data_store.set(key_string.clone(), DataItem {
+                        data: value_string.clone(),
+                        expiry: args.expiry
+                    });
+                    self.rdb_saver.dirty += 1;
+                    self.invalidate_key(&key_string);
+                    if is_new {
+                        self.notify_keyspace_event(NOTIFY_NEW, "new", &key_string);
+                    }
+                    self.notify_keyspace_event(NOTIFY_STRING, "set", &key_string);
+                    if args.expiry.is_some() {
+                        self.notify_keyspace_event(NOTIFY_GENERIC, "expire", &key_string);
+                    }
+
+                    // Relative expiries are propagated as absolute deadlines, replaying the command
+                    // later must not extend the lifetime of the key.
+                    let mut argv = vec!["SET".to_string(), key_string, value_string];
+                    if let Some(expiry) = args.expiry {
+                        argv.push("PXAT".to_string());
+                        argv.push(expiry.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis().to_string());
+                    }
+                    self.propagate(argv);
+                    vec![
+                        InterpreterResponse::String("+OK\r\n".to_string())
+                    ]
+                },
+                "get" => {
+                    let key = leader_args.front().unwrap();
+                    let key_string = match key {
+                        DS::BulkString(start, end) => {
+                            self.source_code.get(*start..*end).unwrap()
+                        },
+                        _ => {
+                            return vec![
+                                InterpreterResponse::String("-ERROR Expected the key to be a string".to_owned())
+                            ];
+                        }
+                    };
+                    let key_string = key_string.to_string();
+                    self.expire_if_needed(&key_string);
+                    self.tracking.key_read(self.current_client, &key_string, self.current_caching);
+                    let mut response = String::from("$");
+                    match self.data_store.get(key_string.clone()) {
+                        Some(v) => {
+                            response.push_str(&format!("{}", v.data.len()));
+                            response.push_str("\r\n");
+                            response.push_str(&v.data);
+                            response.push_str("\r\n");
+                        },
+                        None => {
+                            response.push_str("-1");
+                            response.push_str("\r\n");
+                            self.notify_keyspace_event(NOTIFY_KEY_MISS, "keymiss", &key_string);
+                        }
+                    }
+                    vec![
+                        InterpreterResponse::String(response)
+                    ]
+                },
+                "config" => {
+                    let config_action = leader_args.pop_front();
+                    if let Some(ca) = config_action {
+                        if let DS::BulkString(_, _) = ca {
+                            let action = ca.get_value(&self.source_code);
+                            match action.to_lowercase().as_str() {
+                                "get" => {
+                                    let mut pairs: Vec<Reply> = vec![];
+                                    for key_ds in leader_args {
+                                        let key = key_ds.get_value(&self.source_code);
+                                        for (name, value) in self.server_options.get_config(&key) {
+                                            pairs.push(Reply::ReplyBulkString(name));
+                                            pairs.push(Reply::ReplyBulkString(value));
+                                        }
+                                    }
+                                    vec![
+                                        InterpreterResponse::String(Helper::build_resp(&Reply::ReplyArray(pairs)))
+                                    ]
+                                },
+                                "set" => {
+                                    if leader_args.is_empty() || leader_args.len() % 2 == 1 {
+                                        return vec![
+                                            InterpreterResponse::String("-ERR wrong number of arguments for 'config|set' command\r\n".to_owned())
+                                        ];
+                                    }
+                                    while let (Some(key_ds), Some(value_ds)) = (leader_args.pop_front(), leader_args.pop_front()) {
+                                        let key = key_ds.get_value(&self.source_code);
+                                        let value = value_ds.get_value(&self.source_code);
+                                        if let Err(e) = self.server_options.set_config(&key, &value) {
+                                            return vec![
+                                                InterpreterResponse::String(format!("-ERR {}\r\n", e))
+                                            ];
+                                        }
+                                    }
+                                    if let Err(e) = self.apply_config_changes() {
+                                        return vec![
+                                            InterpreterResponse::String(format!("-ERR {}\r\n", e))
+                                        ];
+                                    }
+                                    vec![
+                                        InterpreterResponse::String("+OK\r\n".to_owned())
+                                    ]
+                                },
+                                c => {
+                                    vec![
+                                        InterpreterResponse::String(format!("-ERR unknown subcommand '{}'\r\n", c))
+                                    ]
+                                },
+                            }
+                        } else {
+                            vec![
+                                InterpreterResponse::String("-ERROR config action invalid".to_owned())
+                            ]
+                        }
+                    } else {
+                        vec![
+                            InterpreterResponse::String("-ERROR config action invalid".to_owned())
+                        ]
+                    }
+                },
+                "keys" => {
+                    let keys = self.data_store.memory.keys().map(|x| Reply::ReplyBulkString(x.to_string())).collect::<Vec<Reply>>();
+                    vec![
+                        InterpreterResponse::String(Helper::build_resp(&Reply::ReplyArray(keys)))
+                    ]
+                },
+                "info" => {
+                    let section = match leader_args.pop_front() {
+                        Some(DS::BulkString(start, end)) => {
+                            self.source_code.get(start..end).expect("Expected a value for the section").to_lowercase()
+                        },
+                        _ => "default".to_string()
+                    };
+                    let mut info = String::new();
+                    if ["replication", "default", "all", "everything"].contains(&section.as_str()) {
+                        info.push_str(&self.replication_info());
+                    }
+                    if ["persistence", "default", "all", "everything"].contains(&section.as_str()) {
+                        if !info.is_empty() {
+                            info.push_str("\r\n");
+                        }
+                        info.push_str("# Persistence\r\n");
+                        info.push_str(&self.rdb_saver.info());
+                        info.push_str(&format!("aof_enabled:{}\r\n", self.append_only_file.is_some() as u8));
+                        if let Some(aof) = &self.append_only_file {
+                            info.push_str(&aof.info());
+                        }
+                    }
+                    vec![
+                        InterpreterResponse::String(Helper::build_resp(&Reply::ReplyBulkString(info)))
+                    ]
+                },
+                "save" => {
+                    if self.rdb_saver.is_saving() {
+                        return vec![
+                            InterpreterResponse::String("-ERR Background save already in progress\r\n".to_owned())
+                        ];
+                    }
+                    match self.rdb_saver.save(&self.server_options.rdb_path(), &self.data_store.memory, &self.data_store.functions.codes()) {
+                        Ok(()) => {
+                            vec![
+                                InterpreterResponse::String("+OK\r\n".to_owned())
+                            ]
+                        },
+                        Err(e) => {
+                            vec![
+                                InterpreterResponse::String(format!("-ERR {}\r\n", e))
+                            ]
+                        }
+                    }
+                },
+                "shutdown" => {
+                    // Saves a snapshot first when snapshotting is configured, unless told otherwise
+                    let mut save = !self.server_options.save_params.is_empty();
+                    for option in leader_args.iter().map(|x| x.get_value(&self.source_code).to_lowercase()) {
+                        match option.as_str() {
+                            "nosave" => save = false,
+                            "save" => save = true,
+                            "now" | "force" => {},
+                            _ => {
+                                return vec![
+                                    InterpreterResponse::String("-ERR syntax error\r\n".to_owned())
+                                ];
+                            }
+                        }
+                    }
+                    if save {
+                        if let Err(e) = self.rdb_saver.save(&self.server_options.rdb_path(), &self.data_store.memory, &self.data_store.functions.codes()) {
+                            println!("ERROR Can't save the snapshot before shutting down: {}", e);
+                            return vec![
+                                InterpreterResponse::String("-ERR Errors trying to SHUTDOWN. Check logs.\r\n".to_owned())
+                            ];
+                        }
+                    }
+                    // Like redis, the client gets no reply, its connection is closed
+                    self.shutdown_requested = true;
+                    vec![]
+                },
+                "bgsave" => {
+                    match self.rdb_saver.bgsave(&self.server_options.rdb_path(), &self.data_store.memory, &self.data_store.functions.codes()) {
+                        Ok(()) => {
+                            vec![
+                                InterpreterResponse::String(Helper::build_resp(&Reply::ReplyString("Background saving started".to_string())))
+                            ]
+                        },
+                        Err(e) => {
+                            vec![
+                                InterpreterResponse::String(format!("-ERR {}\r\n", e))
+                            ]
+                        }
+                    }
+                },
+                "bgrewriteaof" => {
+                    let aof = match &mut self.append_only_file {
+                        Some(aof) => aof,
+                        None => {
+                            return vec![
+                                InterpreterResponse::String("-ERR Background append only file rewriting can only run when appendonly is enabled\r\n".to_owned())
+                            ];
+                        }
+                    };
+                    if aof.rewrite_in_progress() {
+                        return vec![
+                            InterpreterResponse::String("-ERR Background append only file rewriting already in progress\r\n".to_owned())
+                        ];
+                    }
+                    if self.rdb_saver.is_saving() {
+                        aof.rewrite_scheduled = true;
+                        return vec![
+                            InterpreterResponse::String(Helper::build_resp(&Reply::ReplyString("Background append only file rewriting scheduled".to_string())))
+                        ];
+                    }
+                    match aof.start_rewrite(&self.data_store.memory, &self.data_store.functions.codes(), self.server_options.aof_use_rdb_preamble) {
+                        Ok(()) => {
+                            vec![
+                                InterpreterResponse::String(Helper::build_resp(&Reply::ReplyString("Background append only file rewriting started".to_string())))
+                            ]
+                        },
+                        Err(e) => {
+                            vec![
+                                InterpreterResponse::String(format!("-ERR {}\r\n", e))
+                            ]
+                        }
+                    }
+                },
+                "lastsave" => {
+                    let last_save = self.rdb_saver.last_save.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+                    vec![
+                        InterpreterResponse::String(Helper::build_resp(&Reply::ReplyInteger(last_save as i64)))
+                    ]
+                },
+                "psync" => {
+                    let can_serve = match &self.server_options.server_role {
+                        Some(ServerRole::Master(Some(_))) => Ok(()),
+                        // A replica proxies the stream of its master, which it needs to be following
+                        Some(ServerRole::Slave(_)) if self.master_link_up() => Ok(()),
+                        Some(ServerRole::Slave(_)) => Err("-NOMASTERLINK Can't SYNC while not connected with my master\r\n"),
+                        _ => Err("-ERR can only ask for psync from master\r\n")
+                    };
+                    match can_serve {
+                        Ok(()) => {
+                            if self.backlog.is_none() {
+                                self.backlog = Some(ReplicationBacklog::new(self.server_options.repl_backlog_size, self.master_repl_offset()));
+                            }
+                            let replid = leader_args.pop_front().map(|x| x.get_value(&self.source_code)).unwrap_or_default();
+                            let psync_offset = leader_args.pop_front().map(|x| x.get_value(&self.source_code)).unwrap_or_default();
+                            if self.try_partial_resync(&replid, &psync_offset) {
+                                return vec![];
+                            }
+                            // The FULLRESYNC reply goes out together with the snapshot, once it is ready
+                            if !self.replicas.iter().any(|x| x.client_id == self.current_client) {
+                                println!("Replica {} asks for synchronization", self.current_client);
+                                let replica = self.new_replica();
+                                self.replicas.push(replica);
+                            }
+                            self.start_replication_snapshot();
+                            vec![]
+                        },
+                        Err(error) => {
+                            vec![
+                                InterpreterResponse::String(error.to_string())
+                            ]
+                        }
+                    }
+                },
+                "replconf" => {
+                    let option = leader_args.pop_front().map(|x| x.get_value(&self.source_code).to_lowercase()).unwrap_or_default();
+                    match option.as_str() {
+                        "ack" => {
+                            let offset = leader_args.pop_front().and_then(|x| x.get_value(&self.source_code).parse::<u64>().ok());
+                            let aof_offset = match leader_args.pop_front().map(|x| x.get_value(&self.source_code).to_lowercase()) {
+                                Some(x) if x == "fack" => leader_args.pop_front().and_then(|x| x.get_value(&self.source_code).parse::<u64>().ok()),
+                                _ => None
+                            };
+                            if let Some(replica) = self.replicas.iter_mut().find(|x| x.client_id == self.current_client) {
+                                if let Some(offset) = offset {
+                                    replica.ack_offset = offset;
+                                }
+                                if let Some(aof_offset) = aof_offset {
+                                    replica.aof_ack_offset = aof_offset;
+                                }
+                                replica.ack_time = Instant::now();
+                            }
+                            // Acknowledgements are never replied to, the replica doesn't read replies
+                            vec![]
+                        },
+                        "getack" => {
+                            // Only meaningful in the replication stream, replicas answer it there
+                            vec![]
+                        },
+                        "ip-address" => {
+                            match leader_args.pop_front() {
+                                Some(ip) => {
+                                    let ip = ip.get_value(&self.source_code);
+                                    self.announced_ips.insert(self.current_client, ip);
+                                    vec![
+                                        InterpreterResponse::String("+OK\r\n".to_string())
+                                    ]
+                                },
+                                None => {
+                                    vec![
+                                        InterpreterResponse::String("-ERR syntax error\r\n".to_string())
+                                    ]
+                                }
+                            }
+                        },
+                        "listening-port" => {
+                            match leader_args.pop_front().and_then(|x| x.get_value(&self.source_code).parse::<u32>().ok()) {
+                                Some(port) => {
+                                    self.listening_ports.insert(self.current_client, port);
+                                    vec![
+                                        InterpreterResponse::String("+OK\r\n".to_string())
+                                    ]
+                                },
+                                None => {
+                                    vec![
+                                        InterpreterResponse::String("-ERR value is not an integer or out of range\r\n".to_string())
+                                    ]
+                                }
+                            }
+                        },
+                        _ => {
+                            vec![
+                                InterpreterResponse::String("+OK\r\n".to_string())
+                            ]
+                        }
+                    }
+                },
+                "wait" | "waitaof" => {
+                    if let Some(ServerRole::Slave(_)) = &self.server_options.server_role {
+                        return vec![
+                            InterpreterResponse::String(format!("-ERR {} cannot be used with replica instances.\r\n", leader_cmd.to_uppercase()))
+                        ];
+                    }
+                    let expected_args = if leader_cmd == "wait" { 2 } else { 3 };
+                    if leader_args.len() != expected_args {
+                        return vec![
+                            InterpreterResponse::String(format!("-ERR wrong number of arguments for '{}' command\r\n", leader_cmd))
+                        ];
+                    }
+                    let numbers: Vec<Option<u64>> = leader_args.iter().map(|x| x.get_value(&self.source_code).parse::<u64>().ok()).collect();
+                    if numbers.iter().any(|x| x.is_none()) {
+                        return vec![
+                            InterpreterResponse::String("-ERR value is not an integer or out of range\r\n".to_owned())
+                        ];
+                    }
+                    let numbers: Vec<u64> = numbers.into_iter().flatten().collect();
+                    let (numlocal, numreplicas, timeout) = if leader_cmd == "wait" {
+                        (None, numbers[0], numbers[1])
+                    } else {
+                        (Some(numbers[0]), numbers[1], numbers[2])
+                    };
+                    let numlocal = match numlocal {
+                        Some(numlocal) => {
+                            if numlocal > 0 && self.append_only_file.is_none() {
+                                return vec![
+                                    InterpreterResponse::String("-ERR WAITAOF cannot be used when numlocal is set but appendonly is disabled.\r\n".to_owned())
+                                ];
+                            }
+                            Some(self.fsync_append_only() as u64)
+                        },
+                        None => None
+                    };
+                    let waiting = WaitingClient {
+                        client_id: self.current_client,
+                        offset: self.client_write_offsets.get(&self.current_client).copied().unwrap_or(0),
+                        numreplicas,
+                        numlocal,
+                        // A timeout of 0 blocks forever
+                        deadline: if timeout > 0 { Some(Instant::now() + Duration::from_millis(timeout)) } else { None }
+                    };
+                    if self.acked_replicas(waiting.offset, numlocal.is_some()) >= numreplicas {
+                        return vec![
+                            InterpreterResponse::String(self.wait_reply(&waiting))
+                        ];
+                    }
+                    // Ask for acknowledgements right away instead of waiting for the periodic ones
+                    self.propagate_to_replicas(vec!["REPLCONF".to_string(), "GETACK".to_string(), "*".to_string()]);
+                    self.waiting_clients.push(waiting);
+                    vec![]
+                },
+                "multi" => {
+                    if self.transactions.contains_key(&self.current_client) {
+                        return vec![
+                            InterpreterResponse::String("-ERR MULTI calls can not be nested\r\n".to_string())
+                        ];
+                    }
+                    self.transactions.insert(self.current_client, Transaction::default());
+                    vec![
+                        InterpreterResponse::String("+OK\r\n".to_string())
+                    ]
+                },
+                "exec" => {
+                    let transaction = match self.transactions.remove(&self.current_client) {
+                        Some(x) => x,
+                        None => {
+                            return vec![
+                                InterpreterResponse::String("-ERR EXEC without MULTI\r\n".to_string())
+                            ];
+                        }
+                    };
+                    let watch_dirty = self.data_store.is_watch_dirty(self.current_client);
+                    self.data_store.unwatch(self.current_client);
+                    // Errors while queueing win over a modified watched key, like in Redis
+                    if transaction.dirty {
+                        return vec![
+                            InterpreterResponse::String("-EXECABORT Transaction discarded because of previous errors.\r\n".to_string())
+                        ];
+                    }
+                    if watch_dirty {
+                        let null = if self.protocol(self.current_client) == 3 { "_\r\n" } else { "*-1\r\n" };
+                        return vec![
+                            InterpreterResponse::String(null.to_string())
+                        ];
+                    }
+                    vec![
+                        InterpreterResponse::String(self.exec_transaction(transaction.commands))
+                    ]
+                },
+                "discard" => {
+                    match self.transactions.remove(&self.current_client) {
+                        Some(_) => {
+                            self.data_store.unwatch(self.current_client);
+                            vec![
+                                InterpreterResponse::String("+OK\r\n".to_string())
+                            ]
+                        },
+                        None => {
+                            vec![
+                                InterpreterResponse::String("-ERR DISCARD without MULTI\r\n".to_string())
+                            ]
+                        }
+                    }
+                },
+                "del" => {
+                    let keys: Vec<String> = leader_args.iter().map(|x| x.get_value(&self.source_code)).collect();
+                    let mut deleted = 0;
+                    for key in &keys {
+                        self.expire_if_needed(key);
+                        if self.data_store.delete(key).is_some() {
+                            deleted += 1;
+                            self.rdb_saver.dirty += 1;
+                            self.invalidate_key(key);
+                            self.notify_keyspace_event(NOTIFY_GENERIC, "del", key);
+                        }
+                    }
+                    if deleted > 0 {
+                        let mut argv = vec!["DEL".to_string()];
+                        argv.extend(keys);
+                        self.propagate(argv);
+                    }
+                    vec![
+                        InterpreterResponse::String(Helper::build_resp(&Reply::ReplyInteger(deleted)))
+                    ]
+                },
+                "watch" => {
+                    if self.transactions.contains_key(&self.current_client) {
+                        return vec![
+                            InterpreterResponse::String("-ERR WATCH inside MULTI is not allowed\r\n".to_string())
+                        ];
+                    }
+                    for key in leader_args {
+                        let key = key.get_value(&self.source_code);
+                        self.data_store.watch(&key, self.current_client);
+                    }
+                    vec![
+                        InterpreterResponse::String("+OK\r\n".to_string())
+                    ]
+                },
+                "unwatch" => {
+                    self.data_store.unwatch(self.current_client);
+                    vec![
+                        InterpreterResponse::String("+OK\r\n".to_string())
+                    ]
+                },
+                "flushdb" | "flushall" => {
+                    let mode = leader_args.pop_front().map(|x| x.get_value(&self.source_code).to_lowercase());
+                    if !matches!(mode.as_deref(), None | Some("sync") | Some("async")) {
+                        return vec![
+                            InterpreterResponse::String("-ERR syntax error\r\n".to_string())
+                        ];
+                    }
+                    // There is a single database, both empty it
+                    self.data_store.flush();
+                    self.invalidate_all();
+                    self.propagate(vec![leader_cmd.to_uppercase()]);
+                    vec![
+                        InterpreterResponse::String("+OK\r\n".to_string())
+                    ]
+                },
+                "replicaof" | "slaveof" => {
+                    let args: Vec<String> = leader_args.iter().map(|x| x.get_value(&self.source_code)).collect();
+                    if args.len() != 2 {
+                        return vec![
+                            InterpreterResponse::String(format!("-ERR wrong number of arguments for '{}' command\r\n", leader_cmd))
+                        ];
+                    }
+                    if args[0].eq_ignore_ascii_case("no") && args[1].eq_ignore_ascii_case("one") {
+                        if let Some(ServerRole::Slave(_)) = &self.server_options.server_role {
+                            self.promote_to_master();
+                        }
+                        return vec![
+                            InterpreterResponse::String("+OK\r\n".to_owned())
+                        ];
+                    }
+                    let master_port = match args[1].parse::<u32>() {
+                        Ok(x) if x <= 65535 => x,
+                        _ => {
+                            return vec![
+                                InterpreterResponse::String("-ERR Invalid master port\r\n".to_owned())
+                            ];
+                        }
+                    };
+                    if self.master_address() == Some((args[0].clone(), master_port)) {
+                        return vec![
+                            InterpreterResponse::String("+OK Already connected to specified master\r\n".to_owned())
+                        ];
+                    }
+                    self.follow_master(args[0].clone(), master_port);
+                    vec![
+                        InterpreterResponse::String("+OK\r\n".to_owned())
+                    ]
+                },
+                "ping" => {
+                    // RESP2 subscribers can't tell a reply from a message unless it is an array
+                    if self.protocol(self.current_client) == 2 && self.pubsub.is_subscribed(self.current_client) {
+                        let message = leader_args.pop_front().map(|x| x.get_value(&self.source_code)).unwrap_or_default();
+                        return vec![
+                            InterpreterResponse::String(Helper::build_resp(&Reply::ReplyArray(vec![
+                                Reply::ReplyBulkString("pong".to_string()),
+                                Reply::ReplyBulkString(message),
+                            ])))
+                        ];
+                    }
+                    vec![
+                        InterpreterResponse::String("+PONG\r\n".to_string())
+                    ]
+                },
+                "subscribe" | "psubscribe" => {
+                    let mut response = String::new();
+                    for name in leader_args {
+                        let name = name.get_value(&self.source_code);
+                        if leader_cmd == "subscribe" {
+                            self.pubsub.subscribe(self.current_client, &name);
+                        } else {
+                            self.pubsub.psubscribe(self.current_client, &name);
+                        }
+                        response.push_str(&self.subscription_reply(&leader_cmd, Some(&name), self.pubsub.subscription_count(self.current_client)));
+                    }
+                    vec![
+                        InterpreterResponse::String(response)
+                    ]
+                },
+                "unsubscribe" | "punsubscribe" => {
+                    // Without arguments, from everything subscribed to
+                    let mut names: Vec<String> = leader_args.iter().map(|x| x.get_value(&self.source_code)).collect();
+                    if names.is_empty() {
+                        names = if leader_cmd == "unsubscribe" {
+                            self.pubsub.channels_of(self.current_client)
+                        } else {
+                            self.pubsub.patterns_of(self.current_client)
+                        };
+                    }
+                    if names.is_empty() {
+                        return vec![
+                            InterpreterResponse::String(self.subscription_reply(&leader_cmd, None, self.pubsub.subscription_count(self.current_client)))
+                        ];
+                    }
+                    let mut response = String::new();
+                    for name in names {
+                        if leader_cmd == "unsubscribe" {
+                            self.pubsub.unsubscribe(self.current_client, &name);
+                        } else {
+                            self.pubsub.punsubscribe(self.current_client, &name);
+                        }
+                        response.push_str(&self.subscription_reply(&leader_cmd, Some(&name), self.pubsub.subscription_count(self.current_client)));
+                    }
+                    vec![
+                        InterpreterResponse::String(response)
+                    ]
+                },
+                "ssubscribe" => {
+                    let mut response = String::new();
+                    for name in leader_args {
+                        let name = name.get_value(&self.source_code);
+                        self.pubsub.ssubscribe(self.current_client, &name);
+                        response.push_str(&self.subscription_reply(&leader_cmd, Some(&name), self.pubsub.shard_subscription_count(self.current_client)));
+                    }
+                    vec![
+                        InterpreterResponse::String(response)
+                    ]
+                },
+                "sunsubscribe" => {
+                    let mut names: Vec<String> = leader_args.iter().map(|x| x.get_value(&self.source_code)).collect();
+                    if names.is_empty() {
+                        names = self.pubsub.shard_channels_of(self.current_client);
+                    }
+                    if names.is_empty() {
+                        return vec![
+                            InterpreterResponse::String(self.subscription_reply(&leader_cmd, None, 0))
+                        ];
+                    }
+                    let mut response = String::new();
+                    for name in names {
+                        self.pubsub.sunsubscribe(self.current_client, &name);
+                        response.push_str(&self.subscription_reply(&leader_cmd, Some(&name), self.pubsub.shard_subscription_count(self.current_client)));
+                    }
+                    vec![
+                        InterpreterResponse::String(response)
+                    ]
+                },
+                "publish" | "spublish" => {
+                    let args: Vec<String> = leader_args.iter().map(|x| x.get_value(&self.source_code)).collect();
+                    if args.len() != 2 {
+                        return vec![
+                            InterpreterResponse::String(format!("-ERR wrong number of arguments for '{}' command\r\n", leader_cmd))
+                        ];
+                    }
+                    let receivers = if leader_cmd == "publish" {
+                        self.publish(&args[0], &args[1])
+                    } else {
+                        self.spublish(&args[0], &args[1])
+                    };
+                    // Subscribers of the replicas get the message too, after the writes a transaction
+                    // or a script made before publishing it
+                    self.propagate(vec![leader_cmd.to_uppercase(), args[0].clone(), args[1].clone()]);
+                    vec![
+                        InterpreterResponse::String(Helper::build_resp(&Reply::ReplyInteger(receivers as i64)))
+                    ]
+                },
+                "pubsub" => {
+                    let subcommand = leader_args.pop_front().map(|x| x.get_value(&self.source_code).to_lowercase()).unwrap_or_default();
+                    let args: Vec<String> = leader_args.iter().map(|x| x.get_value(&self.source_code)).collect();
+                    match subcommand.as_str() {
+                        "channels" if args.len() <= 1 => {
+                            let channels = self.pubsub.active_channels(args.first().map(|x| x.as_str()));
+                            vec![
+                                InterpreterResponse::String(Helper::build_resp(&Reply::ReplyArray(channels.into_iter().map(Reply::ReplyBulkString).collect())))
+                            ]
+                        },
+                        "shardchannels" if args.len() <= 1 => {
+                            let channels = self.pubsub.active_shard_channels(args.first().map(|x| x.as_str()));
+                            vec![
+                                InterpreterResponse::String(Helper::build_resp(&Reply::ReplyArray(channels.into_iter().map(Reply::ReplyBulkString).collect())))
+                            ]
                         },
-                        _ => {
-                            return vec![
-                                InterpreterResponse::String("-ERROR Expected the key to be a string".to_owned())
+                        "numsub" | "shardnumsub" => {
+                            let mut counts: Vec<Reply> = vec![];
+                            for channel in args {
+                                let count = if subcommand == "numsub" {
+                                    self.pubsub.numsub(&channel)
+                                } else {
+                                    self.pubsub.shardnumsub(&channel)
+                                };
+                                counts.push(Reply::ReplyBulkString(channel));
+                                counts.push(Reply::ReplyInteger(count as i64));
+                            }
+                            vec![
+                                InterpreterResponse::String(Helper::build_resp(&Reply::ReplyArray(counts)))
+                            ]
+                        },
+                        "numpat" if args.is_empty() => {
+                            vec![
+                                InterpreterResponse::String(Helper::build_resp(&Reply::ReplyInteger(self.pubsub.numpat() as i64)))
+                            ]
+                        },
+                        "channels" | "shardchannels" | "numpat" => {
+                            vec![
+                                InterpreterResponse::String(format!("-ERR wrong number of arguments for 'pubsub|{}' command\r\n", subcommand))
                             ]
-                        }
-                    }
-
-                    let value_string;
-                    match value {
-                        DS::BulkString(start, end) => {
-                            value_string = self.source_code.get(start..end).unwrap();
                         },
                         _ => {
-                            return vec![
-                                InterpreterResponse::String("-ERROR Expected the value to be a string".to_owned())
+                            vec![
+                                InterpreterResponse::String(format!("-ERR unknown subcommand '{}'. Try PUBSUB HELP.\r\n", subcommand))
                             ]
                         }
                     }
-                    
-                    let mut args = SetOptions {
-                        expiry: None
+                },
+                "auth" => {
+                    let args: Vec<String> = leader_args.iter().map(|x| x.get_value(&self.source_code)).collect();
+                    let default_nopass = self.server_options.acl.user("default").is_some_and(|x| x.nopass());
+                    let result = match args.as_slice() {
+                        [_] if default_nopass => Err("-ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?\r\n".to_string()),
+                        [password] => self.authenticate("default", password),
+                        [username, password] => self.authenticate(username, password),
+                        [] => Err("-ERR wrong number of arguments for 'auth' command\r\n".to_string()),
+                        _ => Err("-ERR syntax error\r\n".to_string())
                     };
-                    
-                    if leader_args.len() != 0 {
-                        while let Some(current_option) = leader_args.pop_front() {
-                            match current_option {
-                                DS::BulkString(start, end) => {
-                                    match self.source_code.get(start..end).unwrap().to_lowercase().as_str() {
-                                        "px" => {
-                                            let d = leader_args.pop_front().unwrap();
-                                            if let DS::BulkString(duration_start, duration_end) = d {
-                                                let x: u64 = self.source_code.get(duration_start..duration_end)
-                                                    .expect("")
-                                                    .to_owned()
-                                                    .parse::<u64>()
-                                                    .unwrap();
-                                                
-                                                args.expiry = Some(SystemTime::now() + Duration::from_millis(x));
-                                            }
-                                        },
-                                        _ => {}
-                                    }
-                                },
-                                _ => {
-                                    return vec![
-                                        InterpreterResponse::String("-ERROR Invalid argument type".to_owned())
-                                    ]
-                                }
-                            }
-                        }
-                    }
-                    
-                    self.data_store.set(key_string.to_owned(), DataItem {
-                        data: value_string.to_owned(),
-                        expiry: args.expiry
-                    });
                     vec![
-                        InterpreterResponse::String("+OK\r\n".to_string())
+                        InterpreterResponse::String(result.err().unwrap_or("+OK\r\n".to_string()))
                     ]
                 },
-                "get" => {
-                    let key = leader_args.front().unwrap();
-                    let key_string;
-                    match key {
-                        DS::BulkString(start, end) => {
-                            key_string = self.source_code.get(*start..*end).unwrap();
-                        },
-                        _ => {
+                "hello" => {
+                    let args: Vec<String> = leader_args.iter().map(|x| x.get_value(&self.source_code)).collect();
+                    let mut protocol = None;
+                    if let Some(protover) = args.first() {
+                        match protover.parse::<u8>() {
+                            Ok(x @ (2 | 3)) => {
+                                protocol = Some(x);
+                            },
+                            Ok(_) => {
+                                return vec![
+                                    InterpreterResponse::String("-NOPROTO unsupported protocol version\r\n".to_string())
+                                ];
+                            },
+                            Err(_) => {
+                                return vec![
+                                    InterpreterResponse::String("-ERR Protocol version is not an integer or out of range\r\n".to_string())
+                                ];
+                            }
+                        }
+                    }
+                    let mut credentials = None;
+                    let mut i = 1;
+                    while i < args.len() {
+                        match args[i].to_lowercase().as_str() {
+                            "auth" if i + 2 < args.len() => {
+                                credentials = Some((args[i + 1].clone(), args[i + 2].clone()));
+                                i += 3;
+                            },
+                            _ => {
+                                return vec![
+                                    InterpreterResponse::String(format!("-ERR Syntax error in HELLO option '{}'\r\n", args[i]))
+                                ];
+                            }
+                        }
+                    }
+                    if let Some((username, password)) = credentials {
+                        if let Err(e) = self.authenticate(&username, &password) {
                             return vec![
-                                InterpreterResponse::String("-ERROR Expected the key to be a string".to_owned())
+                                InterpreterResponse::String(e)
                             ];
                         }
                     }
-                    let mut response = String::from("$");
-                    match self.data_store.get(key_string.to_string()) {
-                        Some(v) => {
-                            let current_time = SystemTime::now();
-                            if let Some(expiry) = v.expiry {
-                                if expiry < current_time {
-                                    self.data_store.remove(key_string.to_string());
-                                    response.push_str("-1");
-                                    response.push_str("\r\n");
-                                } else {
-                                    response.push_str(&format!("{}", v.data.len()));
-                                    response.push_str("\r\n");
-                                    response.push_str(&v.data);
-                                    response.push_str("\r\n");
+                    if self.auth_required() {
+                        return vec![
+                            InterpreterResponse::String("-NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time\r\n".to_string())
+                        ];
+                    }
+                    if let Some(protocol) = protocol {
+                        self.client_protocols.insert(self.current_client, protocol);
+                    }
+                    let role = match &self.server_options.server_role {
+                        Some(ServerRole::Slave(_)) => "replica",
+                        _ => "master"
+                    };
+                    let fields = vec![
+                        ("server", Reply::ReplyBulkString("redis".to_string())),
+                        ("version", Reply::ReplyBulkString(SERVER_VERSION.to_string())),
+                        ("proto", Reply::ReplyInteger(self.protocol(self.current_client) as i64)),
+                        ("id", Reply::ReplyInteger(self.current_client as i64)),
+                        ("mode", Reply::ReplyBulkString("standalone".to_string())),
+                        ("role", Reply::ReplyBulkString(role.to_string())),
+                        ("modules", Reply::ReplyArray(vec![])),
+                    ];
+                    vec![
+                        InterpreterResponse::String(self.map_reply(&fields))
+                    ]
+                },
+                "client" => {
+                    let subcommand = leader_args.pop_front().map(|x| x.get_value(&self.source_code).to_lowercase()).unwrap_or_default();
+                    let args: Vec<String> = leader_args.iter().map(|x| x.get_value(&self.source_code)).collect();
+                    match subcommand.as_str() {
+                        "id" if args.is_empty() => {
+                            vec![
+                                InterpreterResponse::String(Helper::build_resp(&Reply::ReplyInteger(self.current_client as i64)))
+                            ]
+                        },
+                        "tracking" if !args.is_empty() => {
+                            let response = match args[0].to_lowercase().as_str() {
+                                "on" => self.enable_tracking(&args[1..]),
+                                "off" if args.len() == 1 => {
+                                    self.tracking.disable(self.current_client);
+                                    Ok(())
+                                },
+                                _ => Err("-ERR syntax error\r\n".to_string())
+                            };
+                            vec![
+                                InterpreterResponse::String(response.map(|_| "+OK\r\n".to_string()).unwrap_or_else(|e| e))
+                            ]
+                        },
+                        "caching" if args.len() == 1 => {
+                            let caching = match args[0].to_lowercase().as_str() {
+                                "yes" => true,
+                                "no" => false,
+                                _ => {
+                                    return vec![
+                                        InterpreterResponse::String("-ERR syntax error\r\n".to_string())
+                                    ];
                                 }
-                            } else {
-                                response.push_str(&format!("{}", v.data.len()));
-                                response.push_str("\r\n");
-                                response.push_str(&v.data);
-                                response.push_str("\r\n");
+                            };
+                            let error = match self.tracking.options(self.current_client) {
+                                Some(options) if options.optin && caching => None,
+                                Some(options) if options.optout && !caching => None,
+                                Some(options) if options.optin => Some("-ERR CLIENT CACHING NO is only valid when tracking is enabled in OPTOUT mode.\r\n"),
+                                Some(options) if options.optout => Some("-ERR CLIENT CACHING YES is only valid when tracking is enabled in OPTIN mode.\r\n"),
+                                _ => Some("-ERR CLIENT CACHING can be called only when the client is in tracking mode with OPTIN or OPTOUT mode enabled\r\n")
+                            };
+                            if let Some(error) = error {
+                                return vec![
+                                    InterpreterResponse::String(error.to_string())
+                                ];
                             }
+                            self.tracking.set_caching(self.current_client, caching);
+                            vec![
+                                InterpreterResponse::String("+OK\r\n".to_string())
+                            ]
                         },
-                        None => {
-                            response.push_str("-1");
-                            response.push_str("\r\n");
+                        "getredir" if args.is_empty() => {
+                            // -1 when not tracking, 0 when not redirecting
+                            let redirect = match self.tracking.options(self.current_client) {
+                                Some(options) => options.redirect.map(|x| x as i64).unwrap_or(0),
+                                None => -1
+                            };
+                            vec![
+                                InterpreterResponse::String(Helper::build_resp(&Reply::ReplyInteger(redirect)))
+                            ]
+                        },
+                        "id" | "tracking" | "caching" | "getredir" => {
+                            vec![
+                                InterpreterResponse::String(format!("-ERR wrong number of arguments for 'client|{}' command\r\n", subcommand))
+                            ]
+                        },
+                        _ => {
+                            vec![
+                                InterpreterResponse::String(format!("-ERR unknown subcommand '{}'. Try CLIENT HELP.\r\n", subcommand))
+                            ]
                         }
                     }
-                    return vec![
-                        InterpreterResponse::String(response)
-                    ];
                 },
-                "config" => {
-                    let config_action = leader_args.pop_front();
-                    if let Some(ca) = config_action {
-                        if let DS::BulkString(_, _) = ca {
-                            let action = ca.get_value(&self.source_code);
-                            match action.to_lowercase().as_str() {
-                                "get" => {
-                                    let config_key = leader_args.pop_front();
-                                    if let Some(key_ds) = config_key {
-                                        let key = key_ds.get_value(&self.source_code);
-                                        if key == "dir" {
-                                            let dir_name: String = self.server_options.rdb_dir_name.clone().expect("expected a directory name found nothing").as_path().to_str().expect("no dirname").to_owned();
-                                            return vec![
-                                                InterpreterResponse::String(format!("*2\r\n$3\r\ndir\r\n${}\r\n{}\r\n", dir_name.len(), dir_name).to_owned())
-                                            ];
-                                        } else if key == "dbfilename" {
-                                            let db_file_name: String = self.server_options.rdb_file_name.clone().expect("expected a file name found nothing").as_path().to_str().expect("no dbfilename").to_owned();
-                                            return vec![
-                                                InterpreterResponse::String(format!("*2\r\n$10\r\ndbfilename\r\n${}\r\n{}\r\n", db_file_name.len(), db_file_name).to_owned())
-                                            ];
-
-                                        } else {
-                                            return vec![
-                                                InterpreterResponse::String("".to_owned())
-                                            ];
-                                        }
-
-                                    } else {
-                                        return vec![
-                                            InterpreterResponse::String("".to_owned())
-                                        ];
-                                    }
+                "acl" => {
+                    let subcommand = leader_args.pop_front().map(|x| x.get_value(&self.source_code).to_lowercase()).unwrap_or_default();
+                    let args: Vec<String> = leader_args.iter().map(|x| x.get_value(&self.source_code)).collect();
+                    let result = match subcommand.as_str() {
+                        "setuser" if !args.is_empty() => {
+                            self.server_options.acl.set_user(&args[0], &args[1..]).map(|_| "+OK\r\n".to_string())
+                        },
+                        "getuser" if args.len() == 1 => {
+                            match self.server_options.acl.user(&args[0]) {
+                                Some(user) => {
+                                    let fields = vec![
+                                        ("flags", Reply::ReplyArray(user.flags().into_iter().map(Reply::ReplyBulkString).collect())),
+                                        ("passwords", Reply::ReplyArray(user.password_hashes().iter().map(|x| Reply::ReplyBulkString(x.clone())).collect())),
+                                        ("commands", Reply::ReplyBulkString(user.describe_commands())),
+                                        ("keys", Reply::ReplyBulkString(user.describe_keys())),
+                                        ("channels", Reply::ReplyBulkString(user.describe_channels())),
+                                        ("selectors", Reply::ReplyArray(vec![])),
+                                    ];
+                                    Ok(self.map_reply(&fields))
                                 },
-                                "set" => {
-                                    let config_key = leader_args.pop_front();
-                                    if let Some(key_ds) = config_key {
-                                        let key = key_ds.get_value(&self.source_code);
-                                        if key == "dir" {
-                                            let new_dir_value = leader_args.pop_front().expect("Expected a value").get_value(&self.source_code);
-                                            self.server_options.rdb_dir_name = Some(std::path::PathBuf::from(new_dir_value));
-                                            return vec![
-                                                InterpreterResponse::String("+OK\r\n".to_owned())
-                                            ];
-                                        } else if key == "dbfilename" {
-                                            let new_db_file_name_value = leader_args.pop_front().expect("Expected a value").get_value(&self.source_code);
-                                            self.server_options.rdb_file_name = Some(std::path::PathBuf::from(new_db_file_name_value));
-                                            return vec![
-                                                InterpreterResponse::String("+OK\r\n".to_owned())
-                                            ];
-                                        } else {
-                                            return vec![
-                                                InterpreterResponse::String("-ERROR trying to set invalid config option\r\n".to_owned())
-                                            ];
+                                None => Ok(Helper::build_resp(&Reply::ReplyNull))
+                            }
+                        },
+                        "deluser" if !args.is_empty() => {
+                            if args.iter().any(|x| x == "default") {
+                                Err("ERR The 'default' user cannot be removed".to_string())
+                            } else {
+                                let deleted = args.iter().filter(|x| self.server_options.acl.delete_user(x)).count();
+                                self.drop_clients_of_deleted_users();
+                                Ok(Helper::build_resp(&Reply::ReplyInteger(deleted as i64)))
+                            }
+                        },
+                        "users" if args.is_empty() => {
+                            let names = self.server_options.acl.users().map(|x| Reply::ReplyBulkString(x.name.clone())).collect();
+                            Ok(Helper::build_resp(&Reply::ReplyArray(names)))
+                        },
+                        "list" if args.is_empty() => {
+                            let lines = self.server_options.acl.users().map(|x| Reply::ReplyBulkString(x.describe())).collect();
+                            Ok(Helper::build_resp(&Reply::ReplyArray(lines)))
+                        },
+                        "whoami" if args.is_empty() => {
+                            let username = self.client_users.get(&self.current_client).cloned().unwrap_or("default".to_string());
+                            Ok(Helper::build_resp(&Reply::ReplyBulkString(username)))
+                        },
+                        "cat" if args.len() <= 1 => {
+                            let names = match args.first() {
+                                None => Some(acl::category_names()),
+                                Some(category) => acl::category_commands(&category.to_lowercase()).map(|x| x.to_vec())
+                            };
+                            match names {
+                                Some(names) => Ok(Helper::build_resp(&Reply::ReplyArray(names.into_iter().map(|x| Reply::ReplyBulkString(x.to_string())).collect()))),
+                                None => Err(format!("ERR Unknown category '{}'", args[0]))
+                            }
+                        },
+                        "log" if args.len() <= 1 => self.acl_log(args.first()),
+                        "load" | "save" if args.is_empty() => {
+                            match self.server_options.aclfile.clone() {
+                                None => Err("ERR This Redis instance is not configured to use an ACL file. You may want to specify users via the ACL SETUSER command and then issue a CONFIG REWRITE (assuming you have a Redis configuration file set) in order to store users in the Redis configuration.".to_string()),
+                                Some(path) if subcommand == "load" => {
+                                    let loaded = self.server_options.acl.load_file(std::path::Path::new(&path));
+                                    self.drop_clients_of_deleted_users();
+                                    loaded.map(|_| "+OK\r\n".to_string()).map_err(|e| format!("ERR {}", e))
+                                },
+                                Some(path) => {
+                                    match self.server_options.acl.save_file(std::path::Path::new(&path)) {
+                                        Ok(_) => Ok("+OK\r\n".to_string()),
+                                        Err(e) => {
+                                            println!("ERROR {}", e);
+                                            Err("ERR There was an error trying to save the ACLs. Please check the server logs for more information".to_string())
                                         }
-
-                                    } else {
-                                        return vec![
-                                            InterpreterResponse::String("-ERROR no config key sent\r\n".to_owned())
-                                        ];
                                     }
-                                },
-                                c => {
-                                    println!("{:?}", c);
-                                    todo!("");
-                                },
+                                }
                             }
-                        } else {
+                        },
+                        "genpass" if args.len() <= 1 => {
+                            let bits = match args.first().map(|x| x.parse::<usize>()) {
+                                None => Ok(256),
+                                Some(Ok(x)) if (1..=4096).contains(&x) => Ok(x),
+                                _ => Err("ERR ACL GENPASS argument must be the number of bits for the output password, a positive number up to 4096".to_string())
+                            };
+                            bits.map(|x| Helper::build_resp(&Reply::ReplyBulkString(Helper::random_hex(x.div_ceil(4)))))
+                        },
+                        "setuser" | "getuser" | "deluser" | "users" | "list" | "whoami" | "cat" | "log" | "load" | "save" | "genpass" => {
+                            Err(format!("ERR wrong number of arguments for 'acl|{}' command", subcommand))
+                        },
+                        _ => Err(format!("ERR unknown subcommand '{}'. Try ACL HELP.", subcommand))
+                    };
+                    vec![
+                        InterpreterResponse::String(result.unwrap_or_else(|e| format!("-{}\r\n", e)))
+                    ]
+                },
+                "eval" | "evalsha" | "eval_ro" | "evalsha_ro" => {
+                    let args: Vec<String> = leader_args.iter().map(|x| x.get_value(&self.source_code)).collect();
+                    let (keys, arguments) = match Self::script_keys(&leader_cmd, &args) {
+                        Ok(x) => x,
+                        Err(e) => {
                             return vec![
-                                InterpreterResponse::String("-ERROR config action invalid".to_owned())
+                                InterpreterResponse::String(e)
                             ];
                         }
+                    };
+                    let loaded = if leader_cmd.starts_with("evalsha") {
+                        let sha = args[0].to_lowercase();
+                        match self.scripts.get(&sha) {
+                            Some(script) => Ok((sha, script.clone())),
+                            None => Err("-NOSCRIPT No matching script. Please use EVAL.\r\n".to_string())
+                        }
                     } else {
+                        self.load_script(&args[0])
+                    };
+                    let (sha, script) = match loaded {
+                        Ok(x) => x,
+                        Err(e) => {
+                            return vec![
+                                InterpreterResponse::String(e)
+                            ];
+                        }
+                    };
+                    let read_only = leader_cmd.ends_with("_ro") || script.no_writes;
+                    let run = ScriptRun::eval(script, sha, keys, arguments);
+                    self.run_script(run, read_only, false)
+                },
+                "fcall" | "fcall_ro" => {
+                    let args: Vec<String> = leader_args.iter().map(|x| x.get_value(&self.source_code)).collect();
+                    let (keys, arguments) = match Self::script_keys(&leader_cmd, &args) {
+                        Ok(x) => x,
+                        Err(e) => {
+                            return vec![
+                                InterpreterResponse::String(e)
+                            ];
+                        }
+                    };
+                    let (library, function) = match self.data_store.functions.find(&args[0]) {
+                        Some(x) => x,
+                        None => {
+                            return vec![
+                                InterpreterResponse::String("-ERR Function not found\r\n".to_string())
+                            ];
+                        }
+                    };
+                    if leader_cmd == "fcall_ro" && !function.no_writes() {
                         return vec![
-                            InterpreterResponse::String("-ERROR config action invalid".to_owned())
+                            InterpreterResponse::String("-ERR Can not execute a script with write flag using *_ro command.\r\n".to_string())
                         ];
                     }
+                    let read_only = leader_cmd == "fcall_ro" || function.no_writes();
+                    let run = ScriptRun::fcall(library, function.name, keys, arguments);
+                    self.run_script(run, read_only, true)
                 },
-                "keys" => {
-                    let keys = self.data_store.memory.keys().map(|x| Reply::ReplyBulkString(x.to_string())).collect::<Vec<Reply>>();
-                    return vec![
-                        InterpreterResponse::String(Helper::build_resp(&Reply::ReplyArray(keys)))
-                    ];
-                },
-                "info" => {
-                    let argument = leader_args.pop_front();
-                    match argument {
-                        Some(DS::BulkString(start, end)) => {
-                            let _info_about = self.source_code.get(start..end).to_owned().expect("Expected a value for replication");
-                            match &self.server_options.server_role {
-                                Some(ServerRole::Slave(_slave_option)) => {
-                                    vec![
-                                        InterpreterResponse::String(Helper::build_resp(&Reply::ReplyBulkString("role:slave".to_string())))
-                                    ]
-                                }
-                                Some(ServerRole::Master(Some(_master_option))) => {
-                                    vec![
-                                        InterpreterResponse::String(Helper::build_resp(&Reply::ReplyBulkString(format!("role:master\r\nmaster_replid:{}\r\nmaster_repl_offset:{}", _master_option.master_replid, _master_option.master_repl_offset))))
-                                    ]
+                "function" => {
+                    let mut argv = vec!["FUNCTION".to_string()];
+                    argv.extend(leader_args.iter().map(|x| x.get_value(&self.source_code)));
+                    if argv.len() < 2 {
+                        return vec![
+                            InterpreterResponse::String("-ERR wrong number of arguments for 'function' command\r\n".to_string())
+                        ];
+                    }
+                    let subcommand = argv[1].to_lowercase();
+                    let args = argv[2..].to_vec();
+                    if let Some(e) = self.check_replica_access(&format!("function|{}", subcommand)) {
+                        return vec![
+                            InterpreterResponse::String(e)
+                        ];
+                    }
+                    let result = match subcommand.as_str() {
+                        "load" if args.len() == 1 || args.len() == 2 => {
+                            if args.len() == 2 && args[0].to_lowercase() != "replace" {
+                                return vec![
+                                    InterpreterResponse::String(format!("-ERR Unknown option given: {}\r\n", args[0]))
+                                ];
+                            }
+                            self.data_store.functions.load(&args[args.len() - 1], args.len() == 2)
+                                .map(|name| Helper::build_resp(&Reply::ReplyBulkString(name)))
+                        },
+                        "delete" if args.len() == 1 => {
+                            match self.data_store.functions.delete(&args[0]) {
+                                true => Ok("+OK\r\n".to_string()),
+                                false => {
+                                    return vec![
+                                        InterpreterResponse::String("-ERR Library not found\r\n".to_string())
+                                    ];
                                 }
-                                _ => {
-                                    vec![
-                                        InterpreterResponse::String(Helper::build_resp(&Reply::ReplyBulkString("role:master".to_string())))
-                                    ]
+                            }
+                        },
+                        "flush" if args.len() <= 1 => {
+                            if !args.iter().all(|x| ["async", "sync"].contains(&x.to_lowercase().as_str())) {
+                                return vec![
+                                    InterpreterResponse::String("-ERR FUNCTION FLUSH only supports SYNC|ASYNC option\r\n".to_string())
+                                ];
+                            }
+                            self.data_store.functions.flush();
+                            Ok("+OK\r\n".to_string())
+                        },
+                        "restore" if args.len() == 1 || args.len() == 2 => {
+                            let policy = match args.get(1).map(|x| x.to_lowercase()).as_deref() {
+                                None | Some("append") => RestorePolicy::Append,
+                                Some("replace") => RestorePolicy::Replace,
+                                Some("flush") => RestorePolicy::Flush,
+                                Some(_) => {
+                                    return vec![
+                                        InterpreterResponse::String("-ERR Wrong restore policy given, value should be either FLUSH, APPEND or REPLACE.\r\n".to_string())
+                                    ];
                                 }
+                            };
+                            // The payload is binary, it travels as base64 since commands are text here
+                            let payload = BASE64.decode(&args[0]).ok();
+                            match payload.and_then(|x| rdb::restore_functions(&x).ok()) {
+                                Some(codes) => self.data_store.functions.restore(&codes, policy).map(|_| "+OK\r\n".to_string()),
+                                None => Err("ERR payload version or checksum are wrong".to_string())
                             }
-                        }
+                        },
+                        "list" => {
+                            return vec![
+                                InterpreterResponse::String(self.list_functions(&args))
+                            ];
+                        },
+                        "dump" if args.is_empty() => {
+                            let payload = rdb::dump_functions(&self.data_store.functions.codes());
+                            let encoded = BASE64.encode(payload);
+                            return vec![
+                                InterpreterResponse::String(Helper::build_resp(&Reply::ReplyBulkString(encoded)))
+                            ];
+                        },
+                        "kill" if args.is_empty() => {
+                            return vec![
+                                InterpreterResponse::String(self.kill_script(true).to_string())
+                            ];
+                        },
+                        "load" | "delete" | "flush" | "restore" | "dump" | "kill" => {
+                            return vec![
+                                InterpreterResponse::String(format!("-ERR wrong number of arguments for 'function|{}' command\r\n", subcommand))
+                            ];
+                        },
                         _ => {
+                            return vec![
+                                InterpreterResponse::String(format!("-ERR unknown subcommand '{}'. Try FUNCTION HELP.\r\n", argv[1]))
+                            ];
+                        }
+                    };
+                    match result {
+                        Ok(response) => {
+                            self.rdb_saver.dirty += 1;
+                            self.propagate(argv);
                             vec![
-                                InterpreterResponse::String(Helper::build_resp(&Reply::ReplyString("-invalid argument for `info` command".to_string())))
+                                InterpreterResponse::String(response)
+                            ]
+                        },
+                        Err(e) => {
+                            vec![
+                                InterpreterResponse::String(format!("-{}\r\n", e))
                             ]
                         }
                     }
                 },
-                "psync" => {
-                    match &self.server_options.server_role {
-                        Some(ServerRole::Master(Some(master_options))) => {
-                            let empty_rdb_file_content = match BASE64_STANDARD.decode(b"UkVESVMwMDEx+glyZWRpcy12ZXIFNy4yLjD6CnJlZGlzLWJpdHPAQPoFY3RpbWXCbQi8ZfoIdXNlZC1tZW3CsMQQAPoIYW9mLWJhc2XAAP/wbjv+wP9aog==") {
-                                Ok(value) => value,
-                                _ => panic!("Can not decode empty RDB file.")
+                "script" => {
+                    let subcommand = leader_args.pop_front().map(|x| x.get_value(&self.source_code).to_lowercase()).unwrap_or_default();
+                    let args: Vec<String> = leader_args.iter().map(|x| x.get_value(&self.source_code)).collect();
+                    match subcommand.as_str() {
+                        "load" if args.len() == 1 => {
+                            let response = match self.load_script(&args[0]) {
+                                Ok((sha, _)) => Helper::build_resp(&Reply::ReplyBulkString(sha)),
+                                Err(e) => e
                             };
                             vec![
-                                InterpreterResponse::String(Helper::build_resp(&Reply::ReplyBulkString(format!("FULLRESYNC {} {}", master_options.master_replid, master_options.master_repl_offset)))),
-                                InterpreterResponse::Bytes(
-                                    [format!("${}\r\n", empty_rdb_file_content.len()).as_bytes().to_vec(), empty_rdb_file_content].concat()
-                                ),
+                                InterpreterResponse::String(response)
+                            ]
+                        },
+                        "exists" if !args.is_empty() => {
+                            let exists = args.iter().map(|x| Reply::ReplyInteger(self.scripts.contains_key(&x.to_lowercase()) as i64)).collect();
+                            vec![
+                                InterpreterResponse::String(Helper::build_resp(&Reply::ReplyArray(exists)))
+                            ]
+                        },
+                        "flush" if args.len() <= 1 => {
+                            if !args.iter().all(|x| ["async", "sync"].contains(&x.to_lowercase().as_str())) {
+                                return vec![
+                                    InterpreterResponse::String("-ERR SCRIPT FLUSH only support SYNC|ASYNC option\r\n".to_string())
+                                ];
+                            }
+                            self.scripts.clear();
+                            vec![
+                                InterpreterResponse::String("+OK\r\n".to_string())
+                            ]
+                        },
+                        "kill" if args.is_empty() => {
+                            vec![
+                                InterpreterResponse::String(self.kill_script(false).to_string())
+                            ]
+                        },
+                        "load" | "exists" | "flush" | "kill" => {
+                            vec![
+                                InterpreterResponse::String(format!("-ERR wrong number of arguments for 'script|{}' command\r\n", subcommand))
                             ]
                         },
                         _ => {
                             vec![
-                                InterpreterResponse::String(Helper::build_resp(&Reply::ReplyBulkString("-Error can only ask for psync from master".to_string())))
+                                InterpreterResponse::String(format!("-ERR unknown subcommand '{}'. Try SCRIPT HELP.\r\n", subcommand))
                             ]
                         }
                     }
                 },
-                "ping" => {
-                    return vec![
-                        InterpreterResponse::String("+PONG\r\n".to_string())
-                    ];
-                },
                 _ => {
-                    return vec![
+                    vec![
                         InterpreterResponse::String("+OK\r\n".to_string())
-                    ];
+                    ]
                 }
             }
+        } else {
+            vec![
+                InterpreterResponse::String("- Error while interpreting the message".to_string())
+            ]
         }
     }
 }
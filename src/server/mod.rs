@@ -1,5 +1,15 @@
+#[allow(clippy::module_inception)]
 mod server;
 pub mod parser;
 pub mod interpreter;
 pub mod client_replication_interpreter;
-pub use server::{Server, ServerOptions, ServerRole, SlaveServerOptions, MasterServerOptions};
+pub mod config;
+pub mod replication;
+pub mod commands;
+pub mod transaction;
+pub mod pubsub;
+pub mod notifications;
+pub mod tracking;
+pub mod script;
+pub mod acl;
+pub use server::{Server, ServerOptions, ServerRole, SlaveServerOptions, MasterServerOptions, SaveParam};
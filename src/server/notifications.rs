@@ -0,0 +1,54 @@
+/// Classes of keyspace events, selected with `notify-keyspace-events`.
+pub const NOTIFY_KEYSPACE: u32 = 1 << 0; // K
+pub const NOTIFY_KEYEVENT: u32 = 1 << 1; // E
+pub const NOTIFY_GENERIC: u32 = 1 << 2; // g
+pub const NOTIFY_STRING: u32 = 1 << 3; // $
+pub const NOTIFY_LIST: u32 = 1 << 4; // l
+pub const NOTIFY_SET: u32 = 1 << 5; // s
+pub const NOTIFY_HASH: u32 = 1 << 6; // h
+pub const NOTIFY_ZSET: u32 = 1 << 7; // z
+pub const NOTIFY_EXPIRED: u32 = 1 << 8; // x
+pub const NOTIFY_EVICTED: u32 = 1 << 9; // e
+pub const NOTIFY_STREAM: u32 = 1 << 10; // t
+pub const NOTIFY_KEY_MISS: u32 = 1 << 11; // m, not part of A
+pub const NOTIFY_NEW: u32 = 1 << 12; // n, not part of A
+pub const NOTIFY_ALL: u32 = NOTIFY_GENERIC | NOTIFY_STRING | NOTIFY_LIST | NOTIFY_SET | NOTIFY_HASH | NOTIFY_ZSET | NOTIFY_EXPIRED | NOTIFY_EVICTED | NOTIFY_STREAM; // A
+
+/// The classes in the order `CONFIG GET` reports them.
+const CLASSES: [(char, u32); 9] = [
+    ('g', NOTIFY_GENERIC), ('$', NOTIFY_STRING), ('l', NOTIFY_LIST), ('s', NOTIFY_SET), ('h', NOTIFY_HASH),
+    ('z', NOTIFY_ZSET), ('x', NOTIFY_EXPIRED), ('e', NOTIFY_EVICTED), ('t', NOTIFY_STREAM),
+];
+
+pub fn parse_notify_flags(value: &str) -> Result<u32, String> {
+    let mut flags = 0;
+    for c in value.chars() {
+        flags |= match c {
+            'A' => NOTIFY_ALL,
+            'K' => NOTIFY_KEYSPACE,
+            'E' => NOTIFY_KEYEVENT,
+            'm' => NOTIFY_KEY_MISS,
+            'n' => NOTIFY_NEW,
+            _ => match CLASSES.iter().find(|(x, _)| *x == c) {
+                Some((_, flag)) => *flag,
+                None => return Err("Invalid event class character. Use 'Ag$lshzxetKEmn'.".to_string())
+            }
+        };
+    }
+    Ok(flags)
+}
+
+pub fn notify_flags_to_string(flags: u32) -> String {
+    let mut value = String::new();
+    if flags & NOTIFY_ALL == NOTIFY_ALL {
+        value.push('A');
+    } else {
+        value.extend(CLASSES.iter().filter(|(_, flag)| flags & flag != 0).map(|(c, _)| *c));
+    }
+    for (c, flag) in [('K', NOTIFY_KEYSPACE), ('E', NOTIFY_KEYEVENT), ('m', NOTIFY_KEY_MISS), ('n', NOTIFY_NEW)] {
+        if flags & flag != 0 {
+            value.push(c);
+        }
+    }
+    value
+}
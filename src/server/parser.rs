@@ -2,9 +2,21 @@
 pub enum DS {
     RedArray(RedArray),
     String(usize, usize),
-    BulkString(usize, usize)
+    BulkString(usize, usize),
+    Error(usize, usize),
+    Integer(i64),
+    Null
 }
 
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    // The buffer ends in the middle of a value, more data is needed
+    Incomplete,
+    // The buffer does not contain valid RESP
+    Invalid(String)
+}
+
+#[allow(dead_code)]
 impl DS {
     pub fn debug(&self, source_code: &str) {
         match self {
@@ -19,35 +31,42 @@ impl DS {
 
     pub fn get_value(&self, source_code: &str) -> String {
         match self {
-            Self::BulkString(start, end) => {
-                return source_code.get(*start..*end).expect("-ERROR Expected a value found nothing\r\n").to_owned();
+            Self::BulkString(start, end) | Self::String(start, end) | Self::Error(start, end) => {
+                source_code.get(*start..*end).expect("-ERROR Expected a value found nothing\r\n").to_owned()
             },
-            Self::String(start, end) => {
-                return source_code.get(*start..*end).expect("-ERROR Expected a value found nothing\r\n").to_owned();
+            Self::Integer(i) => {
+                i.to_string()
             },
             _ => {
-                return format!("{:?}", self);
+                format!("{:?}", self)
             }
         }
     }
 
     pub fn get_type(&self) -> String {
         match self {
-            Self::String(_, _) => {
-                return String::from("string");
-            },
-            Self::BulkString(_, _) => {
-                return String::from("string");
+            Self::String(_, _) | Self::BulkString(_, _) => {
+                String::from("string")
             },
             Self::RedArray(_) => {
-                return String::from("list");
+                String::from("list")
+            },
+            Self::Error(_, _) => {
+                String::from("error")
             },
+            Self::Integer(_) => {
+                String::from("integer")
+            },
+            Self::Null => {
+                String::from("none")
+            }
         }
     }
 }
 
 #[derive(Debug)]
 pub struct RedArray {
+    #[allow(dead_code)]
     pub length: usize,
     pub value: Vec<DS>
 }
@@ -71,104 +90,187 @@ impl RESPParser {
         self.current_index = 0;
     }
 
-    pub fn parse(&mut self) -> DS {
-        match self.source_code.chars().nth(self.current_index).unwrap() {
-            '*' => {
-                // Parse Array
-                return self.parse_array();
-            },
-            '$' => {
-                // Parse Bulk String
-                let s = self.parse_string();
-                self.advance();
-                return s;
-            }, 
-            '+' => {
-                self.parse_simple_string()
-            }
-            c => {
-                unreachable!("found: {}", c);
-                //return DS::Integer(2);
-            }
-        }
+    /// Byte offset right after the last value that was parsed successfully.
+    pub fn position(&self) -> usize {
+        self.current_index
     }
 
-    fn parse_simple_string(&mut self) -> DS {
-        self.advance();
-        let mut end_index = 0;
+    /// Parses the next value. On failure the position is left untouched so the caller can wait for
+    /// more data and try again.
+    pub fn try_parse(&mut self) -> Result<DS, ParseError> {
         let start_index = self.current_index;
-        loop {
-            match self.source_code.chars().nth(self.current_index) {
-                Some(curr_character) => {
-                    if curr_character == '\r' {
-                        self.advance();
-                        let curr_character = self.source_code.chars().nth(self.current_index).unwrap();
-                        if curr_character == '\n' {
-                            self.advance();
-                            break
-                        }
-                    }
-                    end_index += 1;
-                    self.advance();
-                },
-                None => break
-            };
+        let result = self.parse_value();
+        if result.is_err() {
+            self.current_index = start_index;
         }
-        return DS::String(start_index, start_index + end_index)
+        result
     }
 
-    fn advance(&mut self) {
-        if self.current_index < self.source_code.len() - 1 {
-            self.current_index += 1;
-            let mut curr_character = self.source_code.chars().nth(self.current_index).unwrap_or_else(|| return '\0');
-            if curr_character == '\0' {
-                return;
-            }
-            while curr_character == '\r' || curr_character == '\n' && self.current_index < self.source_code.len() {
+    fn parse_value(&mut self) -> Result<DS, ParseError> {
+        let marker = *self.source_code.as_bytes().get(self.current_index).ok_or(ParseError::Incomplete)?;
+        match marker {
+            b'*' => {
+                // Parse Array
+                self.current_index += 1;
+                let number_of_elements = self.parse_number()?;
+                if number_of_elements < 0 {
+                    return Ok(DS::Null);
+                }
+                let mut tokens: Vec<DS> = Vec::new();
+                for _ in 0..number_of_elements {
+                    tokens.push(self.parse_value()?);
+                }
+                Ok(DS::RedArray(RedArray {
+                    length: number_of_elements as usize,
+                    value: tokens
+                }))
+            },
+            b'$' => {
+                // Parse Bulk String
+                self.current_index += 1;
+                let str_len = self.parse_number()?;
+                if str_len < 0 {
+                    return Ok(DS::Null);
+                }
+                let start = self.current_index;
+                let end = start + str_len as usize;
+                if self.source_code.len() < end + 2 {
+                    return Err(ParseError::Incomplete);
+                }
+                if &self.source_code.as_bytes()[end..end + 2] != b"\r\n" {
+                    return Err(ParseError::Invalid("expected CRLF after bulk string".to_string()));
+                }
+                self.current_index = end + 2;
+                Ok(DS::BulkString(start, end))
+            },
+            b'+' => {
                 self.current_index += 1;
-                match self.source_code.chars().nth(self.current_index) {
-                    None => {
-                        break;
-                    },
-                    Some(x) => {
-                        curr_character = x;
-                    }
-                };
+                let (start, end) = self.parse_line()?;
+                Ok(DS::String(start, end))
+            },
+            b'-' => {
+                self.current_index += 1;
+                let (start, end) = self.parse_line()?;
+                Ok(DS::Error(start, end))
+            },
+            b':' => {
+                self.current_index += 1;
+                Ok(DS::Integer(self.parse_number()?))
+            },
+            _ => {
+                self.parse_inline()
             }
         }
     }
 
-    fn parse_array(&mut self) -> DS {
-        self.advance();
-        let number_of_elements = self.parse_number();
-        self.advance();
+    /// Reads up to the next CRLF and returns the range of the line without the terminator.
+    fn parse_line(&mut self) -> Result<(usize, usize), ParseError> {
+        let start = self.current_index;
+        let end = self.source_code[start..]
+            .find("\r\n")
+            .map(|x| start + x)
+            .ok_or(ParseError::Incomplete)?;
+        self.current_index = end + 2;
+        Ok((start, end))
+    }
+
+    fn parse_number(&mut self) -> Result<i64, ParseError> {
+        let (start, end) = self.parse_line()?;
+        self.source_code[start..end]
+            .parse::<i64>()
+            .map_err(|_| ParseError::Invalid(format!("invalid length {:?}", &self.source_code[start..end])))
+    }
+
+    /// Inline commands are plain space separated words ended by a newline, as sent by telnet.
+    fn parse_inline(&mut self) -> Result<DS, ParseError> {
+        let start = self.current_index;
+        let end = self.source_code[start..]
+            .find('\n')
+            .map(|x| start + x)
+            .ok_or(ParseError::Incomplete)?;
+        self.current_index = end + 1;
+        let line_end = if end > start && self.source_code.as_bytes()[end - 1] == b'\r' { end - 1 } else { end };
         let mut tokens: Vec<DS> = Vec::new();
-        for _ in 0..number_of_elements {
-            tokens.push(self.parse());
+        let mut word_start: Option<usize> = None;
+        for (i, c) in self.source_code[start..line_end].char_indices() {
+            if c.is_whitespace() {
+                if let Some(ws) = word_start.take() {
+                    tokens.push(DS::BulkString(ws, start + i));
+                }
+            } else if word_start.is_none() {
+                word_start = Some(start + i);
+            }
+        }
+        if let Some(ws) = word_start {
+            tokens.push(DS::BulkString(ws, line_end));
         }
-        return DS::RedArray(RedArray {
-            length: number_of_elements as usize,
+        Ok(DS::RedArray(RedArray {
+            length: tokens.len(),
             value: tokens
-        })
+        }))
     }
+}
 
-    fn parse_number(&mut self) -> u32 {
-        let mut x = String::from("");
-        while '0' <= self.source_code.chars().nth(self.current_index).expect("expected a char") && self.source_code.chars().nth(self.current_index).expect("expected a char") <= '9' {
-            x.push(self.source_code.chars().nth(self.current_index).expect("Expected a character"));
-            self.current_index += 1;
+/// Reads a command, an array of bulk strings as stored in the append only file, straight from
+/// the raw bytes. Returns its arguments and how many bytes it took: the offsets of the decoded
+/// text would be off as soon as a value isn't valid UTF-8.
+pub fn read_command(data: &[u8]) -> Result<(Vec<String>, usize), ParseError> {
+    let (count, mut index) = read_length(data, 0, b'*')?;
+    let mut argv = Vec::with_capacity(count);
+    for _ in 0..count {
+        let (length, start) = read_length(data, index, b'$')?;
+        let end = start + length;
+        if data.len() < end + 2 {
+            return Err(ParseError::Incomplete);
         }
+        if &data[end..end + 2] != b"\r\n" {
+            return Err(ParseError::Invalid("expected CRLF after a bulk string".to_string()));
+        }
+        argv.push(String::from_utf8_lossy(&data[start..end]).to_string());
+        index = end + 2;
+    }
+    Ok((argv, index))
+}
+
+/// Reads the `<marker><length>\r\n` header at `index`, returns the length and where the data
+/// after it starts.
+fn read_length(data: &[u8], index: usize, marker: u8) -> Result<(usize, usize), ParseError> {
+    match data.get(index) {
+        None => return Err(ParseError::Incomplete),
+        Some(x) if *x != marker => return Err(ParseError::Invalid(format!("expected '{}'", marker as char))),
+        _ => {}
+    }
+    let end = data[index + 1..]
+        .windows(2)
+        .position(|x| x == b"\r\n")
+        .map(|x| index + 1 + x)
+        .ok_or(ParseError::Incomplete)?;
+    let text = String::from_utf8_lossy(&data[index + 1..end]);
+    let length = text.parse::<usize>().map_err(|_| ParseError::Invalid(format!("invalid length {:?}", text)))?;
+    Ok((length, end + 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let to_return: u32 = x.parse().unwrap();
-        return to_return;
+    #[test]
+    fn read_command_counts_bytes() {
+        let mut data = b"*2\r\n$3\r\nGET\r\n$3\r\n\xff\xfe\xfd\r\n".to_vec();
+        let length = data.len();
+        data.extend_from_slice(b"*1\r\n$4\r\nPING\r\n");
+        let (argv, read) = read_command(&data).unwrap();
+        assert_eq!(read, length);
+        assert_eq!(argv, vec!["GET".to_string(), "\u{fffd}\u{fffd}\u{fffd}".to_string()]);
+        assert_eq!(read_command(&data[read..]).unwrap(), (vec!["PING".to_string()], 14));
     }
 
-    fn parse_string(&mut self) -> DS {
-        self.advance();
-        let str_len = self.parse_number() as usize;
-        self.advance();
-        self.current_index += str_len;
-        
-        return DS::BulkString(self.current_index - str_len, self.current_index);
+    #[test]
+    fn read_command_errors() {
+        assert_eq!(read_command(b""), Err(ParseError::Incomplete));
+        assert_eq!(read_command(b"*2\r\n$3\r\nGET\r\n$3\r\nke"), Err(ParseError::Incomplete));
+        assert_eq!(read_command(b"*1\r\n$3\r\nGETX\r\n"), Err(ParseError::Invalid("expected CRLF after a bulk string".to_string())));
+        assert_eq!(read_command(b"+OK\r\n"), Err(ParseError::Invalid("expected '*'".to_string())));
+        assert_eq!(read_command(b"*x\r\n"), Err(ParseError::Invalid("invalid length \"x\"".to_string())));
     }
 }
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+
+use crate::helpers::Helper;
+
+/// Who is subscribed to what, by channel name and by glob-style pattern. Shard channels are a
+/// namespace of their own, without a cluster every slot is served here.
+#[derive(Default)]
+pub struct PubSub {
+    // Subscribers of each channel or pattern, in the order they subscribed
+    channels: HashMap<String, Vec<u64>>,
+    patterns: HashMap<String, Vec<u64>>,
+    shard_channels: HashMap<String, Vec<u64>>,
+    // Subscriptions of each client, in the order they were made
+    client_channels: HashMap<u64, Vec<String>>,
+    client_patterns: HashMap<u64, Vec<String>>,
+    client_shard_channels: HashMap<u64, Vec<String>>,
+}
+
+/// A subscriber a published message has to be delivered to, with the pattern that matched when
+/// it subscribed with `PSUBSCRIBE`.
+pub struct Receiver {
+    pub client_id: u64,
+    pub pattern: Option<String>,
+}
+
+impl PubSub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns false when the client was already subscribed.
+    pub fn subscribe(&mut self, client_id: u64, channel: &str) -> bool {
+        Self::add(&mut self.channels, &mut self.client_channels, client_id, channel)
+    }
+
+    pub fn unsubscribe(&mut self, client_id: u64, channel: &str) -> bool {
+        Self::remove(&mut self.channels, &mut self.client_channels, client_id, channel)
+    }
+
+    pub fn psubscribe(&mut self, client_id: u64, pattern: &str) -> bool {
+        Self::add(&mut self.patterns, &mut self.client_patterns, client_id, pattern)
+    }
+
+    pub fn punsubscribe(&mut self, client_id: u64, pattern: &str) -> bool {
+        Self::remove(&mut self.patterns, &mut self.client_patterns, client_id, pattern)
+    }
+
+    pub fn ssubscribe(&mut self, client_id: u64, channel: &str) -> bool {
+        Self::add(&mut self.shard_channels, &mut self.client_shard_channels, client_id, channel)
+    }
+
+    pub fn sunsubscribe(&mut self, client_id: u64, channel: &str) -> bool {
+        Self::remove(&mut self.shard_channels, &mut self.client_shard_channels, client_id, channel)
+    }
+
+    fn add(subscribers: &mut HashMap<String, Vec<u64>>, subscriptions: &mut HashMap<u64, Vec<String>>, client_id: u64, name: &str) -> bool {
+        let clients = subscribers.entry(name.to_string()).or_default();
+        if clients.contains(&client_id) {
+            return false;
+        }
+        clients.push(client_id);
+        subscriptions.entry(client_id).or_default().push(name.to_string());
+        true
+    }
+
+    fn remove(subscribers: &mut HashMap<String, Vec<u64>>, subscriptions: &mut HashMap<u64, Vec<String>>, client_id: u64, name: &str) -> bool {
+        let clients = match subscribers.get_mut(name) {
+            Some(x) => x,
+            None => return false
+        };
+        if !clients.contains(&client_id) {
+            return false;
+        }
+        clients.retain(|x| *x != client_id);
+        if clients.is_empty() {
+            subscribers.remove(name);
+        }
+        if let Some(names) = subscriptions.get_mut(&client_id) {
+            names.retain(|x| x != name);
+            if names.is_empty() {
+                subscriptions.remove(&client_id);
+            }
+        }
+        true
+    }
+
+    pub fn channels_of(&self, client_id: u64) -> Vec<String> {
+        self.client_channels.get(&client_id).cloned().unwrap_or_default()
+    }
+
+    pub fn patterns_of(&self, client_id: u64) -> Vec<String> {
+        self.client_patterns.get(&client_id).cloned().unwrap_or_default()
+    }
+
+    pub fn shard_channels_of(&self, client_id: u64) -> Vec<String> {
+        self.client_shard_channels.get(&client_id).cloned().unwrap_or_default()
+    }
+
+    /// Whether the client subscribed to anything, which restricts the commands it can send.
+    pub fn is_subscribed(&self, client_id: u64) -> bool {
+        self.subscription_count(client_id) > 0 || self.shard_subscription_count(client_id) > 0
+    }
+
+    pub fn shard_subscription_count(&self, client_id: u64) -> usize {
+        self.client_shard_channels.get(&client_id).map(|x| x.len()).unwrap_or(0)
+    }
+
+    /// Channels and patterns the client is subscribed to, what the subscribe replies report.
+    pub fn subscription_count(&self, client_id: u64) -> usize {
+        self.client_channels.get(&client_id).map(|x| x.len()).unwrap_or(0)
+            + self.client_patterns.get(&client_id).map(|x| x.len()).unwrap_or(0)
+    }
+
+    /// Drops every subscription of a client that disconnected.
+    pub fn remove_client(&mut self, client_id: u64) {
+        for channel in self.channels_of(client_id) {
+            self.unsubscribe(client_id, &channel);
+        }
+        for pattern in self.patterns_of(client_id) {
+            self.punsubscribe(client_id, &pattern);
+        }
+        for channel in self.shard_channels_of(client_id) {
+            self.sunsubscribe(client_id, &channel);
+        }
+    }
+
+    /// Everyone a message published to the channel goes to. A client subscribed both to the
+    /// channel and to matching patterns gets the message once for each of them.
+    pub fn receivers(&self, channel: &str) -> Vec<Receiver> {
+        let mut receivers: Vec<Receiver> = self.channels.get(channel)
+            .map(|clients| clients.iter().map(|x| Receiver { client_id: *x, pattern: None }).collect())
+            .unwrap_or_default();
+        for (pattern, clients) in &self.patterns {
+            if Helper::glob_match(pattern, channel) {
+                receivers.extend(clients.iter().map(|x| Receiver { client_id: *x, pattern: Some(pattern.clone()) }));
+            }
+        }
+        receivers
+    }
+
+    pub fn shard_receivers(&self, channel: &str) -> Vec<u64> {
+        self.shard_channels.get(channel).cloned().unwrap_or_default()
+    }
+
+    /// Channels with at least one subscriber, optionally filtered by a glob-style pattern.
+    pub fn active_channels(&self, pattern: Option<&str>) -> Vec<String> {
+        Self::active(&self.channels, pattern)
+    }
+
+    pub fn active_shard_channels(&self, pattern: Option<&str>) -> Vec<String> {
+        Self::active(&self.shard_channels, pattern)
+    }
+
+    fn active(subscribers: &HashMap<String, Vec<u64>>, pattern: Option<&str>) -> Vec<String> {
+        subscribers.keys()
+            .filter(|x| match pattern {
+                Some(pattern) => Helper::glob_match(pattern, x),
+                None => true
+            })
+            .cloned()
+            .collect()
+    }
+
+    pub fn numsub(&self, channel: &str) -> usize {
+        self.channels.get(channel).map(|x| x.len()).unwrap_or(0)
+    }
+
+    pub fn shardnumsub(&self, channel: &str) -> usize {
+        self.shard_channels.get(channel).map(|x| x.len()).unwrap_or(0)
+    }
+
+    pub fn numpat(&self) -> usize {
+        self.patterns.len()
+    }
+}
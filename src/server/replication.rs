@@ -0,0 +1,128 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+
+pub enum ReplicaState {
+    // Needs a snapshot but none can be started right now
+    WaitBgsaveStart,
+    // A snapshot of the dataset is being produced, propagated writes are buffered meanwhile
+    WaitBgsaveEnd,
+    // The snapshot was handed over, the replica receives the stream of writes
+    Online,
+}
+
+/// A connection that asked for the replication stream with `PSYNC`. Writes are propagated to it
+/// for as long as the connection stays open.
+pub struct Replica {
+    pub client_id: u64,
+    // Where other nodes can reach the replica, its address and the port from `REPLCONF listening-port`
+    pub ip: String,
+    pub listening_port: u32,
+    pub state: ReplicaState,
+    // Propagated bytes not yet handed over to the connection
+    pub output: Vec<u8>,
+    // Offset of the replication stream the snapshot being produced corresponds to
+    pub psync_offset: u64,
+    // What the replica reported with `REPLCONF ACK <offset> [FACK <aof offset>]`
+    pub ack_offset: u64,
+    pub aof_ack_offset: u64,
+    pub ack_time: Instant,
+}
+
+impl ReplicaState {
+    /// How `INFO replication` names the state.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReplicaState::WaitBgsaveStart | ReplicaState::WaitBgsaveEnd => "wait_bgsave",
+            ReplicaState::Online => "online"
+        }
+    }
+}
+
+impl Replica {
+    pub fn new(client_id: u64, ip: String, listening_port: u32) -> Self {
+        Self {
+            client_id,
+            ip,
+            listening_port,
+            state: ReplicaState::WaitBgsaveStart,
+            output: vec![],
+            psync_offset: 0,
+            ack_offset: 0,
+            aof_ack_offset: 0,
+            ack_time: Instant::now()
+        }
+    }
+}
+
+/// A client blocked by `WAIT` or `WAITAOF` until enough replicas acknowledged the replication
+/// stream up to `offset`, or the timeout expires.
+pub struct WaitingClient {
+    pub client_id: u64,
+    pub offset: u64,
+    pub numreplicas: u64,
+    // For `WAITAOF`, how many local fsyncs were done, the replicas have to fsync too
+    pub numlocal: Option<u64>,
+    pub deadline: Option<Instant>,
+}
+
+/// Commands received from the master are interpreted as coming from this client.
+pub const MASTER_CLIENT_ID: u64 = 0;
+
+/// The most recent part of the replication stream, kept so that a replica whose link dropped
+/// for a moment can get just the bytes it missed instead of a full resynchronization.
+pub struct ReplicationBacklog {
+    // Used as a ring buffer: new bytes are pushed at the back, the oldest drop off the front
+    buffer: VecDeque<u8>,
+    size: usize,
+    // Replication offset of the first byte held
+    start_offset: u64,
+}
+
+impl ReplicationBacklog {
+    pub fn new(size: u64, offset: u64) -> Self {
+        Self {
+            buffer: VecDeque::new(),
+            size: size as usize,
+            start_offset: offset
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buffer.extend(data);
+        self.trim();
+    }
+
+    pub fn resize(&mut self, size: u64) {
+        self.size = size as usize;
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        if self.buffer.len() > self.size {
+            let excess = self.buffer.len() - self.size;
+            self.buffer.drain(..excess);
+            self.start_offset += excess as u64;
+        }
+    }
+
+    /// Replication offset of the oldest byte held.
+    pub fn first_byte_offset(&self) -> u64 {
+        self.start_offset
+    }
+
+    pub fn histlen(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Everything from `offset` to the end of the stream, `None` when it is no longer held.
+    pub fn since(&self, offset: u64) -> Option<Vec<u8>> {
+        if offset < self.start_offset || offset > self.start_offset + self.buffer.len() as u64 {
+            return None;
+        }
+        Some(self.buffer.range((offset - self.start_offset) as usize..).copied().collect())
+    }
+}
@@ -0,0 +1,20 @@
+use std::time::Instant;
+
+use crate::scripting::engine::ScriptRun;
+
+/// A script started by `EVAL`, its client waits for the reply.
+pub struct RunningScript {
+    pub run: ScriptRun,
+    pub client_id: u64,
+    pub started: Instant,
+    // Once it wrote, `SCRIPT KILL` can't stop it anymore
+    pub wrote: bool,
+    // `EVAL_RO`, or a script with the `no-writes` flag
+    pub read_only: bool,
+    // Run by `EXEC`, its writes are propagated with the ones of the transaction
+    pub nested: bool,
+    // Run by `FCALL`, `FUNCTION KILL` stops it instead of `SCRIPT KILL`
+    pub function: bool,
+    // Went on in the background after `busy-reply-threshold`, the other clients get `-BUSY`
+    pub detached: bool,
+}
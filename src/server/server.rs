@@ -1,11 +1,20 @@
-use std::net::{TcpListener, TcpStream};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
 use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::fs;
+use std::time::{Duration, Instant};
 
 use crate::datastore::store::DataStore;
-use crate::server::parser::RESPParser;
-use crate::server::interpreter::{RESPInterpreter, Reply};
-use crate::server::client_replication_interpreter::ReplicationInterpreter;
-use crate::helpers::Helper;
+use crate::server::parser::{read_command, RESPParser, ParseError, DS};
+use crate::server::interpreter::RESPInterpreter;
+use crate::aof::aof::{AppendFsync, AppendOnlyFile};
+use crate::rdb::rdb::RDBFileHelper;
+use crate::scripting::functions::RestorePolicy;
+use crate::server::acl::Acl;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(16);
 
 #[derive(Debug,Clone)]
 pub struct SlaveServerOptions {
@@ -16,7 +25,11 @@ pub struct SlaveServerOptions {
 #[derive(Debug,Clone)]
 pub struct MasterServerOptions {
     pub master_replid: String,
-    pub master_repl_offset: u32,
+    pub master_repl_offset: u64,
+    // The replication id of the previous master, still accepted for partial resynchronizations
+    // up to `second_repl_offset`
+    pub master_replid2: String,
+    pub second_repl_offset: i64,
 }
 
 
@@ -26,24 +39,116 @@ pub enum ServerRole {
     Slave(SlaveServerOptions)
 }
 
+#[derive(Debug,Clone)]
+pub struct SaveParam {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
 #[derive(Debug,Clone)]
 pub struct ServerOptions {
     pub rdb_file_name: Option<std::path::PathBuf>,
     pub rdb_dir_name: Option<std::path::PathBuf>,
     pub port: Option<u32>,
     pub server_role: Option<ServerRole>,
+    pub save_params: Vec<SaveParam>,
+    pub appendonly: bool,
+    pub appendfilename: String,
+    pub appendfsync: AppendFsync,
+    pub aof_load_truncated: bool,
+    pub appenddirname: String,
+    pub aof_use_rdb_preamble: bool,
+    pub auto_aof_rewrite_percentage: u64,
+    pub auto_aof_rewrite_min_size: u64,
+    pub repl_diskless_sync: bool,
+    pub repl_backlog_size: u64,
+    // Seconds without news from the other side before a replication link is dropped
+    pub repl_timeout: u64,
+    pub repl_ping_replica_period: u64,
+    pub replica_read_only: bool,
+    pub replica_serve_stale_data: bool,
+    pub replica_announce_ip: Option<String>,
+    // 0 announces the port we listen on
+    pub replica_announce_port: u32,
+    // Classes of keyspace events published, see `notifications`
+    pub notify_keyspace_events: u32,
+    // Milliseconds a script runs before other clients are answered with `-BUSY`
+    pub busy_reply_threshold: u64,
+    // Password clients must give with `AUTH`, none when not set
+    pub requirepass: Option<String>,
+    // Password a replica gives its master during the handshake
+    pub masterauth: Option<String>,
+    // The users, `requirepass` being the password of the default one
+    pub acl: Acl,
+    // Where `ACL LOAD` and `ACL SAVE` read and write the users, also loaded at startup
+    pub aclfile: Option<String>,
+    pub acllog_max_len: usize,
 }
 
 pub struct Server {
     listener: TcpListener,
     clients: Vec<Client>,
+    next_client_id: u64,
     store: DataStore,
     pub server_options: ServerOptions,
-    pub replication_stream: Option<TcpStream>
+    pub replication_stream: Option<TcpStream>,
+    // The master the replication stream is connected to
+    master_address: Option<(String, u32)>,
+    last_connect_attempt: Option<Instant>,
+    // Doubles after every failed attempt to connect to the master
+    reconnect_delay: Duration
 }
 
 pub struct Client {
+    pub id: u64,
     pub client: TcpStream,
+    // Bytes received but not yet forming a complete command
+    pub query_buffer: Vec<u8>,
+    // Bytes waiting for the socket to accept them
+    pub output_buffer: Vec<u8>,
+    pub closed: bool,
+    // Waiting for a reply, like the one of `WAIT`, before the next commands are served
+    pub blocked: bool,
+}
+
+impl Client {
+    /// Writes as much of the output buffer as the socket accepts without blocking.
+    fn flush_output(&mut self) {
+        while !self.output_buffer.is_empty() {
+            match self.client.write(&self.output_buffer) {
+                Ok(0) => {
+                    self.closed = true;
+                    return;
+                },
+                Ok(written) => {
+                    self.output_buffer.drain(..written);
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => {
+                    return;
+                },
+                Err(_) => {
+                    self.closed = true;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// The next complete command of the query buffer from `offset`, as RESP text, and how many
+    /// bytes it took. Only whole commands are decoded, a character or a binary value split
+    /// between two reads stays intact.
+    fn next_command(&self, offset: usize) -> Result<(String, usize), ParseError> {
+        let data = &self.query_buffer[offset..];
+        if data.first() == Some(&b'*') {
+            let (argv, length) = read_command(data)?;
+            return Ok((AppendOnlyFile::encode_command(&argv), length));
+        }
+        // Inline commands end with the line
+        match data.iter().position(|x| *x == b'\n') {
+            Some(end) => Ok((String::from_utf8_lossy(&data[..=end]).to_string(), end + 1)),
+            None => Err(ParseError::Incomplete)
+        }
+    }
 }
 
 impl Server {
@@ -53,32 +158,116 @@ impl Server {
                 Self {
                     listener: TcpListener::bind(address).unwrap(),
                     clients: vec![],
+                    next_client_id: 1,
                     store: data,
                     server_options: server_opts,
-                    replication_stream: None
+                    replication_stream: None,
+                    master_address: None,
+                    last_connect_attempt: None,
+                    reconnect_delay: MIN_RECONNECT_DELAY
                 }
             },
             None => {
                 Self {
                     listener: TcpListener::bind(address).unwrap(),
                     clients: vec![],
+                    next_client_id: 1,
                     store: DataStore::new(),
                     server_options: server_opts,
-                    replication_stream: None
+                    replication_stream: None,
+                    master_address: None,
+                    last_connect_attempt: None,
+                    reconnect_delay: MIN_RECONNECT_DELAY
+                }
+            }
+        }
+    }
+
+    /// Rebuilds the dataset from the append only log: the base file, which may be an RDB
+    /// snapshot, followed by every incremental file listed in the manifest.
+    pub fn load_append_only_file(&mut self) -> Result<(), String> {
+        let (dir, legacy_path) = (self.server_options.aof_dir(), self.server_options.aof_path());
+        let file_name = self.server_options.appendfilename.clone();
+        if !self.server_options.appendonly || !AppendOnlyFile::exists(&dir, &legacy_path, &file_name) {
+            return Ok(());
+        }
+        let manifest = AppendOnlyFile::resolve_manifest(&dir, &legacy_path, &file_name)?;
+        let files = manifest.files();
+        let mut commands = 0;
+        for (i, info) in files.iter().enumerate() {
+            let path = dir.join(&info.file_name);
+            let content = fs::read(&path).map_err(|e| format!("Can't read the append only file {:?}: {}", path, e))?;
+            if content.starts_with(b"REDIS") {
+                let base = RDBFileHelper::decode(&content).map_err(|_| format!("Bad RDB format reading the append only file base {:?}", path))?;
+                self.store.extend(base.data);
+                self.store.functions.restore(&base.functions, RestorePolicy::Append)
+                    .map_err(|e| format!("Can't load the functions of the append only file base {:?}: {}", path, e))?;
+                continue;
+            }
+            commands += self.replay_commands(&path, &content, i == files.len() - 1)?;
+        }
+        println!("DB loaded from append only file: {} commands", commands);
+        Ok(())
+    }
+
+    /// Replays one file of the log. A truncated last command, usually left by a crash in the
+    /// middle of a write, is cut off when it is in the last file and `aof-load-truncated` allows it.
+    fn replay_commands(&mut self, path: &std::path::Path, content: &[u8], is_last_file: bool) -> Result<usize, String> {
+        let aof_load_truncated = self.server_options.aof_load_truncated;
+        let mut interpreter = RESPInterpreter::new(&mut self.store, &mut self.server_options);
+        interpreter.set_loading(true);
+        let mut commands = 0;
+        // Commands are cut from the raw bytes, so offsets stay right with binary values
+        let mut offset = 0;
+        while offset < content.len() {
+            match read_command(&content[offset..]) {
+                Ok((argv, length)) => {
+                    offset += length;
+                    if argv.is_empty() {
+                        continue;
+                    }
+                    let source_code = AppendOnlyFile::encode_command(&argv);
+                    let mut rp = RESPParser::new();
+                    rp.register(&source_code);
+                    interpreter.register(&source_code);
+                    if let Ok(ds) = rp.try_parse() {
+                        interpreter.interpret(ds);
+                    }
+                    commands += 1;
+                },
+                Err(ParseError::Incomplete) => {
+                    if !aof_load_truncated || !is_last_file {
+                        return Err(format!("Unexpected end of file reading the append only file {:?} at offset {}", path, offset));
+                    }
+                    println!("WARNING The append only file {:?} is truncated, discarding the last {} bytes", path, content.len() - offset);
+                    let file = fs::OpenOptions::new().write(true).open(path).map_err(|e| format!("Can't truncate the append only file: {}", e))?;
+                    file.set_len(offset as u64).map_err(|e| format!("Can't truncate the append only file: {}", e))?;
+                    break;
+                },
+                Err(ParseError::Invalid(e)) => {
+                    return Err(format!("Bad file format reading the append only file {:?} at offset {}: {}", path, offset, e));
                 }
             }
         }
+        Ok(commands)
     }
 
-    pub fn connect_to_master(&mut self) {
-        match &self.server_options.server_role {
-            Some(ServerRole::Slave(slave_options)) => {
-                let mut master_connection_stream = TcpStream::connect(format!("{}:{}", slave_options.master_host, slave_options.master_port)).unwrap();
-                let _ = master_connection_stream.write(Helper::build_resp(&Reply::ReplyArray(vec![Reply::ReplyBulkString("PING".to_string())])).as_bytes());
-                self.replication_stream = Some(master_connection_stream);
+    /// Opens the link with the master without stalling the event loop for long when it is
+    /// unreachable.
+    fn connect(host: &str, port: u32) -> std::io::Result<TcpStream> {
+        let mut last_error = std::io::Error::new(ErrorKind::NotFound, "can't resolve the master address");
+        for address in (host, port as u16).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    stream.set_nonblocking(true)?;
+                    return Ok(stream);
+                },
+                Err(e) => {
+                    last_error = e;
+                }
             }
-            _ => {}
         }
+        Err(last_error)
     }
 
     pub fn run_event_loop(&mut self) {
@@ -88,72 +277,216 @@ impl Server {
         // 2. Is any connection which we already have is sending something?
         // 3. Are we getting any new message from the replication_stream?
         let mut rp = RESPParser::new();
-        let mut client_interpreter = ReplicationInterpreter::new(None, &self.server_options.port.unwrap_or(6379));
         let mut interpreter = RESPInterpreter::new(&mut self.store, &mut self.server_options);
-        self.listener.set_nonblocking(true).unwrap();
-        if let Some(rs) = &self.replication_stream {
-            let _ = rs.set_nonblocking(true);
+        if interpreter.appendonly() {
+            if let Err(e) = interpreter.start_append_only(false) {
+                panic!("Can't open the append only file: {}", e);
+            }
         }
+        self.listener.set_nonblocking(true).unwrap();
         loop {
+            interpreter.cron();
+
             // Check 1 ie... for any new connection
-            match self.listener.accept() {
-                Ok(stream) => {
-                    println!("New connection found {:?}", stream.1);
-                    stream.0.set_nonblocking(true).unwrap();
-                    self.clients.push(Client {
-                        client: stream.0,
-                    });
-                },
-                Err(_) => {}
+            if let Ok(stream) = self.listener.accept() {
+                println!("New connection found {:?}", stream.1);
+                stream.0.set_nonblocking(true).unwrap();
+                interpreter.client_connected(self.next_client_id, stream.1);
+                self.clients.push(Client {
+                    id: self.next_client_id,
+                    client: stream.0,
+                    query_buffer: vec![],
+                    output_buffer: vec![],
+                    closed: false,
+                    blocked: false,
+                });
+                self.next_client_id += 1;
             }
 
             // Check 2 looping through every client and checking for new messages
             for client in &mut self.clients {
                 let mut data: [u8; 1024] = [0; 1024]; 
                 let data_read = client.client.read(&mut data);
-                if let Ok(read_size) = data_read {
-                    if read_size == 0 {
+                let mut received = false;
+                match data_read {
+                    Ok(0) => {
+                        client.closed = true;
+                    },
+                    Err(e) if e.kind() != ErrorKind::WouldBlock && e.kind() != ErrorKind::Interrupted => {
+                        client.closed = true;
+                    },
+                    Err(_) => {},
+                    Ok(read_size) => {
+                        client.query_buffer.extend_from_slice(&data[..read_size]);
+                        received = true;
+                    }
+                }
+                if client.blocked {
+                    if interpreter.is_blocked(client.id) {
                         continue;
-                    } else {
-                        let str_message = String::from_utf8(data.to_vec()).unwrap();
-                        let message = str_message.trim().replace("\0", "");
-                        rp.register(&message);
-                        interpreter.register(&message);
-                        let ds = rp.parse();
-                        let response = interpreter.interpret(ds);
-                        for resp in response {
-                            match resp {
-                                super::interpreter::InterpreterResponse::Bytes(b) => {
-                                    let _ = client.client.write(&b);
-                                },
-                                super::interpreter::InterpreterResponse::String(s) => {
-                                    let _ = client.client.write(s.as_bytes());
-                                }
+                    }
+                    // The reply that unblocked the client goes before the ones of the commands
+                    // that were queued behind
+                    client.blocked = false;
+                    if let Some(output) = interpreter.take_client_output(client.id) {
+                        client.output_buffer.extend_from_slice(&output);
+                    }
+                    received = true;
+                }
+                if !received || client.query_buffer.is_empty() {
+                    continue;
+                }
+                // Messages published to the client earlier go out before these replies
+                if let Some(output) = interpreter.take_client_output(client.id) {
+                    client.output_buffer.extend_from_slice(&output);
+                }
+                interpreter.set_client(client.id);
+                // A single read can carry several pipelined commands, or only part of one
+                let mut consumed = 0;
+                while consumed < client.query_buffer.len() {
+                    let source_code = match client.next_command(consumed) {
+                        Ok((source_code, length)) => {
+                            consumed += length;
+                            source_code
+                        },
+                        Err(ParseError::Incomplete) => break,
+                        Err(ParseError::Invalid(e)) => {
+                            client.output_buffer.extend_from_slice(format!("-ERR Protocol error: {}\r\n", e).as_bytes());
+                            consumed = client.query_buffer.len();
+                            break;
+                        }
+                    };
+                    rp.register(&source_code);
+                    interpreter.register(&source_code);
+                    let response = match rp.try_parse() {
+                        Ok(DS::RedArray(a)) if a.value.is_empty() => continue,
+                        Ok(ds) => interpreter.interpret(ds),
+                        Err(_) => continue
+                    };
+                    for resp in response {
+                        match resp {
+                            super::interpreter::InterpreterResponse::String(s) => {
+                                client.output_buffer.extend_from_slice(s.as_bytes());
                             }
                         }
                     }
+                    if interpreter.is_blocked(client.id) {
+                        client.blocked = true;
+                        break;
+                    }
+                    if interpreter.shutdown_requested() {
+                        break;
+                    }
                 }
+                client.query_buffer.drain(..consumed);
             }
+            interpreter.serve_waiting_clients();
 
-            match &mut self.replication_stream {
-                Some(replication_stream) => {
-                    let mut replication_data: [u8; 1024] = [0; 1024];
-                    let replication_data_read = replication_stream.read(&mut replication_data);
-                    if let Ok(read_size) = replication_data_read {
-                        if read_size != 0 {
-                            let str_message = String::from_utf8_lossy(&replication_data);
-                            let message = str_message.trim().replace("\0", "");
-                            rp.register(&message);
-                            let ds = rp.parse();
-                            client_interpreter.register(ds, &message);
-                            let response = client_interpreter.interpret();
-                            if let Some(response) = response {
-                                let _ = replication_stream.write(response.as_bytes());
-                            }
+            // Writes propagated during this iteration go out to every replica, then each client
+            // gets as much of its pending output as its socket takes
+            for client in &mut self.clients {
+                if let Some(output) = interpreter.take_replica_output(client.id) {
+                    client.output_buffer.extend_from_slice(&output);
+                }
+                if !client.blocked {
+                    if let Some(output) = interpreter.take_client_output(client.id) {
+                        client.output_buffer.extend_from_slice(&output);
+                    }
+                }
+                if interpreter.should_close(client.id) {
+                    client.closed = true;
+                }
+                client.flush_output();
+            }
+            if interpreter.shutdown_requested() {
+                interpreter.prepare_shutdown();
+                for client in &self.clients {
+                    let _ = client.client.shutdown(Shutdown::Both);
+                }
+                return;
+            }
+            self.clients.retain(|client| {
+                if client.closed {
+                    interpreter.client_closed(client.id);
+                }
+                !client.closed
+            });
+
+            // Follow the master set at startup or with `REPLICAOF`, retrying while it is unreachable
+            let master_address = interpreter.master_address();
+            if master_address != self.master_address {
+                self.replication_stream = None;
+                self.master_address = master_address;
+                self.last_connect_attempt = None;
+                self.reconnect_delay = MIN_RECONNECT_DELAY;
+            }
+            if let (Some((host, port)), None) = (&self.master_address, &self.replication_stream) {
+                let retry_due = match self.last_connect_attempt {
+                    Some(attempt) => attempt.elapsed() >= self.reconnect_delay,
+                    None => true
+                };
+                if retry_due {
+                    self.last_connect_attempt = Some(Instant::now());
+                    match Self::connect(host, *port) {
+                        Ok(mut stream) => {
+                            println!("MASTER <-> REPLICA sync started");
+                            let _ = stream.write_all(&interpreter.master_link_connected());
+                            self.replication_stream = Some(stream);
+                        },
+                        Err(e) => {
+                            self.reconnect_delay = (self.reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+                            println!("Error condition on socket for SYNC: {}, retrying in {}s", e, self.reconnect_delay.as_secs());
+                        }
+                    }
+                }
+            }
+
+            let mut link_lost = false;
+            // The stream of the master waits for the running script, like every other client
+            if let (Some(replication_stream), false) = (&mut self.replication_stream, interpreter.script_running()) {
+                let mut replication_data: [u8; 1024] = [0; 1024];
+                let received = match replication_stream.read(&mut replication_data) {
+                    Ok(0) => {
+                        link_lost = true;
+                        0
+                    },
+                    Ok(read_size) => read_size,
+                    Err(e) if e.kind() != ErrorKind::WouldBlock && e.kind() != ErrorKind::Interrupted => {
+                        link_lost = true;
+                        0
+                    },
+                    Err(_) => 0
+                };
+                let mut output = match interpreter.process_master_link(&replication_data[..received]) {
+                    Ok(output) => output,
+                    Err(e) => {
+                        println!("ERROR {}", e);
+                        link_lost = true;
+                        vec![]
+                    }
+                };
+                match interpreter.master_link_cron() {
+                    Ok(mut ack) => output.append(&mut ack),
+                    Err(e) => {
+                        println!("{}", e);
+                        link_lost = true;
+                    }
+                }
+                if !link_lost && !output.is_empty() {
+                    if let Err(e) = replication_stream.write_all(&output) {
+                        if e.kind() != ErrorKind::WouldBlock {
+                            link_lost = true;
                         }
                     }
                 }
-                None => {}
+            }
+            if interpreter.master_link_up() {
+                self.reconnect_delay = MIN_RECONNECT_DELAY;
+            }
+            if link_lost {
+                println!("Connection with master lost");
+                interpreter.master_link_lost();
+                self.replication_stream = None;
             }
         }
     }
@@ -0,0 +1,98 @@
+use std::collections::{HashMap, HashSet};
+
+/// How a client enabled `CLIENT TRACKING`.
+#[derive(Default, Clone)]
+pub struct TrackingOptions {
+    // Client that receives the invalidation messages instead, over `__redis__:invalidate`
+    pub redirect: Option<u64>,
+    // Invalidations for every key matching the prefixes, whether the client read it or not
+    pub bcast: bool,
+    pub prefixes: Vec<String>,
+    // Only the keys read right after `CLIENT CACHING yes` are tracked, or all but the ones read
+    // right after `CLIENT CACHING no`
+    pub optin: bool,
+    pub optout: bool,
+    // No invalidation for the keys the client modified itself
+    pub noloop: bool,
+}
+
+/// Server side of client-side caching: which clients may hold a copy of which keys.
+#[derive(Default)]
+pub struct Tracking {
+    clients: HashMap<u64, TrackingOptions>,
+    // Clients that read each key since it was last invalidated
+    keys: HashMap<String, HashSet<u64>>,
+    // Set with `CLIENT CACHING`, for the next command of the client only
+    caching: HashMap<u64, bool>,
+}
+
+impl Tracking {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn enable(&mut self, client_id: u64, options: TrackingOptions) {
+        self.clients.insert(client_id, options);
+    }
+
+    /// Also when the client disconnects. The keys it read are forgotten with it, so the table
+    /// doesn't keep growing with ids no one will ever be invalidated for.
+    pub fn disable(&mut self, client_id: u64) {
+        self.caching.remove(&client_id);
+        if self.clients.remove(&client_id).is_none() {
+            return;
+        }
+        self.keys.retain(|_, clients| {
+            clients.remove(&client_id);
+            !clients.is_empty()
+        });
+    }
+
+    pub fn options(&self, client_id: u64) -> Option<&TrackingOptions> {
+        self.clients.get(&client_id)
+    }
+
+    pub fn set_caching(&mut self, client_id: u64, caching: bool) {
+        self.caching.insert(client_id, caching);
+    }
+
+    /// Called before every command but `CLIENT CACHING`, returns what applies to it.
+    pub fn take_caching(&mut self, client_id: u64) -> Option<bool> {
+        self.caching.remove(&client_id)
+    }
+
+    /// The client read the key, remembers it unless its mode says otherwise.
+    pub fn key_read(&mut self, client_id: u64, key: &str, caching: Option<bool>) {
+        let options = match self.clients.get(&client_id) {
+            Some(x) => x,
+            None => return
+        };
+        if options.bcast || (options.optin && caching != Some(true)) || (options.optout && caching == Some(false)) {
+            return;
+        }
+        self.keys.entry(key.to_string()).or_default().insert(client_id);
+    }
+
+    /// Clients to invalidate the key for, after `writer` modified it. In the default mode they
+    /// have to read it again to keep tracking it.
+    pub fn key_modified(&mut self, key: &str, writer: u64) -> Vec<(u64, TrackingOptions)> {
+        let mut targets: Vec<u64> = self.keys.remove(key).map(|x| x.into_iter().collect()).unwrap_or_default();
+        for (client_id, options) in &self.clients {
+            if options.bcast && (options.prefixes.is_empty() || options.prefixes.iter().any(|x| key.starts_with(x.as_str()))) {
+                targets.push(*client_id);
+            }
+        }
+        targets.sort();
+        targets.dedup();
+        targets.into_iter()
+            .filter_map(|x| self.clients.get(&x).map(|options| (x, options.clone())))
+            .filter(|(x, options)| !(options.noloop && *x == writer))
+            .collect()
+    }
+
+    /// Every tracking client has to drop its whole cache, after a flush.
+    pub fn flush(&mut self) -> Vec<(u64, TrackingOptions)> {
+        self.keys.clear();
+        self.clients.iter().map(|(x, options)| (*x, options.clone())).collect()
+    }
+}
@@ -0,0 +1,8 @@
+/// The state of a client between `MULTI` and `EXEC`.
+#[derive(Default)]
+pub struct Transaction {
+    // Arguments of every queued command, the command name first
+    pub commands: Vec<Vec<String>>,
+    // A command couldn't be queued, `EXEC` will refuse to run the others
+    pub dirty: bool,
+}
@@ -0,0 +1,135 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::aof::aof::AOFManifest;
+use crate::server::parser::{read_command, ParseError};
+use crate::tools::check_rdb;
+
+/// The outcome of checking one file of the log.
+struct FileCheck {
+    valid_up_to: usize,
+    length: usize,
+    error: Option<String>
+}
+
+/// `mmdb-check-aof [--fix] <file.aof|file.manifest>`: validates the append only log offline.
+/// Given a manifest every file it lists is checked in order. With `--fix` the last file is
+/// truncated to its last valid command.
+pub fn run(args: Vec<String>) -> i32 {
+    let fix = args.iter().any(|x| x == "--fix");
+    let paths: Vec<&String> = args.iter().filter(|x| *x != "--fix").collect();
+    let path = match paths.first() {
+        Some(x) if paths.len() == 1 => PathBuf::from(x),
+        _ => {
+            println!("Usage: mmdb-check-aof [--fix] <file.aof|file.manifest>");
+            return 1;
+        }
+    };
+
+    let files: Vec<PathBuf> = if path.extension().is_some_and(|x| x == "manifest") {
+        let manifest = match AOFManifest::load(&path) {
+            Ok(x) => x,
+            Err(e) => {
+                println!("{}", e);
+                return 1;
+            }
+        };
+        let dir = path.parent().unwrap_or(Path::new("."));
+        manifest.files().iter().map(|x| dir.join(&x.file_name)).collect()
+    } else {
+        vec![path]
+    };
+
+    for (i, file) in files.iter().enumerate() {
+        let is_last = i == files.len() - 1;
+        let content = match fs::read(file) {
+            Ok(x) => x,
+            Err(e) => {
+                println!("Cannot open {:?}: {}", file, e);
+                return 1;
+            }
+        };
+        println!("Checking {:?}", file);
+        let result = if content.starts_with(b"REDIS") {
+            let (ok, read) = check_rdb::check(&content);
+            FileCheck {
+                valid_up_to: read,
+                length: content.len(),
+                error: if ok { None } else { Some("RDB base is corrupted".to_string()) }
+            }
+        } else {
+            check_commands(&content)
+        };
+        let error = match result.error {
+            None => {
+                println!("{:?} is valid", file);
+                continue;
+            },
+            Some(e) => e
+        };
+        println!("0x{:x}: {}", result.valid_up_to, error);
+        let diff = result.length - result.valid_up_to;
+        println!("AOF analyzed: filename={:?}, size={}, ok_up_to={}, diff={}", file, result.length, result.valid_up_to, diff);
+        if !fix {
+            println!("AOF is not valid. Use the --fix option to try fixing it.");
+            return 1;
+        }
+        if !is_last {
+            println!("Only the last file of the AOF can be fixed, {:?} is followed by more files.", file);
+            return 1;
+        }
+        let truncated = fs::OpenOptions::new().write(true).open(file)
+            .and_then(|x| x.set_len(result.valid_up_to as u64));
+        match truncated {
+            Ok(()) => {
+                println!("Successfully truncated AOF {:?}", file);
+            },
+            Err(e) => {
+                println!("Failed to truncate AOF {:?}: {}", file, e);
+                return 1;
+            }
+        }
+    }
+    0
+}
+
+fn check_commands(content: &[u8]) -> FileCheck {
+    let mut commands: BTreeMap<String, u64> = BTreeMap::new();
+    let mut error = None;
+    // Read from the raw bytes so the offset `--fix` truncates at is right with binary values
+    let mut offset = 0;
+    while offset < content.len() {
+        match read_command(&content[offset..]) {
+            Ok((argv, length)) if !argv.is_empty() => {
+                *commands.entry(argv[0].to_uppercase()).or_default() += 1;
+                offset += length;
+            },
+            Ok(_) => {
+                error = Some(format!("Expected a command at offset {}", offset));
+                break;
+            },
+            Err(ParseError::Incomplete) => {
+                error = Some("Unexpected EOF, the last command is truncated".to_string());
+                break;
+            },
+            Err(ParseError::Invalid(_)) if content[offset] != b'*' => {
+                error = Some(format!("Expected a command at offset {}", offset));
+                break;
+            },
+            Err(ParseError::Invalid(e)) => {
+                error = Some(format!("Invalid RESP: {}", e));
+                break;
+            }
+        }
+    }
+    println!("--- Commands");
+    for (command, count) in &commands {
+        println!("{}: {}", command, count);
+    }
+    FileCheck {
+        valid_up_to: offset,
+        length: content.len(),
+        error
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::rdb::rdb::{RDBEntry, RDBFileHelper};
+
+#[derive(Default)]
+struct DbStats {
+    keys: u64,
+    expires: u64,
+    already_expired: u64
+}
+
+/// `mmdb-check-rdb <file>`: reads a dump offline and reports what it contains, or where it stops
+/// making sense. Exits with 1 when the file is corrupted.
+pub fn run(args: Vec<String>) -> i32 {
+    let file_name = match args.first() {
+        Some(x) if args.len() == 1 => x,
+        _ => {
+            println!("Usage: mmdb-check-rdb <rdb-file-name>");
+            return 1;
+        }
+    };
+    let content = match fs::read(file_name) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("Cannot open {}: {}", file_name, e);
+            return 1;
+        }
+    };
+    println!("[offset 0] Checking RDB file {}", file_name);
+    let (ok, _) = check(&content);
+    if ok {
+        println!("\\o/ RDB looks OK! \\o/");
+        0
+    } else {
+        1
+    }
+}
+
+/// Checks an RDB payload, printing a report. Returns whether it is valid and how many bytes of it
+/// were read, which lets the AOF checker validate RDB base files too.
+pub fn check(content: &[u8]) -> (bool, usize) {
+    let now = SystemTime::now();
+    let mut opcodes: BTreeMap<&'static str, u64> = BTreeMap::new();
+    let mut dbs: BTreeMap<u64, DbStats> = BTreeMap::new();
+    let mut earliest_expiry: Option<SystemTime> = None;
+    let mut latest_expiry: Option<SystemTime> = None;
+    let mut last_key: Option<(usize, String)> = None;
+    let result = RDBFileHelper::walk(content, |entry| {
+        match entry {
+            RDBEntry::Header { version } => {
+                println!("[offset 5] RDB version {}", version);
+            },
+            RDBEntry::Aux { offset, key, value } => {
+                *opcodes.entry("AUX").or_default() += 1;
+                println!("[offset {}] AUX FIELD {} = '{}'", offset, key, value);
+            },
+            RDBEntry::Function { offset, code } => {
+                *opcodes.entry("FUNCTION2").or_default() += 1;
+                println!("[offset {}] FUNCTION {}", offset, code.lines().next().unwrap_or_default());
+            },
+            RDBEntry::SelectDb { offset, db } => {
+                *opcodes.entry("SELECTDB").or_default() += 1;
+                println!("[offset {}] Selecting DB ID {}", offset, db);
+            },
+            RDBEntry::ResizeDb { offset, db_size, expires_size } => {
+                *opcodes.entry("RESIZEDB").or_default() += 1;
+                println!("[offset {}] Resize DB: {} keys, {} expires", offset, db_size, expires_size);
+            },
+            RDBEntry::Key { offset, db, key, item } => {
+                last_key = Some((offset, key));
+                *opcodes.entry("STRING").or_default() += 1;
+                let stats = dbs.entry(db).or_default();
+                stats.keys += 1;
+                if let Some(expiry) = item.expiry {
+                    *opcodes.entry("EXPIRETIME").or_default() += 1;
+                    stats.expires += 1;
+                    if expiry < now {
+                        stats.already_expired += 1;
+                    }
+                    earliest_expiry = Some(earliest_expiry.map_or(expiry, |x| x.min(expiry)));
+                    latest_expiry = Some(latest_expiry.map_or(expiry, |x| x.max(expiry)));
+                }
+            },
+            RDBEntry::Eof { offset, checksum } => {
+                *opcodes.entry("EOF").or_default() += 1;
+                match checksum {
+                    Some(x) => println!("[offset {}] Checksum OK ({:#x})", offset, x),
+                    None => println!("[offset {}] Checksum disabled", offset)
+                }
+            }
+        }
+    });
+
+    println!("--- Opcodes");
+    for (opcode, count) in &opcodes {
+        println!("{}: {}", opcode, count);
+    }
+    println!("--- Keys per DB");
+    for (db, stats) in &dbs {
+        println!("db{}: keys={} expires={} already_expired={}", db, stats.keys, stats.expires, stats.already_expired);
+    }
+    if let (Some(earliest), Some(latest)) = (earliest_expiry, latest_expiry) {
+        let as_millis = |x: SystemTime| x.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        println!("--- Expiries");
+        println!("earliest: {} ms, latest: {} ms (unix time)", as_millis(earliest), as_millis(latest));
+    }
+
+    match result {
+        Ok(read) => {
+            if read < content.len() {
+                println!("[offset {}] {} trailing bytes after the end of the RDB payload", read, content.len() - read);
+            }
+            (true, read)
+        },
+        Err(e) => {
+            println!("--- RDB ERROR DETECTED ---");
+            println!("[offset {}] {}", e.offset, e.message);
+            if let Some((offset, key)) = last_key {
+                println!("[offset {}] Last valid key read: '{}'", offset, key);
+            }
+            (false, e.offset)
+        }
+    }
+}
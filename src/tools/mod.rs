@@ -0,0 +1,3 @@
+pub mod check_rdb;
+pub mod check_aof;
+pub mod rdb_convert;
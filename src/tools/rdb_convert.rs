@@ -0,0 +1,143 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use crate::aof::aof::AppendOnlyFile;
+use crate::datastore::store::DataItem;
+use crate::helpers::json::{self, JsonValue};
+use crate::rdb::rdb::RDBFileHelper;
+
+const USAGE: &str = "Usage: mmdb-rdb-convert export [--format json|resp] <dump.rdb> [output]
+       mmdb-rdb-convert import <keys.json> <dump.rdb>";
+
+/// `mmdb-rdb-convert`: turns a dump into newline delimited JSON or into a RESP command stream that
+/// can be piped into any redis compatible server, and builds a dump back from such JSON.
+pub fn run(args: Vec<String>) -> i32 {
+    let result = match args.first().map(|x| x.as_str()) {
+        Some("export") => export(&args[1..]),
+        Some("import") => import(&args[1..]),
+        _ => Err(USAGE.to_string())
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+fn export(args: &[String]) -> Result<(), String> {
+    let mut format = "json".to_string();
+    let mut paths: Vec<&String> = vec![];
+    let mut i = 0;
+    while i < args.len() {
+        if args[i] == "--format" {
+            format = args.get(i + 1).ok_or(USAGE.to_string())?.to_lowercase();
+            i += 2;
+            continue;
+        }
+        paths.push(&args[i]);
+        i += 1;
+    }
+    let (input, output) = match paths.as_slice() {
+        [input] => (*input, None),
+        [input, output] => (*input, Some(*output)),
+        _ => return Err(USAGE.to_string())
+    };
+    let content = fs::read(input).map_err(|e| format!("Can't read {}: {}", input, e))?;
+    let data = RDBFileHelper::decode(&content).map_err(|e| format!("Can't decode {}: {}", input, e))?.data;
+    // Sorted so that exporting the same dump twice gives the same output
+    let now = SystemTime::now();
+    let data: BTreeMap<String, DataItem> = data.into_iter()
+        .filter(|(_, item)| match item.expiry { Some(x) => x > now, None => true })
+        .collect();
+
+    let exported: Vec<u8> = match format.as_str() {
+        "json" => {
+            let mut lines = String::new();
+            for (key, item) in &data {
+                let ttl = match item.expiry {
+                    Some(expiry) => expiry.duration_since(now).unwrap_or_default().as_millis() as i64,
+                    None => -1
+                };
+                lines.push_str(&format!(
+                    "{{\"key\":{},\"type\":\"string\",\"ttl\":{},\"value\":{}}}\n",
+                    json_string(key), ttl, json_string(&item.data)
+                ));
+            }
+            lines.into_bytes()
+        },
+        "resp" => {
+            AppendOnlyFile::dataset_commands(&data)
+        },
+        _ => return Err(format!("Unknown format '{}', expected json or resp", format))
+    };
+
+    match output {
+        Some(path) => fs::write(path, exported).map_err(|e| format!("Can't write {}: {}", path, e)),
+        None => std::io::stdout().write_all(&exported).map_err(|e| e.to_string())
+    }
+}
+
+fn import(args: &[String]) -> Result<(), String> {
+    let (input, output) = match args {
+        [input, output] => (input, output),
+        _ => return Err(USAGE.to_string())
+    };
+    let content = fs::read_to_string(input).map_err(|e| format!("Can't read {}: {}", input, e))?;
+    let now = SystemTime::now();
+    let mut data: HashMap<String, DataItem> = HashMap::new();
+    for (line_number, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let error = |message: &str| format!("{}:{}: {}", input, line_number + 1, message);
+        let object = match json::parse(line.as_bytes()).map_err(|e| error(&e))? {
+            x @ JsonValue::Object(_) => x,
+            _ => return Err(error("expected a JSON object"))
+        };
+        let key = match object.get("key") {
+            Some(JsonValue::String(x)) => String::from_utf8_lossy(x).to_string(),
+            _ => return Err(error("expected a string \"key\""))
+        };
+        match object.get("type") {
+            None => {},
+            Some(JsonValue::String(x)) if x == b"string" => {},
+            Some(_) => return Err(error("only keys of type \"string\" are supported"))
+        }
+        let value = match object.get("value") {
+            Some(JsonValue::String(x)) => String::from_utf8_lossy(x).to_string(),
+            _ => return Err(error("expected a string \"value\""))
+        };
+        let expiry = match object.get("ttl") {
+            None | Some(JsonValue::Null) => None,
+            Some(JsonValue::Number(x)) if *x < 0.0 => None,
+            Some(JsonValue::Number(x)) => Some(now + Duration::from_millis(*x as u64)),
+            Some(_) => return Err(error("expected a number of milliseconds for \"ttl\""))
+        };
+        data.insert(key, DataItem { data: value, expiry });
+    }
+    RDBFileHelper::save_to(Path::new(output), &data, &[]).map_err(|e| format!("Can't write {}: {}", output, e))?;
+    eprintln!("Imported {} keys into {}", data.len(), output);
+    Ok(())
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c)
+        }
+    }
+    escaped.push('"');
+    escaped
+}